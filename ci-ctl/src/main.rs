@@ -1,6 +1,12 @@
 use clap::{Parser, Subcommand};
 
-use ci_lib_core::dbctx::DbCtx;
+use std::io::Read;
+use std::sync::Arc;
+
+use chrono::{NaiveDate, TimeZone, Utc};
+
+use ci_lib_core::dbctx::{DbCtx, DbError, DEFAULT_RUN_TIMEOUT_MS, MAX_INDEXED_LOG_BYTES};
+use ci_lib_core::sql::{CredentialState, Host, JobCreation, RunnerCredential, LogQuery, MetricPolicy, RetentionPolicy, RunState};
 use ci_lib_native::{GithubApi, notifier::NotifierConfig};
 
 #[derive(Parser)]
@@ -35,6 +41,124 @@ enum Command {
         #[command(subcommand)]
         what: JobAction,
     },
+
+    /// name, label, enable, disable or retire the hosts runners connect from
+    Host {
+        #[command(subcommand)]
+        what: HostAction,
+    },
+
+    /// register, list or revoke the credentials runners authenticate with
+    Runner {
+        #[command(subcommand)]
+        what: RunnerAction,
+    },
+
+    /// set how long a repo's logs and metrics are kept. omitted limits mean "forever".
+    Retention {
+        repo_name: String,
+        /// delete artifacts of runs older than this many days
+        #[arg(long)]
+        log_days: Option<u64>,
+        /// delete metrics of runs older than this many days
+        #[arg(long)]
+        metric_days: Option<u64>,
+        /// always keep runs for this many of the most recent commits on each branch
+        #[arg(long)]
+        keep_commits: Option<u64>,
+    },
+
+    /// set how much a repo's metrics can change between commits before the change is flagged.
+    /// omitted settings keep their defaults.
+    MetricPolicy {
+        repo_name: String,
+        /// flag changes larger than this fraction of the baseline (0.05 is 5%)
+        #[arg(long)]
+        threshold: Option<f64>,
+        /// average this many of the nearest ancestor commits built on the same host as a baseline
+        #[arg(long)]
+        window: Option<u64>,
+        /// also require changes to be larger than this many standard deviations of the baseline
+        #[arg(long)]
+        noise_sigmas: Option<f64>,
+    },
+
+    /// set how long a repo's runs may take before they're killed. goodfiles can still ask for a
+    /// different timeout with `Build.timeout`. omitting the limit restores the default.
+    Timeout {
+        repo_name: String,
+        /// kill runs that take longer than this many minutes
+        #[arg(long)]
+        minutes: Option<u64>,
+    },
+
+    /// record the parents of every commit reachable in a local clone of a repo, replacing
+    /// whatever parents were guessed from push events
+    ImportAncestry {
+        repo_name: String,
+        clone_path: String,
+    },
+
+    /// delete artifacts and metrics that have outlived their repo's retention policy
+    Gc {
+        /// directory the driver stores artifacts in
+        artifact_path: String,
+    },
+
+    /// write everything in the database, and optionally every artifact, to an archive that
+    /// `import` can load into a fresh database. safe to run while the driver is running
+    Export {
+        archive_path: String,
+        /// directory the driver stores artifacts in. artifacts are only exported if this is set
+        #[arg(long)]
+        artifacts: Option<String>,
+    },
+
+    /// load an archive written by `export` into a new database at DB_PATH
+    Import {
+        archive_path: String,
+        /// directory to write the archive's artifacts to. artifacts are only imported if this is
+        /// set
+        #[arg(long)]
+        artifacts: Option<String>,
+    },
+
+    /// search the output of every run
+    Logs {
+        #[command(subcommand)]
+        what: LogsAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum LogsAction {
+    /// list lines of stdout and stderr containing some text, oldest first
+    Search {
+        text: String,
+        #[arg(long)]
+        repo: Option<String>,
+        /// a host's id or name
+        #[arg(long)]
+        host: Option<String>,
+        /// only search logs from this day (YYYY-MM-DD, UTC) on
+        #[arg(long)]
+        since: Option<String>,
+        /// only search logs from before this day (YYYY-MM-DD, UTC)
+        #[arg(long)]
+        until: Option<String>,
+        #[arg(long, default_value_t = 20)]
+        limit: u64,
+        /// how many lines to show before and after each match
+        #[arg(long, default_value_t = 2)]
+        context: u64,
+    },
+    /// index every finished log for search again, from the files in the driver's artifact
+    /// directory. logs written before search existed (schema version 16) can't be found until
+    /// this has been run once; running it again is harmless
+    Reindex {
+        /// directory the driver stores artifacts in
+        artifact_path: String,
+    },
 }
 
 #[derive(Subcommand)]
@@ -43,14 +167,88 @@ enum JobAction {
     Rerun {
         which: u32
     },
+    /// queue a finished run again. its earlier attempts, and their artifacts and metrics, are kept
+    Retry {
+        run: u64,
+    },
     RerunCommit {
-        commit: String
+        commit: String,
+        /// the repo whose job to rerun, if more than one repo has a job for this commit
+        #[arg(long)]
+        repo: Option<String>,
     },
     Create {
         repo: String,
         commit: String,
         pusher_email: String,
-    }
+    },
+    /// stop a pending or running run. a running run is stopped by the driver within a few seconds
+    Cancel {
+        run: u64,
+    },
+    /// mark a run as a baseline, so it is never garbage collected
+    Baseline {
+        run: u64,
+        /// unmark the run instead
+        #[arg(long)]
+        unset: bool,
+    },
+}
+
+// hosts are referred to by either their id or their name.
+#[derive(Subcommand)]
+enum HostAction {
+    /// list every host, including disabled and retired ones
+    List,
+    /// describe a host's hardware, labels and state
+    Show {
+        host: String,
+    },
+    /// give a host a name, or remove its name if none is provided
+    Name {
+        host: String,
+        name: Option<String>,
+    },
+    Enable {
+        host: String,
+    },
+    /// stop giving a host work until it is enabled again
+    Disable {
+        host: String,
+    },
+    /// stop giving a host work for good. its runs and metrics are kept
+    Retire {
+        host: String,
+    },
+    Label {
+        host: String,
+        #[arg(required = true)]
+        labels: Vec<String>,
+    },
+    Unlabel {
+        host: String,
+        #[arg(required = true)]
+        labels: Vec<String>,
+    },
+}
+
+// runner credentials are referred to by either their id or their name.
+#[derive(Subcommand)]
+enum RunnerAction {
+    /// issue a new credential for a runner. the token is printed once and can't be recovered
+    Register {
+        name: String,
+        /// stop accepting the credential after this many days
+        #[arg(long)]
+        expires_days: Option<u64>,
+    },
+    /// list every credential, including expired and revoked ones
+    List,
+    /// stop accepting a credential. a runner connected with it is disconnected, and its host
+    /// can only be used again with a new credential
+    Revoke {
+        runner: String,
+    },
 }
 
 #[derive(Subcommand)]
@@ -69,6 +267,89 @@ enum AddItem {
     },
 }
 
+fn open_db(config_path: &str, db_path: &str) -> DbCtx {
+    let db = match DbCtx::new(config_path, db_path) {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("[!] can't open database at {}: {}", db_path, e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = db.migrate() {
+        eprintln!("[!] can't use database at {}: {}", db_path, e);
+        std::process::exit(1);
+    }
+    db
+}
+
+fn find_host(db: &DbCtx, which: &str) -> Option<Host> {
+    let host = match which.parse::<u64>() {
+        Ok(host_id) => db.host_by_id(host_id),
+        Err(_) => db.host_by_name(which),
+    };
+    match host {
+        Ok(Some(host)) => Some(host),
+        Ok(None) => {
+            eprintln!("[-] no such host: {}", which);
+            None
+        }
+        Err(e) => {
+            eprintln!("[!] couldn't look up host {}: {}", which, e);
+            None
+        }
+    }
+}
+
+fn find_runner_credential(db: &DbCtx, which: &str) -> Option<RunnerCredential> {
+    let credential = match which.parse::<u64>() {
+        Ok(credential_id) => db.runner_credential_by_id(credential_id),
+        Err(_) => db.runner_credential_by_name(which),
+    };
+    match credential {
+        Ok(Some(credential)) => Some(credential),
+        Ok(None) => {
+            eprintln!("[-] no such runner credential: {}", which);
+            None
+        }
+        Err(e) => {
+            eprintln!("[!] couldn't look up runner credential {}: {}", which, e);
+            None
+        }
+    }
+}
+
+fn new_runner_token() -> String {
+    let mut data = [0u8; 32];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut data))
+        .expect("can read /dev/urandom");
+
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// the start of `day`, a YYYY-MM-DD date in UTC, in ms since the epoch.
+fn day_start_ms(day: &str) -> Option<u64> {
+    let date = NaiveDate::parse_from_str(day, "%Y-%m-%d").ok()?;
+    let start = Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0)?);
+    Some(start.timestamp_millis() as u64)
+}
+
+fn host_state(host: &Host) -> &'static str {
+    match (host.enabled, host.retired_time) {
+        (_, Some(_)) => "retired",
+        (true, None) => "enabled",
+        (false, None) => "disabled",
+    }
+}
+
+fn credential_state(credential: &RunnerCredential) -> &'static str {
+    match credential.state(ci_lib_core::now_ms()) {
+        CredentialState::Active => "active",
+        CredentialState::Expired => "expired",
+        CredentialState::Revoked => "revoked",
+    }
+}
+
 fn main() {
     let args = Args::parse();
 
@@ -79,57 +360,166 @@ fn main() {
         Command::Job { what } => {
             match what {
                 JobAction::List => {
-                    let db = DbCtx::new(&config_path, &db_path);
-                    let conn = db.conn.lock().unwrap();
-                    let mut query = conn.prepare(ci_lib_core::sql::SELECT_ALL_RUNS_WITH_JOB_INFO).unwrap();
-                    let mut jobs = query.query([]).unwrap();
-                    while let Some(row) = jobs.next().unwrap() {
-                        let (job_id, run_id, state, created_time, commit_id, run_preferences): (u64, u64, u64, u64, u64, Option<String>) = row.try_into().unwrap();
-
-                        eprint!("[+] {:04} ({:04}) | {: >8?} | {} | {}", run_id, job_id, state, created_time, commit_id);
-                        if let Some(run_preferences) = run_preferences {
+                    let db = open_db(&config_path, &db_path);
+                    let runs = match db.get_all_runs() {
+                        Ok(runs) => runs,
+                        Err(e) => {
+                            eprintln!("[!] couldn't list runs: {}", e);
+                            return;
+                        }
+                    };
+                    for run in runs {
+                        let job = match db.job_by_id(run.job_id) {
+                            Ok(Some(job)) => job,
+                            Ok(None) => {
+                                eprintln!("[-] run {} references missing job {}", run.id, run.job_id);
+                                continue;
+                            }
+                            Err(e) => {
+                                eprintln!("[!] couldn't look up job {}: {}", run.job_id, e);
+                                return;
+                            }
+                        };
+
+                        eprint!("[+] {:04} ({:04}) | {: >8?} | {} | {}", run.id, job.id, run.state, run.create_time, job.commit_id);
+                        if let Some(run_preferences) = job.run_preferences {
                             eprintln!(" | run preference: {}", run_preferences);
                         } else {
-                            eprintln!("");
+                            eprintln!();
                         }
                     }
                     eprintln!("jobs");
                 },
                 JobAction::Rerun { which } => {
-                    let db = DbCtx::new(&config_path, &db_path);
-                    let task_id = db.new_run(which as u64, None).expect("db can be queried").id;
-                    eprintln!("[+] rerunning job {} as task {}", which, task_id);
-                }
-                JobAction::RerunCommit { commit } => {
-                    let db = DbCtx::new(&config_path, &db_path);
-                    let job_id = db.job_for_commit(&commit).unwrap();
-                    if let Some(job_id) = job_id {
-                        let task_id = db.new_run(job_id, None).expect("db can be queried").id;
-                        eprintln!("[+] rerunning job {} (commit {}) as task {}", job_id, commit, task_id);
-                    } else {
-                        eprintln!("[-] no job for commit {}", commit);
+                    let db = open_db(&config_path, &db_path);
+                    match db.new_run(which as u64, None) {
+                        Ok(run) => eprintln!("[+] rerunning job {} as task {}", which, run.id),
+                        Err(e) => eprintln!("[!] couldn't rerun job {}: {}", which, e),
+                    }
+                }
+                JobAction::RerunCommit { commit, repo } => {
+                    let db = open_db(&config_path, &db_path);
+                    let job_id = match repo {
+                        Some(repo_name) => {
+                            let repo_id = match db.repo_id_by_name(&repo_name) {
+                                Ok(Some(repo_id)) => repo_id,
+                                Ok(None) => {
+                                    eprintln!("[-] no such repo: {}", repo_name);
+                                    return;
+                                }
+                                Err(e) => {
+                                    eprintln!("[!] couldn't look up repo {}: {}", repo_name, e);
+                                    return;
+                                }
+                            };
+                            match db.job_for_commit(repo_id, &commit) {
+                                Ok(Some(job_id)) => job_id,
+                                Ok(None) => {
+                                    eprintln!("[-] no job for commit {} in repo {}", commit, repo_name);
+                                    return;
+                                }
+                                Err(e) => {
+                                    eprintln!("[!] couldn't look up job for commit {}: {}", commit, e);
+                                    return;
+                                }
+                            }
+                        }
+                        None => {
+                            let jobs = match db.jobs_for_sha(&commit) {
+                                Ok(jobs) => jobs,
+                                Err(e) => {
+                                    eprintln!("[!] couldn't look up jobs for commit {}: {}", commit, e);
+                                    return;
+                                }
+                            };
+                            match jobs.as_slice() {
+                                [] => {
+                                    eprintln!("[-] no job for commit {}", commit);
+                                    return;
+                                }
+                                [job] => job.id,
+                                _ => {
+                                    eprintln!("[-] commit {} has jobs in more than one repo, pick one with --repo:", commit);
+                                    for job in jobs.iter() {
+                                        let repo_name = db.repo_id_by_remote(job.remote_id).ok().flatten()
+                                            .and_then(|repo_id| db.repo_by_id(repo_id).ok().flatten())
+                                            .map(|repo| repo.name)
+                                            .unwrap_or_else(|| "<unknown>".to_owned());
+                                        eprintln!("  job {} in repo {}", job.id, repo_name);
+                                    }
+                                    return;
+                                }
+                            }
+                        }
+                    };
+                    match db.new_run(job_id, None) {
+                        Ok(run) => eprintln!("[+] rerunning job {} (commit {}) as task {}", job_id, commit, run.id),
+                        Err(e) => eprintln!("[!] couldn't rerun job {}: {}", job_id, e),
                     }
                 }
                 JobAction::Create { repo, commit, pusher_email } => {
-                    let db = DbCtx::new(&config_path, &db_path);
+                    let db = open_db(&config_path, &db_path);
                     let parts = repo.split(":").collect::<Vec<&str>>();
                     let (remote_kind, repo_path) = (parts[0], parts[1]);
-                    let remote = match db.remote_by_path_and_api(&remote_kind, &repo_path).expect("can query") {
-                        Some(remote) => remote,
-                        None => {
+                    let remote = match db.remote_by_path_and_api(remote_kind, repo_path) {
+                        Ok(Some(remote)) => remote,
+                        Ok(None) => {
                             eprintln!("[-] no remote registered as {}:{}", remote_kind, repo_path);
                             return;
                         }
+                        Err(e) => {
+                            eprintln!("[!] couldn't look up remote {}:{}: {}", remote_kind, repo_path, e);
+                            return;
+                        }
                     };
 
-                    let repo_default_run_pref: Option<String> = db.conn.lock().unwrap()
-                        .query_row("select default_run_preference from repos where id=?1;", [remote.repo_id], |row| {
-                            Ok((row.get(0)).unwrap())
-                        })
-                        .expect("can query");
-
-                    let (job_id, _commit_id) = db.new_job(remote.id, &commit, Some(&pusher_email), repo_default_run_pref).expect("can create");
-                    let _ = db.new_run(job_id, None).unwrap();
+                    match db.create_job(remote.id, &commit, Some(&pusher_email)) {
+                        Ok(JobCreation::Created { job_id, run_id, .. }) => {
+                            eprintln!("[+] created job {} for commit {} as task {}", job_id, commit, run_id);
+                        }
+                        Ok(JobCreation::Existing { job_id, .. }) => {
+                            eprintln!("[-] commit {} already has job {}", commit, job_id);
+                        }
+                        Err(DbError::NotFound) => {
+                            eprintln!("[-] remote {}:{} references a missing repo", remote_kind, repo_path);
+                        }
+                        Err(e) => {
+                            eprintln!("[!] couldn't create job for commit {}: {}", commit, e);
+                        }
+                    }
+                }
+                JobAction::Retry { run } => {
+                    let db = open_db(&config_path, &db_path);
+                    match db.retry_run(run) {
+                        Ok(()) => eprintln!("[+] run {} queued for another attempt", run),
+                        Err(DbError::NotFound) => eprintln!("[-] no finished run {}", run),
+                        Err(e) => eprintln!("[!] couldn't retry run {}: {}", run, e),
+                    }
+                }
+                JobAction::Cancel { run } => {
+                    let db = Arc::new(open_db(&config_path, &db_path));
+                    match db.cancel_run(run) {
+                        Ok(RunState::Cancelled) => {
+                            eprintln!("[+] run {} cancelled", run);
+                            tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async move {
+                                if let Err(e) = ci_lib_native::dbctx_ext::notify_cancelled(&db, run).await {
+                                    eprintln!("[!] couldn't notify cancellation of run {}: {}", run, e);
+                                }
+                            });
+                        }
+                        Ok(_) => eprintln!("[+] run {} will be stopped by the driver shortly", run),
+                        Err(DbError::NotFound) => eprintln!("[-] no pending or running run {}", run),
+                        Err(e) => eprintln!("[!] couldn't cancel run {}: {}", run, e),
+                    }
+                }
+                JobAction::Baseline { run, unset } => {
+                    let db = open_db(&config_path, &db_path);
+                    match db.set_run_baseline(run, !unset) {
+                        Ok(()) if unset => eprintln!("[+] run {} is no longer a baseline", run),
+                        Ok(()) => eprintln!("[+] run {} is now a baseline", run),
+                        Err(DbError::NotFound) => eprintln!("[-] no run {}", run),
+                        Err(e) => eprintln!("[!] couldn't update run {}: {}", run, e),
+                    }
                 }
             }
         },
@@ -154,23 +544,22 @@ fn main() {
                         }
                     };
 
-                    let db = DbCtx::new(&config_path, &db_path);
+                    let db = open_db(&config_path, &db_path);
                     let repo_id = match db.new_repo(&name) {
                         Ok(repo_id) => repo_id,
+                        Err(DbError::ConstraintViolation(_)) => {
+                            eprintln!("[!] repo '{}' already exists", name);
+                            return;
+                        }
                         Err(e) => {
-                            if e.contains("UNIQUE constraint failed") {
-                                eprintln!("[!] repo '{}' already exists", name);
-                                return;
-                            } else {
-                                eprintln!("[!] failed to create repo entry: {}", e);
-                                return;
-                            }
+                            eprintln!("[!] failed to create repo entry: {}", e);
+                            return;
                         }
                     };
                     println!("[+] new repo created: '{}' id {}", &name, repo_id);
                     if let Some((remote, remote_kind, config_path)) = remote_config {
                         let full_config_file_path = format!("{}/{}", &db.config_path.display(), config_path);
-                        let _config = match remote_kind.as_ref() {
+                        match remote_kind.as_ref() {
                             "github" => {
                                 assert!(NotifierConfig::github_from_file(&full_config_file_path).is_ok());
                             }
@@ -181,47 +570,47 @@ fn main() {
                                 panic!("[-] notifiers for '{}' remotes are not supported", other);
                             }
                         };
-                        db.new_remote(repo_id, remote.as_str(), remote_kind.as_str(), config_path.as_str()).unwrap();
+                        if let Err(e) = db.new_remote(repo_id, remote.as_str(), remote_kind.as_str(), config_path.as_str()) {
+                            eprintln!("[!] failed to create remote entry: {}", e);
+                            return;
+                        }
                         println!("[+] new remote created: repo '{}', {} remote at {}", &name, remote_kind, remote);
-                        match remote_kind.as_str() {
-                            "github" => {
-                                // attempt to create a webhook now...
-                                let (ci_server, token, webhook_token) = match NotifierConfig::github_from_file(&full_config_file_path).expect("notifier config is valid") {
-                                    NotifierConfig::GitHub { ci_server, token, webhook_token } => (ci_server, token, webhook_token),
-                                    _ => {
-                                        panic!("unexpected notifier config format, should have been github..")
-                                    }
-                                };
-                                let gh = GithubApi { ci_server: &ci_server, token: &token, webhook_token: &webhook_token };
-                                tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async move {
-                                    match gh.has_ci_webhook(remote.as_str()).await {
-                                        Ok(present) => {
-                                            if !present {
-                                                println!("[.] trying to create push webhook on github.com/{}", remote.as_str());
-                                                let res = gh.create_ci_webhook(remote.as_str()).await;
-                                                if let Err(e) = res {
-                                                    println!("[!] failed to create webhook on github.com/{}: {}", remote.as_str(), e);
-                                                } else {
-                                                    println!("[+] created webhook on github.com/{}. CI is good to go?", remote.as_str());
-                                                }
+                        if remote_kind.as_str() == "github" {
+                            // attempt to create a webhook now...
+                            let (ci_server, token, webhook_token) = match NotifierConfig::github_from_file(&full_config_file_path).expect("notifier config is valid") {
+                                NotifierConfig::GitHub { ci_server, token, webhook_token } => (ci_server, token, webhook_token),
+                                _ => {
+                                    panic!("unexpected notifier config format, should have been github..")
+                                }
+                            };
+                            let gh = GithubApi { ci_server: &ci_server, token: &token, webhook_token: &webhook_token };
+                            tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async move {
+                                match gh.has_ci_webhook(remote.as_str()).await {
+                                    Ok(present) => {
+                                        if !present {
+                                            println!("[.] trying to create push webhook on github.com/{}", remote.as_str());
+                                            let res = gh.create_ci_webhook(remote.as_str()).await;
+                                            if let Err(e) = res {
+                                                println!("[!] failed to create webhook on github.com/{}: {}", remote.as_str(), e);
                                             } else {
-                                                println!("[+] ci.butactuallyin.space webhook appears to already be present on github.com/{}", remote.as_str());
+                                                println!("[+] created webhook on github.com/{}. CI is good to go?", remote.as_str());
                                             }
-                                        }
-                                        Err(e) => {
-                                            println!("[!] unable to check for presence of ci.butactuallin.space webhook on github.com/{}: {}", remote.as_str(), e);
-                                            println!("[!] you must make sure your github repo has a webhook set for `https://ci.butactuallyin.space/{}` to receive at least the `push` event.", remote.as_str());
-                                            println!("      the secret sent with calls to this webhook should be the same preshared secret as the CI server is configured to know.");
+                                        } else {
+                                            println!("[+] ci.butactuallyin.space webhook appears to already be present on github.com/{}", remote.as_str());
                                         }
                                     }
-                                });
-                            }
-                            _ => { }
+                                    Err(e) => {
+                                        println!("[!] unable to check for presence of ci.butactuallin.space webhook on github.com/{}: {}", remote.as_str(), e);
+                                        println!("[!] you must make sure your github repo has a webhook set for `https://ci.butactuallyin.space/{}` to receive at least the `push` event.", remote.as_str());
+                                        println!("      the secret sent with calls to this webhook should be the same preshared secret as the CI server is configured to know.");
+                                    }
+                                }
+                            });
                         }
                     }
                 },
                 AddItem::Remote { repo_name, remote, remote_kind, config } => {
-                    let db = DbCtx::new(&config_path, &db_path);
+                    let db = open_db(&config_path, &db_path);
                     let repo_id = match db.repo_id_by_name(&repo_name) {
                         Ok(Some(id)) => id,
                         Ok(None) => {
@@ -245,11 +634,474 @@ fn main() {
                             panic!("notifiers for '{}' remotes are not supported", other);
                         }
                     };
-                    db.new_remote(repo_id, remote.as_str(), remote_kind.as_str(), config.as_str()).unwrap();
+                    if let Err(e) = db.new_remote(repo_id, remote.as_str(), remote_kind.as_str(), config.as_str()) {
+                        eprintln!("[!] failed to create remote entry: {}", e);
+                        return;
+                    }
                     println!("[+] new remote created: repo '{}', {} remote at {}", &repo_name, remote_kind, remote);
                 },
             }
         },
+        Command::Host { what } => {
+            let db = open_db(&config_path, &db_path);
+            match what {
+                HostAction::List => {
+                    let hosts = match db.all_hosts() {
+                        Ok(hosts) => hosts,
+                        Err(e) => {
+                            eprintln!("[!] couldn't list hosts: {}", e);
+                            return;
+                        }
+                    };
+                    for host in hosts {
+                        let labels = match db.host_labels(host.id) {
+                            Ok(labels) => labels,
+                            Err(e) => {
+                                eprintln!("[!] couldn't look up labels for host {}: {}", host.id, e);
+                                return;
+                            }
+                        };
+                        println!("[+] {:04} | {: <20} | {: <8} | {} | {}",
+                            host.id, host.display_name(), host_state(&host), host.cpu_model_name, labels.join(","));
+                    }
+                }
+                HostAction::Show { host } => {
+                    let host = match find_host(&db, &host) {
+                        Some(host) => host,
+                        None => { return; }
+                    };
+                    let labels = match db.host_labels(host.id) {
+                        Ok(labels) => labels,
+                        Err(e) => {
+                            eprintln!("[!] couldn't look up labels for host {}: {}", host.id, e);
+                            return;
+                        }
+                    };
+                    println!("id:        {}", host.id);
+                    println!("runner id: {}", host.runner_id.as_deref().unwrap_or("<none>"));
+                    println!("name:      {}", host.name.as_deref().unwrap_or("<none>"));
+                    println!("state:     {}", host_state(&host));
+                    if let Some(retired_time) = host.retired_time {
+                        println!("retired:   {}", retired_time);
+                    }
+                    println!("labels:    {}", labels.join(", "));
+                    println!("hostname:  {}", host.hostname);
+                    println!("cpu:       {} {} (family {}, model {}, microcode {})",
+                        host.cpu_vendor_id, host.cpu_model_name, host.cpu_family, host.cpu_model, host.cpu_microcode);
+                    println!("           {} cores, {}khz max", host.cpu_cores, host.cpu_max_freq_khz);
+                    println!("memory:    {}", host.mem_total);
+                    println!("env:       {} {} {}", host.arch, host.family, host.os);
+
+                    let facts = match db.host_facts(host.id) {
+                        Ok(facts) => facts,
+                        Err(e) => {
+                            eprintln!("[!] couldn't look up hardware history for host {}: {}", host.id, e);
+                            return;
+                        }
+                    };
+                    println!("hardware history:");
+                    for fact in facts {
+                        let seen = match (fact.first_seen, fact.last_seen) {
+                            (Some(first_seen), Some(last_seen)) => format!("seen {} to {}", first_seen, last_seen),
+                            _ => "seen before hardware was tracked".to_string(),
+                        };
+                        println!("  v{}: {} | microcode {} | {} | {} {} | {}",
+                            fact.version, fact.hostname, fact.cpu_microcode, fact.mem_total, fact.family, fact.os, seen);
+                    }
+                }
+                HostAction::Name { host, name } => {
+                    let host = match find_host(&db, &host) {
+                        Some(host) => host,
+                        None => { return; }
+                    };
+                    match db.set_host_name(host.id, name.as_deref()) {
+                        Ok(()) => match name {
+                            Some(name) => println!("[+] host {} is now named '{}'", host.id, name),
+                            None => println!("[+] host {} no longer has a name", host.id),
+                        },
+                        Err(DbError::ConstraintViolation(_)) => {
+                            eprintln!("[-] another host is already named '{}'", name.unwrap_or_default());
+                        }
+                        Err(e) => eprintln!("[!] couldn't name host {}: {}", host.id, e),
+                    }
+                }
+                HostAction::Enable { host } => {
+                    let host = match find_host(&db, &host) {
+                        Some(host) => host,
+                        None => { return; }
+                    };
+                    if host.retired_time.is_some() {
+                        eprintln!("[-] host {} is retired and can't be enabled again", host.id);
+                        return;
+                    }
+                    match db.set_host_enabled(host.id, true) {
+                        Ok(()) => println!("[+] host {} ({}) enabled", host.id, host.display_name()),
+                        Err(e) => eprintln!("[!] couldn't enable host {}: {}", host.id, e),
+                    }
+                }
+                HostAction::Disable { host } => {
+                    let host = match find_host(&db, &host) {
+                        Some(host) => host,
+                        None => { return; }
+                    };
+                    match db.set_host_enabled(host.id, false) {
+                        Ok(()) => println!("[+] host {} ({}) disabled", host.id, host.display_name()),
+                        Err(e) => eprintln!("[!] couldn't disable host {}: {}", host.id, e),
+                    }
+                }
+                HostAction::Retire { host } => {
+                    let host = match find_host(&db, &host) {
+                        Some(host) => host,
+                        None => { return; }
+                    };
+                    match db.retire_host(host.id) {
+                        Ok(()) => println!("[+] host {} ({}) retired", host.id, host.display_name()),
+                        Err(e) => eprintln!("[!] couldn't retire host {}: {}", host.id, e),
+                    }
+                }
+                HostAction::Label { host, labels } => {
+                    let host = match find_host(&db, &host) {
+                        Some(host) => host,
+                        None => { return; }
+                    };
+                    for label in labels.iter() {
+                        if let Err(e) = db.add_host_label(host.id, label) {
+                            eprintln!("[!] couldn't label host {} with '{}': {}", host.id, label, e);
+                            return;
+                        }
+                    }
+                    println!("[+] labeled host {} ({}) with {}", host.id, host.display_name(), labels.join(", "));
+                }
+                HostAction::Unlabel { host, labels } => {
+                    let host = match find_host(&db, &host) {
+                        Some(host) => host,
+                        None => { return; }
+                    };
+                    for label in labels.iter() {
+                        match db.remove_host_label(host.id, label) {
+                            Ok(()) => println!("[+] removed label '{}' from host {}", label, host.id),
+                            Err(DbError::NotFound) => eprintln!("[-] host {} isn't labeled '{}'", host.id, label),
+                            Err(e) => {
+                                eprintln!("[!] couldn't remove label '{}' from host {}: {}", label, host.id, e);
+                                return;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Command::Runner { what } => {
+            let db = open_db(&config_path, &db_path);
+            match what {
+                RunnerAction::Register { name, expires_days } => {
+                    let token = new_runner_token();
+                    let expires_time = expires_days.map(|days| ci_lib_core::now_ms() + days * 24 * 60 * 60 * 1000);
+                    match db.add_runner_credential(&name, &token, expires_time) {
+                        Ok(credential_id) => {
+                            println!("[+] registered runner '{}' as credential {}", name, credential_id);
+                            println!("[+] token: {}", token);
+                            println!("[+] set this as `auth_secret` in the runner's config. it won't be shown again.");
+                        }
+                        Err(DbError::ConstraintViolation(_)) => {
+                            eprintln!("[-] a runner named '{}' is already registered", name);
+                        }
+                        Err(e) => eprintln!("[!] couldn't register runner '{}': {}", name, e),
+                    }
+                }
+                RunnerAction::List => {
+                    let credentials = match db.all_runner_credentials() {
+                        Ok(credentials) => credentials,
+                        Err(e) => {
+                            eprintln!("[!] couldn't list runner credentials: {}", e);
+                            return;
+                        }
+                    };
+                    for credential in credentials {
+                        let host = credential.host_id.map(|id| format!("host {}", id)).unwrap_or_else(|| "unbound".to_string());
+                        let last_used = credential.last_used_time.map(|t| t.to_string()).unwrap_or_else(|| "never".to_string());
+                        let expires = credential.expires_time.map(|t| t.to_string()).unwrap_or_else(|| "never".to_string());
+                        println!("[+] {:04} | {: <20} | {: <8} | {: <10} | last used {} | expires {}",
+                            credential.id, credential.name, credential_state(&credential), host, last_used, expires);
+                    }
+                }
+                RunnerAction::Revoke { runner } => {
+                    let credential = match find_runner_credential(&db, &runner) {
+                        Some(credential) => credential,
+                        None => { return; }
+                    };
+                    match db.revoke_runner_credential(credential.id) {
+                        Ok(()) => {
+                            println!("[+] revoked runner credential {} ({})", credential.id, credential.name);
+                            println!("[+] a runner connected with it will be disconnected within a few seconds");
+                        }
+                        Err(e) => eprintln!("[!] couldn't revoke runner credential {}: {}", credential.id, e),
+                    }
+                }
+            }
+        }
+        Command::Retention { repo_name, log_days, metric_days, keep_commits } => {
+            let db = open_db(&config_path, &db_path);
+            let repo_id = match db.repo_id_by_name(&repo_name) {
+                Ok(Some(id)) => id,
+                Ok(None) => {
+                    eprintln!("[-] repo '{}' does not exist", repo_name);
+                    return;
+                },
+                Err(e) => {
+                    eprintln!("[!] couldn't look up repo '{}': {}", repo_name, e);
+                    return;
+                }
+            };
+            let policy = RetentionPolicy { repo_id, log_days, metric_days, keep_commits };
+            match db.set_retention_policy(&policy) {
+                Ok(()) => println!("[+] retention for '{}': {:?}", repo_name, policy),
+                Err(e) => eprintln!("[!] couldn't set retention policy: {}", e),
+            }
+        }
+        Command::MetricPolicy { repo_name, threshold, window, noise_sigmas } => {
+            let db = open_db(&config_path, &db_path);
+            let repo_id = match db.repo_id_by_name(&repo_name) {
+                Ok(Some(id)) => id,
+                Ok(None) => {
+                    eprintln!("[-] repo '{}' does not exist", repo_name);
+                    return;
+                },
+                Err(e) => {
+                    eprintln!("[!] couldn't look up repo '{}': {}", repo_name, e);
+                    return;
+                }
+            };
+            let defaults = MetricPolicy::default_for(repo_id);
+            let policy = MetricPolicy {
+                repo_id,
+                threshold: threshold.unwrap_or(defaults.threshold),
+                window: window.unwrap_or(defaults.window),
+                noise_sigmas,
+            };
+            match db.set_metric_policy(&policy) {
+                Ok(()) => println!("[+] metric policy for '{}': {:?}", repo_name, policy),
+                Err(e) => eprintln!("[!] couldn't set metric policy: {}", e),
+            }
+        }
+        Command::Timeout { repo_name, minutes } => {
+            let db = open_db(&config_path, &db_path);
+            let repo_id = match db.repo_id_by_name(&repo_name) {
+                Ok(Some(id)) => id,
+                Ok(None) => {
+                    eprintln!("[-] repo '{}' does not exist", repo_name);
+                    return;
+                },
+                Err(e) => {
+                    eprintln!("[!] couldn't look up repo '{}': {}", repo_name, e);
+                    return;
+                }
+            };
+            match db.set_repo_run_timeout(repo_id, minutes.map(|minutes| minutes * 60 * 1000)) {
+                Ok(()) => match minutes {
+                    Some(minutes) => println!("[+] runs of '{}' time out after {} minutes", repo_name, minutes),
+                    None => println!("[+] runs of '{}' time out after the default {} minutes", repo_name, DEFAULT_RUN_TIMEOUT_MS / 60 / 1000),
+                },
+                Err(e) => eprintln!("[!] couldn't set run timeout: {}", e),
+            }
+        }
+        Command::ImportAncestry { repo_name, clone_path } => {
+            let db = open_db(&config_path, &db_path);
+            let repo_id = match db.repo_id_by_name(&repo_name) {
+                Ok(Some(id)) => id,
+                Ok(None) => {
+                    eprintln!("[-] repo '{}' does not exist", repo_name);
+                    return;
+                },
+                Err(e) => {
+                    eprintln!("[!] couldn't look up repo '{}': {}", repo_name, e);
+                    return;
+                }
+            };
+            let rev_list = std::process::Command::new("git")
+                .arg("-C")
+                .arg(&clone_path)
+                .args(["rev-list", "--parents", "--all"])
+                .output();
+            let rev_list = match rev_list {
+                Ok(output) if output.status.success() => output.stdout,
+                Ok(output) => {
+                    eprintln!("[!] git rev-list failed: {}", String::from_utf8_lossy(&output.stderr));
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("[!] couldn't run git: {}", e);
+                    return;
+                }
+            };
+
+            let mut imported = 0;
+            for line in String::from_utf8_lossy(&rev_list).lines() {
+                let mut shas = line.split_whitespace();
+                let sha = match shas.next() {
+                    Some(sha) => sha,
+                    None => { continue; }
+                };
+                let parents: Vec<&str> = shas.collect();
+                if let Err(e) = db.replace_commit_parents(repo_id, sha, &parents) {
+                    eprintln!("[!] couldn't record parents of {}: {}", sha, e);
+                    return;
+                }
+                imported += 1;
+            }
+            println!("[+] recorded parents for {} commits in '{}'", imported, repo_name);
+        }
+        Command::Gc { artifact_path } => {
+            let db = open_db(&config_path, &db_path);
+            match ci_lib_native::gc::collect_garbage(&db, std::path::Path::new(&artifact_path)) {
+                Ok(report) => {
+                    println!("[+] collected {} runs: {} artifacts ({}kb), {} metrics",
+                        report.runs_collected, report.artifacts_deleted, report.bytes_freed / 1024, report.metrics_deleted);
+                }
+                Err(e) => {
+                    eprintln!("[!] gc failed: {}", e);
+                }
+            }
+        }
+        Command::Export { archive_path, artifacts } => {
+            let db = open_db(&config_path, &db_path);
+            let file = match std::fs::OpenOptions::new().write(true).create_new(true).open(&archive_path) {
+                Ok(file) => file,
+                Err(e) => {
+                    eprintln!("[!] can't create {}: {}", archive_path, e);
+                    return;
+                }
+            };
+            let mut out = std::io::BufWriter::new(file);
+            match db.export(&mut out, artifacts.as_ref().map(std::path::Path::new)) {
+                Ok(report) => {
+                    println!("[+] exported {} rows at schema version {}, and {} artifacts ({}kb)",
+                        report.rows, report.schema_version, report.artifact_files, report.artifact_bytes / 1024);
+                }
+                Err(e) => {
+                    eprintln!("[!] export failed, {} is incomplete: {}", archive_path, e);
+                }
+            }
+        }
+        Command::Import { archive_path, artifacts } => {
+            if std::path::Path::new(&db_path).exists() {
+                eprintln!("[-] {} already exists, import into a new database instead", db_path);
+                return;
+            }
+            let file = match std::fs::File::open(&archive_path) {
+                Ok(file) => file,
+                Err(e) => {
+                    eprintln!("[!] can't open {}: {}", archive_path, e);
+                    return;
+                }
+            };
+            let db = match DbCtx::new(&config_path, &db_path) {
+                Ok(db) => db,
+                Err(e) => {
+                    eprintln!("[!] can't create database at {}: {}", db_path, e);
+                    return;
+                }
+            };
+            match db.import(std::io::BufReader::new(file), artifacts.as_ref().map(std::path::Path::new)) {
+                Ok(report) => {
+                    println!("[+] imported {} rows from schema version {}, and {} artifacts ({}kb)",
+                        report.rows, report.schema_version, report.artifact_files, report.artifact_bytes / 1024);
+                    if !report.skipped.is_empty() {
+                        println!("[-] this database has nowhere to put: {}", report.skipped.join(", "));
+                    }
+                }
+                Err(e) => {
+                    eprintln!("[!] import failed, {} is empty and can be removed: {}", db_path, e);
+                }
+            }
+        }
+        Command::Logs { what } => {
+            match what {
+                LogsAction::Search { text, repo, host, since, until, limit, context } => {
+                    let db = open_db(&config_path, &db_path);
+                    let host_id = match host {
+                        Some(host) => match find_host(&db, &host) {
+                            Some(host) => Some(host.id),
+                            None => { return; }
+                        },
+                        None => None,
+                    };
+                    let since = match since.as_deref().map(day_start_ms) {
+                        Some(None) => {
+                            eprintln!("[-] --since must be a date like 2023-07-01");
+                            return;
+                        }
+                        since => since.flatten(),
+                    };
+                    let until = match until.as_deref().map(day_start_ms) {
+                        Some(None) => {
+                            eprintln!("[-] --until must be a date like 2023-07-01");
+                            return;
+                        }
+                        until => until.flatten(),
+                    };
+
+                    let query = LogQuery {
+                        text,
+                        repo_name: repo,
+                        host_id,
+                        since,
+                        until,
+                        limit,
+                        context,
+                    };
+                    let matches = match db.search_logs(&query) {
+                        Ok(matches) => matches,
+                        Err(e) => {
+                            eprintln!("[!] couldn't search logs: {}", e);
+                            return;
+                        }
+                    };
+                    for log_match in matches.iter() {
+                        let created_time = Utc.timestamp_millis_opt(log_match.created_time as i64).unwrap().to_rfc2822();
+                        let host = log_match.host_id.map(|host_id| format!("host {}", host_id)).unwrap_or_else(|| "no host".to_owned());
+                        println!("[+] run {} | {} | {} | {} | {} | {}, line {}",
+                            log_match.run_id, log_match.repo_name, &log_match.sha[..9.min(log_match.sha.len())], host, created_time,
+                            log_match.artifact_name, log_match.line_number);
+                        for (line_number, line) in log_match.lines.iter() {
+                            let marker = if *line_number == log_match.line_number { ">" } else { " " };
+                            println!("  {} {:>6} | {}", marker, line_number, line);
+                        }
+                    }
+                    println!("[+] {} matches", matches.len());
+                }
+                LogsAction::Reindex { artifact_path } => {
+                    use std::io::Read;
+
+                    let db = open_db(&config_path, &db_path);
+                    let artifacts = match db.completed_log_artifacts() {
+                        Ok(artifacts) => artifacts,
+                        Err(e) => {
+                            eprintln!("[!] couldn't list logs: {}", e);
+                            return;
+                        }
+                    };
+                    let mut reindexed = 0;
+                    for artifact in artifacts.iter() {
+                        let artifact_file = std::path::Path::new(&artifact_path)
+                            .join(artifact.run_id.to_string())
+                            .join(artifact.id.to_string());
+                        let mut log = Vec::new();
+                        let res = std::fs::File::open(&artifact_file)
+                            .and_then(|file| file.take(MAX_INDEXED_LOG_BYTES).read_to_end(&mut log));
+                        if let Err(e) = res {
+                            eprintln!("[-] couldn't read log {} ({}): {}", artifact.id, artifact_file.display(), e);
+                            continue;
+                        }
+                        if let Err(e) = db.reindex_log(artifact.id, &log) {
+                            eprintln!("[!] couldn't index log {}: {}", artifact.id, e);
+                            return;
+                        }
+                        reindexed += 1;
+                    }
+                    println!("[+] reindexed {} of {} logs", reindexed, artifacts.len());
+                }
+            }
+        }
         Command::Validate => {
             println!("ok");
         }
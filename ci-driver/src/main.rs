@@ -5,10 +5,9 @@ use std::io::Read;
 use futures_util::StreamExt;
 use std::fmt;
 use std::path::PathBuf;
-use tokio::spawn;
+use tokio::{spawn, task::spawn_blocking};
 use tokio_stream::wrappers::ReceiverStream;
 use std::sync::{Arc, Weak};
-use std::time::{SystemTime, UNIX_EPOCH};
 use axum_server::tls_rustls::RustlsConfig;
 use axum::body::StreamBody;
 use axum::http::{StatusCode};
@@ -17,17 +16,16 @@ use axum::Router;
 use axum::routing::*;
 use axum::extract::State;
 use axum::extract::BodyStream;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
 use axum::response::IntoResponse;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::error::TrySendError;
 use serde::{Deserialize, Serialize};
 
-use ci_lib_core::dbctx::DbCtx;
+use ci_lib_core::dbctx::{DbCtx, DbError, DEFAULT_RUN_TIMEOUT_MS};
 use ci_lib_core::sql;
-use ci_lib_core::sql::{PendingRun, Job, Run};
-use ci_lib_core::sql::JobResult;
-use ci_lib_core::sql::RunState;
-use ci_lib_core::protocol::{ClientProto, TaskInfo, RequestedJob};
+use ci_lib_core::sql::{CredentialState, MetricFinding, PendingRun, Job, RunnerCredential};
+use ci_lib_core::protocol::{self, ClientProto, CommandInfo, FrameDecoder, Negotiated, TaskOutcome, TerminateReason, RequestedJob};
 
 lazy_static! {
     static ref AUTH_SECRET: RwLock<Option<String>> = RwLock::new(None);
@@ -51,24 +49,26 @@ fn reserve_artifacts_dir(mut artifact_path: PathBuf, run: u64) -> std::io::Resul
     }
 }
 
-async fn activate_run(dbctx: Arc<DbCtx>, candidate: RunnerClient, artifact_path: PathBuf, job: &Job, run: &PendingRun) -> Result<(), String> {
+async fn activate_run(dbctx: Arc<DbCtx>, candidate: RunnerClient, artifact_path: PathBuf, job: &Job, run: &PendingRun, max_requeues: u32) -> Result<(), String> {
     eprintln!("activating task {:?}", run);
 
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("now is before epoch")
-        .as_millis();
+    let remote = dbctx.remote_by_id(job.remote_id)
+        .map_err(|e| format!("failed to look up remote {}: {}", job.remote_id, e))?
+        .ok_or_else(|| format!("job {} has no remote", job.id))?;
+    let repo = dbctx.repo_by_id(remote.repo_id)
+        .map_err(|e| format!("failed to look up repo {}: {}", remote.repo_id, e))?
+        .ok_or_else(|| format!("remote {} has no repo", remote.id))?;
 
-    let remote = dbctx.remote_by_id(job.remote_id).expect("query succeeds").expect("job has remote");
-    let repo = dbctx.repo_by_id(remote.repo_id).expect("query succeeds").expect("remote has repo");
-
-    let commit_sha = dbctx.commit_sha(job.commit_id).expect("query succeeds");
+    let commit_sha = dbctx.commit_sha(job.commit_id)
+        .map_err(|e| format!("failed to look up commit {}: {}", job.commit_id, e))?;
 
     let artifacts: PathBuf = reserve_artifacts_dir(artifact_path, run.id).expect("can reserve a directory for artifacts");
 
     eprintln!("running {}", &repo.name);
 
-    let res = candidate.submit(&dbctx, &run, &remote.remote_git_url, &commit_sha).await;
+    let timeout_ms = repo.default_run_timeout.unwrap_or(DEFAULT_RUN_TIMEOUT_MS);
+
+    let res = candidate.submit(&dbctx, run, &remote.remote_git_url, &commit_sha, &artifacts, timeout_ms, max_requeues).await;
 
     let mut client_job = match res {
         Ok(Some(client_job)) => { client_job }
@@ -81,16 +81,6 @@ async fn activate_run(dbctx: Arc<DbCtx>, candidate: RunnerClient, artifact_path:
         }
     };
 
-    let host_id = client_job.client.host_id;
-
-    let connection = dbctx.conn.lock().unwrap();
-    connection.execute(
-        "update runs set started_time=?1, host_id=?2, state=1, artifacts_path=?3, build_token=?4 where id=?5",
-        (now as u64, host_id, format!("{}", artifacts.display()), &client_job.client.build_token, run.id)
-    )
-        .expect("can update");
-    std::mem::drop(connection);
-
     spawn(async move {
         client_job.run().await
     });
@@ -98,10 +88,85 @@ async fn activate_run(dbctx: Arc<DbCtx>, candidate: RunnerClient, artifact_path:
     Ok(())
 }
 
+/// how messages get between the driver and a runner. runners pick one in their config, and either
+/// way the messages are the same `ClientProto`s.
+#[allow(clippy::large_enum_variant)]
+enum RunnerTransport {
+    /// a long-lived `POST /api/next_job`: the runner writes to the request body, and the driver
+    /// writes to the response.
+    Http {
+        tx: mpsc::Sender<Result<String, String>>,
+        rx: BodyStream,
+        // holds whatever has been read from `rx` past the last whole message.
+        frames: FrameDecoder,
+    },
+    /// a websocket from `/api/next_job/ws`, with one message per text frame.
+    WebSocket(WebSocket),
+}
+
+impl RunnerTransport {
+    async fn send_typed<T: serde::Serialize>(&mut self, msg: &T) -> Result<(), String> {
+        let frame = protocol::encode_frame(msg)
+            .map_err(|e| format!("json error: {:?}", e))?;
+        match self {
+            RunnerTransport::Http { tx, .. } => {
+                tx.send(Ok(frame))
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+            RunnerTransport::WebSocket(socket) => {
+                socket.send(Message::Text(frame))
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    async fn recv_typed<T: serde::de::DeserializeOwned>(&mut self) -> Result<Option<T>, String> {
+        match self {
+            RunnerTransport::Http { rx, frames, .. } => {
+                recv_frame(rx, frames).await
+            }
+            RunnerTransport::WebSocket(socket) => {
+                loop {
+                    let frame = match socket.recv().await {
+                        Some(Ok(Message::Text(text))) => text.into_bytes(),
+                        Some(Ok(Message::Binary(bytes))) => bytes,
+                        // pings are answered for us, and pongs only say the runner is there.
+                        Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => continue,
+                        Some(Ok(Message::Close(_))) | None => return Ok(None),
+                        // most often the connection was reset out from under us.
+                        Some(Err(e)) => return Err(format!("websocket connection lost: {}", e)),
+                    };
+                    return serde_json::from_slice(&frame)
+                        .map(Option::Some)
+                        .map_err(|e| e.to_string());
+                }
+            }
+        }
+    }
+
+    // keep the connection looking busy to anything between us and the runner. plain http has no
+    // way to do this without the runner's help.
+    async fn keepalive(&mut self) -> Result<(), String> {
+        match self {
+            RunnerTransport::Http { .. } => Ok(()),
+            RunnerTransport::WebSocket(socket) => {
+                socket.send(Message::Ping(Vec::new()))
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
 struct RunnerClient {
-    tx: mpsc::Sender<Result<String, String>>,
-    rx: BodyStream,
+    transport: RunnerTransport,
+    protocol: Negotiated,
     host_id: u32,
+    // the credential the runner authenticated with. `None` for runners still using the driver's
+    // shared secret.
+    credential_id: Option<u64>,
     build_token: String,
     accepted_sources: Option<Vec<String>>,
 }
@@ -122,78 +187,246 @@ struct ClientJob {
     remote_git_url: String,
     sha: String,
     task: PendingRun,
+    // the attempt of `task` this client is running. metrics and the final status are recorded
+    // against it.
+    attempt_id: u64,
+    started_ms: u64,
+    // when the attempt times out, in ms since the epoch. once it has been terminated, this is
+    // instead when we stop waiting for the runner to hang up.
+    deadline_ms: u64,
+    terminated: Option<TerminateReason>,
+    last_heartbeat_ms: u64,
+    // the last time the runner sent anything at all. only meaningful for runners that send
+    // heartbeats; for others, silence is normal.
+    last_seen_ms: u64,
+    // the runner has reported how the attempt ended, so there's nothing left to keep alive.
+    complete: bool,
+    // how many times the run may be requeued after an infrastructure error.
+    max_requeues: u32,
     client: RunnerClient,
     // exists only as confirmation this `ClientJob` is somewhere, still alive and being processed.
     task_witness: Arc<()>,
 }
 
 impl ClientJob {
+    // tell everyone who wants to know about `task`'s repo that it's done. this takes `&mut self`
+    // only because a websocket isn't `Sync`, so a `&ClientJob` can't be held across an await.
+    async fn notify_complete(&mut self, outcome: TaskOutcome, desc: &str, findings: &[MetricFinding]) {
+        if let Err(e) = ci_lib_native::dbctx_ext::notify_complete(&self.dbctx, self.task.id, outcome, desc, findings).await {
+            eprintln!("[-] could not notify completion of run {}: {}", self.task.id, e);
+        }
+    }
+
+    // the attempt has run past its deadline or been cancelled: record as much, and tell the
+    // runner to stop. the runner keeps its connection until it has uploaded what it can.
+    async fn terminate(&mut self, reason: TerminateReason) {
+        let outcome = reason.outcome();
+        let desc = match reason {
+            TerminateReason::TimedOut => {
+                let timeout_ms = self.deadline_ms.saturating_sub(self.started_ms);
+                format!("timed out after {}s", timeout_ms / 1000)
+            }
+            TerminateReason::Cancelled => {
+                "cancelled".to_string()
+            }
+        };
+        eprintln!("[-] run {} (attempt {}) {}", self.task.id, self.attempt_id, desc);
+
+        if let Err(e) = self.dbctx.complete_attempt(self.attempt_id, outcome, &desc) {
+            eprintln!("[-] could not record end of run {} (attempt {}): {}", self.task.id, self.attempt_id, e);
+        }
+
+        if let Err(e) = self.client.send_typed(&ClientProto::terminate(reason)).await {
+            eprintln!("[-] could not tell runner to stop run {}: {}", self.task.id, e);
+        }
+
+        self.terminated = Some(reason);
+        self.deadline_ms = ci_lib_core::now_ms() + TERMINATE_GRACE_MS;
+
+        self.notify_complete(outcome, &desc, &[]).await;
+    }
+
+    fn sends_heartbeats(&self) -> bool {
+        self.client.protocol.has_feature(protocol::FEATURE_HEARTBEAT)
+    }
+
+    // the runner is still connected, so as far as the reaper is concerned this attempt is alive.
+    // runners that send heartbeats of their own are only alive as long as they keep sending them.
+    fn heartbeat(&mut self) {
+        let now = ci_lib_core::now_ms();
+        if self.sends_heartbeats() || self.complete || self.terminated.is_some() || now < self.last_heartbeat_ms + CONNECTED_HEARTBEAT_RECORD_MS {
+            return;
+        }
+
+        if let Err(e) = self.dbctx.record_heartbeat(self.attempt_id) {
+            eprintln!("[-] could not record heartbeat for run {} (attempt {}): {}", self.task.id, self.attempt_id, e);
+        }
+        self.last_heartbeat_ms = now;
+    }
+
+    // time the attempt out if it's past its deadline. a runner that's already been stopped and
+    // is still connected after its grace period is given up on, and `false` says as much.
+    async fn enforce_deadline(&mut self) -> bool {
+        if ci_lib_core::now_ms() < self.deadline_ms {
+            return true;
+        }
+        if self.terminated.is_some() {
+            eprintln!("[-] runner for run {} did not hang up after being stopped, giving up on it", self.task.id);
+            return false;
+        }
+        self.terminate(TerminateReason::TimedOut).await;
+        true
+    }
+
+    // a revoked runner doesn't get to finish, or even upload what it has. the caller hangs up on it.
+    async fn drop_revoked_runner(&mut self) {
+        eprintln!("[-] credential for run {} (attempt {}) is no longer usable, disconnecting its runner", self.task.id, self.attempt_id);
+        if self.terminated.is_none() {
+            abandon_attempt(&self.dbctx, self.task.id, self.attempt_id, "credential revocation", self.max_requeues).await;
+        }
+    }
+
+    // everything that needs looking at whether or not the runner has anything to say: whether
+    // it's still there, still allowed to be, and whether anyone has cancelled its run. `false`
+    // means the runner has been given up on.
+    async fn check_in(&mut self) -> bool {
+        if let Err(e) = self.client.transport.keepalive().await {
+            // a websocket that's been reset fails here, possibly before it fails a read.
+            eprintln!("[-] could not ping runner for run {} (attempt {}): {}", self.task.id, self.attempt_id, e);
+            if !self.complete && self.terminated.is_none() {
+                abandon_attempt(&self.dbctx, self.task.id, self.attempt_id, "a broken connection", self.max_requeues).await;
+            }
+            return false;
+        }
+        let now = ci_lib_core::now_ms();
+        let silent = now.saturating_sub(self.last_seen_ms) > LOST_RUNNER_TIMEOUT_MS;
+        if self.sends_heartbeats() && silent && !self.complete {
+            // whether or not it was stopped, a runner this quiet isn't coming back.
+            eprintln!("[-] runner for run {} (attempt {}) hasn't been heard from in {}ms", self.task.id, self.attempt_id, now - self.last_seen_ms);
+            if self.terminated.is_none() {
+                abandon_attempt(&self.dbctx, self.task.id, self.attempt_id, "missed heartbeats", self.max_requeues).await;
+            }
+            return false;
+        }
+        if !self.complete && !credential_usable(&self.dbctx, self.client.credential_id) {
+            self.drop_revoked_runner().await;
+            return false;
+        }
+        if self.terminated.is_none() {
+            match self.dbctx.cancel_requested(self.task.id) {
+                Ok(true) => self.terminate(TerminateReason::Cancelled).await,
+                Ok(false) => {}
+                Err(e) => eprintln!("[-] could not check whether run {} was cancelled: {}", self.task.id, e),
+            }
+        }
+        true
+    }
+
     pub async fn run(&mut self) {
+        // a single timer across the whole attempt, so messages from the runner can't keep
+        // pushing these checks back.
+        let mut checks = tokio::time::interval_at(tokio::time::Instant::now() + CANCEL_POLL_INTERVAL, CANCEL_POLL_INTERVAL);
+        checks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
         loop {
+            // checked on every trip around, so a runner that never goes quiet still times out.
+            if !self.enforce_deadline().await {
+                return;
+            }
+            self.heartbeat();
             eprintln!("waiting on response..");
-            let msg = match self.client.recv_typed::<ClientProto>().await.expect("recv works") {
-                Some(msg) => msg,
-                None => {
+            let until_deadline = std::time::Duration::from_millis(self.deadline_ms.saturating_sub(ci_lib_core::now_ms()));
+            let deadline = tokio::time::Instant::now() + until_deadline;
+            let msg = tokio::select! {
+                msg = self.client.recv_typed::<ClientProto>() => msg,
+                _ = tokio::time::sleep_until(deadline) => continue,
+                _ = checks.tick() => {
+                    if !self.check_in().await {
+                        return;
+                    }
+                    continue;
+                }
+            };
+            let msg = match msg {
+                Ok(Some(msg)) => msg,
+                Ok(None) => {
                     eprintln!("client hung up. task's done, i hope?");
                     return;
                 }
+                Err(e) => {
+                    // a broken connection or a garbled message: either way there's no telling what
+                    // the runner meant, so it's treated the same as a runner that went quiet.
+                    eprintln!("[-] could not read from runner for run {} (attempt {}): {}", self.task.id, self.attempt_id, e);
+                    if !self.complete && self.terminated.is_none() {
+                        abandon_attempt(&self.dbctx, self.task.id, self.attempt_id, "a broken connection", self.max_requeues).await;
+                    }
+                    return;
+                }
             };
+            self.last_seen_ms = ci_lib_core::now_ms();
             eprintln!("got {:?}", msg);
             match msg {
-                ClientProto::NewTaskPlease { allowed_pushers: _, host_info: _ } => {
+                ClientProto::NewTaskPlease { .. } => {
                     eprintln!("misdirected task request (after handshake?)");
                     return;
                 }
+                ClientProto::TaskStatus(_) if self.terminated.is_some() => {
+                    eprintln!("[.] run {} was already stopped, ignoring its final status", self.task.id);
+                }
+                ClientProto::TaskStatus(_) if !credential_usable(&self.dbctx, self.client.credential_id) => {
+                    // revoked since the last check in: its word on how the run went isn't taken.
+                    self.drop_revoked_runner().await;
+                    return;
+                }
                 ClientProto::TaskStatus(task_info) => {
-                    let (result, state): (Result<String, String>, RunState) = match task_info {
-                        TaskInfo::Finished { status } => {
-                            eprintln!("task update: state is finished and result is {}", status);
-                            match status.as_str() {
-                                "pass" => {
-                                    (Ok("success".to_string()), RunState::Finished)
-                                },
-                                other => {
-                                    eprintln!("unhandled task completion status: {}", other);
-                                    (Err(other.to_string()), RunState::Error)
-                                }
+                    let outcome = task_info.outcome();
+                    let desc = task_info.description().to_string();
+                    eprintln!("task update: run {} ended: {} ({})", self.task.id, outcome.summary(), desc);
+
+                    self.complete = true;
+
+                    // the runner couldn't do its job, but another runner might.
+                    if outcome.is_retryable() {
+                        match self.dbctx.requeue_attempt(self.attempt_id, outcome.summary(), self.max_requeues) {
+                            Ok(true) => {
+                                eprintln!("[!] run {} (attempt {}) hit an infrastructure error, requeued", self.task.id, self.attempt_id);
+                                continue;
+                            }
+                            Ok(false) => {}
+                            Err(e) => {
+                                eprintln!("[-] could not requeue run {} (attempt {}): {}", self.task.id, self.attempt_id, e);
                             }
-                        },
-                        TaskInfo::Interrupted { status, description } => {
-                            eprintln!("task update: state is interrupted and result is {}", status);
-                            let desc = description.unwrap_or_else(|| status.clone());
-                            (Err(desc), RunState::Error)
-                        }
-                    };
-
-                    let job = self.dbctx.job_by_id(self.task.job_id).expect("can query").expect("job exists");
-                    let repo_id = self.dbctx.repo_id_by_remote(job.remote_id).unwrap().expect("remote exists");
-
-                    for notifier in ci_lib_native::dbctx_ext::notifiers_by_repo(&self.dbctx, repo_id).expect("can get notifiers") {
-                        if let Err(e) = notifier.tell_complete_job(&self.dbctx, repo_id, &self.sha, self.task.id, result.clone()).await {
-                            eprintln!("could not notify {:?}: {:?}", notifier.remote_path, e);
                         }
                     }
 
-                    let now = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .expect("now is before epoch")
-                        .as_millis();
+                    if let Err(e) = self.dbctx.complete_attempt(self.attempt_id, outcome, &desc) {
+                        eprintln!("[-] could not record completion of run {} (attempt {}): {}", self.task.id, self.attempt_id, e);
+                    }
 
-                    let build_result = if result.is_ok() {
-                        JobResult::Pass
+                    // metrics from a build that didn't pass aren't worth comparing.
+                    let findings = if outcome == TaskOutcome::Passed {
+                        match ci_lib_native::regressions::detect_metric_changes(&self.dbctx, self.attempt_id) {
+                            Ok(findings) => findings,
+                            Err(e) => {
+                                eprintln!("[-] could not compare metrics for run {} (attempt {}): {}", self.task.id, self.attempt_id, e);
+                                Vec::new()
+                            }
+                        }
                     } else {
-                        JobResult::Fail
-                    };
-                    let result_desc = match result {
-                        Ok(msg) => msg,
-                        Err(msg) => msg,
+                        Vec::new()
                     };
 
-                    self.dbctx.conn.lock().unwrap().execute(
-                        "update runs set complete_time=?1, state=?2, build_result=?3, final_status=?4 where id=?5",
-                        (now as u64, state as u64, build_result as u8, result_desc, self.task.id)
-                    )
-                        .expect("can update");
+                    self.notify_complete(outcome, &desc, &findings).await;
+                }
+                ClientProto::SetTimeout { timeout_ms } => {
+                    if self.terminated.is_some() {
+                        eprintln!("[.] run {} was already stopped, ignoring new timeout of {}ms", self.task.id, timeout_ms);
+                        continue;
+                    }
+                    eprintln!("[.] run {} (attempt {}) now times out after {}ms", self.task.id, self.attempt_id, timeout_ms);
+                    if let Err(e) = self.dbctx.set_run_timeout(self.task.id, timeout_ms) {
+                        eprintln!("[-] could not record new timeout for run {}: {}", self.task.id, e);
+                    }
+                    self.deadline_ms = self.started_ms.saturating_add(timeout_ms);
                 }
                 ClientProto::ArtifactCreate => {
                     eprintln!("creating artifact");
@@ -203,12 +436,31 @@ impl ClientJob {
                     })).await.unwrap();
                 },
                 ClientProto::Metric { name, value } => {
-                    self.dbctx.insert_metric(self.task.id, &name, &value)
-                        .expect("TODO handle metric insert error?");
+                    if let Err(e) = self.dbctx.insert_metric(self.attempt_id, &name, &value) {
+                        eprintln!("[-] could not record metric {} for run {} (attempt {}): {}", name, self.task.id, self.attempt_id, e);
+                    }
+                }
+                ClientProto::Command(CommandInfo::Started { command, cwd, id, step, stdout_artifact, stderr_artifact }) => {
+                    if let Err(e) = self.dbctx.start_command(self.attempt_id, id, &command, cwd.as_deref(), &step) {
+                        eprintln!("[-] could not record start of command {} for run {} (attempt {}): {}", id, self.task.id, self.attempt_id, e);
+                        continue;
+                    }
+                    if stdout_artifact.is_some() || stderr_artifact.is_some() {
+                        if let Err(e) = self.dbctx.link_command_artifacts(self.attempt_id, id, stdout_artifact.as_deref(), stderr_artifact.as_deref()) {
+                            eprintln!("[-] could not link output of command {} for run {} (attempt {}): {}", id, self.task.id, self.attempt_id, e);
+                        }
+                    }
                 }
-                ClientProto::Command(_command_info) => {
-                    // record information about commands, start/stop, etc. probably also allow
-                    // artifacts to be attached to commands and default to attaching stdout/stderr?
+                ClientProto::Heartbeat(_) if self.complete || self.terminated.is_some() => {}
+                ClientProto::Heartbeat(info) => {
+                    if let Err(e) = self.dbctx.record_runner_heartbeat(self.attempt_id, &info) {
+                        eprintln!("[-] could not record heartbeat for run {} (attempt {}): {}", self.task.id, self.attempt_id, e);
+                    }
+                }
+                ClientProto::Command(CommandInfo::Finished { exit_code, id }) => {
+                    if let Err(e) = self.dbctx.finish_command(self.attempt_id, id, exit_code) {
+                        eprintln!("[-] could not record end of command {} for run {} (attempt {}): {}", id, self.task.id, self.attempt_id, e);
+                    }
                 }
                 other => {
                     eprintln!("unhandled message {:?}", other);
@@ -218,13 +470,40 @@ impl ClientJob {
     }
 }
 
+// read the next message a runner sent, however the body happened to be chunked. `None` means the
+// runner hung up between messages.
+async fn recv_frame<T: serde::de::DeserializeOwned>(rx: &mut BodyStream, frames: &mut FrameDecoder) -> Result<Option<T>, String> {
+    loop {
+        if let Some(frame) = frames.next_frame().map_err(|e| e.to_string())? {
+            return serde_json::from_slice(&frame)
+                .map(Option::Some)
+                .map_err(|e| e.to_string());
+        }
+
+        match rx.next().await {
+            Some(Ok(bytes)) => {
+                frames.push(&bytes);
+            }
+            Some(Err(e)) => {
+                eprintln!("e: {:?}", e);
+                return Err(format!("no client job: {:?}", e));
+            }
+            None => {
+                frames.finish().map_err(|e| e.to_string())?;
+                return Ok(None);
+            }
+        }
+    }
+}
+
 impl RunnerClient {
-    async fn new(sender: mpsc::Sender<Result<String, String>>, resp: BodyStream, accepted_sources: Option<Vec<String>>, host_id: u32) -> Result<Self, String> {
+    async fn new(transport: RunnerTransport, protocol: Negotiated, accepted_sources: Option<Vec<String>>, host_id: u32, credential_id: Option<u64>) -> Result<Self, String> {
         let token = token_for_job();
         let client = RunnerClient {
-            tx: sender,
-            rx: resp,
+            transport,
+            protocol,
             host_id,
+            credential_id,
             build_token: token,
             accepted_sources,
         };
@@ -252,31 +531,11 @@ impl RunnerClient {
     }
 
     async fn send_typed<T: serde::Serialize>(&mut self, msg: &T) -> Result<(), String> {
-        self.tx.send(Ok(serde_json::to_string(msg).unwrap()))
-            .await
-            .map_err(|e| e.to_string())
-    }
-
-    async fn recv(&mut self) -> Result<Option<serde_json::Value>, String> {
-        match self.rx.next().await {
-            Some(Ok(bytes)) => {
-                serde_json::from_slice(&bytes)
-                    .map(Option::Some)
-                    .map_err(|e| e.to_string())
-            },
-            Some(Err(e)) => {
-                eprintln!("e: {:?}", e);
-                Err(format!("no client job: {:?}", e))
-            },
-            None => {
-                Ok(None)
-            }
-        }
+        self.transport.send_typed(msg).await
     }
 
     async fn recv_typed<T: serde::de::DeserializeOwned>(&mut self) -> Result<Option<T>, String> {
-        let json = self.recv().await?;
-        Ok(json.map(|v| serde_json::from_value(v).unwrap()))
+        self.transport.recv_typed().await
     }
 
     // is this client willing to run the job based on what it has told us so far?
@@ -290,18 +549,31 @@ impl RunnerClient {
         }
     }
 
-    async fn submit(mut self, dbctx: &Arc<DbCtx>, job: &PendingRun, remote_git_url: &str, sha: &str) -> Result<Option<ClientJob>, String> {
+    #[allow(clippy::too_many_arguments)]
+    async fn submit(mut self, dbctx: &Arc<DbCtx>, job: &PendingRun, remote_git_url: &str, sha: &str, artifacts: &std::path::Path, timeout_ms: u64, max_requeues: u32) -> Result<Option<ClientJob>, String> {
         self.send_typed(&ClientProto::new_task(RequestedJob {
             commit: sha.to_string(),
             remote_url: remote_git_url.to_string(),
             build_token: self.build_token.to_string(),
+            timeout_ms: Some(timeout_ms),
         })).await?;
         match self.recv_typed::<ClientProto>().await {
             Ok(Some(ClientProto::Started)) => {
+                let attempt_id = dbctx.start_run(job.id, self.host_id, &format!("{}", artifacts.display()), &self.build_token, timeout_ms)
+                    .map_err(|e| format!("failed to record start of run {}: {}", job.id, e))?;
+                let started_ms = ci_lib_core::now_ms();
                 let task_witness = Arc::new(());
                 ACTIVE_TASKS.lock().unwrap().insert(job.id, Arc::downgrade(&task_witness));
                 Ok(Some(ClientJob {
                     task: job.clone(),
+                    attempt_id,
+                    started_ms,
+                    deadline_ms: started_ms + timeout_ms,
+                    terminated: None,
+                    last_heartbeat_ms: started_ms,
+                    last_seen_ms: started_ms,
+                    complete: false,
+                    max_requeues,
                     dbctx: Arc::clone(dbctx),
                     sha: sha.to_string(),
                     remote_git_url: remote_git_url.to_string(),
@@ -341,12 +613,16 @@ async fn handle_artifact(State(ctx): State<DriverState>, headers: HeaderMap, art
         }
     };
 
-    let (run, artifact_path, token_validity) = match ctx.dbctx.run_for_token(&run_token).unwrap() {
-        Some(result) => result,
-        None => {
+    let (attempt_id, run, artifact_path, token_validity) = match ctx.dbctx.attempt_for_token(run_token) {
+        Ok(Some(attempt)) => (attempt.attempt_id, attempt.run_id, attempt.artifacts_path, attempt.validity),
+        Ok(None) => {
             eprintln!("bad artifact post: headers: {:?}\nrun token is not known", headers);
             return (StatusCode::BAD_REQUEST, "").into_response();
         }
+        Err(e) => {
+            eprintln!("[-] could not look up run token: {}", e);
+            return (ci_lib_native::db_error_status(&e), "").into_response();
+        }
     };
 
     if token_validity != sql::TokenValidity::Valid {
@@ -377,7 +653,7 @@ async fn handle_artifact(State(ctx): State<DriverState>, headers: HeaderMap, art
         }
     };
 
-    let mut artifact = match ci_lib_native::dbctx_ext::reserve_artifact(&ctx.dbctx, ctx.artifact_path, run, artifact_name, artifact_desc).await {
+    let mut artifact = match ci_lib_native::dbctx_ext::reserve_artifact(&ctx.dbctx, ctx.artifact_path, run, attempt_id, artifact_name, artifact_desc).await {
         Ok(artifact) => artifact,
         Err(err) => {
             eprintln!("failure to reserve artifact: {:?}", err);
@@ -388,74 +664,226 @@ async fn handle_artifact(State(ctx): State<DriverState>, headers: HeaderMap, art
     eprintln!("spawning task...");
     let dbctx_ref = Arc::clone(&ctx.dbctx);
     spawn(async move {
-        artifact.store_all(artifact_content).await.unwrap();
-        dbctx_ref.finalize_artifact(artifact.artifact_id).await.unwrap();
+        if let Err(e) = artifact.store_all(artifact_content).await {
+            // runners that are stopped or cancelled hang up mid-upload. whatever made it here is
+            // still the artifact, and it isn't going to get any more complete.
+            eprintln!("[-] upload of artifact {} was cut short, keeping what arrived: {}", artifact.artifact_id, e);
+        }
+        // indexing a log reads all of it, which is no work for an executor thread.
+        let artifact_id = artifact.artifact_id;
+        let artifact_file = artifact.path().to_owned();
+        let finalized = spawn_blocking(move || dbctx_ref.finalize_artifact(artifact_id, &artifact_file)).await;
+        match finalized {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => eprintln!("[-] could not finalize artifact {}: {}", artifact_id, e),
+            Err(e) => eprintln!("[-] finalizing artifact {} failed: {}", artifact_id, e),
+        }
     });
     eprintln!("done?");
 
     (StatusCode::OK, "").into_response()
 }
 
-#[derive(Serialize, Deserialize)]
-struct WorkRequest {
-    kind: String,
-    accepted_pushers: Option<Vec<String>>
+/// who a runner authenticated as.
+enum RunnerAuth {
+    /// a credential from `ci_ctl runner register`.
+    Credential(RunnerCredential),
+    /// the driver's shared `auth_secret`, for runners that haven't been given a credential yet.
+    SharedSecret,
 }
 
-async fn handle_next_job(State(ctx): State<DriverState>, headers: HeaderMap, mut job_resp: BodyStream) -> impl IntoResponse {
-    let _auth_token = match headers.get("authorization") {
-        Some(token) => {
-            if Some(token.to_str().unwrap_or("")) != AUTH_SECRET.read().unwrap().as_ref().map(|x| &**x) {
-                eprintln!("BAD AUTH SECRET SUBMITTED: {:?}", token);
-                return (StatusCode::BAD_REQUEST, "").into_response();
-            }
-        }
+// runners authenticate with their own credential or, if the driver still has one, its shared
+// secret, however they connect.
+fn authenticate_runner(dbctx: &DbCtx, headers: &HeaderMap) -> Option<RunnerAuth> {
+    let token = match headers.get("authorization") {
+        Some(token) => token.to_str().unwrap_or(""),
         None => {
-            eprintln!("bad artifact post: headers: {:?}\nno authorization", headers);
-            return (StatusCode::BAD_REQUEST, "").into_response();
+            eprintln!("bad runner connection: headers: {:?}\nno authorization", headers);
+            return None;
         }
     };
 
-    let (tx_sender, tx_receiver) = mpsc::channel(8);
-    let resp_body = StreamBody::new(ReceiverStream::new(tx_receiver));
-    tx_sender.send(Ok("hello".to_string())).await.expect("works");
+    match dbctx.authenticate_runner(token) {
+        Ok(Some(credential)) => {
+            match credential.state(ci_lib_core::now_ms()) {
+                CredentialState::Active => {
+                    return Some(RunnerAuth::Credential(credential));
+                }
+                state => {
+                    eprintln!("[-] runner credential {} ({}) is {:?}, refusing connection", credential.id, credential.name, state);
+                    return None;
+                }
+            }
+        }
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("[-] could not look up runner credential: {}", e);
+            return None;
+        }
+    }
+
+    if Some(token) != AUTH_SECRET.read().unwrap().as_deref() {
+        eprintln!("BAD AUTH SECRET SUBMITTED: {:?}", token);
+        return None;
+    }
 
-    let request = job_resp.next().await.expect("request chunk").expect("chunk exists");
-    let request = std::str::from_utf8(&request).unwrap();
-    let request: ClientProto = match serde_json::from_str(&request) {
-        Ok(v) => v,
+    Some(RunnerAuth::SharedSecret)
+}
+
+// whether a runner that authenticated with `credential_id` may keep its connection. an operator
+// may revoke a credential at any time, so this is checked while the runner waits for and runs work.
+fn credential_usable(dbctx: &DbCtx, credential_id: Option<u64>) -> bool {
+    let credential_id = match credential_id {
+        Some(credential_id) => credential_id,
+        None => { return true; }
+    };
+
+    match dbctx.runner_credential_by_id(credential_id) {
+        Ok(Some(credential)) => credential.state(ci_lib_core::now_ms()) == CredentialState::Active,
+        Ok(None) => false,
         Err(e) => {
-            eprintln!("couldn't parse work request: {:?}", e);
-            return (StatusCode::MISDIRECTED_REQUEST, resp_body).into_response();
+            // don't drop a runner over a busy database; the next check will tell.
+            eprintln!("[-] could not look up runner credential {}: {}", credential_id, e);
+            true
+        }
+    }
+}
+
+/// why a runner didn't make it through `handshake`.
+#[derive(Debug)]
+enum HandshakeError {
+    /// the runner hung up, sent something other than `NewTaskPlease`, or sent nothing at all.
+    Misdirected(String),
+    /// the runner took too long to say anything. it may be older than this driver.
+    TooSlow,
+    /// the driver and runner have no protocol version in common. the runner has been told why.
+    Rejected,
+    /// the runner's credential isn't for the host it's running on, or the host needs a credential
+    /// the runner doesn't have. the runner has been told why.
+    Forbidden,
+    Db(DbError),
+}
+
+// read a runner's `NewTaskPlease`, settle on a protocol with it, and work out which host it is.
+// this is the same for every transport.
+async fn handshake(dbctx: &DbCtx, mut transport: RunnerTransport, auth: RunnerAuth) -> Result<RunnerClient, HandshakeError> {
+    // runners from before messages were framed never finish their request, so give up on a
+    // handshake that takes too long rather than waiting on it forever.
+    let request = tokio::time::timeout(HANDSHAKE_TIMEOUT, transport.recv_typed::<ClientProto>()).await;
+    let request: ClientProto = match request {
+        Ok(Ok(Some(v))) => v,
+        Ok(Ok(None)) => {
+            return Err(HandshakeError::Misdirected("runner hung up before asking for work".to_string()));
+        }
+        Ok(Err(e)) => {
+            return Err(HandshakeError::Misdirected(format!("couldn't parse work request: {:?}", e)));
+        }
+        Err(_) => {
+            return Err(HandshakeError::TooSlow);
         }
     };
-    let (accepted_pushers, host_info) = match request {
-        ClientProto::NewTaskPlease { allowed_pushers, host_info } => (allowed_pushers, host_info),
+    let (accepted_pushers, host_info, runner_id, protocol_versions, features) = match request {
+        ClientProto::NewTaskPlease { allowed_pushers, host_info, runner_id, protocol_versions, features } => {
+            (allowed_pushers, host_info, runner_id, protocol_versions, features)
+        }
         other => {
-            eprintln!("bad request kind: {:?}", &other);
-            return (StatusCode::MISDIRECTED_REQUEST, resp_body).into_response();
+            return Err(HandshakeError::Misdirected(format!("bad request kind: {:?}", &other)));
+        }
+    };
+
+    let protocol = match protocol::negotiate(&protocol_versions, &features) {
+        Ok(protocol) => protocol,
+        Err(reason) => {
+            eprintln!("[-] rejecting runner {:?} on {}: {}", runner_id, host_info.hostname, reason);
+            if let Err(e) = transport.send_typed(&ClientProto::rejected(reason)).await {
+                eprintln!("[-] could not tell runner why it was rejected: {}", e);
+            }
+            return Err(HandshakeError::Rejected);
         }
     };
 
-    eprintln!("client identifies itself as {:?}", host_info);
+    eprintln!("client identifies itself as {:?} on {:?}, speaking protocol {:?}", runner_id, host_info, protocol);
 
-    let host_info_id = ctx.dbctx.id_for_host(&host_info).expect("can get a host info id");
+    let credential_id = match &auth {
+        RunnerAuth::Credential(credential) => Some(credential.id),
+        RunnerAuth::SharedSecret => None,
+    };
 
-    let client = match RunnerClient::new(tx_sender, job_resp, accepted_pushers, host_info_id).await {
-        Ok(v) => v,
+    // the runner's claim to be this host is checked before anything it says about the host is
+    // believed.
+    let host_id = match runner_id.as_ref() {
+        Some(runner_id) => dbctx.id_for_runner(runner_id, &host_info, credential_id),
+        None => dbctx.id_for_host(&host_info, credential_id),
+    };
+    let host_info_id = match host_id {
+        Ok(host_id) => host_id,
+        Err(DbError::ConstraintViolation(reason)) => {
+            let reason = match &auth {
+                RunnerAuth::Credential(credential) => format!("credential '{}' can't be used on {}: {}", credential.name, host_info.hostname, reason),
+                RunnerAuth::SharedSecret => reason,
+            };
+            eprintln!("[-] rejecting runner {:?} on {}: {}", runner_id, host_info.hostname, reason);
+            if let Err(e) = transport.send_typed(&ClientProto::rejected(reason)).await {
+                eprintln!("[-] could not tell runner why it was rejected: {}", e);
+            }
+            return Err(HandshakeError::Forbidden);
+        }
         Err(e) => {
-            eprintln!("unable to register client: {}", e);
+            eprintln!("[-] could not get a host id for {:?}: {}", host_info, e);
+            return Err(HandshakeError::Db(e));
+        }
+    };
+
+    transport.send_typed(&ClientProto::hello(&protocol)).await
+        .map_err(|e| HandshakeError::Misdirected(format!("could not say hello: {}", e)))?;
+
+    RunnerClient::new(transport, protocol, accepted_pushers, host_info_id, credential_id).await
+        .map_err(|e| HandshakeError::Misdirected(format!("unable to register client: {}", e)))
+}
+
+async fn handle_next_job(State(ctx): State<DriverState>, headers: HeaderMap, job_resp: BodyStream) -> impl IntoResponse {
+    let auth = match authenticate_runner(&ctx.dbctx, &headers) {
+        Some(auth) => auth,
+        None => { return (StatusCode::BAD_REQUEST, "").into_response(); }
+    };
+
+    let (tx_sender, tx_receiver) = mpsc::channel(8);
+    let resp_body = StreamBody::new(ReceiverStream::new(tx_receiver));
+
+    let transport = RunnerTransport::Http {
+        tx: tx_sender,
+        rx: job_resp,
+        frames: FrameDecoder::new(),
+    };
+
+    let client = match handshake(&ctx.dbctx, transport, auth).await {
+        Ok(client) => client,
+        Err(HandshakeError::Misdirected(e)) => {
+            eprintln!("{}", e);
             return (StatusCode::MISDIRECTED_REQUEST, resp_body).into_response();
         }
+        Err(HandshakeError::TooSlow) => {
+            eprintln!("[-] runner didn't finish asking for work within {:?}. it may be older than this driver", HANDSHAKE_TIMEOUT);
+            return (StatusCode::UPGRADE_REQUIRED, resp_body).into_response();
+        }
+        Err(HandshakeError::Rejected) => {
+            return (StatusCode::UPGRADE_REQUIRED, resp_body).into_response();
+        }
+        Err(HandshakeError::Forbidden) => {
+            return (StatusCode::FORBIDDEN, resp_body).into_response();
+        }
+        Err(HandshakeError::Db(e)) => {
+            return (ci_lib_native::db_error_status(&e), resp_body).into_response();
+        }
     };
 
     match ctx.client_sender.try_send(client) {
         Ok(()) => {
             eprintln!("client requested work...");
-            return (StatusCode::OK, resp_body).into_response();
+            (StatusCode::OK, resp_body).into_response()
         }
         Err(TrySendError::Full(_client)) => {
-            return (StatusCode::IM_A_TEAPOT, resp_body).into_response();
+            (StatusCode::IM_A_TEAPOT, resp_body).into_response()
         }
         Err(TrySendError::Closed(_client)) => {
             panic!("client holder is gone?");
@@ -463,11 +891,43 @@ async fn handle_next_job(State(ctx): State<DriverState>, headers: HeaderMap, mut
     }
 }
 
+// the same as `handle_next_job`, over a websocket. there's no status code to send once the socket
+// is up, so a runner that can't be served is just told so (if it can be) and hung up on.
+async fn handle_next_job_ws(State(ctx): State<DriverState>, headers: HeaderMap, upgrade: WebSocketUpgrade) -> impl IntoResponse {
+    let auth = match authenticate_runner(&ctx.dbctx, &headers) {
+        Some(auth) => auth,
+        None => { return (StatusCode::BAD_REQUEST, "").into_response(); }
+    };
+
+    upgrade.on_upgrade(move |socket| async move {
+        let client = match handshake(&ctx.dbctx, RunnerTransport::WebSocket(socket), auth).await {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("[-] websocket runner handshake failed: {:?}", e);
+                return;
+            }
+        };
+
+        match ctx.client_sender.try_send(client) {
+            Ok(()) => {
+                eprintln!("client requested work over a websocket...");
+            }
+            Err(TrySendError::Full(_client)) => {
+                eprintln!("[-] too many runners waiting for work, hanging up on a websocket runner");
+            }
+            Err(TrySendError::Closed(_client)) => {
+                panic!("client holder is gone?");
+            }
+        }
+    }).into_response()
+}
+
 async fn make_api_server(artifact_path: PathBuf, dbctx: Arc<DbCtx>) -> (Router, mpsc::Receiver<RunnerClient>) {
     let (pending_client_sender, pending_client_receiver) = mpsc::channel(8);
 
     let router = Router::new()
         .route("/api/next_job", post(handle_next_job))
+        .route("/api/next_job/ws", get(handle_next_job_ws))
         .route("/api/artifact", post(handle_artifact))
         .with_state(DriverState{
             artifact_path,
@@ -492,7 +952,11 @@ struct DriverConfig {
     db_path: PathBuf,
     artifact_path: PathBuf,
     server_addr: String,
-    auth_secret: String,
+    /// a secret shared by every runner without a credential of its own. runners should be moved
+    /// to credentials from `ci_ctl runner register`, after which this can be removed.
+    auth_secret: Option<String>,
+    /// how many times a run is queued again after losing its runner before it's given up on.
+    lost_run_requeues: Option<u32>,
 }
 
 #[tokio::main]
@@ -503,24 +967,24 @@ async fn main() {
     args.next().expect("first arg exists");
     let config_path = args.next().unwrap_or("./driver_config.json".to_string());
     let driver_config: DriverConfig = serde_json::from_reader(std::fs::File::open(config_path).expect("file exists and is accessible")).expect("valid json for DriverConfig");
-    let mut auth_secret = AUTH_SECRET.write().unwrap();
-    *auth_secret = Some(driver_config.auth_secret.clone());
-    std::mem::drop(auth_secret);
+    *AUTH_SECRET.write().unwrap() = driver_config.auth_secret.clone();
 
     let config = RustlsConfig::from_pem_file(
         driver_config.cert_path.clone(),
         driver_config.key_path.clone(),
     ).await.unwrap();
 
-    let dbctx = Arc::new(DbCtx::new(&driver_config.config_path, &driver_config.db_path));
+    let dbctx = Arc::new(DbCtx::new(&driver_config.config_path, &driver_config.db_path).expect("can open database"));
 
-    dbctx.create_tables().unwrap();
+    dbctx.migrate().expect("can migrate database to a usable schema");
 
     let (api_server, mut channel) = make_api_server(driver_config.artifact_path.clone(), Arc::clone(&dbctx)).await;
     spawn(axum_server::bind_rustls(driver_config.server_addr.parse().unwrap(), config)
           .serve(api_server.into_make_service()));
 
-    spawn(old_task_reaper(Arc::clone(&dbctx)));
+    let max_requeues = driver_config.lost_run_requeues.unwrap_or(DEFAULT_LOST_RUN_REQUEUES);
+    spawn(lost_run_reaper(Arc::clone(&dbctx), max_requeues));
+    spawn(garbage_collector(Arc::clone(&dbctx), driver_config.artifact_path.clone()));
 
     loop {
         let candidate = match channel.recv().await
@@ -534,28 +998,43 @@ async fn main() {
         let artifact_path = driver_config.artifact_path.clone();
         spawn(async move {
             let host_id = candidate.host_id;
-            let res = find_client_task(dbctx, candidate, artifact_path).await;
+            let res = find_client_task(dbctx, candidate, artifact_path, max_requeues).await;
             eprintln!("task client for {}: {:?}", host_id, res);
         });
     }
 }
 
-async fn find_client_task(dbctx: Arc<DbCtx>, mut candidate: RunnerClient, artifact_path: PathBuf) -> Result<(), String> {
+async fn find_client_task(dbctx: Arc<DbCtx>, mut candidate: RunnerClient, artifact_path: PathBuf, max_requeues: u32) -> Result<(), String> {
     let find_client_task_start = std::time::Instant::now();
 
     let (run, job) = 'find_work: loop {
+        // an operator may disable or retire a host while its runner is waiting for work, so check
+        // every time around rather than once when the runner connects.
+        let host = dbctx.host_by_id(candidate.host_id as u64)
+            .map_err(|e| format!("failed to look up host {}: {}", candidate.host_id, e))?
+            .ok_or_else(|| format!("host {} does not exist", candidate.host_id))?;
+        if !host.accepts_work() {
+            return Err(format!("host {} ({}) is not accepting work", host.id, host.display_name()));
+        }
+        if !credential_usable(&dbctx, candidate.credential_id) {
+            return Err(format!("credential for host {} ({}) is no longer usable", host.id, host.display_name()));
+        }
+
         // try to find a job for this candidate:
         // * start with pending runs - these need *some* client to run them, but do not care which
         // * if no new jobs, maybe an existing job still needs a rerun on this client?
         // * otherwise, um, i dunno. do nothing?
 
-        let runs = dbctx.get_pending_runs(Some(candidate.host_id)).unwrap();
+        let runs = dbctx.get_pending_runs(Some(candidate.host_id))
+            .map_err(|e| format!("failed to get pending runs: {}", e))?;
 
-        if runs.len() > 0 {
+        if !runs.is_empty() {
             println!("{} new runs", runs.len());
 
             for run in runs.into_iter() {
-                let job = dbctx.job_by_id(run.job_id).expect("can query").expect("job exists");
+                let job = dbctx.job_by_id(run.job_id)
+                    .map_err(|e| format!("failed to look up job {}: {}", run.job_id, e))?
+                    .ok_or_else(|| format!("run {} has no job", run.id))?;
 
                 if candidate.will_accept(&job) {
                     break 'find_work (run, job);
@@ -564,11 +1043,13 @@ async fn find_client_task(dbctx: Arc<DbCtx>, mut candidate: RunnerClient, artifa
             }
         }
 
-        let alt_run_jobs = dbctx.jobs_needing_task_runs_for_host(candidate.host_id as u64).expect("can query");
+        let alt_run_jobs = dbctx.jobs_needing_task_runs_for_host(candidate.host_id as u64)
+            .map_err(|e| format!("failed to find jobs needing runs: {}", e))?;
 
         for job in alt_run_jobs.into_iter() {
             if candidate.will_accept(&job) {
-                let run = dbctx.new_run(job.id, Some(candidate.host_id)).unwrap();
+                let run = dbctx.new_run(job.id, Some(candidate.host_id))
+                    .map_err(|e| format!("failed to create run for job {}: {}", job.id, e))?;
                 break 'find_work (run, job);
             }
         }
@@ -585,54 +1066,114 @@ async fn find_client_task(dbctx: Arc<DbCtx>, mut candidate: RunnerClient, artifa
     };
 
     eprintln!("enqueueing job {} for alternate run under host id {}", job.id, candidate.host_id);
-    activate_run(Arc::clone(&dbctx), candidate, artifact_path, &job, &run).await?;
+    activate_run(Arc::clone(&dbctx), candidate, artifact_path, &job, &run, max_requeues).await?;
 
     Ok(())
 }
 
-async fn old_task_reaper(dbctx: Arc<DbCtx>) {
-    let mut potentially_stale_tasks = dbctx.get_active_runs().unwrap();
+// how long a runner has to say what protocol it speaks once it connects.
+const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
 
-    let active_tasks = ACTIVE_TASKS.lock().unwrap();
+// how long a runner has to hang up after being told to stop its task.
+const TERMINATE_GRACE_MS: u64 = 60_000;
 
-    for (id, _witness) in active_tasks.iter() {
-        if let Some(idx) = potentially_stale_tasks.iter().position(|task| task.id == *id) {
-            potentially_stale_tasks.swap_remove(idx);
-        }
-    }
+// how often a running task checks whether it has been cancelled.
+const CANCEL_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+// how often a running task records that its runner is still connected, for runners that don't
+// send heartbeats of their own. this must be well under `LOST_RUNNER_TIMEOUT_MS`.
+const CONNECTED_HEARTBEAT_RECORD_MS: u64 = 5_000;
 
-    std::mem::drop(active_tasks);
+// an attempt without a heartbeat for this long has lost its runner. the reaper and a connected
+// runner's own task go by the same limit, so they can't disagree about when a runner is gone.
+const LOST_RUNNER_TIMEOUT_MS: u64 = protocol::HEARTBEAT_INTERVAL_MS * protocol::MISSED_HEARTBEAT_LIMIT;
 
-    // ok, so we have tasks that are not active, now if the task is started we know someone should
-    // be running it and they are not. retain only those tasks, as they are ones we may want to
-    // mark dead.
-    //
-    // further, filter out any tasks created in the last 60 seconds. this is a VERY generous grace
-    // period for clients that have accepted a job but for some reason we have not recorded them as
-    // active (perhaps they are slow to ack somehow).
+const REAP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
 
-    let stale_threshold = ci_lib_core::now_ms() - 60_000;
+const DEFAULT_LOST_RUN_REQUEUES: u32 = 2;
 
-    let stale_tasks: Vec<Run> = potentially_stale_tasks.into_iter().filter(|task| {
-        match (task.state, task.start_time) {
-            // `run` is atomically set to `Started` and adorned with a `start_time`. disagreement
-            // between the two means this run is corrupt and should be reaped.
-            (RunState::Started, None) => {
-                true
-            },
-            (RunState::Started, Some(start_time)) => {
-                start_time < stale_threshold
+const GC_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+// artifacts are the bulk of what the driver writes to disk, so it's the driver's job to clean them
+// up too. `ci_ctl gc` does the same on demand.
+async fn garbage_collector(dbctx: Arc<DbCtx>, artifact_path: PathBuf) {
+    loop {
+        match ci_lib_native::gc::collect_garbage(&dbctx, &artifact_path) {
+            Ok(report) => {
+                if report.runs_collected > 0 {
+                    eprintln!("[+] gc: {:?}", report);
+                }
             }
-            // and if it's not `started`, it's either pending (not assigned yet, so not stale), or
-            // one of the complete statuses.
-            _ => {
-                false
+            Err(e) => {
+                eprintln!("[-] gc failed: {}", e);
             }
         }
-    }).collect();
 
-    for task in stale_tasks.iter() {
-        eprintln!("looks like task {} is stale, reaping", task.id);
-        dbctx.reap_task(task.id).expect("works");
+        tokio::time::sleep(GC_INTERVAL).await;
+    }
+}
+
+// a run whose runner goes away without finishing stays `Started` until the reaper notices its
+// heartbeats have stopped. then it's queued again for another runner, up to `max_requeues` times.
+async fn lost_run_reaper(dbctx: Arc<DbCtx>, max_requeues: u32) {
+    loop {
+        reap_lost_runs(&dbctx, max_requeues).await;
+        tokio::time::sleep(REAP_INTERVAL).await;
+    }
+}
+
+async fn reap_lost_runs(dbctx: &Arc<DbCtx>, max_requeues: u32) {
+    let stale_attempts = match dbctx.stale_attempts(ci_lib_core::now_ms().saturating_sub(LOST_RUNNER_TIMEOUT_MS)) {
+        Ok(attempts) => attempts,
+        Err(e) => {
+            eprintln!("[-] could not list stale attempts to reap: {}", e);
+            return;
+        }
+    };
+
+    for attempt in stale_attempts.iter() {
+        // a run this driver is still handling has a runner, whatever its heartbeats say.
+        let active = ACTIVE_TASKS.lock().unwrap()
+            .get(&attempt.run_id)
+            .map(|witness| witness.strong_count() > 0)
+            .unwrap_or(false);
+        if active {
+            continue;
+        }
+
+        abandon_attempt(dbctx, attempt.run_id, attempt.id, "runner loss", max_requeues).await;
+    }
+}
+
+// give up on an attempt whose runner is gone: queue its run again for another runner if it can
+// be, and otherwise record it as ended. `reason` should read well as "retried after {reason}".
+async fn abandon_attempt(dbctx: &Arc<DbCtx>, run_id: u64, attempt_id: u64, reason: &str, max_requeues: u32) {
+    match dbctx.requeue_attempt(attempt_id, reason, max_requeues) {
+        Ok(true) => {
+            eprintln!("[!] run {} lost its runner (attempt {}), requeued", run_id, attempt_id);
+            return;
+        }
+        Ok(false) => {}
+        Err(DbError::NotFound) => {
+            // finished between being found lost and being abandoned.
+            return;
+        }
+        Err(e) => {
+            eprintln!("[-] could not abandon run {} (attempt {}): {}", run_id, attempt_id, e);
+            return;
+        }
+    }
+
+    eprintln!("[!] run {} lost its runner (attempt {}), giving up on it", run_id, attempt_id);
+    let (outcome, final_status) = match dbctx.cancel_requested(run_id) {
+        Ok(true) => (TaskOutcome::Cancelled, "cancelled"),
+        _ => (TaskOutcome::InfraError, "lost signal"),
+    };
+    if let Err(e) = dbctx.complete_attempt(attempt_id, outcome, final_status) {
+        eprintln!("[-] could not record end of run {} (attempt {}): {}", run_id, attempt_id, e);
+        return;
+    }
+    if let Err(e) = ci_lib_native::dbctx_ext::notify_complete(dbctx, run_id, outcome, final_status, &[]).await {
+        eprintln!("[-] could not notify completion of run {}: {}", run_id, e);
     }
 }
@@ -0,0 +1,558 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{BufRead, Read, Write};
+use std::path::Path;
+
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::{params_from_iter, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use serde_json::Map;
+
+use crate::dbctx::{DbCtx, DbError};
+use crate::migrations;
+use crate::sql;
+
+/// the version of the archive format itself, as opposed to the schema of the database in it.
+/// bump this for changes to `ArchiveRecord` that older binaries would misread.
+pub const ARCHIVE_FORMAT_VERSION: u32 = 1;
+
+/// artifact files are split into records of at most this many bytes, so that importing an archive
+/// never needs a whole artifact in memory at once.
+const ARTIFACT_CHUNK_BYTES: usize = 256 * 1024;
+
+/// one line of an archive. an archive is a header, then any number of rows and artifact chunks,
+/// then an end record. an archive without an end record was cut short, and is not imported.
+///
+/// rows are written as they are in the database the archive came from, at the schema version its
+/// header names. importing an archive recreates the database at that version, loads the rows, and
+/// then applies whatever migrations have come since. so an archive can be imported by any binary
+/// at least as new as the one that wrote it.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind")]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveRecord {
+    Header {
+        format_version: u32,
+        schema_version: u32,
+        created_time: u64,
+    },
+    Row {
+        table: String,
+        /// column name to value. blobs are written as `{"blob": "<base64>"}`.
+        values: Map<String, serde_json::Value>,
+    },
+    /// the next `data` (base64) of an artifact's file. an artifact's chunks are in order and not
+    /// interleaved with any other artifact's. every file has at least one chunk, so an empty file
+    /// is a single chunk with no data.
+    ArtifactData {
+        run_id: u64,
+        artifact_id: u64,
+        data: String,
+    },
+    End {
+        rows: u64,
+        artifact_files: u64,
+    },
+    /// a record from a newer binary that this one has no use for.
+    #[serde(other)]
+    Unknown,
+}
+
+/// what `DbCtx::export` wrote, or `DbCtx::import` read.
+#[derive(Debug, Default)]
+pub struct ArchiveReport {
+    pub schema_version: u32,
+    pub rows: u64,
+    pub artifact_files: u64,
+    pub artifact_bytes: u64,
+    /// tables and columns in the archive that the database had no place for, as `table` or
+    /// `table.column`.
+    pub skipped: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    Db(DbError),
+    Io(std::io::Error),
+    /// the archive isn't one we can import: malformed, truncated, too new, or headed for a
+    /// database that isn't empty.
+    Invalid(String),
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveError::Db(e) => write!(f, "database error: {}", e),
+            ArchiveError::Io(e) => write!(f, "io error: {}", e),
+            ArchiveError::Invalid(msg) => write!(f, "invalid archive: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {}
+
+impl From<DbError> for ArchiveError {
+    fn from(e: DbError) -> Self {
+        ArchiveError::Db(e)
+    }
+}
+
+impl From<rusqlite::Error> for ArchiveError {
+    fn from(e: rusqlite::Error) -> Self {
+        ArchiveError::Db(e.into())
+    }
+}
+
+impl From<std::io::Error> for ArchiveError {
+    fn from(e: std::io::Error) -> Self {
+        ArchiveError::Io(e)
+    }
+}
+
+fn write_record(out: &mut impl Write, record: &ArchiveRecord) -> Result<(), ArchiveError> {
+    serde_json::to_writer(&mut *out, record)
+        .map_err(|e| ArchiveError::Io(e.into()))?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+fn value_to_json(value: ValueRef) -> serde_json::Value {
+    match value {
+        ValueRef::Null => serde_json::Value::Null,
+        ValueRef::Integer(i) => serde_json::Value::from(i),
+        ValueRef::Real(f) => serde_json::Value::from(f),
+        ValueRef::Text(text) => serde_json::Value::from(String::from_utf8_lossy(text).into_owned()),
+        ValueRef::Blob(blob) => serde_json::json!({ "blob": base64::encode(blob) }),
+    }
+}
+
+fn json_to_value(table: &str, column: &str, value: &serde_json::Value) -> Result<Value, ArchiveError> {
+    let value = match value {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Real(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        serde_json::Value::String(text) => Value::Text(text.clone()),
+        serde_json::Value::Object(obj) => match obj.get("blob").and_then(|blob| blob.as_str()) {
+            Some(blob) => Value::Blob(base64::decode(blob)
+                .map_err(|e| ArchiveError::Invalid(format!("{}.{} has a bad blob: {}", table, column, e)))?),
+            None => {
+                return Err(ArchiveError::Invalid(format!("{}.{} has an unexpected value: {}", table, column, value)));
+            }
+        },
+        other => {
+            return Err(ArchiveError::Invalid(format!("{}.{} has an unexpected value: {}", table, column, other)));
+        }
+    };
+    Ok(value)
+}
+
+// every table whose rows belong in an archive. sqlite's own tables and `schema_version` are
+// recreated on import. so are virtual tables (the log search index) and the tables backing them,
+// which rebuild themselves as rows are imported.
+fn archived_tables(conn: &rusqlite::Connection) -> Result<Vec<String>, DbError> {
+    let mut tables = Vec::new();
+    let mut virtual_tables = Vec::new();
+
+    let mut tables_query = conn.prepare("select name, sql from sqlite_master where type='table' order by name")?;
+    let mut result = tables_query.query([])?;
+    while let Some(row) = result.next()? {
+        let (name, create_sql): (String, Option<String>) = row.try_into()?;
+        if create_sql.map(|create_sql| create_sql.to_ascii_uppercase().starts_with("CREATE VIRTUAL TABLE")).unwrap_or(false) {
+            virtual_tables.push(name);
+        } else if !name.starts_with("sqlite_") && name != "schema_version" {
+            tables.push(name);
+        }
+    }
+
+    tables.retain(|table| {
+        !virtual_tables.iter().any(|virtual_table| table.starts_with(&format!("{}_", virtual_table)))
+    });
+
+    Ok(tables)
+}
+
+impl DbCtx {
+    /// write every row of the database, and optionally the file of every finished artifact under
+    /// `artifact_path`, to `out`.
+    ///
+    /// rows are read in one transaction, so the archive is a consistent snapshot even while the
+    /// driver is writing to the database.
+    pub fn export(&self, out: &mut impl Write, artifact_path: Option<&Path>) -> Result<ArchiveReport, ArchiveError> {
+        let conn = self.reader();
+        let tx = conn.unchecked_transaction()?;
+
+        let schema_version: u32 = tx.query_row(sql::SCHEMA_VERSION, [], |row| row.get(0))?;
+        let mut report = ArchiveReport {
+            schema_version,
+            ..ArchiveReport::default()
+        };
+
+        write_record(out, &ArchiveRecord::Header {
+            format_version: ARCHIVE_FORMAT_VERSION,
+            schema_version,
+            created_time: crate::now_ms(),
+        })?;
+
+        for table in archived_tables(&tx)? {
+            let mut rows_query = tx.prepare(&format!("select * from \"{}\"", table))?;
+            let columns: Vec<String> = rows_query.column_names().into_iter().map(str::to_owned).collect();
+            let mut result = rows_query.query([])?;
+            while let Some(row) = result.next()? {
+                let mut values = Map::new();
+                for (idx, column) in columns.iter().enumerate() {
+                    values.insert(column.clone(), value_to_json(row.get_ref(idx)?));
+                }
+                write_record(out, &ArchiveRecord::Row { table: table.clone(), values })?;
+                report.rows += 1;
+            }
+        }
+
+        if let Some(artifact_path) = artifact_path {
+            // an unfinished artifact is still being written, so whatever is there now isn't worth
+            // keeping.
+            let mut artifacts_query = tx.prepare("select run_id, id from artifacts where completed_time is not null order by id")?;
+            let mut result = artifacts_query.query([])?;
+            while let Some(row) = result.next()? {
+                let (run_id, artifact_id): (u64, u64) = row.try_into()?;
+                let artifact_file = artifact_path.join(run_id.to_string()).join(artifact_id.to_string());
+                let mut file = match std::fs::File::open(&artifact_file) {
+                    Ok(file) => file,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        // collected, or never written. the record is still worth having.
+                        continue;
+                    }
+                    Err(e) => {
+                        return Err(e.into());
+                    }
+                };
+
+                // every file gets at least one chunk, so an empty file is still in the archive.
+                let mut buf = vec![0; ARTIFACT_CHUNK_BYTES];
+                let mut n_read = file.read(&mut buf)?;
+                loop {
+                    write_record(out, &ArchiveRecord::ArtifactData {
+                        run_id,
+                        artifact_id,
+                        data: base64::encode(&buf[..n_read]),
+                    })?;
+                    report.artifact_bytes += n_read as u64;
+
+                    n_read = file.read(&mut buf)?;
+                    if n_read == 0 {
+                        break;
+                    }
+                }
+                report.artifact_files += 1;
+            }
+        }
+
+        write_record(out, &ArchiveRecord::End {
+            rows: report.rows,
+            artifact_files: report.artifact_files,
+        })?;
+        out.flush()?;
+
+        Ok(report)
+    }
+
+    /// load an archive written by `export` into this database, which must be empty, and bring it
+    /// up to the newest schema. artifact files in the archive are written under `artifact_path`,
+    /// or skipped if there is none.
+    ///
+    /// rows are loaded in one transaction: if anything goes wrong, the database is left empty.
+    /// artifact files written before the failure are left behind.
+    pub fn import(&self, input: impl BufRead, artifact_path: Option<&Path>) -> Result<ArchiveReport, ArchiveError> {
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let existing_tables: u64 = tx.query_row("select count(*) from sqlite_master where type='table'", [], |row| row.get(0))?;
+        if existing_tables != 0 {
+            return Err(ArchiveError::Invalid("archives can only be imported into an empty database".to_string()));
+        }
+
+        let mut lines = input.lines();
+        let mut next_record = || -> Result<Option<ArchiveRecord>, ArchiveError> {
+            match lines.next() {
+                Some(line) => {
+                    let line = line?;
+                    serde_json::from_str(&line)
+                        .map(Some)
+                        .map_err(|e| ArchiveError::Invalid(format!("bad record: {}", e)))
+                }
+                None => Ok(None),
+            }
+        };
+
+        let schema_version = match next_record()? {
+            Some(ArchiveRecord::Header { format_version, schema_version, .. }) => {
+                if format_version > ARCHIVE_FORMAT_VERSION {
+                    return Err(ArchiveError::Invalid(format!(
+                        "archive format is version {}, but this binary only knows up to version {}",
+                        format_version, ARCHIVE_FORMAT_VERSION
+                    )));
+                }
+                if schema_version > migrations::latest_version() {
+                    return Err(ArchiveError::Invalid(format!(
+                        "archive schema is version {}, but this binary only knows up to version {}",
+                        schema_version, migrations::latest_version()
+                    )));
+                }
+                schema_version
+            }
+            _ => {
+                return Err(ArchiveError::Invalid("archive does not start with a header".to_string()));
+            }
+        };
+
+        let mut report = ArchiveReport {
+            schema_version,
+            ..ArchiveReport::default()
+        };
+
+        // recreate the database as it was, so the rows fit, then migrate it from there.
+        Self::apply_migrations(&tx, schema_version)?;
+
+        let mut table_columns: HashMap<String, HashSet<String>> = HashMap::new();
+        for table in archived_tables(&tx)? {
+            let columns_query = tx.prepare(&format!("select * from \"{}\" limit 0", table))?;
+            let columns = columns_query.column_names().into_iter().map(str::to_owned).collect();
+            table_columns.insert(table, columns);
+        }
+        let mut skipped: HashSet<String> = HashSet::new();
+
+        // the artifact file currently being written, if any.
+        let mut artifact_file: Option<(u64, std::fs::File)> = None;
+
+        let (rows, artifact_files) = loop {
+            match next_record()? {
+                Some(ArchiveRecord::Row { table, values }) => {
+                    // counted whether or not there's a place for it, as `export` counted it.
+                    report.rows += 1;
+                    let known_columns = match table_columns.get(&table) {
+                        Some(columns) => columns,
+                        None => {
+                            skipped.insert(table);
+                            continue;
+                        }
+                    };
+
+                    let mut columns = Vec::new();
+                    let mut row_values = Vec::new();
+                    for (column, value) in values.iter() {
+                        if known_columns.contains(column) {
+                            row_values.push(json_to_value(&table, column, value)?);
+                            columns.push(format!("\"{}\"", column));
+                        } else {
+                            skipped.insert(format!("{}.{}", table, column));
+                        }
+                    }
+
+                    let placeholders = (1..=columns.len()).map(|idx| format!("?{}", idx)).collect::<Vec<_>>().join(", ");
+                    tx.execute(
+                        &format!("insert into \"{}\" ({}) values ({})", table, columns.join(", "), placeholders),
+                        params_from_iter(row_values.iter())
+                    )?;
+                }
+                Some(ArchiveRecord::ArtifactData { run_id, artifact_id, data }) => {
+                    let artifact_path = match artifact_path {
+                        Some(artifact_path) => artifact_path,
+                        None => { continue; }
+                    };
+                    let data = base64::decode(&data)
+                        .map_err(|e| ArchiveError::Invalid(format!("artifact {} has bad data: {}", artifact_id, e)))?;
+
+                    if artifact_file.as_ref().map(|(id, _)| *id) != Some(artifact_id) {
+                        let run_dir = artifact_path.join(run_id.to_string());
+                        std::fs::create_dir_all(&run_dir)?;
+                        let file = std::fs::OpenOptions::new()
+                            .write(true)
+                            .create_new(true)
+                            .open(run_dir.join(artifact_id.to_string()))?;
+                        artifact_file = Some((artifact_id, file));
+                        report.artifact_files += 1;
+                    }
+
+                    if let Some((_, file)) = artifact_file.as_mut() {
+                        file.write_all(&data)?;
+                    }
+                    report.artifact_bytes += data.len() as u64;
+                }
+                Some(ArchiveRecord::End { rows, artifact_files }) => {
+                    break (rows, artifact_files);
+                }
+                Some(ArchiveRecord::Header { .. }) => {
+                    return Err(ArchiveError::Invalid("archive has a second header".to_string()));
+                }
+                Some(ArchiveRecord::Unknown) => {}
+                None => {
+                    return Err(ArchiveError::Invalid("archive ends early".to_string()));
+                }
+            }
+        };
+
+        if rows != report.rows {
+            return Err(ArchiveError::Invalid(format!("archive says it has {} rows, but has {}", rows, report.rows)));
+        }
+        if artifact_path.is_some() && artifact_files != report.artifact_files {
+            return Err(ArchiveError::Invalid(format!("archive says it has {} artifact files, but has {}", artifact_files, report.artifact_files)));
+        }
+
+        Self::apply_migrations(&tx, migrations::latest_version())?;
+
+        tx.commit()?;
+
+        report.skipped = skipped.into_iter().collect();
+        report.skipped.sort();
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dbctx::DEFAULT_RUN_TIMEOUT_MS;
+    use crate::sql::JobCreation;
+
+    // a directory of its own under the system's temporary directory, for artifact files.
+    fn scratch_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("ci-archive-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("can create scratch directory");
+        dir
+    }
+
+    #[test]
+    fn export_then_import_keeps_every_artifact() {
+        let db = DbCtx::in_memory().expect("can open database");
+        db.migrate().expect("can migrate");
+        let repo_id = db.new_repo("ci").unwrap();
+        let remote_id = db.new_remote(repo_id, "iximeow/ci", "github", "ci.json").unwrap();
+        let run_id = match db.create_job(remote_id, "c1", None).unwrap() {
+            JobCreation::Created { run_id, .. } => run_id,
+            JobCreation::Existing { .. } => panic!("c1 already had a job"),
+        };
+        let attempt_id = db.start_run(run_id, 1, "artifacts", "token-c1", DEFAULT_RUN_TIMEOUT_MS).unwrap();
+
+        let exported_from = scratch_dir("export");
+        std::fs::create_dir_all(exported_from.join(run_id.to_string())).unwrap();
+        // larger than one chunk, to be sure chunks are put back together in order.
+        let log: Vec<u8> = (0..ARTIFACT_CHUNK_BYTES + 10).map(|i| (i % 251) as u8).collect();
+        let mut artifacts = Vec::new();
+        for (name, contents) in [("build (stdout)", log.as_slice()), ("build (stderr)", b"".as_slice())] {
+            let artifact_id = db.new_artifact(attempt_id, name, "output").unwrap();
+            std::fs::write(exported_from.join(run_id.to_string()).join(artifact_id.to_string()), contents).unwrap();
+            db.writer().execute("update artifacts set completed_time=1 where id=?1", [artifact_id]).unwrap();
+            artifacts.push((artifact_id, contents));
+        }
+
+        let mut archive = Vec::new();
+        let exported = db.export(&mut archive, Some(&exported_from)).expect("can export");
+        assert_eq!(exported.artifact_files, 2);
+        assert_eq!(exported.artifact_bytes, log.len() as u64);
+
+        let imported_to = scratch_dir("import");
+        let restored = DbCtx::in_memory().expect("can open database");
+        let imported = restored.import(archive.as_slice(), Some(&imported_to)).expect("can import");
+        assert_eq!(imported.rows, exported.rows);
+        assert_eq!(imported.artifact_files, 2);
+        assert_eq!(imported.artifact_bytes, exported.artifact_bytes);
+        for (artifact_id, contents) in artifacts {
+            let file = imported_to.join(run_id.to_string()).join(artifact_id.to_string());
+            assert_eq!(std::fs::read(&file).expect("artifact was imported"), contents);
+        }
+        assert_eq!(restored.artifacts_for_run(run_id, None).unwrap().len(), 2);
+
+        let _ = std::fs::remove_dir_all(&exported_from);
+        let _ = std::fs::remove_dir_all(&imported_to);
+    }
+
+    // an archive of `records`, one per line, after a header at `schema_version` and before an end
+    // record that counts the rows among them.
+    fn archive(schema_version: u32, records: &[serde_json::Value]) -> Vec<u8> {
+        let mut lines = vec![serde_json::json!({
+            "kind": "header", "format_version": ARCHIVE_FORMAT_VERSION, "schema_version": schema_version, "created_time": 1000,
+        })];
+        lines.extend(records.iter().cloned());
+        let rows = records.iter().filter(|record| record["kind"] == "row").count();
+        lines.push(serde_json::json!({ "kind": "end", "rows": rows, "artifact_files": 0 }));
+        lines.iter().map(|line| format!("{}\n", line)).collect::<String>().into_bytes()
+    }
+
+    fn row(table: &str, values: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({ "kind": "row", "table": table, "values": values })
+    }
+
+    #[test]
+    fn older_archives_are_migrated_after_import() {
+        let archive = archive(1, &[
+            row("repos", serde_json::json!({ "id": 1, "repo_name": "ci" })),
+            row("remotes", serde_json::json!({
+                "id": 1, "repo_id": 1, "remote_path": "iximeow/ci", "remote_api": "github",
+                "remote_url": "https://www.github.com/iximeow/ci", "remote_git_url": "https://www.github.com/iximeow/ci.git",
+                "notifier_config_path": "ci.json",
+            })),
+            row("commits", serde_json::json!({ "id": 1, "sha": "abc123" })),
+            row("jobs", serde_json::json!({ "id": 1, "source": "push", "created_time": 1000, "remote_id": 1, "commit_id": 1 })),
+            row("runs", serde_json::json!({
+                "id": 1, "job_id": 1, "state": 2, "build_token": "token", "created_time": 1000, "started_time": 1100,
+                "complete_time": 1200, "build_result": 0, "final_status": "passed",
+            })),
+        ]);
+
+        let db = DbCtx::in_memory().expect("can open database");
+        let report = db.import(archive.as_slice(), None).expect("can import");
+        assert_eq!((report.schema_version, report.rows), (1, 5));
+        assert!(report.skipped.is_empty());
+
+        let schema_version: u32 = db.reader().query_row(sql::SCHEMA_VERSION, [], |row| row.get(0)).unwrap();
+        assert_eq!(schema_version, migrations::latest_version());
+        // commits gained a repo, and runs gained attempts, on the way.
+        assert!(db.commit_id_by_sha(1, "abc123").unwrap().is_some());
+        assert_eq!(db.attempts_for_run(1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn unknown_tables_and_columns_are_skipped() {
+        let archive = archive(migrations::latest_version(), &[
+            row("repos", serde_json::json!({ "id": 1, "repo_name": "ci", "color": "blue" })),
+            row("widgets", serde_json::json!({ "id": 1 })),
+            serde_json::json!({ "kind": "something_newer" }),
+        ]);
+
+        let db = DbCtx::in_memory().expect("can open database");
+        let report = db.import(archive.as_slice(), None).expect("can import");
+        assert_eq!(report.skipped, vec!["repos.color".to_string(), "widgets".to_string()]);
+        assert_eq!(db.repo_id_by_name("ci").unwrap(), Some(1));
+    }
+
+    #[test]
+    fn truncated_archives_are_refused() {
+        let archive = archive(migrations::latest_version(), &[
+            row("repos", serde_json::json!({ "id": 1, "repo_name": "ci" })),
+        ]);
+        let archive = String::from_utf8(archive).unwrap();
+        let truncated: String = archive.lines()
+            .filter(|line| !line.contains("\"kind\":\"end\""))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        assert_ne!(truncated.len(), archive.len());
+
+        let db = DbCtx::in_memory().expect("can open database");
+        assert!(matches!(db.import(truncated.as_bytes(), None), Err(ArchiveError::Invalid(_))));
+        // nothing of it is left behind, so the import can be tried again.
+        let tables: u64 = db.reader().query_row("select count(*) from sqlite_master where type='table'", [], |row| row.get(0)).unwrap();
+        assert_eq!(tables, 0);
+    }
+
+    #[test]
+    fn archives_are_only_imported_into_empty_databases() {
+        let db = DbCtx::in_memory().expect("can open database");
+        db.migrate().expect("can migrate");
+        let archive = archive(migrations::latest_version(), &[]);
+        assert!(matches!(db.import(archive.as_slice(), None), Err(ArchiveError::Invalid(_))));
+    }
+}
+
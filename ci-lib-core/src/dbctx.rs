@@ -1,233 +1,652 @@
-use std::sync::Mutex;
-use rusqlite::{params, Connection, OptionalExtension};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension, TransactionBehavior};
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::path::Path;
 use std::path::PathBuf;
 
 use crate::sql;
+use crate::migrations::{self, MIGRATIONS};
 
 use crate::sql::ArtifactRecord;
+use crate::sql::Attempt;
+use crate::sql::AttemptProgress;
+use crate::sql::CommandRecord;
 use crate::sql::CommitName;
 use crate::sql::Run;
+use crate::sql::RunState;
 use crate::sql::TokenValidity;
+use crate::sql::TokenAttempt;
 use crate::sql::MetricRecord;
+use crate::sql::MetricValue;
+use crate::sql::MetricPolicy;
+use crate::sql::MetricFinding;
 use crate::sql::PendingRun;
 use crate::sql::Job;
+use crate::sql::JobCreation;
 use crate::sql::Remote;
 use crate::sql::Repo;
+use crate::sql::RetentionPolicy;
+use crate::sql::ExpiredRun;
+use crate::sql::Host;
+use crate::sql::LogMatch;
+use crate::sql::LogQuery;
+use crate::sql::HostFacts;
+use crate::sql::RunnerCredential;
+use crate::sql::CredentialState;
+use crate::protocol::{HeartbeatInfo, TaskOutcome};
+
+/// everything that can go wrong talking to `state.db`.
+///
+/// this is deliberately coarse: callers mostly want to know if they should report "no such
+/// thing", "try again later", or "something is broken", not which sqlite error code came back.
+#[derive(Debug)]
+pub enum DbError {
+    /// a query that must produce a row produced nothing.
+    NotFound,
+    /// a lookup by something short of a unique key, like a sha prefix, matched more than one row.
+    Ambiguous(String),
+    /// a write would break a UNIQUE, NOT NULL or similar constraint, or was otherwise not
+    /// acceptable for the table it targets.
+    ConstraintViolation(String),
+    /// another connection held the database lock for longer than we were willing to wait.
+    Busy,
+    /// a row was read, but doesn't look like any row we would have written: an unknown state,
+    /// missing columns, that kind of thing.
+    CorruptRow(String),
+    /// a value could not be converted to or from the rust type we asked for.
+    Conversion(String),
+    /// the database schema is not one this binary can work with.
+    Schema(String),
+    /// anything else sqlite had to say.
+    Sqlite(rusqlite::Error),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DbError::NotFound => f.write_str("no such record"),
+            DbError::Ambiguous(msg) => write!(f, "ambiguous: {}", msg),
+            DbError::ConstraintViolation(msg) => write!(f, "constraint violation: {}", msg),
+            DbError::Busy => f.write_str("database is busy"),
+            DbError::CorruptRow(msg) => write!(f, "corrupt row: {}", msg),
+            DbError::Conversion(msg) => write!(f, "conversion failure: {}", msg),
+            DbError::Schema(msg) => write!(f, "unusable schema: {}", msg),
+            DbError::Sqlite(e) => write!(f, "sqlite error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+impl From<rusqlite::Error> for DbError {
+    fn from(e: rusqlite::Error) -> Self {
+        use rusqlite::Error;
+
+        match e {
+            Error::QueryReturnedNoRows => DbError::NotFound,
+            Error::SqliteFailure(code, msg) => match code.code {
+                ErrorCode::ConstraintViolation => {
+                    DbError::ConstraintViolation(msg.unwrap_or_else(|| code.to_string()))
+                }
+                ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked => DbError::Busy,
+                _ => DbError::Sqlite(Error::SqliteFailure(code, msg)),
+            },
+            Error::FromSqlConversionFailure(idx, _, err) => {
+                if let Some(invalid) = err.downcast_ref::<sql::InvalidValue>() {
+                    DbError::CorruptRow(format!("column {}: {}", idx, invalid))
+                } else {
+                    DbError::Conversion(format!("column {}: {}", idx, err))
+                }
+            }
+            Error::IntegralValueOutOfRange(idx, value) => {
+                DbError::Conversion(format!("column {}: {} is out of range", idx, value))
+            }
+            Error::InvalidColumnType(idx, name, ty) => {
+                DbError::Conversion(format!("column {} ({}) has unexpected type {}", idx, name, ty))
+            }
+            Error::InvalidColumnIndex(idx) => {
+                DbError::CorruptRow(format!("row has no column {}", idx))
+            }
+            Error::InvalidColumnName(name) => {
+                DbError::CorruptRow(format!("row has no column {}", name))
+            }
+            other => DbError::Sqlite(other),
+        }
+    }
+}
+
+/// branches are shown without their `refs/heads/` prefix, everything else by its full ref name.
+pub fn short_ref_name(ref_name: &str) -> &str {
+    ref_name.strip_prefix("refs/heads/").unwrap_or(ref_name)
+}
+
+/// runner credentials are looked up by the hex sha256 of their token.
+fn hash_token(token: &str) -> String {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// how long a run may take if neither its repo nor its goodfile say otherwise. a run's build token
+/// expires when its time is up, too.
+pub const DEFAULT_RUN_TIMEOUT_MS: u64 = 1000 * 60 * 30;
 
-const TOKEN_EXPIRY_MS: u64 = 1000 * 60 * 30;
+/// how much of a log `DbCtx::finalize_artifact` indexes for search. anything past this is still
+/// kept, just not searchable.
+pub const MAX_INDEXED_LOG_BYTES: u64 = 16 * 1024 * 1024;
+
+/// how many read-only connections `DbCtx::new` opens alongside its one writer.
+const READ_CONNECTIONS: usize = 4;
+
+/// how long a connection will wait on another connection's lock before giving up with
+/// `DbError::Busy`.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct DbCtx {
     pub config_path: PathBuf,
-    // don't love this but.. for now...
-    pub conn: Mutex<Connection>,
+    // sqlite only ever allows one writer at a time, so all writes go through this one connection.
+    // in WAL mode readers don't block the writer or each other, so reads are spread across
+    // `readers` instead and don't queue up behind metric inserts or run updates.
+    writer: Mutex<Connection>,
+    readers: Vec<Mutex<Connection>>,
+    next_reader: AtomicUsize,
 }
 
 impl DbCtx {
-    pub fn new<P: AsRef<Path>>(config_path: P, db_path: P) -> Self {
+    pub fn new<P: AsRef<Path>>(config_path: P, db_path: P) -> Result<Self, DbError> {
+        let db_path = db_path.as_ref();
+
+        // the writer goes first: it creates the database if it doesn't exist yet, and the switch
+        // to WAL is persistent, so readers opened after this find the database already in WAL
+        // mode.
+        let writer = Connection::open(db_path)?;
+        writer.busy_timeout(BUSY_TIMEOUT)?;
+        let journal_mode: String = writer.query_row("pragma journal_mode=wal;", [], |row| row.get(0))?;
+        // an in-memory database has no journal to put in WAL mode, and doesn't need one.
+        if !journal_mode.eq_ignore_ascii_case("wal") && !journal_mode.eq_ignore_ascii_case("memory") {
+            eprintln!("[!] could not put {} in WAL mode, journal mode is {}", db_path.display(), journal_mode);
+        }
+        // in WAL mode this is still durable across application crashes, just not across power loss
+        // between checkpoints. that's fine for CI state.
+        writer.pragma_update(None, "synchronous", "normal")?;
+
+        let mut readers = Vec::with_capacity(READ_CONNECTIONS);
+        for _ in 0..READ_CONNECTIONS {
+            let reader = Connection::open(db_path)?;
+            reader.busy_timeout(BUSY_TIMEOUT)?;
+            reader.pragma_update(None, "query_only", true)?;
+            readers.push(Mutex::new(reader));
+        }
+
+        Ok(DbCtx {
+            config_path: config_path.as_ref().to_owned(),
+            writer: Mutex::new(writer),
+            readers,
+            next_reader: AtomicUsize::new(0),
+        })
+    }
+
+    /// wrap an already-open connection, using it for reads and writes alike. this is for
+    /// environments like `ci-wasm-frontend`, where the database lives somewhere we can only get
+    /// one connection to, and WAL isn't an option anyway.
+    pub fn from_connection<P: AsRef<Path>>(config_path: P, conn: Connection) -> Self {
         DbCtx {
             config_path: config_path.as_ref().to_owned(),
-            conn: Mutex::new(Connection::open(db_path).unwrap())
+            writer: Mutex::new(conn),
+            readers: Vec::new(),
+            next_reader: AtomicUsize::new(0),
+        }
+    }
+
+    /// a private database that only lives as long as the returned `DbCtx`. its connections share
+    /// one in-memory database, so reads see writes just as they would with a file. this is mostly
+    /// useful for tests, which still need to `migrate` it like any other database.
+    pub fn in_memory() -> Result<Self, DbError> {
+        static NEXT_DB: AtomicUsize = AtomicUsize::new(0);
+        let db_path = format!("file:ci-memdb-{}-{}?mode=memory&cache=shared",
+            std::process::id(), NEXT_DB.fetch_add(1, Ordering::Relaxed));
+        Self::new(".", db_path.as_str())
+    }
+
+    pub(crate) fn writer(&self) -> MutexGuard<'_, Connection> {
+        self.writer.lock().unwrap()
+    }
+
+    /// a connection for queries that only read. prefers whichever reader is idle, and only waits
+    /// if every reader is in use.
+    pub(crate) fn reader(&self) -> MutexGuard<'_, Connection> {
+        if self.readers.is_empty() {
+            return self.writer();
         }
+
+        let start = self.next_reader.fetch_add(1, Ordering::Relaxed);
+        for i in 0..self.readers.len() {
+            if let Ok(conn) = self.readers[(start + i) % self.readers.len()].try_lock() {
+                return conn;
+            }
+        }
+
+        self.readers[start % self.readers.len()].lock().unwrap()
+    }
+
+    /// bring the database up to the newest schema this binary knows about, returning the schema
+    /// version the database is now at.
+    ///
+    /// all outstanding migrations are applied in one transaction, so a failed migration leaves
+    /// the database as it was. if the database is at a version newer than any migration we know
+    /// of, some newer binary has been here and we must not touch anything; that is an error.
+    pub fn migrate(&self) -> Result<u32, DbError> {
+        let mut conn = self.writer();
+
+        // take the write lock up front: if another process is migrating at the same time, we
+        // should see the version it leaves behind rather than both applying the same migrations.
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let latest_version = migrations::latest_version();
+        Self::apply_migrations(&tx, latest_version)?;
+
+        tx.commit()?;
+
+        Ok(latest_version)
     }
 
-    pub fn create_tables(&self) -> Result<(), ()> {
-        let conn = self.conn.lock().unwrap();
-        conn.execute(sql::CREATE_ARTIFACTS_TABLE, params![]).unwrap();
-        conn.execute(sql::CREATE_JOBS_TABLE, params![]).unwrap();
-        conn.execute(sql::CREATE_METRICS_TABLE, params![]).unwrap();
-        conn.execute(sql::CREATE_COMMITS_TABLE, params![]).unwrap();
-        conn.execute(sql::CREATE_COMMIT_NAMES_TABLE, params![]).unwrap();
-        conn.execute(sql::CREATE_COMMIT_NAMES_INDEX, params![]).unwrap();
-        conn.execute(sql::CREATE_REPOS_TABLE, params![]).unwrap();
-        conn.execute(sql::CREATE_REPO_NAME_INDEX, params![]).unwrap();
-        conn.execute(sql::CREATE_REMOTES_TABLE, params![]).unwrap();
-        conn.execute(sql::CREATE_REMOTES_INDEX, params![]).unwrap();
-        conn.execute(sql::CREATE_RUNS_TABLE, params![]).unwrap();
-        conn.execute(sql::CREATE_HOSTS_TABLE, params![]).unwrap();
+    /// apply every migration up to and including `target_version` that `tx`'s database doesn't
+    /// have yet.
+    pub(crate) fn apply_migrations(tx: &rusqlite::Transaction, target_version: u32) -> Result<(), DbError> {
+        tx.execute(sql::CREATE_SCHEMA_VERSION_TABLE, params![])?;
+
+        let current_version: u32 = tx.query_row(sql::SCHEMA_VERSION, [], |row| row.get(0))?;
+        let latest_version = migrations::latest_version();
+
+        if current_version > latest_version {
+            return Err(DbError::Schema(format!(
+                "database schema is version {}, but this binary only knows up to version {}. refusing to use it.",
+                current_version, latest_version
+            )));
+        }
+
+        for migration in MIGRATIONS.iter().filter(|m| m.version > current_version && m.version <= target_version) {
+            eprintln!("[.] applying migration {}: {}", migration.version, migration.description);
+            for statement in migration.statements.iter() {
+                tx.execute_batch(statement)
+                    .map_err(|e| DbError::Schema(format!("migration {} failed: {}", migration.version, e)))?;
+            }
+            tx.execute(
+                "insert into schema_version (version, description, applied_time) values (?1, ?2, ?3);",
+                params![migration.version, migration.description, crate::now_ms()]
+            )?;
+        }
 
         Ok(())
     }
 
-    pub fn insert_metric(&self, run_id: u64, name: &str, value: &str) -> Result<(), String> {
-        let conn = self.conn.lock().unwrap();
-        conn
+    pub fn insert_metric(&self, attempt_id: u64, name: &str, value: &MetricValue) -> Result<(), DbError> {
+        let (text, numeric_value, unit, direction) = match value {
+            MetricValue::Text(text) => (text.clone(), None, None, None),
+            MetricValue::Number { value, unit, direction } => {
+                (value.to_string(), Some(*value), unit.map(|unit| unit.as_str()), direction.map(|direction| direction.as_str()))
+            }
+        };
+
+        let conn = self.writer();
+        let rows_modified = conn
             .execute(
-                "insert into metrics (run_id, name, value) values (?1, ?2, ?3) on conflict (run_id, name) do update set value=excluded.value",
-                params![run_id, name, value]
-            )
-            .expect("can upsert");
+                "insert into metrics (run_id, attempt_id, name, value, numeric_value, unit, direction) \
+                 select run_id, id, ?2, ?3, ?4, ?5, ?6 from attempts where id=?1 \
+                 on conflict (attempt_id, name) do update set \
+                    value=excluded.value, numeric_value=excluded.numeric_value, unit=excluded.unit, direction=excluded.direction",
+                params![attempt_id, name, text, numeric_value, unit, direction]
+            )?;
+
+        if rows_modified == 0 {
+            return Err(DbError::NotFound);
+        }
+
         Ok(())
     }
 
-    pub fn new_commit(&self, sha: &str) -> Result<u64, String> {
-        let conn = self.conn.lock().unwrap();
-        // TODO: ... if there's a unique constraint on the commits/sha column and the insert would
-        // be a dupe, is that an error (eek expect()) or is that simply Ok(0)? either way this
-        // should be made to not return the wrong id if there's a race on insert.
+    pub fn new_repo(&self, name: &str) -> Result<u64, DbError> {
+        let conn = self.writer();
         conn
             .execute(
-                "insert into commits (sha) values (?1)",
-                [sha.clone()]
-            )
-            .expect("can insert");
+                "insert into repos (repo_name) values (?1)",
+                [name]
+            )?;
 
         Ok(conn.last_insert_rowid() as u64)
     }
 
-    pub fn new_repo(&self, name: &str) -> Result<u64, String> {
-        let conn = self.conn.lock().unwrap();
-        conn
+    pub fn new_artifact(&self, attempt_id: u64, name: &str, desc: &str) -> Result<u64, DbError> {
+        let created_time = crate::now_ms();
+        let conn = self.writer();
+        let rows_modified = conn
             .execute(
-                "insert into repos (repo_name) values (?1)",
-                [name.clone()]
-            )
-            .map_err(|e| {
-                format!("{:?}", e)
-            })?;
+                "insert into artifacts (run_id, attempt_id, name, desc, created_time) select run_id, id, ?2, ?3, ?4 from attempts where id=?1",
+                params![attempt_id, name, desc, created_time]
+            )?;
+
+        if rows_modified == 0 {
+            return Err(DbError::NotFound);
+        }
 
         Ok(conn.last_insert_rowid() as u64)
     }
 
-    pub async fn finalize_artifact(&self, artifact_id: u64) -> Result<(), String> {
-        let conn = self.conn.lock().unwrap();
-        conn
-            .execute(
-                "update artifacts set completed_time=?1 where id=?2",
-                params![crate::now_ms(), artifact_id]
+    /// record that `artifact_id` has been completely written to `artifact_file`. if the artifact is
+    /// a command's stdout or stderr, its lines are indexed for `search_logs` too. this reads as much
+    /// as `MAX_INDEXED_LOG_BYTES` of the file, so async callers should run it off their executor.
+    pub fn finalize_artifact(&self, artifact_id: u64, artifact_file: &Path) -> Result<(), DbError> {
+        let is_log: bool = self.reader()
+            .query_row(
+                "select name like '%(stdout)%' or name like '%(stderr)%' from artifacts where id=?1",
+                [artifact_id],
+                |row| row.get(0)
             )
-            .map(|_| ())
-            .map_err(|e| {
-                format!("{:?}", e)
-            })
+            .optional()?
+            .ok_or(DbError::NotFound)?;
+
+        // read before taking the writer, so a large log doesn't hold up everyone else's writes.
+        let mut log = Vec::new();
+        if is_log {
+            use std::io::Read;
+            let res = std::fs::File::open(artifact_file)
+                .and_then(|file| file.take(MAX_INDEXED_LOG_BYTES).read_to_end(&mut log));
+            if let Err(e) = res {
+                eprintln!("[-] could not read artifact {} to index it: {}", artifact_id, e);
+                log.clear();
+            }
+        }
+
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+
+        tx.execute(
+            "update artifacts set completed_time=?1 where id=?2",
+            params![crate::now_ms(), artifact_id]
+        )?;
+
+        Self::index_log(&tx, artifact_id, &log)?;
+
+        tx.commit()?;
+        Ok(())
     }
 
-    pub fn lookup_artifact(&self, run_id: u64, artifact_id: u64) -> Result<Option<ArtifactRecord>, String> {
-        let conn = self.conn.lock().unwrap();
-        conn
+    /// index `log`, the contents of log artifact `artifact_id`, for `search_logs`, replacing
+    /// whatever was indexed for it before. `finalize_artifact` does this for new logs; this is for
+    /// logs from before they were indexed, via `ci-ctl logs reindex`. only the first
+    /// `MAX_INDEXED_LOG_BYTES` of a log are meant to be indexed.
+    pub fn reindex_log(&self, artifact_id: u64, log: &[u8]) -> Result<(), DbError> {
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+        Self::index_log(&tx, artifact_id, log)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn index_log(conn: &Connection, artifact_id: u64, log: &[u8]) -> Result<(), rusqlite::Error> {
+        // an artifact indexed more than once is indexed as it is now, not as every version of it.
+        conn.execute("delete from log_lines where artifact_id=?1", [artifact_id])?;
+
+        let mut insert_line = conn.prepare("insert into log_lines (artifact_id, line_number, line) values (?1, ?2, ?3)")?;
+        for (idx, line) in String::from_utf8_lossy(log).lines().enumerate() {
+            insert_line.execute(params![artifact_id, idx as u64 + 1, line])?;
+        }
+        Ok(())
+    }
+
+    /// every stdout and stderr artifact that has been completely written, oldest first.
+    pub fn completed_log_artifacts(&self) -> Result<Vec<ArtifactRecord>, DbError> {
+        let conn = self.reader();
+
+        let mut artifacts_query = conn.prepare(sql::COMPLETED_LOG_ARTIFACTS)?;
+        let mut result = artifacts_query.query([])?;
+        let mut artifacts = Vec::new();
+
+        while let Some(row) = result.next()? {
+            let (id, run_id, attempt_id, name, desc, created_time, completed_time) = row.try_into()?;
+            artifacts.push(ArtifactRecord { id, run_id, attempt_id, name, desc, created_time, completed_time });
+        }
+
+        Ok(artifacts)
+    }
+
+    /// lines of stdout and stderr that contain `query.text`, oldest first.
+    pub fn search_logs(&self, query: &LogQuery) -> Result<Vec<LogMatch>, DbError> {
+        // search for the text as one phrase. otherwise punctuation, which is common in the things
+        // people look for in logs, would be read as fts5 query syntax.
+        let phrase = format!("\"{}\"", query.text.replace('"', "\"\""));
+
+        let conn = self.reader();
+        let mut matches = Vec::new();
+        {
+            let mut search_query = conn.prepare(sql::SEARCH_LOGS)?;
+            let mut result = search_query.query(params![phrase, query.repo_name, query.host_id, query.since, query.until, query.limit])?;
+
+            while let Some(row) = result.next()? {
+                let (run_id, attempt_id, artifact_id, artifact_name, repo_name, remote_path, sha, host_id, created_time, line_number) = row.try_into()?;
+                matches.push(LogMatch {
+                    run_id, attempt_id, artifact_id, artifact_name, repo_name, remote_path, sha, host_id, created_time, line_number,
+                    lines: Vec::new(),
+                });
+            }
+        }
+
+        let mut lines_query = conn.prepare(sql::LOG_LINES_BETWEEN)?;
+        for log_match in matches.iter_mut() {
+            let first = log_match.line_number.saturating_sub(query.context);
+            let last = log_match.line_number + query.context;
+            let mut result = lines_query.query(params![log_match.artifact_id, first, last])?;
+            while let Some(row) = result.next()? {
+                log_match.lines.push(row.try_into()?);
+            }
+        }
+
+        Ok(matches)
+    }
+
+    pub fn lookup_artifact(&self, run_id: u64, artifact_id: u64) -> Result<Option<ArtifactRecord>, DbError> {
+        let conn = self.reader();
+        let artifact = conn
             .query_row(sql::ARTIFACT_BY_ID, [artifact_id, run_id], |row| {
-                let (id, run_id, name, desc, created_time, completed_time) = row.try_into().unwrap();
+                let (id, run_id, attempt_id, name, desc, created_time, completed_time) = row.try_into()?;
 
                 Ok(ArtifactRecord {
-                    id, run_id, name, desc, created_time, completed_time
+                    id, run_id, attempt_id, name, desc, created_time, completed_time
                 })
             })
-            .optional()
-            .map_err(|e| e.to_string())
+            .optional()?;
+        Ok(artifact)
     }
 
-    pub fn commit_sha(&self, commit_id: u64) -> Result<String, String> {
-        self.conn.lock()
-            .unwrap()
+    pub fn commit_sha(&self, commit_id: u64) -> Result<String, DbError> {
+        let sha = self.reader()
             .query_row(
                 "select sha from commits where id=?1",
                 [commit_id],
                 |row| { row.get(0) }
-            )
-            .map_err(|e| e.to_string())
+            )?;
+        Ok(sha)
+    }
+
+    pub fn commit_id_by_sha(&self, repo_id: u64, sha: &str) -> Result<Option<u64>, DbError> {
+        let commit_id = self.reader()
+            .query_row(sql::COMMIT_TO_ID, params![repo_id, sha], |row| row.get(0))
+            .optional()?;
+        Ok(commit_id)
+    }
+
+    /// find the commit in `repo_id` whose sha starts with `prefix`, returning its id and full sha.
+    /// a prefix that more than one commit starts with is `DbError::Ambiguous`.
+    pub fn commit_by_sha_prefix(&self, repo_id: u64, prefix: &str) -> Result<Option<(u64, String)>, DbError> {
+        let conn = self.reader();
+        // compared with `substr` rather than `like`, so a prefix is only ever a prefix: `%` and `_`
+        // are just characters no sha has.
+        let mut commits_query = conn.prepare(
+            "select id, sha from commits where repo_id=?1 and substr(sha, 1, length(?2))=?2 limit 2;"
+        )?;
+        let mut commits = commits_query
+            .query_map(params![repo_id, prefix], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(u64, String)>, _>>()?;
+
+        if commits.len() > 1 {
+            return Err(DbError::Ambiguous(format!("more than one commit starts with {}", prefix)));
+        }
+
+        Ok(commits.pop())
     }
 
-    pub fn job_for_commit(&self, sha: &str) -> Result<Option<u64>, String> {
-        self.conn.lock()
-            .unwrap()
+    pub fn job_for_commit(&self, repo_id: u64, sha: &str) -> Result<Option<u64>, DbError> {
+        let job_id = self.reader()
             .query_row(
-                "select id from commits where sha=?1",
-                [sha],
+                "select jobs.id from jobs join commits on commits.id=jobs.commit_id where commits.repo_id=?1 and commits.sha=?2",
+                params![repo_id, sha],
                 |row| { row.get(0) }
             )
-            .optional()
-            .map_err(|e| e.to_string())
+            .optional()?;
+        Ok(job_id)
+    }
+
+    /// every job for `sha`, across all repos that have seen it.
+    pub fn jobs_for_sha(&self, sha: &str) -> Result<Vec<Job>, DbError> {
+        let conn = self.reader();
+        let mut jobs_query = conn.prepare(sql::JOBS_FOR_SHA)?;
+        let mut result = jobs_query.query([sha])?;
+        let mut jobs = Vec::new();
+
+        while let Some(row) = result.next()? {
+            let (id, source, created_time, remote_id, commit_id, run_preferences) = row.try_into()?;
+            jobs.push(Job {
+                id, source, created_time, remote_id, commit_id, run_preferences
+            });
+        }
+
+        Ok(jobs)
     }
 
-    pub fn run_for_token(&self, token: &str) -> Result<Option<(u64, Option<String>, TokenValidity)>, String> {
-        self.conn.lock()
-            .unwrap()
+    /// the attempt `token` was issued for, and whether the token is still good. a token is only as
+    /// good as the credential of the host it was issued to, so revoking a runner also stops it
+    /// from uploading anything more for the attempts it was running.
+    pub fn attempt_for_token(&self, token: &str) -> Result<Option<TokenAttempt>, DbError> {
+        let attempt = self.reader()
             .query_row(
-                "select id, artifacts_path, started_time, run_timeout from runs where build_token=?1",
+                sql::ATTEMPT_FOR_TOKEN,
                 [token],
                 |row| {
-                    let timeout: Option<u64> = row.get(3).unwrap();
-                    let timeout = timeout.unwrap_or(TOKEN_EXPIRY_MS);
+                    let timeout: Option<u64> = row.get(4)?;
+                    let timeout = timeout.unwrap_or(DEFAULT_RUN_TIMEOUT_MS);
 
                     let now = crate::now_ms();
 
-                    let time: Option<u64> = row.get(2).unwrap();
+                    let time: Option<u64> = row.get(3)?;
+                    let revoked_time: Option<u64> = row.get(5)?;
+                    let credential_expires: Option<u64> = row.get(6)?;
                     let validity = if let Some(time) = time {
                         if now > time + timeout {
                             TokenValidity::Expired
+                        } else if revoked_time.is_some() || credential_expires.map(|expires| expires <= now).unwrap_or(false) {
+                            TokenValidity::Revoked
                         } else {
                             TokenValidity::Valid
                         }
                     } else {
                         TokenValidity::Invalid
                     };
-                    Ok((row.get(0).unwrap(), row.get(1).unwrap(), validity))
+                    Ok(TokenAttempt {
+                        attempt_id: row.get(0)?,
+                        run_id: row.get(1)?,
+                        artifacts_path: row.get(2)?,
+                        validity,
+                    })
                 }
             )
-            .optional()
-            .map_err(|e| e.to_string())
+            .optional()?;
+        Ok(attempt)
     }
 
-    pub fn job_by_id(&self, id: u64) -> Result<Option<Job>, String> {
-        self.conn.lock()
-            .unwrap()
+    pub fn job_by_id(&self, id: u64) -> Result<Option<Job>, DbError> {
+        let job = self.reader()
             .query_row(crate::sql::JOB_BY_ID, [id], |row| {
-                let (id, source, created_time, remote_id, commit_id, run_preferences) = row.try_into().unwrap();
+                let (id, source, created_time, remote_id, commit_id, run_preferences) = row.try_into()?;
 
                 Ok(Job {
                     id, source, created_time, remote_id, commit_id, run_preferences
                 })
             })
-            .optional()
-            .map_err(|e| e.to_string())
+            .optional()?;
+        Ok(job)
     }
 
-    pub fn remote_by_path_and_api(&self, api: &str, path: &str) -> Result<Option<Remote>, String> {
-        self.conn.lock()
-            .unwrap()
+    pub fn remote_by_path_and_api(&self, api: &str, path: &str) -> Result<Option<Remote>, DbError> {
+        let remote = self.reader()
             .query_row("select id, repo_id, remote_path, remote_api, remote_url, remote_git_url, notifier_config_path from remotes where remote_api=?1 and remote_path=?2", [api, path], |row| {
-                let (id, repo_id, remote_path, remote_api, remote_url, remote_git_url, notifier_config_path) = row.try_into().unwrap();
+                let (id, repo_id, remote_path, remote_api, remote_url, remote_git_url, notifier_config_path) = row.try_into()?;
 
                 Ok(Remote {
                     id, repo_id, remote_path, remote_api, remote_url, remote_git_url, notifier_config_path
                 })
             })
-            .optional()
-            .map_err(|e| e.to_string())
+            .optional()?;
+        Ok(remote)
+    }
+
+    pub fn remote_by_path(&self, path: &str) -> Result<Option<Remote>, DbError> {
+        let remote = self.reader()
+            .query_row("select id, repo_id, remote_path, remote_api, remote_url, remote_git_url, notifier_config_path from remotes where remote_path=?1", [path], |row| {
+                let (id, repo_id, remote_path, remote_api, remote_url, remote_git_url, notifier_config_path) = row.try_into()?;
+
+                Ok(Remote {
+                    id, repo_id, remote_path, remote_api, remote_url, remote_git_url, notifier_config_path
+                })
+            })
+            .optional()?;
+        Ok(remote)
+    }
+
+    pub fn remote_by_git_url(&self, git_url: &str) -> Result<Option<Remote>, DbError> {
+        let remote = self.reader()
+            .query_row("select id, repo_id, remote_path, remote_api, remote_url, remote_git_url, notifier_config_path from remotes where remote_git_url=?1", [git_url], |row| {
+                let (id, repo_id, remote_path, remote_api, remote_url, remote_git_url, notifier_config_path) = row.try_into()?;
+
+                Ok(Remote {
+                    id, repo_id, remote_path, remote_api, remote_url, remote_git_url, notifier_config_path
+                })
+            })
+            .optional()?;
+        Ok(remote)
     }
 
-    pub fn remote_by_id(&self, id: u64) -> Result<Option<Remote>, String> {
-        self.conn.lock()
-            .unwrap()
+    pub fn remote_by_id(&self, id: u64) -> Result<Option<Remote>, DbError> {
+        let remote = self.reader()
             .query_row("select id, repo_id, remote_path, remote_api, remote_url, remote_git_url, notifier_config_path from remotes where id=?1", [id], |row| {
-                let (id, repo_id, remote_path, remote_api, remote_url, remote_git_url, notifier_config_path) = row.try_into().unwrap();
+                let (id, repo_id, remote_path, remote_api, remote_url, remote_git_url, notifier_config_path) = row.try_into()?;
 
                 Ok(Remote {
                     id, repo_id, remote_path, remote_api, remote_url, remote_git_url, notifier_config_path
                 })
             })
-            .optional()
-            .map_err(|e| e.to_string())
+            .optional()?;
+        Ok(remote)
     }
 
-    pub fn repo_id_by_remote(&self, remote_id: u64) -> Result<Option<u64>, String> {
-        self.conn.lock()
-            .unwrap()
+    pub fn repo_id_by_remote(&self, remote_id: u64) -> Result<Option<u64>, DbError> {
+        let repo_id = self.reader()
             .query_row("select repo_id from remotes where id=?1", [remote_id], |row| row.get(0))
-            .optional()
-            .map_err(|e| e.to_string())
+            .optional()?;
+        Ok(repo_id)
     }
 
-    pub fn repo_id_by_name(&self, repo_name: &str) -> Result<Option<u64>, String> {
-        self.conn.lock()
-            .unwrap()
+    pub fn repo_id_by_name(&self, repo_name: &str) -> Result<Option<u64>, DbError> {
+        let repo_id = self.reader()
             .query_row("select id from repos where repo_name=?1", [repo_name], |row| row.get(0))
-            .optional()
-            .map_err(|e| e.to_string())
+            .optional()?;
+        Ok(repo_id)
     }
 
-    pub fn new_remote(&self, repo_id: u64, remote: &str, remote_kind: &str, config_path: &str) -> Result<u64, String> {
+    pub fn new_remote(&self, repo_id: u64, remote: &str, remote_kind: &str, config_path: &str) -> Result<u64, DbError> {
         let (remote_path, remote_api, remote_url, remote_git_url) = match remote_kind {
             "github" => {
                 (remote.to_owned(), remote_kind.to_owned(), format!("https://www.github.com/{}", remote), format!("https://www.github.com/{}.git", remote))
@@ -236,93 +655,147 @@ impl DbCtx {
                 (remote.to_owned(), "email".to_owned(), format!("https://www.github.com/{}", remote), format!("http://www.github.com/{}.git", remote))
             },
             other => {
-                panic!("unsupported remote kind: {}", other);
+                return Err(DbError::ConstraintViolation(format!("unsupported remote kind: {}", other)));
             }
         };
 
-        let conn = self.conn.lock().unwrap();
+        let conn = self.writer();
         conn
             .execute(
                 "insert into remotes (repo_id, remote_path, remote_api, remote_url, remote_git_url, notifier_config_path) values (?1, ?2, ?3, ?4, ?5, ?6);",
                 params![repo_id, remote_path, remote_api, remote_url, remote_git_url, config_path]
-            )
-            .expect("can insert");
+            )?;
 
         Ok(conn.last_insert_rowid() as u64)
     }
 
-    pub fn update_commit_name(&self, commit_id: u64, name: &str) -> Result<(), String> {
-        let conn = self.conn.lock().unwrap();
-
-        let rows_modified = conn.execute(
-            "insert into commit_names (commit_id, name, name_state) values (?1, ?2, 0);",
-            params![commit_id, name]
-        ).unwrap();
-
-        assert_eq!(1, rows_modified);
-
+    /// point `ref_name` on `remote_id` at `sha`, as a push or force-push does.
+    pub fn update_ref(&self, remote_id: u64, ref_name: &str, sha: &str) -> Result<(), DbError> {
+        let now = crate::now_ms();
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+
+        tx.execute(
+            "insert into refs (remote_id, ref_name, sha, updated_time) values (?1, ?2, ?3, ?4) \
+             on conflict (remote_id, ref_name) do update set sha=excluded.sha, updated_time=excluded.updated_time;",
+            params![remote_id, ref_name, sha, now]
+        )?;
+        tx.execute(
+            "insert into ref_log (remote_id, ref_name, sha, updated_time) values (?1, ?2, ?3, ?4);",
+            params![remote_id, ref_name, sha, now]
+        )?;
+
+        tx.commit()?;
         Ok(())
     }
 
-    pub fn nice_name_for_commit(&self, commit_id: u64) -> Result<Option<CommitName>, String> {
-        let conn = self.conn.lock().unwrap();
+    /// forget `ref_name` on `remote_id`, as when a branch is deleted. commits it pointed to keep
+    /// the name, but only as a stale one.
+    pub fn delete_ref(&self, remote_id: u64, ref_name: &str) -> Result<(), DbError> {
+        self.writer()
+            .execute("delete from refs where remote_id=?1 and ref_name=?2;", params![remote_id, ref_name])?;
+        Ok(())
+    }
 
-        let mut names_query = conn.prepare(sql::NAMES_FOR_COMMIT).unwrap();
-        let mut result = names_query.query([commit_id]).unwrap();
-        let mut best_name: Option<CommitName> = None;
+    /// where `ref_name` on `remote_id` points, if it exists.
+    pub fn ref_sha(&self, remote_id: u64, ref_name: &str) -> Result<Option<String>, DbError> {
+        let sha = self.reader()
+            .query_row("select sha from refs where remote_id=?1 and ref_name=?2;", params![remote_id, ref_name], |row| row.get(0))
+            .optional()?;
+        Ok(sha)
+    }
 
-        while let Some(row) = result.next().unwrap() {
-            let (_id, name, name_state): (u64, String, u8) = row.try_into().unwrap();
-            if best_name.is_none() || best_name.as_ref().unwrap().stale() {
-                best_name = Some(CommitName {
-                    name,
-                    state: name_state.try_into().unwrap()
-                });
-            }
+    /// the best name for `commit_id`: a ref that points at it now, or failing that, the last ref
+    /// that pointed at it before moving on.
+    pub fn nice_name_for_commit(&self, commit_id: u64) -> Result<Option<CommitName>, DbError> {
+        let conn = self.reader();
+
+        let current: Option<String> = conn
+            .query_row(sql::CURRENT_REF_FOR_COMMIT, [commit_id], |row| row.get(0))
+            .optional()?;
+        if let Some(ref_name) = current {
+            return Ok(Some(CommitName {
+                name: short_ref_name(&ref_name).to_owned(),
+                state: sql::NameState::Fresh,
+            }));
         }
 
-        Ok(best_name)
+        let past: Option<String> = conn
+            .query_row(sql::PAST_REF_FOR_COMMIT, [commit_id], |row| row.get(0))
+            .optional()?;
+        Ok(past.map(|ref_name| CommitName {
+            name: short_ref_name(&ref_name).to_owned(),
+            state: sql::NameState::Stale,
+        }))
     }
 
-    pub fn new_job(&self, remote_id: u64, sha: &str, pusher: Option<&str>, repo_default_run_pref: Option<String>) -> Result<(u64, u64), String> {
-        // TODO: potential race: if two remotes learn about a commit at the same time and we decide
-        // to create two jobs at the same time, this might return an incorrect id if the insert
-        // didn't actually insert a new row.
-        let commit_id = self.new_commit(sha).expect("can create commit record");
-
-        let created_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("now is before epoch")
-            .as_millis() as u64;
+    /// make sure there is a job for `sha` pushed to `remote_id`, creating the commit, its job and
+    /// the job's first run if the commit is new to the remote's repo. everything happens in one
+    /// transaction, so two remotes of a repo learning about the same commit at once end up with
+    /// one job between them, and whichever loses the race is told about the winner's job.
+    pub fn create_job(&self, remote_id: u64, sha: &str, pusher: Option<&str>) -> Result<JobCreation, DbError> {
+        let created_time = crate::now_ms();
+        let mut conn = self.writer();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
 
-        let conn = self.conn.lock().unwrap();
+        let (repo_id, run_preferences): (u64, Option<String>) = tx
+            .query_row(
+                "select repos.id, repos.default_run_preference from remotes join repos on repos.id=remotes.repo_id where remotes.id=?1",
+                [remote_id],
+                |row| row.try_into()
+            )
+            .optional()?
+            .ok_or(DbError::NotFound)?;
+
+        tx.execute(
+            "insert into commits (repo_id, sha) values (?1, ?2) on conflict (repo_id, sha) do nothing",
+            params![repo_id, sha]
+        )?;
+        let commit_id: u64 = tx.query_row(
+            "select id from commits where repo_id=?1 and sha=?2",
+            params![repo_id, sha],
+            |row| row.get(0)
+        )?;
+
+        let existing: Option<u64> = tx
+            .query_row(
+                "select id from jobs where commit_id=?1 order by id asc limit 1",
+                [commit_id],
+                |row| row.get(0)
+            )
+            .optional()?;
+        if let Some(job_id) = existing {
+            return Ok(JobCreation::Existing { job_id, commit_id });
+        }
 
-        let rows_modified = conn.execute(
+        tx.execute(
             "insert into jobs (remote_id, commit_id, created_time, source, run_preferences) values (?1, ?2, ?3, ?4, ?5);",
-            params![remote_id, commit_id, created_time, pusher, repo_default_run_pref]
-        ).unwrap();
-
-        assert_eq!(1, rows_modified);
-
-        let job_id = conn.last_insert_rowid() as u64;
-
-        Ok((job_id, commit_id))
+            params![remote_id, commit_id, created_time, pusher, run_preferences]
+        )?;
+        let job_id = tx.last_insert_rowid() as u64;
+
+        tx.execute(
+            "insert into runs (job_id, state, created_time) values (?1, ?2, ?3);",
+            params![job_id, RunState::Pending as u64, created_time]
+        )?;
+        let run_id = tx.last_insert_rowid() as u64;
+
+        tx.commit()?;
+        Ok(JobCreation::Created { job_id, commit_id, run_id })
     }
 
-    pub fn new_run(&self, job_id: u64, host_preference: Option<u32>) -> Result<PendingRun, String> {
+    pub fn new_run(&self, job_id: u64, host_preference: Option<u32>) -> Result<PendingRun, DbError> {
         let created_time = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("now is before epoch")
             .as_millis() as u64;
 
-        let conn = self.conn.lock().unwrap();
+        let conn = self.writer();
 
-        let rows_modified = conn.execute(
+        conn.execute(
             "insert into runs (job_id, state, created_time, host_preference) values (?1, ?2, ?3, ?4);",
             params![job_id, crate::sql::RunState::Pending as u64, created_time, host_preference]
-        ).unwrap();
-
-        assert_eq!(1, rows_modified);
+        )?;
 
         let run_id = conn.last_insert_rowid() as u64;
 
@@ -330,182 +803,563 @@ impl DbCtx {
             id: run_id,
             job_id,
             create_time: created_time,
+            host_preference,
         })
     }
 
-    pub fn reap_task(&self, task_id: u64) -> Result<(), String> {
-        let conn = self.conn.lock().unwrap();
+    /// start a new attempt of `run_id` on `host_id`, returning the attempt's id. `build_token` is
+    /// how the runner identifies this attempt when it uploads artifacts, and the attempt must
+    /// finish within `run_timeout` milliseconds.
+    pub fn start_run(&self, run_id: u64, host_id: u32, artifacts_path: &str, build_token: &str, run_timeout: u64) -> Result<u64, DbError> {
+        let now = crate::now_ms();
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+
+        let rows_modified = tx.execute(
+            "update runs set started_time=?1, host_id=?2, state=?3, artifacts_path=?4, build_token=?5, run_timeout=?6, \
+             complete_time=null, build_result=null, final_status=null, outcome=null where id=?7",
+            params![now, host_id, RunState::Started as u64, artifacts_path, build_token, run_timeout, run_id]
+        )?;
+
+        if rows_modified == 0 {
+            return Err(DbError::NotFound);
+        }
 
-        conn.execute(
-            "update runs set final_status=\"lost signal\", state=4 where id=?1;",
-            [task_id]
-        ).unwrap();
+        let attempt: u32 = tx.query_row(
+            "select coalesce(max(attempt), 0) + 1 from attempts where run_id=?1",
+            [run_id],
+            |row| row.get(0)
+        )?;
 
-        Ok(())
+        tx.execute(
+            "insert into attempts (run_id, attempt, host_id, build_token, artifacts_path, state, started_time) values (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![run_id, attempt, host_id, build_token, artifacts_path, RunState::Started as u64, now]
+        )?;
+        let attempt_id = tx.last_insert_rowid() as u64;
+
+        tx.commit()?;
+        Ok(attempt_id)
     }
 
-    pub fn metrics_for_run(&self, run: u64) -> Result<Vec<MetricRecord>, String> {
-        let conn = self.conn.lock().unwrap();
+    /// record the outcome of `attempt_id` once it has ended. the attempt's run is updated too,
+    /// unless it has been attempted again since.
+    pub fn complete_attempt(&self, attempt_id: u64, outcome: TaskOutcome, final_status: &str) -> Result<(), DbError> {
+        let now = crate::now_ms();
+        let state = outcome.run_state();
+        let build_result = outcome.build_result() as u8;
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+
+        let rows_modified = tx.execute(
+            "update attempts set complete_time=?1, state=?2, build_result=?3, final_status=?4, outcome=?5 where id=?6",
+            params![now, state as u64, build_result, final_status, outcome.as_str(), attempt_id]
+        )?;
+
+        if rows_modified == 0 {
+            return Err(DbError::NotFound);
+        }
 
-        let mut metrics_query = conn.prepare(sql::METRICS_FOR_RUN).unwrap();
-        let mut result = metrics_query.query([run]).unwrap();
-        let mut metrics = Vec::new();
+        tx.execute(
+            "update runs set complete_time=?1, state=?2, build_result=?3, final_status=?4, outcome=?5 \
+             where id=(select run_id from attempts where id=?6) \
+             and not exists (select 1 from attempts newer where newer.run_id=runs.id and newer.id > ?6)",
+            params![now, state as u64, build_result, final_status, outcome.as_str(), attempt_id]
+        )?;
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// change how long `run_id` may take, counted from the start of its current attempt.
+    pub fn set_run_timeout(&self, run_id: u64, run_timeout: u64) -> Result<(), DbError> {
+        let rows_modified = self.writer()
+            .execute("update runs set run_timeout=?1 where id=?2", params![run_timeout, run_id])?;
 
-        while let Some(row) = result.next().unwrap() {
-            let (id, run_id, name, value): (u64, u64, String, String) = row.try_into().unwrap();
-            metrics.push(MetricRecord { id, run_id, name, value });
+        if rows_modified == 0 {
+            return Err(DbError::NotFound);
         }
 
-        Ok(metrics)
+        Ok(())
     }
 
-    pub fn artifacts_for_run(&self, run: u64, limit: Option<u64>) -> Result<Vec<ArtifactRecord>, String> {
-        let conn = self.conn.lock().unwrap();
+    /// put a finished run back in the queue. the next runner to pick it up starts a new attempt,
+    /// and earlier attempts are kept as they were.
+    pub fn retry_run(&self, run_id: u64) -> Result<(), DbError> {
+        let rows_modified = self.writer().execute(
+            "update runs set state=?1, started_time=null, complete_time=null, build_result=null, final_status=null, \
+             outcome=null, cancel_requested_time=null where id=?2 and state not in (?1, ?3)",
+            params![RunState::Pending as u64, run_id, RunState::Started as u64]
+        )?;
+
+        if rows_modified == 0 {
+            return Err(DbError::NotFound);
+        }
 
-        let mut artifacts_query = conn.prepare(sql::LAST_ARTIFACTS_FOR_RUN).unwrap();
-        let mut result = artifacts_query.query([run, limit.unwrap_or(65535)]).unwrap();
-        let mut artifacts = Vec::new();
+        Ok(())
+    }
 
-        while let Some(row) = result.next().unwrap() {
-            let (id, run_id, name, desc, created_time, completed_time): (u64, u64, String, String, u64, Option<u64>) = row.try_into().unwrap();
-            artifacts.push(ArtifactRecord { id, run_id, name, desc, created_time, completed_time });
+    /// cancel `run_id`, returning the state it's left in. a pending run is cancelled right away.
+    /// a started run stays `Started` until the driver running it notices the request and stops
+    /// the runner.
+    pub fn cancel_run(&self, run_id: u64) -> Result<RunState, DbError> {
+        let now = crate::now_ms();
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+
+        let rows_modified = tx.execute(
+            "update runs set cancel_requested_time=?1 where id=?2 and state in (?3, ?4)",
+            params![now, run_id, RunState::Pending as u64, RunState::Started as u64]
+        )?;
+
+        if rows_modified == 0 {
+            return Err(DbError::NotFound);
         }
 
-        Ok(artifacts)
-    }
+        // the request is kept even for pending runs: if a driver picked the run up just now, it
+        // will still see that it should stop.
+        tx.execute(
+            "update runs set state=?1, complete_time=?2, final_status=\"cancelled\", outcome=?3 where id=?4 and state=?5",
+            params![RunState::Cancelled as u64, now, TaskOutcome::Cancelled.as_str(), run_id, RunState::Pending as u64]
+        )?;
 
-    pub fn repo_by_id(&self, id: u64) -> Result<Option<Repo>, String> {
-        self.conn.lock()
-            .unwrap()
-            .query_row("select id, repo_name, default_run_preference from repos where id=?1", [id], |row| {
-                let (id, repo_name, default_run_preference) = row.try_into().unwrap();
-                Ok(Repo {
-                    id,
-                    name: repo_name,
-                    default_run_preference,
-                })
-            })
-            .optional()
-            .map_err(|e| e.to_string())
+        let state: RunState = tx.query_row("select state from runs where id=?1", [run_id], |row| row.get(0))?;
+
+        tx.commit()?;
+        Ok(state)
     }
 
-    pub fn get_repos(&self) -> Result<Vec<Repo>, String> {
-        let conn = self.conn.lock().unwrap();
+    pub fn cancel_requested(&self, run_id: u64) -> Result<bool, DbError> {
+        let requested: Option<u64> = self.reader()
+            .query_row("select cancel_requested_time from runs where id=?1", [run_id], |row| row.get(0))
+            .optional()?
+            .ok_or(DbError::NotFound)?;
+        Ok(requested.is_some())
+    }
 
-        let mut repos_query = conn.prepare(sql::ALL_REPOS).unwrap();
-        let mut repos = repos_query.query([]).unwrap();
-        let mut result = Vec::new();
+    /// note that `attempt_id`'s runner is still there.
+    pub fn record_heartbeat(&self, attempt_id: u64) -> Result<(), DbError> {
+        let rows_modified = self.writer().execute(
+            "update attempts set heartbeat_time=?1 where id=?2 and state=?3",
+            params![crate::now_ms(), attempt_id, RunState::Started as u64]
+        )?;
 
-        while let Some(row) = repos.next().unwrap() {
-            let (id, repo_name, default_run_preference) = row.try_into().unwrap();
-            result.push(Repo {
-                id,
-                name: repo_name,
-                default_run_preference,
-            });
+        if rows_modified == 0 {
+            return Err(DbError::NotFound);
         }
 
-        Ok(result)
+        Ok(())
     }
 
-    pub fn last_job_from_remote(&self, id: u64) -> Result<Option<Job>, String> {
-        self.recent_jobs_from_remote(id, 1)
-            .map(|mut jobs| jobs.pop())
-    }
+    /// note that `attempt_id`'s runner sent a heartbeat, and what it said.
+    pub fn record_runner_heartbeat(&self, attempt_id: u64, info: &HeartbeatInfo) -> Result<(), DbError> {
+        let step = serde_json::to_string(&info.step)
+            .map_err(|e| DbError::Conversion(format!("step: {}", e)))?;
 
-    pub fn job_by_commit_id(&self, commit_id: u64) -> Result<Option<Job>, String> {
-        let conn = self.conn.lock().unwrap();
+        let rows_modified = self.writer().execute(
+            sql::RECORD_RUNNER_HEARTBEAT,
+            params![crate::now_ms(), step, info.elapsed_ms, info.load_avg, info.mem_available_kb, attempt_id, RunState::Started as u64]
+        )?;
 
-        conn
-            .query_row(sql::JOB_BY_COMMIT_ID, [commit_id], |row| {
-                let (id, source, created_time, remote_id, commit_id, run_preferences) = row.try_into().unwrap();
-                Ok(Job {
-                    id,
-                    remote_id,
-                    commit_id,
-                    created_time,
-                    source,
-                    run_preferences,
-                })
-            })
-            .optional()
-            .map_err(|e| e.to_string())
+        if rows_modified == 0 {
+            return Err(DbError::NotFound);
+        }
+
+        Ok(())
     }
 
-    pub fn recent_jobs_from_remote(&self, id: u64, limit: u64) -> Result<Vec<Job>, String> {
-        let conn = self.conn.lock().unwrap();
+    pub fn progress_for_attempt(&self, attempt_id: u64) -> Result<Option<AttemptProgress>, DbError> {
+        let progress = self.reader()
+            .query_row(sql::ATTEMPT_PROGRESS, [attempt_id], Self::row2progress)
+            .optional()?;
+        Ok(progress)
+    }
 
-        let mut job_query = conn.prepare(sql::LAST_JOBS_FROM_REMOTE).unwrap();
-        let mut result = job_query.query([id, limit]).unwrap();
+    /// started attempts whose runner hasn't been heard from since `since`, in ms since the epoch.
+    pub fn stale_attempts(&self, since: u64) -> Result<Vec<Attempt>, DbError> {
+        let conn = self.reader();
 
-        let mut jobs = Vec::new();
+        let mut attempts_query = conn.prepare(sql::STALE_ATTEMPTS)?;
+        let mut result = attempts_query.query(params![RunState::Started as u64, since])?;
+        let mut attempts = Vec::new();
 
-        while let Some(row) = result.next().unwrap() {
-            let (id, source, created_time, remote_id, commit_id, run_preferences) = row.try_into().unwrap();
-            jobs.push(Job {
-                id,
-                remote_id,
-                commit_id,
-                created_time,
-                source,
-                run_preferences,
-            });
+        while let Some(row) = result.next()? {
+            attempts.push(Self::row2attempt(row)?);
         }
 
-        Ok(jobs)
+        Ok(attempts)
     }
 
-    pub fn get_active_runs(&self) -> Result<Vec<Run>, String> {
-        let conn = self.conn.lock().unwrap();
-
-        let mut started_query = conn.prepare(sql::ACTIVE_RUNS).unwrap();
-        let mut runs = started_query.query([]).unwrap();
-        let mut started = Vec::new();
-
-        while let Some(row) = runs.next().unwrap() {
-            started.push(Self::row2run(row));
+    /// abandon `attempt_id` for `reason`, which should read well as "retried after {reason}", and
+    /// queue its run again. a run is requeued at most `max_requeues` times, and never once it has
+    /// been cancelled. returns whether the run was requeued; if it wasn't, nothing has changed and
+    /// the attempt is still the caller's to complete.
+    pub fn requeue_attempt(&self, attempt_id: u64, reason: &str, max_requeues: u32) -> Result<bool, DbError> {
+        let now = crate::now_ms();
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+
+        let (run_id, cancel_requested): (u64, Option<u64>) = tx.query_row(
+            "select attempts.run_id, runs.cancel_requested_time from attempts join runs on runs.id=attempts.run_id \
+             where attempts.id=?1 and attempts.state=?2",
+            params![attempt_id, RunState::Started as u64],
+            |row| row.try_into()
+        ).optional()?.ok_or(DbError::NotFound)?;
+
+        let requeues: u32 = tx.query_row(
+            "select count(*) from attempts where run_id=?1 and requeue_reason is not null",
+            [run_id],
+            |row| row.get(0)
+        )?;
+
+        if cancel_requested.is_some() || requeues >= max_requeues {
+            return Ok(false);
         }
 
-        Ok(started)
+        let outcome = TaskOutcome::InfraError;
+        tx.execute(
+            "update attempts set state=?1, complete_time=?2, build_result=?3, final_status=?4, outcome=?5, requeue_reason=?4 where id=?6",
+            params![outcome.run_state() as u64, now, outcome.build_result() as u8, reason, outcome.as_str(), attempt_id]
+        )?;
+
+        // if the run has been attempted again since, that attempt is in charge of it instead.
+        tx.execute(
+            "update runs set state=?1, started_time=null, complete_time=null, build_result=null, final_status=null, outcome=null \
+             where id=?2 and not exists (select 1 from attempts newer where newer.run_id=runs.id and newer.id > ?3)",
+            params![RunState::Pending as u64, run_id, attempt_id]
+        )?;
+
+        tx.commit()?;
+        Ok(true)
     }
 
-    pub fn get_pending_runs(&self, host_id: Option<u32>) -> Result<Vec<PendingRun>, String> {
-        let conn = self.conn.lock().unwrap();
+    /// every attempt of `run_id`, oldest first.
+    pub fn attempts_for_run(&self, run_id: u64) -> Result<Vec<Attempt>, DbError> {
+        let conn = self.reader();
 
-        let mut pending_query = conn.prepare(sql::PENDING_RUNS).unwrap();
-        let mut runs = pending_query.query([host_id]).unwrap();
-        let mut pending = Vec::new();
+        let mut attempts_query = conn.prepare(sql::ATTEMPTS_FOR_RUN)?;
+        let mut result = attempts_query.query([run_id])?;
+        let mut attempts = Vec::new();
 
-        while let Some(row) = runs.next().unwrap() {
-            let (id, job_id, create_time) = row.try_into().unwrap();
-            let run = PendingRun {
-                id,
-                job_id,
-                create_time,
-            };
-            pending.push(run);
+        while let Some(row) = result.next()? {
+            attempts.push(Self::row2attempt(row)?);
         }
 
-        Ok(pending)
+        Ok(attempts)
     }
 
-    pub fn jobs_needing_task_runs_for_host(&self, host_id: u64) -> Result<Vec<Job>, String> {
-        // for jobs that this host has not run, we'll arbitrarily say that we won't generate new
-        // runs for jobs more than a day old.
-        //
-        // we don't want to rebuild the entire history every time we see a new host by default; if
-        // you really want to rebuild all of history on a new host, use `ci_ctl` to prepare the
-        // runs.
-        let cutoff = crate::now_ms() - 24 * 3600 * 1000;
+    pub fn attempt_by_id(&self, attempt_id: u64) -> Result<Option<Attempt>, DbError> {
+        let attempt = self.reader()
+            .query_row(sql::ATTEMPT_BY_ID, [attempt_id], Self::row2attempt)
+            .optional()?;
+        Ok(attempt)
+    }
 
-        let conn = self.conn.lock().unwrap();
+    pub fn run_by_id(&self, run_id: u64) -> Result<Option<Run>, DbError> {
+        let run = self.reader()
+            .query_row(sql::RUN_TO_FIELDS, [run_id], Self::row2run)
+            .optional()?;
+        Ok(run)
+    }
+
+    pub fn latest_attempt_for_run(&self, run_id: u64) -> Result<Option<Attempt>, DbError> {
+        let attempt = self.reader()
+            .query_row(sql::LATEST_ATTEMPT_FOR_RUN, [run_id], Self::row2attempt)
+            .optional()?;
+        Ok(attempt)
+    }
+
+    pub fn metrics_for_run(&self, run: u64) -> Result<Vec<MetricRecord>, DbError> {
+        let conn = self.reader();
+
+        let mut metrics_query = conn.prepare(sql::METRICS_FOR_RUN)?;
+        let mut result = metrics_query.query([run])?;
+        let mut metrics = Vec::new();
+
+        while let Some(row) = result.next()? {
+            metrics.push(Self::row2metric(row)?);
+        }
+
+        Ok(metrics)
+    }
+
+    pub fn metrics_for_attempt(&self, attempt_id: u64) -> Result<Vec<MetricRecord>, DbError> {
+        let conn = self.reader();
+
+        let mut metrics_query = conn.prepare(sql::METRICS_FOR_ATTEMPT)?;
+        let mut result = metrics_query.query([attempt_id])?;
+        let mut metrics = Vec::new();
+
+        while let Some(row) = result.next()? {
+            metrics.push(Self::row2metric(row)?);
+        }
+
+        Ok(metrics)
+    }
+
+    pub fn artifacts_for_run(&self, run: u64, limit: Option<u64>) -> Result<Vec<ArtifactRecord>, DbError> {
+        let conn = self.reader();
+
+        let mut artifacts_query = conn.prepare(sql::LAST_ARTIFACTS_FOR_RUN)?;
+        let mut result = artifacts_query.query([run, limit.unwrap_or(65535)])?;
+        let mut artifacts = Vec::new();
+
+        while let Some(row) = result.next()? {
+            let (id, run_id, attempt_id, name, desc, created_time, completed_time) = row.try_into()?;
+            artifacts.push(ArtifactRecord { id, run_id, attempt_id, name, desc, created_time, completed_time });
+        }
+
+        Ok(artifacts)
+    }
+
+    pub fn artifacts_for_attempt(&self, attempt_id: u64, limit: Option<u64>) -> Result<Vec<ArtifactRecord>, DbError> {
+        let conn = self.reader();
+
+        let mut artifacts_query = conn.prepare(sql::ARTIFACTS_FOR_ATTEMPT)?;
+        let mut result = artifacts_query.query([attempt_id, limit.unwrap_or(65535)])?;
+        let mut artifacts = Vec::new();
+
+        while let Some(row) = result.next()? {
+            let (id, run_id, attempt_id, name, desc, created_time, completed_time) = row.try_into()?;
+            artifacts.push(ArtifactRecord { id, run_id, attempt_id, name, desc, created_time, completed_time });
+        }
+
+        Ok(artifacts)
+    }
+
+    /// record that the runner for `attempt_id` started its command `command_id`.
+    pub fn start_command(&self, attempt_id: u64, command_id: u32, argv: &[String], cwd: Option<&str>, step: &[String]) -> Result<(), DbError> {
+        let argv = serde_json::to_string(argv)
+            .map_err(|e| DbError::Conversion(format!("argv: {}", e)))?;
+        let step = serde_json::to_string(step)
+            .map_err(|e| DbError::Conversion(format!("step: {}", e)))?;
+
+        let rows_modified = self.writer()
+            .execute(
+                sql::INSERT_COMMAND,
+                params![attempt_id, command_id, argv, cwd, step, crate::now_ms()]
+            )?;
+
+        if rows_modified == 0 {
+            return Err(DbError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    /// link a command to the artifacts, by name, that its stdout and stderr are written to.
+    pub fn link_command_artifacts(&self, attempt_id: u64, command_id: u32, stdout_artifact: Option<&str>, stderr_artifact: Option<&str>) -> Result<(), DbError> {
+        let rows_modified = self.writer()
+            .execute(
+                sql::LINK_COMMAND_ARTIFACTS,
+                params![attempt_id, command_id, stdout_artifact, stderr_artifact]
+            )?;
+
+        if rows_modified == 0 {
+            return Err(DbError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    pub fn finish_command(&self, attempt_id: u64, command_id: u32, exit_code: Option<i32>) -> Result<(), DbError> {
+        let rows_modified = self.writer()
+            .execute(
+                sql::FINISH_COMMAND,
+                params![attempt_id, command_id, crate::now_ms(), exit_code]
+            )?;
+
+        if rows_modified == 0 {
+            return Err(DbError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    /// the commands run during `attempt_id`, in the order they were run.
+    pub fn commands_for_attempt(&self, attempt_id: u64) -> Result<Vec<CommandRecord>, DbError> {
+        let conn = self.reader();
+        let mut commands_query = conn.prepare(sql::COMMANDS_FOR_ATTEMPT)?;
+        let commands = commands_query.query_map([attempt_id], Self::row2command)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(commands)
+    }
+
+    /// set how long runs of `repo_id` may take by default, or go back to the driver's default if
+    /// `run_timeout` is `None`.
+    pub fn set_repo_run_timeout(&self, repo_id: u64, run_timeout: Option<u64>) -> Result<(), DbError> {
+        let rows_modified = self.writer()
+            .execute("update repos set default_run_timeout=?1 where id=?2", params![run_timeout, repo_id])?;
+
+        if rows_modified == 0 {
+            return Err(DbError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    pub fn repo_by_id(&self, id: u64) -> Result<Option<Repo>, DbError> {
+        let repo = self.reader()
+            .query_row("select id, repo_name, default_run_preference, default_run_timeout from repos where id=?1", [id], |row| {
+                let (id, repo_name, default_run_preference, default_run_timeout) = row.try_into()?;
+                Ok(Repo {
+                    id,
+                    name: repo_name,
+                    default_run_preference,
+                    default_run_timeout,
+                })
+            })
+            .optional()?;
+        Ok(repo)
+    }
+
+    pub fn repo_by_name(&self, name: &str) -> Result<Option<Repo>, DbError> {
+        let repo = self.reader()
+            .query_row("select id, repo_name, default_run_preference, default_run_timeout from repos where repo_name=?1", [name], |row| {
+                let (id, repo_name, default_run_preference, default_run_timeout) = row.try_into()?;
+                Ok(Repo {
+                    id,
+                    name: repo_name,
+                    default_run_preference,
+                    default_run_timeout,
+                })
+            })
+            .optional()?;
+        Ok(repo)
+    }
+
+    pub fn get_repos(&self) -> Result<Vec<Repo>, DbError> {
+        let conn = self.reader();
+
+        let mut repos_query = conn.prepare(sql::ALL_REPOS)?;
+        let mut repos = repos_query.query([])?;
+        let mut result = Vec::new();
+
+        while let Some(row) = repos.next()? {
+            let (id, repo_name, default_run_preference, default_run_timeout) = row.try_into()?;
+            result.push(Repo {
+                id,
+                name: repo_name,
+                default_run_preference,
+                default_run_timeout,
+            });
+        }
+
+        Ok(result)
+    }
+
+    pub fn last_job_from_remote(&self, id: u64) -> Result<Option<Job>, DbError> {
+        self.recent_jobs_from_remote(id, 1)
+            .map(|mut jobs| jobs.pop())
+    }
+
+    pub fn job_by_commit_id(&self, commit_id: u64) -> Result<Option<Job>, DbError> {
+        let conn = self.reader();
+
+        let job = conn
+            .query_row(sql::JOB_BY_COMMIT_ID, [commit_id], |row| {
+                let (id, source, created_time, remote_id, commit_id, run_preferences) = row.try_into()?;
+                Ok(Job {
+                    id,
+                    remote_id,
+                    commit_id,
+                    created_time,
+                    source,
+                    run_preferences,
+                })
+            })
+            .optional()?;
+        Ok(job)
+    }
+
+    pub fn recent_jobs_from_remote(&self, id: u64, limit: u64) -> Result<Vec<Job>, DbError> {
+        let conn = self.reader();
+
+        let mut job_query = conn.prepare(sql::LAST_JOBS_FROM_REMOTE)?;
+        let mut result = job_query.query([id, limit])?;
+
+        let mut jobs = Vec::new();
+
+        while let Some(row) = result.next()? {
+            let (id, source, created_time, remote_id, commit_id, run_preferences) = row.try_into()?;
+            jobs.push(Job {
+                id,
+                remote_id,
+                commit_id,
+                created_time,
+                source,
+                run_preferences,
+            });
+        }
+
+        Ok(jobs)
+    }
+
+    pub fn get_active_runs(&self) -> Result<Vec<Run>, DbError> {
+        let conn = self.reader();
+
+        let mut started_query = conn.prepare(sql::ACTIVE_RUNS)?;
+        let mut runs = started_query.query([])?;
+        let mut started = Vec::new();
+
+        while let Some(row) = runs.next()? {
+            started.push(Self::row2run(row)?);
+        }
+
+        Ok(started)
+    }
 
-        let mut jobs_needing_task_runs = conn.prepare(sql::JOBS_NEEDING_HOST_RUN).unwrap();
-        let mut job_rows = jobs_needing_task_runs.query([cutoff, host_id]).unwrap();
+    pub fn get_all_runs(&self) -> Result<Vec<Run>, DbError> {
+        let conn = self.reader();
+
+        let mut runs_query = conn.prepare(sql::ALL_RUNS)?;
+        let mut runs = runs_query.query([])?;
+        let mut result = Vec::new();
+
+        while let Some(row) = runs.next()? {
+            result.push(Self::row2run(row)?);
+        }
+
+        Ok(result)
+    }
+
+    pub fn get_pending_runs(&self, host_id: Option<u32>) -> Result<Vec<PendingRun>, DbError> {
+        let conn = self.reader();
+
+        let mut pending_query = conn.prepare(sql::PENDING_RUNS)?;
+        let mut runs = pending_query.query([host_id])?;
+        let mut pending = Vec::new();
+
+        while let Some(row) = runs.next()? {
+            let (id, job_id, create_time, host_preference) = row.try_into()?;
+            let run = PendingRun {
+                id,
+                job_id,
+                create_time,
+                host_preference,
+            };
+            pending.push(run);
+        }
+
+        Ok(pending)
+    }
+
+    pub fn jobs_needing_task_runs_for_host(&self, host_id: u64) -> Result<Vec<Job>, DbError> {
+        // for jobs that this host has not run, we'll arbitrarily say that we won't generate new
+        // runs for jobs more than a day old.
+        //
+        // we don't want to rebuild the entire history every time we see a new host by default; if
+        // you really want to rebuild all of history on a new host, use `ci_ctl` to prepare the
+        // runs.
+        let cutoff = crate::now_ms() - 24 * 3600 * 1000;
+
+        let conn = self.reader();
+
+        let mut jobs_needing_task_runs = conn.prepare(sql::JOBS_NEEDING_HOST_RUN)?;
+        let mut job_rows = jobs_needing_task_runs.query([cutoff, host_id])?;
         let mut jobs = Vec::new();
 
-        while let Some(row) = job_rows.next().unwrap() {
-            let (id, source, created_time, remote_id, commit_id, run_preferences) = row.try_into().unwrap();
+        while let Some(row) = job_rows.next()? {
+            let (id, source, created_time, remote_id, commit_id, run_preferences) = row.try_into()?;
 
             jobs.push(Job {
                 id, source, created_time, remote_id, commit_id, run_preferences,
@@ -516,33 +1370,34 @@ impl DbCtx {
     }
 
 
-    pub fn remotes_by_repo(&self, repo_id: u64) -> Result<Vec<Remote>, String> {
+    pub fn remotes_by_repo(&self, repo_id: u64) -> Result<Vec<Remote>, DbError> {
         let mut remotes: Vec<Remote> = Vec::new();
 
-        let conn = self.conn.lock().unwrap();
-        let mut remotes_query = conn.prepare(crate::sql::REMOTES_FOR_REPO).unwrap();
-        let mut remote_results = remotes_query.query([repo_id]).unwrap();
+        let conn = self.reader();
+        let mut remotes_query = conn.prepare(crate::sql::REMOTES_FOR_REPO)?;
+        let mut remote_results = remotes_query.query([repo_id])?;
 
-        while let Some(row) = remote_results.next().unwrap() {
-            let (id, repo_id, remote_path, remote_api, remote_url, remote_git_url, notifier_config_path) = row.try_into().unwrap();
+        while let Some(row) = remote_results.next()? {
+            let (id, repo_id, remote_path, remote_api, remote_url, remote_git_url, notifier_config_path) = row.try_into()?;
             remotes.push(Remote { id, repo_id, remote_path, remote_api, remote_url, remote_git_url, notifier_config_path });
         }
 
         Ok(remotes)
     }
 
-    /// try to find a host close to `host_info`, but maybe not an exact match.
+    /// try to find a host close to `host_info`, but maybe not an exact match. only hosts that
+    /// haven't been claimed by a runner identity or retired are considered.
     ///
-    /// specifically, we'll ignore microcode and family/os - enough that measurements ought to be
-    /// comparable but maybe not perfectly so.
-    pub fn find_id_like_host(&self, host_info: &crate::protocol::HostInfo) -> Result<Option<u32>, String> {
-        self.conn.lock()
-            .unwrap()
+    /// specifically, we'll ignore microcode, mem_total and family/os - enough that measurements
+    /// ought to be comparable but maybe not perfectly so.
+    pub fn find_id_like_host(&self, host_info: &crate::protocol::HostInfo) -> Result<Option<u32>, DbError> {
+        Ok(Self::host_like(&self.reader(), host_info)?)
+    }
+
+    fn host_like(conn: &Connection, host_info: &crate::protocol::HostInfo) -> Result<Option<u32>, rusqlite::Error> {
+        conn
             .query_row(
-                "select id from hosts where \
-                    hostname=?1 and cpu_vendor_id=?2 and cpu_model_name=?3 and cpu_family=?4 and \
-                    cpu_model=?5 and cpu_max_freq_khz=?6 and cpu_cores=?7 and mem_total=?8 and \
-                    arch=?9);",
+                sql::HOST_LIKE_FINGERPRINT,
                 params![
                     &host_info.hostname,
                     &host_info.cpu_info.vendor_id,
@@ -551,31 +1406,23 @@ impl DbCtx {
                     &host_info.cpu_info.model,
                     &host_info.cpu_info.max_freq,
                     &host_info.cpu_info.cores,
-                    &host_info.memory_info.total,
                     &host_info.env_info.arch,
                 ],
                 |row| { row.get(0) }
             )
-            .map_err(|e| e.to_string())
+            .optional()
     }
 
-    /// get an id for the host described by `host_info`. this may create a new record if no such
-    /// host exists.
-    pub fn id_for_host(&self, host_info: &crate::protocol::HostInfo) -> Result<u32, String> {
-        let conn = self.conn.lock().unwrap();
+    /// get an id for the host described by `host_info`, for runners that don't have an identity.
+    /// this may create a new record if no host has exactly this hardware. the runner must be allowed
+    /// to act as the host, as `authorize_host` describes, before anything about it is recorded.
+    pub fn id_for_host(&self, host_info: &crate::protocol::HostInfo, credential_id: Option<u64>) -> Result<u32, DbError> {
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
 
-        conn
-            .execute(
-                "insert or ignore into hosts \
-                 (\
-                     hostname, cpu_vendor_id, cpu_model_name, cpu_family, \
-                     cpu_model, cpu_microcode, cpu_max_freq_khz, cpu_cores, \
-                     mem_total, arch, family, os\
-                 ) values (\
-                     ?1, ?2, ?3, ?4, \
-                     ?5, ?6, ?7, ?8, \
-                     ?9, ?10, ?11, ?12 \
-                 );",
+        let existing: Option<u32> = tx
+            .query_row(
+                sql::HOST_BY_FINGERPRINT,
                 params![
                     &host_info.hostname,
                     &host_info.cpu_info.vendor_id,
@@ -589,17 +1436,114 @@ impl DbCtx {
                     &host_info.env_info.arch,
                     &host_info.env_info.family,
                     &host_info.env_info.os,
-                ]
+                ],
+                |row| { row.get(0) }
             )
-            .expect("can insert");
+            .optional()?;
 
-        conn
+        let host_id = match existing {
+            Some(host_id) => host_id,
+            None => Self::insert_host(&tx, None, host_info)?,
+        };
+
+        Self::authorize_host(&tx, host_id, credential_id)?;
+        Self::record_host_facts(&tx, host_id, host_info)?;
+
+        tx.commit()?;
+        Ok(host_id)
+    }
+
+    /// get an id for the host whose runner identifies itself as `runner_id`, recording
+    /// `host_info` as its current hardware.
+    ///
+    /// the first time a runner identity is seen it claims the closest host without an identity, if
+    /// there is one, so that upgrading a runner doesn't start its metric history over. otherwise a
+    /// new host is created. as with `id_for_host`, nothing is recorded unless the runner is allowed
+    /// to act as the host.
+    pub fn id_for_runner(&self, runner_id: &str, host_info: &crate::protocol::HostInfo, credential_id: Option<u64>) -> Result<u32, DbError> {
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+
+        let existing: Option<u32> = tx
+            .query_row(sql::HOST_BY_RUNNER_ID, [runner_id], |row| row.get(0))
+            .optional()?;
+
+        let host_id = match existing {
+            Some(host_id) => host_id,
+            None => {
+                match Self::host_like(&tx, host_info)? {
+                    Some(host_id) => {
+                        tx.execute("update hosts set runner_id=?1 where id=?2", params![runner_id, host_id])?;
+                        host_id
+                    }
+                    None => Self::insert_host(&tx, Some(runner_id), host_info)?,
+                }
+            }
+        };
+
+        Self::authorize_host(&tx, host_id, credential_id)?;
+        Self::record_host_facts(&tx, host_id, host_info)?;
+
+        tx.commit()?;
+        Ok(host_id)
+    }
+
+    /// check that a runner authenticated with `credential_id`, or the shared secret if `None`, may
+    /// act as `host_id`. a runner with a credential binds the host to it, as in
+    /// `bind_host_credential`. the shared secret is only good for hosts that have never been bound
+    /// to a credential, so that revoking one can't be undone by falling back to the secret. either
+    /// refusal is a `ConstraintViolation` saying why.
+    fn authorize_host(conn: &Connection, host_id: u32, credential_id: Option<u64>) -> Result<(), DbError> {
+        if let Some(credential_id) = credential_id {
+            return Self::bind_credential(conn, host_id as u64, credential_id);
+        }
+
+        let bound = conn
+            .query_row(sql::RUNNER_CREDENTIAL_FOR_HOST, [host_id], Self::row2credential)
+            .optional()?;
+        match bound {
+            Some(credential) if credential.state(crate::now_ms()) == CredentialState::Active => {
+                Err(DbError::ConstraintViolation(format!("host {} belongs to runner '{}', use its credential", host_id, credential.name)))
+            }
+            Some(credential) => {
+                Err(DbError::ConstraintViolation(format!("host {} belonged to runner '{}', which can no longer be used; it needs a new credential", host_id, credential.name)))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn insert_host(conn: &Connection, runner_id: Option<&str>, host_info: &crate::protocol::HostInfo) -> Result<u32, rusqlite::Error> {
+        conn.execute(
+            sql::INSERT_HOST,
+            params![
+                runner_id,
+                &host_info.hostname,
+                &host_info.cpu_info.vendor_id,
+                &host_info.cpu_info.model_name,
+                &host_info.cpu_info.family,
+                &host_info.cpu_info.model,
+                &host_info.cpu_info.microcode,
+                &host_info.cpu_info.max_freq,
+                &host_info.cpu_info.cores,
+                &host_info.memory_info.total,
+                &host_info.env_info.arch,
+                &host_info.env_info.family,
+                &host_info.env_info.os,
+            ]
+        )?;
+        Ok(conn.last_insert_rowid() as u32)
+    }
+
+    /// note that `host_id` was just seen with `host_info`. if that's different from its newest
+    /// facts, `host_info` becomes a new version of its facts and its current hardware.
+    fn record_host_facts(conn: &Connection, host_id: u32, host_info: &crate::protocol::HostInfo) -> Result<(), rusqlite::Error> {
+        let now = crate::now_ms();
+
+        let current: Option<u64> = conn
             .query_row(
-                "select id from hosts where \
-                    hostname=?1 and cpu_vendor_id=?2 and cpu_model_name=?3 and cpu_family=?4 and \
-                    cpu_model=?5 and cpu_microcode=?6 and cpu_max_freq_khz=?7 and \
-                    cpu_cores=?8 and mem_total=?9 and arch=?10 and family=?11 and os=?12;",
+                sql::CURRENT_HOST_FACTS_MATCHING,
                 params![
+                    host_id,
                     &host_info.hostname,
                     &host_info.cpu_info.vendor_id,
                     &host_info.cpu_info.model_name,
@@ -613,63 +1557,712 @@ impl DbCtx {
                     &host_info.env_info.family,
                     &host_info.env_info.os,
                 ],
-                |row| { row.get(0) }
+                |row| row.get(0)
             )
-            .map_err(|e| e.to_string())
+            .optional()?;
+
+        if let Some(facts_id) = current {
+            conn.execute("update host_facts set last_seen=?1 where id=?2", params![now, facts_id])?;
+            return Ok(());
+        }
+
+        conn.execute(
+            sql::INSERT_HOST_FACTS,
+            params![
+                host_id,
+                &host_info.hostname,
+                &host_info.cpu_info.vendor_id,
+                &host_info.cpu_info.model_name,
+                &host_info.cpu_info.family,
+                &host_info.cpu_info.model,
+                &host_info.cpu_info.microcode,
+                &host_info.cpu_info.max_freq,
+                &host_info.cpu_info.cores,
+                &host_info.memory_info.total,
+                &host_info.env_info.arch,
+                &host_info.env_info.family,
+                &host_info.env_info.os,
+                now,
+            ]
+        )?;
+        conn.execute(
+            sql::UPDATE_HOST_HARDWARE,
+            params![
+                host_id,
+                &host_info.hostname,
+                &host_info.cpu_info.vendor_id,
+                &host_info.cpu_info.model_name,
+                &host_info.cpu_info.family,
+                &host_info.cpu_info.model,
+                &host_info.cpu_info.microcode,
+                &host_info.cpu_info.max_freq,
+                &host_info.cpu_info.cores,
+                &host_info.memory_info.total,
+                &host_info.env_info.arch,
+                &host_info.env_info.family,
+                &host_info.env_info.os,
+            ]
+        )?;
+
+        Ok(())
     }
 
-    pub fn host_model_info(&self, host_id: u64) -> Result<(String, String, String, String, u64), String> {
-        let conn = self.conn.lock().unwrap();
-        conn
-            .query_row("select hostname, cpu_vendor_id, cpu_family, cpu_model, cpu_max_freq_khz from hosts where id=?1;", [host_id], |row| {
-                Ok((
-                    row.get(0).unwrap(),
-                    row.get(1).unwrap(),
-                    row.get(2).unwrap(),
-                    row.get(3).unwrap(),
-                    row.get(4).unwrap(),
-                ))
+    /// every version of the hardware `host_id` has reported, oldest first.
+    pub fn host_facts(&self, host_id: u64) -> Result<Vec<HostFacts>, DbError> {
+        let conn = self.reader();
+        let mut facts_query = conn.prepare(sql::FACTS_FOR_HOST)?;
+        let facts = facts_query.query_map([host_id], |row| {
+            let (host_id, version, hostname, cpu_vendor_id, cpu_model_name, cpu_family, cpu_model, cpu_microcode, cpu_max_freq_khz, cpu_cores, mem_total, arch, family, os, first_seen, last_seen) = row.try_into()?;
+            Ok(HostFacts {
+                host_id,
+                version,
+                hostname,
+                cpu_vendor_id,
+                cpu_model_name,
+                cpu_family,
+                cpu_model,
+                cpu_microcode,
+                cpu_max_freq_khz,
+                cpu_cores,
+                mem_total,
+                arch,
+                family,
+                os,
+                first_seen,
+                last_seen,
             })
-            .map_err(|e| e.to_string())
+        })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(facts)
+    }
+
+    pub fn host_model_info(&self, host_id: u64) -> Result<(String, String, String, String, u64), DbError> {
+        let conn = self.reader();
+        let info = conn
+            .query_row("select coalesce(name, hostname), cpu_vendor_id, cpu_family, cpu_model, cpu_max_freq_khz from hosts where id=?1;", [host_id], |row| {
+                row.try_into()
+            })?;
+        Ok(info)
+    }
+
+    pub fn all_hosts(&self) -> Result<Vec<Host>, DbError> {
+        let conn = self.reader();
+        let mut hosts_query = conn.prepare(sql::ALL_HOSTS)?;
+        let hosts = hosts_query.query_map([], Self::row2host)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(hosts)
+    }
+
+    pub fn host_by_id(&self, host_id: u64) -> Result<Option<Host>, DbError> {
+        let host = self.reader()
+            .query_row(sql::HOST_BY_ID, [host_id], Self::row2host)
+            .optional()?;
+        Ok(host)
+    }
+
+    pub fn host_by_name(&self, name: &str) -> Result<Option<Host>, DbError> {
+        let host = self.reader()
+            .query_row(sql::HOST_BY_NAME, [name], Self::row2host)
+            .optional()?;
+        Ok(host)
+    }
+
+    /// name `host_id`, or remove its name if `name` is `None`. names are unique across hosts,
+    /// including retired ones.
+    pub fn set_host_name(&self, host_id: u64, name: Option<&str>) -> Result<(), DbError> {
+        let rows_modified = self.writer()
+            .execute("update hosts set name=?1 where id=?2", params![name, host_id])?;
+
+        if rows_modified == 0 {
+            return Err(DbError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    pub fn set_host_enabled(&self, host_id: u64, enabled: bool) -> Result<(), DbError> {
+        let rows_modified = self.writer()
+            .execute("update hosts set enabled=?1 where id=?2", params![enabled, host_id])?;
+
+        if rows_modified == 0 {
+            return Err(DbError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    /// stop giving `host_id` work, for good. its runs, metrics and name are kept. retiring an
+    /// already-retired host keeps the original retirement time.
+    pub fn retire_host(&self, host_id: u64) -> Result<(), DbError> {
+        let rows_modified = self.writer()
+            .execute(
+                "update hosts set retired_time=coalesce(retired_time, ?1) where id=?2",
+                params![crate::now_ms(), host_id]
+            )?;
+
+        if rows_modified == 0 {
+            return Err(DbError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    /// label `host_id` with `label`. adding a label the host already has does nothing.
+    pub fn add_host_label(&self, host_id: u64, label: &str) -> Result<(), DbError> {
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+
+        let host_exists = tx
+            .query_row("select id from hosts where id=?1", [host_id], |row| row.get::<_, u64>(0))
+            .optional()?
+            .is_some();
+        if !host_exists {
+            return Err(DbError::NotFound);
+        }
+
+        tx.execute(
+            "insert or ignore into host_labels (host_id, label) values (?1, ?2)",
+            params![host_id, label]
+        )?;
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn remove_host_label(&self, host_id: u64, label: &str) -> Result<(), DbError> {
+        let rows_modified = self.writer()
+            .execute("delete from host_labels where host_id=?1 and label=?2", params![host_id, label])?;
+
+        if rows_modified == 0 {
+            return Err(DbError::NotFound);
+        }
+
+        Ok(())
     }
 
-    pub fn runs_for_job_one_per_host(&self, job_id: u64) -> Result<Vec<Run>, String> {
-        let conn = self.conn.lock().unwrap();
-        let mut runs_query = conn.prepare(crate::sql::RUNS_FOR_JOB).unwrap();
-        let mut runs_ids = runs_query.query([job_id]).unwrap();
+    pub fn host_labels(&self, host_id: u64) -> Result<Vec<String>, DbError> {
+        let conn = self.reader();
+        let mut labels_query = conn.prepare(sql::LABELS_FOR_HOST)?;
+        let labels = labels_query.query_map([host_id], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok(labels)
+    }
+
+    /// record a credential named `name` for `token`. the token itself isn't kept, so this is the
+    /// caller's only chance to hand it out.
+    pub fn add_runner_credential(&self, name: &str, token: &str, expires_time: Option<u64>) -> Result<u64, DbError> {
+        let conn = self.writer();
+        conn.execute(
+            "insert into runner_credentials (name, token_hash, created_time, expires_time) values (?1, ?2, ?3, ?4)",
+            params![name, hash_token(token), crate::now_ms(), expires_time]
+        )?;
+        Ok(conn.last_insert_rowid() as u64)
+    }
+
+    /// the credential `token` is for, if any. the credential is returned even if it has expired or
+    /// been revoked, so that the caller can say why it isn't accepted; using an active credential
+    /// is recorded as its last use.
+    pub fn authenticate_runner(&self, token: &str) -> Result<Option<RunnerCredential>, DbError> {
+        let credential = self.reader()
+            .query_row(sql::RUNNER_CREDENTIAL_BY_TOKEN_HASH, [hash_token(token)], Self::row2credential)
+            .optional()?;
+
+        if let Some(credential) = credential.as_ref() {
+            let now = crate::now_ms();
+            if credential.state(now) == CredentialState::Active {
+                self.writer().execute(
+                    "update runner_credentials set last_used_time=?1 where id=?2",
+                    params![now, credential.id]
+                )?;
+            }
+        }
+
+        Ok(credential)
+    }
+
+    pub fn all_runner_credentials(&self) -> Result<Vec<RunnerCredential>, DbError> {
+        let conn = self.reader();
+        let mut credentials_query = conn.prepare(sql::ALL_RUNNER_CREDENTIALS)?;
+        let credentials = credentials_query.query_map([], Self::row2credential)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(credentials)
+    }
+
+    pub fn runner_credential_by_id(&self, credential_id: u64) -> Result<Option<RunnerCredential>, DbError> {
+        let credential = self.reader()
+            .query_row(sql::RUNNER_CREDENTIAL_BY_ID, [credential_id], Self::row2credential)
+            .optional()?;
+        Ok(credential)
+    }
+
+    pub fn runner_credential_by_name(&self, name: &str) -> Result<Option<RunnerCredential>, DbError> {
+        let credential = self.reader()
+            .query_row(sql::RUNNER_CREDENTIAL_BY_NAME, [name], Self::row2credential)
+            .optional()?;
+        Ok(credential)
+    }
+
+    /// the credential `host_id` is bound to, whether or not it can still be used.
+    pub fn runner_credential_for_host(&self, host_id: u64) -> Result<Option<RunnerCredential>, DbError> {
+        let credential = self.reader()
+            .query_row(sql::RUNNER_CREDENTIAL_FOR_HOST, [host_id], Self::row2credential)
+            .optional()?;
+        Ok(credential)
+    }
+
+    /// stop accepting `credential_id`. revoking an already-revoked credential keeps the original
+    /// revocation time.
+    pub fn revoke_runner_credential(&self, credential_id: u64) -> Result<(), DbError> {
+        let rows_modified = self.writer()
+            .execute(
+                "update runner_credentials set revoked_time=coalesce(revoked_time, ?1) where id=?2",
+                params![crate::now_ms(), credential_id]
+            )?;
+
+        if rows_modified == 0 {
+            return Err(DbError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    /// bind `host_id` to the credential its runner authenticated with. a host bound to some other
+    /// credential that is still usable can't be taken over, and a credential already bound to some
+    /// other host can't be bound to this one; both are a `ConstraintViolation`.
+    pub fn bind_host_credential(&self, host_id: u64, credential_id: u64) -> Result<(), DbError> {
+        Self::bind_credential(&self.writer(), host_id, credential_id)
+    }
+
+    fn bind_credential(conn: &Connection, host_id: u64, credential_id: u64) -> Result<(), DbError> {
+        let rows_modified = conn
+            .execute(sql::BIND_HOST_CREDENTIAL, params![credential_id, host_id, crate::now_ms()])
+            .map_err(|e| match DbError::from(e) {
+                DbError::ConstraintViolation(_) => {
+                    DbError::ConstraintViolation(format!("runner credential {} is bound to another host", credential_id))
+                }
+                e => e,
+            })?;
+
+        if rows_modified == 0 {
+            return Err(DbError::ConstraintViolation(format!("host {} is bound to another runner credential", host_id)));
+        }
+
+        Ok(())
+    }
+
+    pub fn runs_for_job_one_per_host(&self, job_id: u64) -> Result<Vec<Run>, DbError> {
+        let conn = self.reader();
+        let mut runs_query = conn.prepare(crate::sql::RUNS_FOR_JOB)?;
+        let mut runs_ids = runs_query.query([job_id])?;
 
         let mut results = Vec::new();
 
-        let mut run_fields_query = conn.prepare(crate::sql::RUN_TO_FIELDS).unwrap();
+        let mut run_fields_query = conn.prepare(crate::sql::RUN_TO_FIELDS)?;
 
-        while let Some(row_id) = runs_ids.next().unwrap() {
-            let id: u32 = row_id.get(0usize).unwrap();
-            let mut run = run_fields_query.query_row([id], |row| Ok(Self::row2run(row))).unwrap();
+        while let Some(row_id) = runs_ids.next()? {
+            let id: u64 = row_id.get(0usize)?;
+            let run = run_fields_query.query_row([id], Self::row2run)?;
             results.push(run);
         }
 
         Ok(results)
     }
 
-    pub fn last_run_for_job(&self, job_id: u64) -> Result<Option<Run>, String> {
-        let conn = self.conn.lock().unwrap();
+    pub fn last_run_for_job(&self, job_id: u64) -> Result<Option<Run>, DbError> {
+        let conn = self.reader();
 
-        conn
-            .query_row(sql::LAST_RUN_FOR_JOB, [job_id], |row| {
-                Ok(Self::row2run(row))
+        let run = conn
+            .query_row(sql::LAST_RUN_FOR_JOB, [job_id], Self::row2run)
+            .optional()?;
+        Ok(run)
+    }
+
+    pub fn retention_policy(&self, repo_id: u64) -> Result<Option<RetentionPolicy>, DbError> {
+        let policy = self.reader()
+            .query_row(sql::RETENTION_POLICY_FOR_REPO, [repo_id], |row| {
+                let (repo_id, log_days, metric_days, keep_commits) = row.try_into()?;
+                Ok(RetentionPolicy { repo_id, log_days, metric_days, keep_commits })
             })
-            .optional()
-            .map_err(|e| e.to_string())
+            .optional()?;
+        Ok(policy)
+    }
+
+    pub fn set_retention_policy(&self, policy: &RetentionPolicy) -> Result<(), DbError> {
+        self.writer()
+            .execute(
+                sql::SET_RETENTION_POLICY,
+                params![policy.repo_id, policy.log_days, policy.metric_days, policy.keep_commits]
+            )?;
+        Ok(())
+    }
+
+    /// mark (or unmark) `run_id` as a baseline. baseline runs are never garbage collected.
+    pub fn set_run_baseline(&self, run_id: u64, baseline: bool) -> Result<(), DbError> {
+        let rows_modified = self.writer()
+            .execute("update runs set baseline=?1 where id=?2", params![baseline, run_id])?;
+
+        if rows_modified == 0 {
+            return Err(DbError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    /// finished runs that have artifacts or metrics their repo's retention policy says should be
+    /// deleted, as of `now` (ms since the epoch).
+    pub fn runs_past_retention(&self, now: u64) -> Result<Vec<ExpiredRun>, DbError> {
+        let conn = self.reader();
+
+        let mut expired_query = conn.prepare(sql::RUNS_PAST_RETENTION)?;
+        let mut rows = expired_query.query([now])?;
+        let mut expired = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            let (run_id, logs_expired, metrics_expired) = row.try_into()?;
+            expired.push(ExpiredRun { run_id, logs_expired, metrics_expired });
+        }
+
+        Ok(expired)
+    }
+
+    /// delete the records of every artifact for `run_id`, returning the records that were
+    /// deleted. the artifact files themselves are the caller's to delete.
+    pub fn delete_artifacts_for_run(&self, run_id: u64) -> Result<Vec<ArtifactRecord>, DbError> {
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+
+        let mut artifacts = Vec::new();
+        {
+            let mut artifacts_query = tx.prepare("select id, run_id, attempt_id, name, desc, created_time, completed_time from artifacts where run_id=?1")?;
+            let mut result = artifacts_query.query([run_id])?;
+
+            while let Some(row) = result.next()? {
+                let (id, run_id, attempt_id, name, desc, created_time, completed_time) = row.try_into()?;
+                artifacts.push(ArtifactRecord { id, run_id, attempt_id, name, desc, created_time, completed_time });
+            }
+        }
+
+        tx.execute("delete from log_lines where artifact_id in (select id from artifacts where run_id=?1)", [run_id])?;
+        tx.execute("delete from artifacts where run_id=?1", [run_id])?;
+        // commands outlive their output; they just no longer link to it.
+        tx.execute("update commands set stdout_artifact_id=null, stderr_artifact_id=null where run_id=?1", [run_id])?;
+        tx.commit()?;
+
+        Ok(artifacts)
+    }
+
+    /// delete all metrics recorded for `run_id`, and any findings about them, returning how many
+    /// metrics were deleted.
+    pub fn delete_metrics_for_run(&self, run_id: u64) -> Result<usize, DbError> {
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+
+        let deleted = tx.execute("delete from metrics where run_id=?1", [run_id])?;
+        tx.execute("delete from metric_findings where run_id=?1", [run_id])?;
+
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    pub fn metric_policy(&self, repo_id: u64) -> Result<Option<MetricPolicy>, DbError> {
+        let policy = self.reader()
+            .query_row(sql::METRIC_POLICY_FOR_REPO, [repo_id], |row| {
+                let (repo_id, threshold, window, noise_sigmas) = row.try_into()?;
+                Ok(MetricPolicy { repo_id, threshold, window, noise_sigmas })
+            })
+            .optional()?;
+        Ok(policy)
+    }
+
+    pub fn set_metric_policy(&self, policy: &MetricPolicy) -> Result<(), DbError> {
+        self.writer()
+            .execute(
+                sql::SET_METRIC_POLICY,
+                params![policy.repo_id, policy.threshold, policy.window, policy.noise_sigmas]
+            )?;
+        Ok(())
+    }
+
+    /// replace whatever was found about `attempt_id`'s metrics with `findings`. the `id` and
+    /// `created_time` of each finding are ignored; they're assigned here.
+    pub fn replace_metric_findings(&self, attempt_id: u64, findings: &[MetricFinding]) -> Result<(), DbError> {
+        let now = crate::now_ms();
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+
+        tx.execute("delete from metric_findings where attempt_id=?1", [attempt_id])?;
+        for finding in findings.iter() {
+            tx.execute(
+                "insert into metric_findings (run_id, attempt_id, name, unit, value, baseline, baseline_samples, change, relative, improved, created_time) \
+                 values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                params![
+                    finding.run_id, attempt_id, finding.name, finding.unit.map(|unit| unit.as_str()),
+                    finding.value, finding.baseline, finding.baseline_samples, finding.change,
+                    finding.relative, finding.improved, now
+                ]
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn metric_findings_for_attempt(&self, attempt_id: u64) -> Result<Vec<MetricFinding>, DbError> {
+        let conn = self.reader();
+
+        let mut findings_query = conn.prepare(sql::METRIC_FINDINGS_FOR_ATTEMPT)?;
+        let mut result = findings_query.query([attempt_id])?;
+        let mut findings = Vec::new();
+
+        while let Some(row) = result.next()? {
+            let (id, run_id, attempt_id, name, unit, value, baseline, baseline_samples, change, relative, improved, created_time) = row.try_into()?;
+            findings.push(MetricFinding {
+                id, run_id, attempt_id, name, unit, value, baseline, baseline_samples, change, relative, improved, created_time
+            });
+        }
+
+        Ok(findings)
+    }
+
+    /// record `parents` as the parents of `sha` in `repo_id`, in order, unless we already know its
+    /// parents.
+    ///
+    /// this is for parents inferred from somewhere less reliable than git itself, like a push
+    /// event, which shouldn't overwrite what `replace_commit_parents` was told.
+    pub fn record_commit_parents(&self, repo_id: u64, sha: &str, parents: &[&str]) -> Result<(), DbError> {
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+
+        let known: bool = tx.query_row(
+            "select exists (select 1 from commit_parents where repo_id=?1 and sha=?2)",
+            params![repo_id, sha],
+            |row| row.get(0)
+        )?;
+        if !known {
+            for (i, parent) in parents.iter().enumerate() {
+                tx.execute(
+                    "insert into commit_parents (repo_id, sha, parent_sha, parent_index) values (?1, ?2, ?3, ?4)",
+                    params![repo_id, sha, parent, i as u64]
+                )?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// set the parents of `sha` in `repo_id` to exactly `parents`, discarding anything previously
+    /// recorded. this is for parents that come from git itself.
+    pub fn replace_commit_parents(&self, repo_id: u64, sha: &str, parents: &[&str]) -> Result<(), DbError> {
+        let mut conn = self.writer();
+        let tx = conn.transaction()?;
+
+        tx.execute("delete from commit_parents where repo_id=?1 and sha=?2", params![repo_id, sha])?;
+        for (i, parent) in parents.iter().enumerate() {
+            tx.execute(
+                "insert into commit_parents (repo_id, sha, parent_sha, parent_index) values (?1, ?2, ?3, ?4)",
+                params![repo_id, sha, parent, i as u64]
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// the parents of `sha` in `repo_id`, first parent first. empty if we don't know of any.
+    pub fn commit_parents(&self, repo_id: u64, sha: &str) -> Result<Vec<String>, DbError> {
+        let conn = self.reader();
+
+        let mut parents_query = conn.prepare(sql::PARENTS_OF_COMMIT)?;
+        let mut rows = parents_query.query(params![repo_id, sha])?;
+        let mut parents = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            parents.push(row.get(0)?);
+        }
+
+        Ok(parents)
+    }
+
+    pub fn first_parent(&self, repo_id: u64, sha: &str) -> Result<Option<String>, DbError> {
+        self.commit_parents(repo_id, sha)
+            .map(|parents| parents.into_iter().next())
+    }
+
+    /// `sha` and up to `limit - 1` of its first-parent ancestors in `repo_id`, newest first.
+    /// history stops early wherever we don't know a commit's parents.
+    pub fn first_parent_history(&self, repo_id: u64, sha: &str, limit: u64) -> Result<Vec<String>, DbError> {
+        let conn = self.reader();
+
+        let mut history_query = conn.prepare(sql::FIRST_PARENT_HISTORY)?;
+        let mut rows = history_query.query(params![sha, limit, repo_id])?;
+        let mut history = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            history.push(row.get(0)?);
+        }
+
+        Ok(history)
+    }
+
+    /// first-parent history of the branch `branch` in `repo_id`, from the commit most recently
+    /// pushed to it.
+    pub fn branch_history(&self, repo_id: u64, branch: &str, limit: u64) -> Result<Vec<String>, DbError> {
+        let tip: Option<String> = self.reader()
+            .query_row(sql::BRANCH_TIP, params![repo_id, branch], |row| row.get(0))
+            .optional()?;
+
+        match tip {
+            Some(tip) => self.first_parent_history(repo_id, &tip, limit),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// the closest ancestor of `sha` in `repo_id` with a finished run on `host_id`, along with that
+    /// run. ancestors more than `max_depth` commits back are not considered.
+    pub fn nearest_ancestor_with_run(&self, repo_id: u64, sha: &str, host_id: u64, max_depth: u64) -> Result<Option<(String, Run)>, DbError> {
+        let ancestor = self.reader()
+            .query_row(sql::NEAREST_ANCESTOR_WITH_RUN, params![sha, host_id, max_depth, repo_id], |row| {
+                Ok((row.get(13)?, Self::row2run(row)?))
+            })
+            .optional()?;
+        Ok(ancestor)
+    }
+
+    /// up to `limit` of the closest ancestors of `sha` in `repo_id` with a finished run on
+    /// `host_id`, nearest first, each with its most recently completed run. ancestors more than
+    /// `max_depth` commits back are not considered.
+    pub fn ancestors_with_runs(&self, repo_id: u64, sha: &str, host_id: u64, max_depth: u64, limit: u64) -> Result<Vec<(String, Run)>, DbError> {
+        let conn = self.reader();
+
+        let mut ancestors_query = conn.prepare(sql::ANCESTORS_WITH_RUNS)?;
+        let mut result = ancestors_query.query(params![sha, host_id, max_depth, repo_id, limit])?;
+        let mut ancestors = Vec::new();
+
+        while let Some(row) = result.next()? {
+            ancestors.push((row.get(13)?, Self::row2run(row)?));
+        }
+
+        Ok(ancestors)
     }
 
-    pub(crate) fn row2run(row: &rusqlite::Row) -> Run {
-        let (id, job_id, artifacts_path, state, host_id, build_token, create_time, start_time, complete_time, run_timeout, build_result, final_text) = row.try_into().unwrap();
-        let state: u8 = state;
-        Run {
+    pub(crate) fn row2metric(row: &rusqlite::Row) -> Result<MetricRecord, rusqlite::Error> {
+        let (id, run_id, attempt_id, name, text, numeric_value, unit, direction) = row.try_into()?;
+        let value = match numeric_value {
+            Some(value) => MetricValue::Number { value, unit, direction },
+            None => MetricValue::Text(text),
+        };
+        Ok(MetricRecord { id, run_id, attempt_id, name, value })
+    }
+
+    // hosts have one more column than rusqlite will convert to a tuple, so this goes field by
+    // field.
+    pub(crate) fn row2host(row: &rusqlite::Row) -> Result<Host, rusqlite::Error> {
+        Ok(Host {
+            id: row.get(0)?,
+            runner_id: row.get(1)?,
+            name: row.get(2)?,
+            hostname: row.get(3)?,
+            cpu_vendor_id: row.get(4)?,
+            cpu_model_name: row.get(5)?,
+            cpu_family: row.get(6)?,
+            cpu_model: row.get(7)?,
+            cpu_microcode: row.get(8)?,
+            cpu_max_freq_khz: row.get(9)?,
+            cpu_cores: row.get(10)?,
+            mem_total: row.get(11)?,
+            arch: row.get(12)?,
+            family: row.get(13)?,
+            os: row.get(14)?,
+            enabled: row.get(15)?,
+            retired_time: row.get(16)?,
+        })
+    }
+
+    pub(crate) fn row2command(row: &rusqlite::Row) -> Result<CommandRecord, rusqlite::Error> {
+        // argv and step are stored as json lists of strings.
+        fn string_list(idx: usize, text: String) -> Result<Vec<String>, rusqlite::Error> {
+            serde_json::from_str(&text).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(idx, rusqlite::types::Type::Text, Box::new(e))
+            })
+        }
+
+        let (id, run_id, attempt_id, command_id, argv, cwd, step, start_time, end_time, exit_code, stdout_artifact_id, stderr_artifact_id) = row.try_into()?;
+        Ok(CommandRecord {
+            id,
+            run_id,
+            attempt_id,
+            command_id,
+            argv: string_list(4, argv)?,
+            cwd,
+            step: string_list(6, step)?,
+            start_time,
+            end_time,
+            exit_code,
+            stdout_artifact_id,
+            stderr_artifact_id,
+        })
+    }
+
+    pub(crate) fn row2attempt(row: &rusqlite::Row) -> Result<Attempt, rusqlite::Error> {
+        let (id, run_id, attempt, host_id, build_token, artifacts_path, state, start_time, complete_time, build_result, final_text, heartbeat_time, requeue_reason, outcome) = row.try_into()?;
+        Ok(Attempt {
+            id,
+            run_id,
+            attempt,
+            host_id,
+            build_token,
+            artifacts_path,
+            state,
+            start_time,
+            complete_time,
+            build_result,
+            final_text,
+            heartbeat_time,
+            requeue_reason,
+            outcome,
+        })
+    }
+
+    pub(crate) fn row2credential(row: &rusqlite::Row) -> Result<RunnerCredential, rusqlite::Error> {
+        let (id, name, created_time, expires_time, revoked_time, last_used_time, host_id) = row.try_into()?;
+        Ok(RunnerCredential {
+            id,
+            name,
+            created_time,
+            expires_time,
+            revoked_time,
+            last_used_time,
+            host_id,
+        })
+    }
+
+    pub(crate) fn row2progress(row: &rusqlite::Row) -> Result<AttemptProgress, rusqlite::Error> {
+        let (attempt_id, heartbeat_time, step, elapsed_ms, load_avg, mem_available_kb): (u64, Option<u64>, Option<String>, _, _, _) = row.try_into()?;
+        // like a command's, the step is a json list of step names.
+        let step = match step {
+            Some(step) => serde_json::from_str(&step).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(2, rusqlite::types::Type::Text, Box::new(e))
+            })?,
+            None => Vec::new(),
+        };
+        Ok(AttemptProgress {
+            attempt_id,
+            heartbeat_time,
+            step,
+            elapsed_ms,
+            load_avg,
+            mem_available_kb,
+        })
+    }
+
+    pub(crate) fn row2run(row: &rusqlite::Row) -> Result<Run, rusqlite::Error> {
+        let (id, job_id, artifacts_path, state, host_id, build_token, create_time, start_time, complete_time, run_timeout, build_result, final_text, outcome) = row.try_into()?;
+        Ok(Run {
             id,
             job_id,
             artifacts_path,
-            state: state.try_into().unwrap(),
+            state,
             host_id,
             create_time,
             start_time,
@@ -678,6 +2271,459 @@ impl DbCtx {
             run_timeout,
             build_result,
             final_text,
+            outcome,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_db() -> DbCtx {
+        let db = DbCtx::in_memory().expect("can open database");
+        db.migrate().expect("can migrate");
+        db
+    }
+
+    // a repo named `name` with one github remote, returning (repo_id, remote_id).
+    fn repo(db: &DbCtx, name: &str) -> (u64, u64) {
+        let repo_id = db.new_repo(name).expect("can create repo");
+        let remote_id = db.new_remote(repo_id, &format!("iximeow/{}", name), "github", "ci.json").expect("can create remote");
+        (repo_id, remote_id)
+    }
+
+    // push `sha` to `branch` and run its job to completion with one artifact and one metric,
+    // returning the run's id.
+    fn finished_run(db: &DbCtx, remote_id: u64, branch: &str, sha: &str) -> u64 {
+        db.update_ref(remote_id, &format!("refs/heads/{}", branch), sha).expect("can update ref");
+        // refs are ranked by when they were pushed, so make sure no two pushes share a timestamp.
+        std::thread::sleep(Duration::from_millis(2));
+        let run_id = match db.create_job(remote_id, sha, None).expect("can create job") {
+            JobCreation::Created { run_id, .. } => run_id,
+            JobCreation::Existing { .. } => panic!("{} already had a job", sha),
+        };
+        let attempt_id = db.start_run(run_id, 1, "artifacts", &format!("token-{}", sha), DEFAULT_RUN_TIMEOUT_MS).expect("can start run");
+        db.new_artifact(attempt_id, "build (stdout)", "stdout").expect("can create artifact");
+        db.insert_metric(attempt_id, "build_ms", &MetricValue::Number { value: 1000.0, unit: None, direction: None }).expect("can insert metric");
+        db.complete_attempt(attempt_id, TaskOutcome::Passed, "passed").expect("can complete attempt");
+        run_id
+    }
+
+    #[test]
+    fn retention_keeps_recent_commits_on_each_branch() {
+        let db = test_db();
+        let (repo_id, remote_id) = repo(&db, "ci");
+        db.set_retention_policy(&RetentionPolicy { repo_id, log_days: Some(1), metric_days: None, keep_commits: Some(2) }).unwrap();
+
+        let baseline = finished_run(&db, remote_id, "main", "c0");
+        db.set_run_baseline(baseline, true).unwrap();
+        let c1 = finished_run(&db, remote_id, "main", "c1");
+        let c2 = finished_run(&db, remote_id, "main", "c2");
+        finished_run(&db, remote_id, "main", "c3");
+        finished_run(&db, remote_id, "main", "c4");
+        // c1 is old on main, but is the newest commit on this branch.
+        db.update_ref(remote_id, "refs/heads/feature", "c1").unwrap();
+
+        let later = crate::now_ms() + 2 * 86_400_000;
+        let expired = db.runs_past_retention(later).unwrap();
+        assert_eq!(expired.len(), 1, "{:?}", expired);
+        assert_eq!(expired[0].run_id, c2);
+        assert!(expired[0].logs_expired);
+        assert!(!expired[0].metrics_expired);
+
+        // nothing is old enough yet.
+        assert!(db.runs_past_retention(crate::now_ms()).unwrap().is_empty());
+
+        // without `keep_commits`, every finished run that isn't a baseline is a candidate.
+        db.set_retention_policy(&RetentionPolicy { repo_id, log_days: Some(1), metric_days: Some(1), keep_commits: None }).unwrap();
+        let mut expired = db.runs_past_retention(later).unwrap();
+        expired.sort_by_key(|run| run.run_id);
+        assert_eq!(expired.len(), 4);
+        assert_eq!(expired[0].run_id, c1);
+        assert!(expired.iter().all(|run| run.logs_expired && run.metrics_expired));
+    }
+
+    #[test]
+    fn retention_only_applies_to_repos_with_a_policy() {
+        let db = test_db();
+        let (repo_id, remote_id) = repo(&db, "ci");
+        let (_, other_remote_id) = repo(&db, "other");
+        db.set_retention_policy(&RetentionPolicy { repo_id, log_days: Some(1), metric_days: None, keep_commits: None }).unwrap();
+
+        let run_id = finished_run(&db, remote_id, "main", "c1");
+        finished_run(&db, other_remote_id, "main", "c1");
+
+        let expired = db.runs_past_retention(crate::now_ms() + 2 * 86_400_000).unwrap();
+        assert_eq!(expired.iter().map(|run| run.run_id).collect::<Vec<_>>(), vec![run_id]);
+    }
+
+    #[test]
+    fn ancestry_is_kept_per_repo() {
+        let db = test_db();
+        let (repo_id, _) = repo(&db, "ci");
+        let (fork_id, _) = repo(&db, "fork");
+
+        db.record_commit_parents(repo_id, "c2", &["c1"]).unwrap();
+        db.record_commit_parents(repo_id, "c1", &["c0"]).unwrap();
+        db.record_commit_parents(fork_id, "c2", &["f1"]).unwrap();
+
+        assert_eq!(db.commit_parents(repo_id, "c2").unwrap(), vec!["c1".to_string()]);
+        assert_eq!(db.commit_parents(fork_id, "c2").unwrap(), vec!["f1".to_string()]);
+        assert_eq!(db.first_parent_history(repo_id, "c2", 10).unwrap(), vec!["c2", "c1", "c0"]);
+        assert_eq!(db.first_parent_history(fork_id, "c2", 10).unwrap(), vec!["c2", "f1"]);
+    }
+
+    #[test]
+    fn guessed_parents_dont_overwrite_known_ones() {
+        let db = test_db();
+        let (repo_id, _) = repo(&db, "ci");
+
+        db.record_commit_parents(repo_id, "c2", &["guess"]).unwrap();
+        db.record_commit_parents(repo_id, "c2", &["another guess"]).unwrap();
+        assert_eq!(db.first_parent(repo_id, "c2").unwrap().as_deref(), Some("guess"));
+
+        db.replace_commit_parents(repo_id, "c2", &["c1", "merged"]).unwrap();
+        assert_eq!(db.commit_parents(repo_id, "c2").unwrap(), vec!["c1".to_string(), "merged".to_string()]);
+        db.record_commit_parents(repo_id, "c2", &["guess"]).unwrap();
+        assert_eq!(db.first_parent(repo_id, "c2").unwrap().as_deref(), Some("c1"));
+    }
+
+    #[test]
+    fn sha_prefixes_must_be_unambiguous() {
+        let db = test_db();
+        let (repo_id, remote_id) = repo(&db, "ci");
+        let (_, other_remote_id) = repo(&db, "other");
+        let commit_id = db.create_job(remote_id, "abc123", None).unwrap().commit_id();
+        db.create_job(remote_id, "abd456", None).unwrap();
+        db.create_job(other_remote_id, "abc999", None).unwrap();
+
+        assert_eq!(db.commit_by_sha_prefix(repo_id, "abc").unwrap(), Some((commit_id, "abc123".to_string())));
+        assert_eq!(db.commit_by_sha_prefix(repo_id, "abc123").unwrap(), Some((commit_id, "abc123".to_string())));
+        assert!(matches!(db.commit_by_sha_prefix(repo_id, "ab"), Err(DbError::Ambiguous(_))));
+        assert_eq!(db.commit_by_sha_prefix(repo_id, "abe").unwrap(), None);
+        // like-patterns are just characters.
+        assert_eq!(db.commit_by_sha_prefix(repo_id, "a%").unwrap(), None);
+        assert_eq!(db.commit_by_sha_prefix(repo_id, "ab_1").unwrap(), None);
+    }
+
+    #[test]
+    fn retrying_a_run_keeps_its_earlier_attempts() {
+        let db = test_db();
+        let (_, remote_id) = repo(&db, "ci");
+        let run_id = finished_run(&db, remote_id, "main", "c1");
+        let first = db.latest_attempt_for_run(run_id).unwrap().expect("run has an attempt");
+
+        db.retry_run(run_id).unwrap();
+        assert_eq!(db.run_by_id(run_id).unwrap().unwrap().state, RunState::Pending);
+        // a run that's already waiting to run can't be retried again.
+        assert!(matches!(db.retry_run(run_id), Err(DbError::NotFound)));
+
+        let second_id = db.start_run(run_id, 2, "artifacts", "token-retry", DEFAULT_RUN_TIMEOUT_MS).unwrap();
+        assert!(matches!(db.retry_run(run_id), Err(DbError::NotFound)));
+        db.insert_metric(second_id, "build_ms", &MetricValue::Number { value: 2000.0, unit: None, direction: None }).unwrap();
+        db.complete_attempt(second_id, TaskOutcome::BuildFailed, "build failed").unwrap();
+
+        let attempts = db.attempts_for_run(run_id).unwrap();
+        assert_eq!(attempts.iter().map(|a| (a.id, a.attempt)).collect::<Vec<_>>(), vec![(first.id, 1), (second_id, 2)]);
+        assert_eq!(attempts[0].outcome, Some(TaskOutcome::Passed));
+        assert_eq!(attempts[1].outcome, Some(TaskOutcome::BuildFailed));
+        assert_eq!(attempts[1].host_id, Some(2));
+
+        // each attempt keeps its own metrics and artifacts.
+        assert_eq!(db.metrics_for_attempt(first.id).unwrap().len(), 1);
+        assert_eq!(db.metrics_for_attempt(second_id).unwrap().len(), 1);
+        assert_eq!(db.artifacts_for_attempt(first.id, None).unwrap().len(), 1);
+        assert!(db.artifacts_for_attempt(second_id, None).unwrap().is_empty());
+
+        // the run mirrors its latest attempt, even if an earlier one reports in late.
+        db.complete_attempt(first.id, TaskOutcome::Passed, "passed").unwrap();
+        let run = db.run_by_id(run_id).unwrap().unwrap();
+        assert_eq!(run.outcome, Some(TaskOutcome::BuildFailed));
+        assert_eq!(run.host_id, Some(2));
+        assert_eq!(db.attempt_for_token("token-c1").unwrap().map(|t| t.attempt_id), Some(first.id));
+        assert_eq!(db.attempt_for_token("token-retry").unwrap().map(|t| t.attempt_id), Some(second_id));
+    }
+
+    // a run for `sha` that has been started, but not finished, returning (run_id, attempt_id).
+    fn started_run(db: &DbCtx, remote_id: u64, sha: &str) -> (u64, u64) {
+        let run_id = match db.create_job(remote_id, sha, None).expect("can create job") {
+            JobCreation::Created { run_id, .. } => run_id,
+            JobCreation::Existing { .. } => panic!("{} already had a job", sha),
+        };
+        let attempt_id = db.start_run(run_id, 1, "artifacts", &format!("token-{}", sha), DEFAULT_RUN_TIMEOUT_MS).expect("can start run");
+        (run_id, attempt_id)
+    }
+
+    #[test]
+    fn lost_runs_are_requeued_a_limited_number_of_times() {
+        let db = test_db();
+        let (_, remote_id) = repo(&db, "ci");
+        let (run_id, mut attempt_id) = started_run(&db, remote_id, "c1");
+
+        for requeue in 0..2 {
+            assert!(db.requeue_attempt(attempt_id, "runner loss", 2).unwrap(), "requeue {} should be allowed", requeue);
+            let run = db.run_by_id(run_id).unwrap().unwrap();
+            assert_eq!(run.state, RunState::Pending);
+            assert!(db.get_pending_runs(Some(1)).unwrap().iter().any(|pending| pending.id == run_id));
+            // the lost attempt can't be requeued twice.
+            assert!(matches!(db.requeue_attempt(attempt_id, "runner loss", 2), Err(DbError::NotFound)));
+            attempt_id = db.start_run(run_id, 1, "artifacts", &format!("token-{}", requeue), DEFAULT_RUN_TIMEOUT_MS).unwrap();
         }
+
+        // out of requeues: the caller has to end the run instead.
+        assert!(!db.requeue_attempt(attempt_id, "runner loss", 2).unwrap());
+        assert_eq!(db.run_by_id(run_id).unwrap().unwrap().state, RunState::Started);
+
+        let attempts = db.attempts_for_run(run_id).unwrap();
+        assert_eq!(attempts.len(), 3);
+        assert_eq!(attempts[0].requeue_reason.as_deref(), Some("runner loss"));
+        assert_eq!(attempts[0].outcome, Some(TaskOutcome::InfraError));
+        assert_eq!(attempts[2].requeue_reason, None);
+    }
+
+    #[test]
+    fn cancelled_runs_are_not_requeued() {
+        let db = test_db();
+        let (_, remote_id) = repo(&db, "ci");
+        let (run_id, attempt_id) = started_run(&db, remote_id, "c1");
+
+        db.cancel_run(run_id).unwrap();
+        assert!(!db.requeue_attempt(attempt_id, "runner loss", 2).unwrap());
+    }
+
+    #[test]
+    fn stale_attempts_are_the_ones_without_recent_heartbeats() {
+        let db = test_db();
+        let (_, remote_id) = repo(&db, "ci");
+        let (_, quiet) = started_run(&db, remote_id, "c1");
+        let (_, alive) = started_run(&db, remote_id, "c2");
+        let (finished_run_id, finished) = started_run(&db, remote_id, "c3");
+        db.complete_attempt(finished, TaskOutcome::Passed, "passed").unwrap();
+        assert!(db.run_by_id(finished_run_id).unwrap().unwrap().complete_time.is_some());
+
+        std::thread::sleep(Duration::from_millis(5));
+        let cutoff = crate::now_ms();
+        std::thread::sleep(Duration::from_millis(5));
+        db.record_heartbeat(alive).unwrap();
+
+        let stale = db.stale_attempts(cutoff).unwrap();
+        assert_eq!(stale.iter().map(|a| a.id).collect::<Vec<_>>(), vec![quiet]);
+    }
+
+    #[test]
+    fn reindexing_a_log_replaces_its_lines() {
+        let db = test_db();
+        let (_, remote_id) = repo(&db, "ci");
+        let (_, attempt_id) = started_run(&db, remote_id, "c1");
+        let stdout = db.new_artifact(attempt_id, "build (stdout)", "stdout").unwrap();
+        // still being written, so not ready to index.
+        db.new_artifact(attempt_id, "test (stdout)", "stdout").unwrap();
+        let other = db.new_artifact(attempt_id, "build.tar", "a tarball").unwrap();
+        for artifact_id in [stdout, other] {
+            db.writer().execute("update artifacts set completed_time=1 where id=?1", [artifact_id]).unwrap();
+        }
+        assert_eq!(db.completed_log_artifacts().unwrap().iter().map(|a| a.id).collect::<Vec<_>>(), vec![stdout]);
+
+        db.reindex_log(stdout, b"compiling\nwarning: unused variable\n").unwrap();
+        db.reindex_log(stdout, b"compiling\nerror: mismatched types\n").unwrap();
+
+        let search = |text: &str| {
+            db.search_logs(&LogQuery {
+                text: text.to_string(),
+                repo_name: None,
+                host_id: None,
+                since: None,
+                until: None,
+                limit: 10,
+                context: 0,
+            }).unwrap()
+        };
+        assert_eq!(search("compiling").len(), 1);
+        assert!(search("unused variable").is_empty());
+        let matches = search("mismatched");
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].line_number, 2);
+    }
+
+    #[test]
+    fn repeated_pushes_share_one_job() {
+        let db = test_db();
+        let (repo_id, remote_id) = repo(&db, "ci");
+        let mirror_id = db.new_remote(repo_id, "iximeow/ci-mirror", "github", "ci.json").unwrap();
+        let (_, other_remote_id) = repo(&db, "other");
+
+        let (job_id, commit_id) = match db.create_job(remote_id, "c1", Some("iximeow")).unwrap() {
+            JobCreation::Created { job_id, commit_id, .. } => (job_id, commit_id),
+            JobCreation::Existing { .. } => panic!("c1 is new"),
+        };
+        for remote in [remote_id, mirror_id] {
+            match db.create_job(remote, "c1", Some("iximeow")).unwrap() {
+                JobCreation::Existing { job_id: existing_job, commit_id: existing_commit } => {
+                    assert_eq!((existing_job, existing_commit), (job_id, commit_id));
+                }
+                JobCreation::Created { .. } => panic!("c1 already has a job"),
+            }
+        }
+        assert_eq!(db.get_pending_runs(None).unwrap().len(), 1);
+
+        // the same commit in another repo is another job.
+        assert!(matches!(db.create_job(other_remote_id, "c1", None).unwrap(), JobCreation::Created { .. }));
+    }
+
+    #[test]
+    fn racing_pushes_create_one_job() {
+        let db = test_db();
+        let (repo_id, remote_id) = repo(&db, "ci");
+        let mirror_id = db.new_remote(repo_id, "iximeow/ci-mirror", "github", "ci.json").unwrap();
+
+        let created = std::thread::scope(|s| {
+            let pushes: Vec<_> = [remote_id, mirror_id, remote_id, mirror_id].into_iter()
+                .map(|remote| {
+                    let db = &db;
+                    s.spawn(move || db.create_job(remote, "c1", None).expect("can create job"))
+                })
+                .collect();
+            pushes.into_iter().map(|push| push.join().unwrap()).collect::<Vec<_>>()
+        });
+        assert_eq!(created.iter().filter(|c| matches!(c, JobCreation::Created { .. })).count(), 1);
+        assert!(created.iter().all(|c| c.job_id() == created[0].job_id()));
+    }
+
+    fn host_info(hostname: &str) -> crate::protocol::HostInfo {
+        use crate::protocol::{CpuInfo, EnvInfo, HostInfo, MemoryInfo};
+        HostInfo {
+            hostname: hostname.to_string(),
+            cpu_info: CpuInfo {
+                model_name: "Some CPU".to_string(),
+                microcode: "0x1".to_string(),
+                cores: 8,
+                vendor_id: "GenuineIntel".to_string(),
+                family: "6".to_string(),
+                model: "85".to_string(),
+                max_freq: 3_000_000,
+            },
+            memory_info: MemoryInfo { total: "16 GB".to_string(), available: "8 GB".to_string() },
+            env_info: EnvInfo { arch: "x86_64".to_string(), family: "unix".to_string(), os: "linux".to_string() },
+        }
+    }
+
+    #[test]
+    fn credentials_are_kept_only_as_hashes() {
+        let db = test_db();
+        let credential_id = db.add_runner_credential("builder", "hunter2", None).unwrap();
+
+        let stored: String = db.reader()
+            .query_row("select token_hash from runner_credentials where id=?1", [credential_id], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, hash_token("hunter2"));
+        assert_ne!(stored, "hunter2");
+
+        assert!(db.authenticate_runner("hunter3").unwrap().is_none());
+        assert!(db.runner_credential_by_id(credential_id).unwrap().unwrap().last_used_time.is_none());
+        let credential = db.authenticate_runner("hunter2").unwrap().expect("token is known");
+        assert_eq!((credential.id, credential.name.as_str()), (credential_id, "builder"));
+        assert!(db.runner_credential_by_id(credential_id).unwrap().unwrap().last_used_time.is_some());
+    }
+
+    #[test]
+    fn revoked_and_expired_credentials_are_not_active() {
+        let db = test_db();
+        let now = crate::now_ms();
+        let revoked = db.add_runner_credential("revoked", "token-1", None).unwrap();
+        let expired = db.add_runner_credential("expired", "token-2", Some(now - 1)).unwrap();
+
+        db.revoke_runner_credential(revoked).unwrap();
+        let revoked_time = db.runner_credential_by_id(revoked).unwrap().unwrap().revoked_time;
+        std::thread::sleep(Duration::from_millis(2));
+        db.revoke_runner_credential(revoked).unwrap();
+        assert_eq!(db.runner_credential_by_id(revoked).unwrap().unwrap().revoked_time, revoked_time);
+        assert!(matches!(db.revoke_runner_credential(revoked + expired), Err(DbError::NotFound)));
+
+        let revoked = db.authenticate_runner("token-1").unwrap().unwrap();
+        assert_eq!(revoked.state(crate::now_ms()), CredentialState::Revoked);
+        let expired = db.authenticate_runner("token-2").unwrap().unwrap();
+        assert_eq!(expired.state(crate::now_ms()), CredentialState::Expired);
+        // trying a dead credential isn't using it.
+        assert_eq!((revoked.last_used_time, expired.last_used_time), (None, None));
+    }
+
+    #[test]
+    fn runners_only_act_as_hosts_their_credential_allows() {
+        let db = test_db();
+        let hosts = |db: &DbCtx| -> u64 { db.reader().query_row("select count(*) from hosts", [], |row| row.get(0)).unwrap() };
+        let first = db.add_runner_credential("first", "token-1", None).unwrap();
+        let second = db.add_runner_credential("second", "token-2", None).unwrap();
+
+        let host_id = db.id_for_runner("runner-a", &host_info("a"), Some(first)).unwrap();
+        assert_eq!(db.runner_credential_for_host(host_id as u64).unwrap().map(|c| c.id), Some(first));
+        assert_eq!(db.id_for_runner("runner-a", &host_info("a"), Some(first)).unwrap(), host_id);
+
+        // the host is bound to `first`, and `first` is bound to the host.
+        assert!(matches!(db.id_for_runner("runner-a", &host_info("a"), None), Err(DbError::ConstraintViolation(_))));
+        assert!(matches!(db.id_for_runner("runner-a", &host_info("a"), Some(second)), Err(DbError::ConstraintViolation(_))));
+        assert!(matches!(db.id_for_runner("runner-b", &host_info("b"), Some(first)), Err(DbError::ConstraintViolation(_))));
+        // and a claim that's refused records nothing.
+        assert_eq!(hosts(&db), 1);
+        let facts: u64 = db.reader().query_row("select count(*) from host_facts where host_id=?1", [host_id], |row| row.get(0)).unwrap();
+        assert_eq!(facts, 1);
+
+        // once `first` is revoked the host needs a new credential, not the shared secret.
+        db.revoke_runner_credential(first).unwrap();
+        assert!(matches!(db.id_for_runner("runner-a", &host_info("a"), None), Err(DbError::ConstraintViolation(_))));
+        assert_eq!(db.id_for_runner("runner-a", &host_info("a"), Some(second)).unwrap(), host_id);
+        assert_eq!(db.runner_credential_for_host(host_id as u64).unwrap().map(|c| c.id), Some(second));
+
+        // hosts that have never had a credential can still use the shared secret.
+        let unbound = db.id_for_host(&host_info("c"), None).unwrap();
+        assert_eq!(db.id_for_host(&host_info("c"), None).unwrap(), unbound);
+        assert_eq!(hosts(&db), 2);
+    }
+
+    #[test]
+    fn revoking_a_runner_invalidates_its_build_tokens() {
+        let db = test_db();
+        let (_, remote_id) = repo(&db, "ci");
+        let credential_id = db.add_runner_credential("builder", "token-1", None).unwrap();
+        let host_id = db.id_for_runner("runner-a", &host_info("a"), Some(credential_id)).unwrap();
+        let unbound_host_id = db.id_for_host(&host_info("b"), None).unwrap();
+        for (sha, host_id) in [("c1", host_id), ("c2", unbound_host_id)] {
+            let job_id = db.create_job(remote_id, sha, None).unwrap().job_id();
+            let run_id = db.last_run_for_job(job_id).unwrap().unwrap().id;
+            db.start_run(run_id, host_id, "artifacts", &format!("token-{}", sha), DEFAULT_RUN_TIMEOUT_MS).unwrap();
+        }
+
+        assert_eq!(db.attempt_for_token("token-c1").unwrap().unwrap().validity, TokenValidity::Valid);
+        db.revoke_runner_credential(credential_id).unwrap();
+        assert_eq!(db.attempt_for_token("token-c1").unwrap().unwrap().validity, TokenValidity::Revoked);
+        // hosts that never had a credential are only held to the token's own time.
+        assert_eq!(db.attempt_for_token("token-c2").unwrap().unwrap().validity, TokenValidity::Valid);
+    }
+
+    #[test]
+    fn finalizing_a_log_twice_indexes_it_once() {
+        let db = test_db();
+        let (_, remote_id) = repo(&db, "ci");
+        let (_, attempt_id) = started_run(&db, remote_id, "c1");
+        let artifact_id = db.new_artifact(attempt_id, "build (stdout)", "stdout").unwrap();
+        let artifact_file = std::env::temp_dir().join(format!("ci-finalize-{}", std::process::id()));
+        std::fs::write(&artifact_file, "compiling\nerror: mismatched types\n").unwrap();
+
+        db.finalize_artifact(artifact_id, &artifact_file).unwrap();
+        db.finalize_artifact(artifact_id, &artifact_file).unwrap();
+        let _ = std::fs::remove_file(&artifact_file);
+
+        let matches = db.search_logs(&LogQuery {
+            text: "mismatched".to_string(),
+            repo_name: None,
+            host_id: None,
+            since: None,
+            until: None,
+            limit: 10,
+            context: 0,
+        }).unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(db.completed_log_artifacts().unwrap().len(), 1);
     }
 }
+
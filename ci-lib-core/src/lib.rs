@@ -4,6 +4,8 @@ use std::time::{SystemTime, UNIX_EPOCH};
 pub mod protocol;
 pub mod sql;
 pub mod dbctx;
+pub mod migrations;
+pub mod archive;
 
 pub fn now_ms() -> u64 {
     SystemTime::now()
@@ -0,0 +1,344 @@
+use crate::sql;
+
+/// a single step in the history of `state.db`'s schema. migrations are applied in order of
+/// `version`, and each is applied at most once: `DbCtx::migrate` records the versions it has
+/// applied in `schema_version`.
+///
+/// migrations must never be edited once they have been released. if a migration is wrong, fix it
+/// with a later migration.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub statements: &'static [&'static str],
+}
+
+/// every migration this binary knows about, ordered by `version`.
+///
+/// version 1 is the schema that existed before migrations did. it is written entirely in terms of
+/// `CREATE .. IF NOT EXISTS` so that databases created before `schema_version` existed are adopted
+/// as version 1 without any changes.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        statements: &[
+            sql::CREATE_ARTIFACTS_TABLE,
+            sql::CREATE_JOBS_TABLE,
+            sql::CREATE_METRICS_TABLE,
+            sql::CREATE_COMMITS_TABLE,
+            sql::CREATE_COMMIT_NAMES_TABLE,
+            sql::CREATE_COMMIT_NAMES_INDEX,
+            sql::CREATE_REPOS_TABLE,
+            sql::CREATE_REPO_NAME_INDEX,
+            sql::CREATE_REMOTES_TABLE,
+            sql::CREATE_REMOTES_INDEX,
+            sql::CREATE_RUNS_TABLE,
+            sql::CREATE_HOSTS_TABLE,
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "retention policies and baseline runs",
+        statements: &[
+            sql::CREATE_RETENTION_POLICIES_TABLE,
+            sql::ADD_RUNS_BASELINE,
+        ],
+    },
+    Migration {
+        version: 3,
+        description: "commit ancestry",
+        statements: &[
+            sql::CREATE_COMMIT_PARENTS_TABLE,
+            sql::CREATE_COMMIT_CHILDREN_INDEX,
+        ],
+    },
+    Migration {
+        version: 4,
+        description: "refs replace commit_names",
+        statements: &[
+            sql::CREATE_REFS_TABLE,
+            sql::CREATE_REFS_SHA_INDEX,
+            sql::CREATE_REF_LOG_TABLE,
+            sql::CREATE_REF_LOG_SHA_INDEX,
+            sql::BACKFILL_REF_LOG,
+            sql::BACKFILL_REFS,
+            sql::DROP_COMMIT_NAMES,
+        ],
+    },
+    Migration {
+        version: 5,
+        description: "commits are scoped to a repo",
+        statements: &[
+            sql::CREATE_REPO_COMMITS_TABLE,
+            sql::BACKFILL_REPO_COMMITS,
+            sql::DROP_COMMITS,
+            sql::RENAME_REPO_COMMITS,
+            sql::CREATE_COMMITS_SHA_INDEX,
+        ],
+    },
+    Migration {
+        version: 6,
+        description: "run attempts",
+        statements: &[
+            sql::CREATE_ATTEMPTS_TABLE,
+            sql::CREATE_ATTEMPTS_TOKEN_INDEX,
+            sql::BACKFILL_ATTEMPTS,
+            sql::ADD_ARTIFACTS_ATTEMPT,
+            sql::BACKFILL_ARTIFACTS_ATTEMPT,
+            sql::CREATE_ARTIFACTS_ATTEMPT_INDEX,
+            sql::CREATE_ATTEMPT_METRICS_TABLE,
+            sql::BACKFILL_ATTEMPT_METRICS,
+            sql::DROP_METRICS,
+            sql::RENAME_ATTEMPT_METRICS,
+            sql::CREATE_METRICS_RUN_INDEX,
+        ],
+    },
+    Migration {
+        version: 7,
+        description: "numeric metrics",
+        statements: &[
+            sql::ADD_METRICS_NUMERIC_VALUE,
+            sql::ADD_METRICS_UNIT,
+            sql::ADD_METRICS_DIRECTION,
+            sql::BACKFILL_METRICS_NUMERIC_VALUE,
+        ],
+    },
+    Migration {
+        version: 8,
+        description: "metric change detection",
+        statements: &[
+            sql::CREATE_METRIC_POLICIES_TABLE,
+            sql::CREATE_METRIC_FINDINGS_TABLE,
+            sql::CREATE_METRIC_FINDINGS_RUN_INDEX,
+        ],
+    },
+    Migration {
+        version: 9,
+        description: "host registry",
+        statements: &[
+            sql::ADD_HOSTS_NAME,
+            sql::CREATE_HOSTS_NAME_INDEX,
+            sql::ADD_HOSTS_ENABLED,
+            sql::ADD_HOSTS_RETIRED_TIME,
+            sql::CREATE_HOST_LABELS_TABLE,
+            sql::CREATE_HOST_LABELS_LABEL_INDEX,
+        ],
+    },
+    Migration {
+        version: 10,
+        description: "runner identities and versioned host facts",
+        statements: &[
+            sql::CREATE_RUNNER_HOSTS_TABLE,
+            sql::BACKFILL_RUNNER_HOSTS,
+            sql::CREATE_HOST_FACTS_TABLE,
+            sql::BACKFILL_HOST_FACTS,
+            sql::DROP_HOSTS,
+            sql::RENAME_RUNNER_HOSTS,
+            sql::CREATE_HOSTS_NAME_INDEX,
+            sql::CREATE_HOSTS_RUNNER_ID_INDEX,
+            sql::CREATE_HOSTS_HOSTNAME_INDEX,
+        ],
+    },
+    Migration {
+        version: 11,
+        description: "command records",
+        statements: &[
+            sql::CREATE_COMMANDS_TABLE,
+            sql::CREATE_COMMANDS_RUN_INDEX,
+        ],
+    },
+    Migration {
+        version: 12,
+        description: "run timeouts",
+        statements: &[
+            sql::ADD_REPOS_DEFAULT_RUN_TIMEOUT,
+        ],
+    },
+    Migration {
+        version: 13,
+        description: "run cancellation",
+        statements: &[
+            sql::ADD_RUNS_CANCEL_REQUESTED_TIME,
+        ],
+    },
+    Migration {
+        version: 14,
+        description: "runner heartbeats and requeues",
+        statements: &[
+            sql::ADD_ATTEMPTS_HEARTBEAT_TIME,
+            sql::ADD_ATTEMPTS_REQUEUE_REASON,
+            sql::CREATE_ATTEMPTS_STATE_INDEX,
+        ],
+    },
+    Migration {
+        version: 15,
+        description: "typed run outcomes",
+        statements: &[
+            sql::ADD_RUNS_OUTCOME,
+            sql::ADD_ATTEMPTS_OUTCOME,
+            sql::BACKFILL_RUNS_OUTCOME,
+            sql::BACKFILL_ATTEMPTS_OUTCOME,
+        ],
+    },
+    Migration {
+        version: 16,
+        description: "log search",
+        statements: &[
+            sql::CREATE_LOG_LINES_TABLE,
+            sql::CREATE_LOG_LINES_ARTIFACT_INDEX,
+            sql::CREATE_LOG_SEARCH_TABLE,
+            sql::CREATE_LOG_LINES_INSERT_TRIGGER,
+            sql::CREATE_LOG_LINES_DELETE_TRIGGER,
+        ],
+    },
+    Migration {
+        version: 17,
+        description: "runner heartbeat progress",
+        statements: &[
+            sql::ADD_ATTEMPTS_HEARTBEAT_STEP,
+            sql::ADD_ATTEMPTS_HEARTBEAT_ELAPSED,
+            sql::ADD_ATTEMPTS_HEARTBEAT_LOAD_AVG,
+            sql::ADD_ATTEMPTS_HEARTBEAT_MEM_AVAILABLE,
+        ],
+    },
+    Migration {
+        version: 18,
+        description: "runner credentials",
+        statements: &[
+            sql::CREATE_RUNNER_CREDENTIALS_TABLE,
+            sql::ADD_HOSTS_CREDENTIAL_ID,
+            sql::CREATE_HOSTS_CREDENTIAL_INDEX,
+        ],
+    },
+    Migration {
+        version: 19,
+        description: "commit ancestry is scoped to a repo",
+        statements: &[
+            sql::CREATE_REPO_COMMIT_PARENTS_TABLE,
+            sql::BACKFILL_REPO_COMMIT_PARENTS,
+            sql::DROP_COMMIT_PARENTS,
+            sql::RENAME_REPO_COMMIT_PARENTS,
+            sql::CREATE_REPO_COMMIT_CHILDREN_INDEX,
+        ],
+    },
+];
+
+/// the schema version a database will be at after applying all of `MIGRATIONS`.
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dbctx::DbCtx;
+    use crate::protocol::TaskOutcome;
+    use crate::sql::MetricValue;
+
+    // a database as it looked before `schema_version` existed: the version 1 tables, with a repo
+    // that has built one commit on one host.
+    fn legacy_db() -> DbCtx {
+        let db = DbCtx::in_memory().expect("can open database");
+        let conn = db.writer();
+        for statement in MIGRATIONS[0].statements.iter() {
+            conn.execute_batch(statement).expect("can create legacy table");
+        }
+        conn.execute_batch("\
+            insert into repos (id, repo_name) values (1, 'ci');
+            insert into remotes (id, repo_id, remote_path, remote_api, remote_url, remote_git_url, notifier_config_path) \
+                values (1, 1, 'iximeow/ci', 'github', 'https://www.github.com/iximeow/ci', 'https://www.github.com/iximeow/ci.git', 'ci.json');
+            insert into commits (id, sha) values (1, 'abc123');
+            insert into commit_names (commit_id, name, name_state) values (1, 'main', 0);
+            insert into jobs (id, source, created_time, remote_id, commit_id) values (1, 'push', 1000, 1, 1);
+            insert into hosts (id, hostname, cpu_vendor_id, cpu_model_name, cpu_family, cpu_model, cpu_microcode, \
+                cpu_max_freq_khz, cpu_cores, mem_total, arch, family, os) \
+                values (1, 'builder', 'AuthenticAMD', 'ryzen', '25', '33', '0xa201016', 4900000, 16, '64G', 'x86_64', 'unix', 'linux');
+            insert into runs (id, job_id, artifacts_path, state, host_id, build_token, created_time, started_time, complete_time, build_result, final_status) \
+                values (1, 1, 'artifacts/1', 2, 1, 'token', 1000, 1100, 1200, 0, 'passed');
+            insert into metrics (run_id, name, value) values (1, 'build_ms', '1234');
+            insert into metrics (run_id, name, value) values (1, 'rustc', '1.70.0');
+            insert into artifacts (run_id, name, desc, created_time, completed_time) values (1, 'build (stdout)', 'stdout', 1100, 1200);
+        ").expect("can populate legacy database");
+        drop(conn);
+        db
+    }
+
+    #[test]
+    fn legacy_database_keeps_its_data() {
+        let db = legacy_db();
+        assert_eq!(db.migrate().expect("can migrate"), latest_version());
+
+        let commit_id = db.commit_id_by_sha(1, "abc123").unwrap().expect("commit belongs to its repo");
+        assert_eq!(db.job_for_commit(1, "abc123").unwrap(), Some(1));
+        assert_eq!(db.job_by_id(1).unwrap().expect("job kept").commit_id, commit_id);
+        assert_eq!(db.ref_sha(1, "refs/heads/main").unwrap().as_deref(), Some("abc123"));
+
+        let run = db.run_by_id(1).unwrap().expect("run kept");
+        assert_eq!(run.outcome, Some(TaskOutcome::Passed));
+        let attempts = db.attempts_for_run(1).unwrap();
+        assert_eq!(attempts.len(), 1);
+        assert_eq!(attempts[0].attempt, 1);
+        assert_eq!(db.attempt_for_token("token").unwrap().map(|t| t.attempt_id), Some(attempts[0].id));
+
+        let metrics = db.metrics_for_attempt(attempts[0].id).unwrap();
+        let value = |name: &str| metrics.iter().find(|m| m.name == name).map(|m| m.value.clone());
+        assert_eq!(value("build_ms"), Some(MetricValue::Number { value: 1234.0, unit: None, direction: None }));
+        assert_eq!(value("rustc"), Some(MetricValue::Text("1.70.0".to_string())));
+
+        let artifacts = db.artifacts_for_attempt(attempts[0].id, None).unwrap();
+        assert_eq!(artifacts.len(), 1);
+        assert_eq!(artifacts[0].name, "build (stdout)");
+
+        let host = db.host_by_id(1).unwrap().expect("host kept");
+        assert_eq!(host.hostname, "builder");
+        assert!(host.enabled && host.runner_id.is_none());
+        let facts = db.host_facts(1).unwrap();
+        assert_eq!(facts.len(), 1);
+        assert_eq!((facts[0].first_seen, facts[0].last_seen), (Some(1100), Some(1100)));
+    }
+
+    #[test]
+    fn migrating_twice_changes_nothing() {
+        let db = legacy_db();
+        assert_eq!(db.migrate().expect("can migrate"), latest_version());
+        assert_eq!(db.migrate().expect("can migrate again"), latest_version());
+        assert_eq!(db.attempts_for_run(1).unwrap().len(), 1);
+        assert_eq!(db.metrics_for_run(1).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn newer_database_is_refused() {
+        let db = DbCtx::in_memory().expect("can open database");
+        db.migrate().expect("can migrate");
+        db.writer().execute(
+            "insert into schema_version (version, description, applied_time) values (?1, 'from the future', 0)",
+            [latest_version() + 1]
+        ).unwrap();
+        assert!(matches!(db.migrate(), Err(crate::dbctx::DbError::Schema(_))));
+    }
+
+    #[test]
+    fn shared_ancestry_is_split_by_the_repos_it_reaches() {
+        let db = DbCtx::in_memory().expect("can open database");
+        {
+            let mut conn = db.writer();
+            let tx = conn.transaction().unwrap();
+            DbCtx::apply_migrations(&tx, 18).expect("can migrate to 18");
+            tx.execute_batch("\
+                insert into repos (id, repo_name) values (1, 'ci'), (2, 'fork');
+                insert into commits (repo_id, sha) values (1, 'c2'), (2, 'f2');
+                insert into commit_parents (sha, parent_sha, parent_index) values ('c2', 'c1', 0), ('c1', 'c0', 0);
+                insert into commit_parents (sha, parent_sha, parent_index) values ('f2', 'c1', 0);
+                insert into commit_parents (sha, parent_sha, parent_index) values ('orphan', 'c0', 0);
+            ").unwrap();
+            tx.commit().unwrap();
+        }
+        db.migrate().expect("can migrate");
+
+        assert_eq!(db.first_parent_history(1, "c2", 10).unwrap(), vec!["c2", "c1", "c0"]);
+        assert_eq!(db.first_parent_history(2, "f2", 10).unwrap(), vec!["f2", "c1", "c0"]);
+        // c2 is only in the history of the first repo.
+        assert!(db.commit_parents(2, "c2").unwrap().is_empty());
+        assert!(db.commit_parents(1, "orphan").unwrap().is_empty());
+    }
+}
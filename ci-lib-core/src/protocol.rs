@@ -1,38 +1,206 @@
 use serde::{Serialize, Deserialize};
 
+use std::fmt;
+
+use crate::sql::{JobResult, MetricValue, RunState};
+
+#[allow(clippy::large_enum_variant)]
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "kind")]
 #[serde(rename_all = "snake_case")]
 pub enum ClientProto {
+    /// the driver's answer to `NewTaskPlease`: the protocol version and features the rest of the
+    /// connection uses. see `negotiate`.
+    Hello {
+        version: u32,
+        #[serde(default)]
+        features: Vec<String>,
+    },
+    /// the driver's answer to a runner it can't find a protocol version in common with. the
+    /// driver hangs up after sending it.
+    Rejected { reason: String },
     Started,
     ArtifactCreate,
     NewTask(RequestedJob),
-    NewTaskPlease { allowed_pushers: Option<Vec<String>>, host_info: HostInfo },
-    Metric { name: String, value: String },
+    NewTaskPlease {
+        allowed_pushers: Option<Vec<String>>,
+        host_info: HostInfo,
+        /// an identity the runner generated once and keeps on disk, so the driver can tell it's the
+        /// same machine even if `host_info` changes. runners from before identities existed don't
+        /// send one, and are told apart by `host_info` alone.
+        #[serde(default)]
+        runner_id: Option<String>,
+        /// every protocol version the runner can speak. runners from before versions existed send
+        /// none, and no driver will talk to them.
+        #[serde(default)]
+        protocol_versions: Vec<u32>,
+        /// optional parts of the protocol the runner supports, on top of its versions.
+        #[serde(default)]
+        features: Vec<String>,
+    },
+    Metric { name: String, value: MetricValue },
     Command(CommandInfo),
     TaskStatus(TaskInfo),
+    /// sent by a runner whose goodfile asked for a different timeout than the one in its
+    /// `RequestedJob`. the timeout still counts from when the task started.
+    SetTimeout { timeout_ms: u64 },
+    /// sent by the driver to tell a runner to kill whatever its task is running and give up on it.
+    Terminate { reason: TerminateReason },
+    /// sent by a runner every `HEARTBEAT_INTERVAL_MS` while it runs a task, if both ends support
+    /// `FEATURE_HEARTBEAT`.
+    Heartbeat(HeartbeatInfo),
     Ping,
     Pong,
 }
 
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TerminateReason {
+    TimedOut,
+    Cancelled,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "command_info")]
 #[serde(rename_all = "snake_case")]
 pub enum CommandInfo {
-    Started { command: Vec<String>, cwd: Option<String>, id: u32 },
+    Started {
+        command: Vec<String>,
+        cwd: Option<String>,
+        id: u32,
+        /// the names of the steps this command ran under, outermost first.
+        #[serde(default)]
+        step: Vec<String>,
+        /// names of the artifacts the command's stdout and stderr are written to, if they're
+        /// kept. the artifacts are created before the command starts.
+        #[serde(default)]
+        stdout_artifact: Option<String>,
+        #[serde(default)]
+        stderr_artifact: Option<String>,
+    },
     Finished { exit_code: Option<i32>, id: u32 },
 }
 
+/// what a runner is up to, as of one of its heartbeats.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HeartbeatInfo {
+    /// the names of the steps the task is in, outermost first.
+    pub step: Vec<String>,
+    /// how long the task has been running, by the runner's clock.
+    pub elapsed_ms: u64,
+    /// the host's one-minute load average, if the runner could read it.
+    #[serde(default)]
+    pub load_avg: Option<f64>,
+    /// memory available to start new processes on the host, if the runner could read it.
+    #[serde(default)]
+    pub mem_available_kb: Option<u64>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "task_info")]
 #[serde(rename_all = "snake_case")]
 pub enum TaskInfo {
-    Finished { status: String },
-    Interrupted { status: String, description: Option<String> },
+    Finished {
+        status: String,
+        // runners from before outcomes were reported leave this out. see `TaskInfo::outcome`.
+        #[serde(default)]
+        outcome: Option<TaskOutcome>,
+    },
+    Interrupted {
+        status: String,
+        description: Option<String>,
+        #[serde(default)]
+        outcome: Option<TaskOutcome>,
+    },
+}
+
+/// how a task ended. only `BuildFailed` is the fault of the commit being built: the other failures
+/// are problems with the repo's setup or with the CI itself, and say nothing about the commit.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskOutcome {
+    Passed,
+    /// the goodfile ran, and a step of the build failed.
+    BuildFailed,
+    /// the commit could not be checked out.
+    CheckoutFailed,
+    /// the goodfile is missing, unreadable, or has a bug of its own.
+    GoodfileError,
+    /// something went wrong with the runner or the host it runs on.
+    InfraError,
+    TimedOut,
+    Cancelled,
+}
+
+impl TaskOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TaskOutcome::Passed => "passed",
+            TaskOutcome::BuildFailed => "build_failed",
+            TaskOutcome::CheckoutFailed => "checkout_failed",
+            TaskOutcome::GoodfileError => "goodfile_error",
+            TaskOutcome::InfraError => "infra_error",
+            TaskOutcome::TimedOut => "timed_out",
+            TaskOutcome::Cancelled => "cancelled",
+        }
+    }
+
+    /// a few words for people, like "build failed".
+    pub fn summary(&self) -> &'static str {
+        match self {
+            TaskOutcome::Passed => "passed",
+            TaskOutcome::BuildFailed => "build failed",
+            TaskOutcome::CheckoutFailed => "checkout failed",
+            TaskOutcome::GoodfileError => "goodfile error",
+            TaskOutcome::InfraError => "infrastructure error",
+            TaskOutcome::TimedOut => "timed out",
+            TaskOutcome::Cancelled => "cancelled",
+        }
+    }
+
+    /// the state a run is left in when its attempt ends this way. a failed build still ran to
+    /// completion, so it is `Finished` with a failing build result.
+    pub fn run_state(&self) -> RunState {
+        match self {
+            TaskOutcome::Passed | TaskOutcome::BuildFailed => RunState::Finished,
+            TaskOutcome::CheckoutFailed | TaskOutcome::GoodfileError | TaskOutcome::InfraError => RunState::Error,
+            TaskOutcome::TimedOut => RunState::TimedOut,
+            TaskOutcome::Cancelled => RunState::Cancelled,
+        }
+    }
+
+    pub fn build_result(&self) -> JobResult {
+        match self {
+            TaskOutcome::Passed => JobResult::Pass,
+            _ => JobResult::Fail,
+        }
+    }
+
+    /// whether trying the same commit again might end differently.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, TaskOutcome::InfraError)
+    }
+}
+
+impl TryFrom<&str> for TaskOutcome {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, String> {
+        match value {
+            "passed" => Ok(TaskOutcome::Passed),
+            "build_failed" => Ok(TaskOutcome::BuildFailed),
+            "checkout_failed" => Ok(TaskOutcome::CheckoutFailed),
+            "goodfile_error" => Ok(TaskOutcome::GoodfileError),
+            "infra_error" => Ok(TaskOutcome::InfraError),
+            "timed_out" => Ok(TaskOutcome::TimedOut),
+            "cancelled" => Ok(TaskOutcome::Cancelled),
+            other => Err(format!("invalid task outcome: {}", other)),
+        }
+    }
 }
 
 impl ClientProto {
-    pub fn metric(name: impl Into<String>, value: impl Into<String>) -> Self {
+    pub fn metric(name: impl Into<String>, value: impl Into<MetricValue>) -> Self {
         ClientProto::Metric { name: name.into(), value: value.into() }
     }
 
@@ -40,8 +208,14 @@ impl ClientProto {
         ClientProto::Command(state)
     }
 
-    pub fn new_task_please(allowed_pushers: Option<Vec<String>>, host_info: HostInfo) -> Self {
-        ClientProto::NewTaskPlease { allowed_pushers, host_info }
+    pub fn new_task_please(allowed_pushers: Option<Vec<String>>, host_info: HostInfo, runner_id: impl Into<String>) -> Self {
+        ClientProto::NewTaskPlease {
+            allowed_pushers,
+            host_info,
+            runner_id: Some(runner_id.into()),
+            protocol_versions: PROTOCOL_VERSIONS.to_vec(),
+            features: PROTOCOL_FEATURES.iter().map(|f| f.to_string()).collect(),
+        }
     }
 
     pub fn task_status(state: TaskInfo) -> Self {
@@ -51,11 +225,120 @@ impl ClientProto {
     pub fn new_task(task: RequestedJob) -> Self {
         ClientProto::NewTask(task)
     }
+
+    pub fn set_timeout(timeout_ms: u64) -> Self {
+        ClientProto::SetTimeout { timeout_ms }
+    }
+
+    pub fn terminate(reason: TerminateReason) -> Self {
+        ClientProto::Terminate { reason }
+    }
+
+    pub fn hello(protocol: &Negotiated) -> Self {
+        ClientProto::Hello { version: protocol.version, features: protocol.features.clone() }
+    }
+
+    pub fn rejected(reason: impl Into<String>) -> Self {
+        ClientProto::Rejected { reason: reason.into() }
+    }
+
+    pub fn heartbeat(info: HeartbeatInfo) -> Self {
+        ClientProto::Heartbeat(info)
+    }
+}
+
+/// the protocol versions this build speaks. a version changes whenever messages change in a way an
+/// older peer can't cope with; additions an older peer can safely go without are features instead.
+///
+/// version 1 is newline-delimited `ClientProto` messages, starting with the runner's
+/// `NewTaskPlease` and the driver's `Hello`. over a websocket, each message is its own text frame.
+pub const PROTOCOL_VERSIONS: &[u32] = &[1];
+
+/// the optional parts of the protocol this build supports. a feature is only used on a connection
+/// if both ends listed it.
+pub const PROTOCOL_FEATURES: &[&str] = &[FEATURE_HEARTBEAT];
+
+/// the runner sends `Heartbeat`s while it runs a task, and the driver gives up on a runner that
+/// stops sending them. without it, the driver only knows a runner is gone once its connection is.
+pub const FEATURE_HEARTBEAT: &str = "heartbeat";
+
+/// how often a runner with `FEATURE_HEARTBEAT` sends a heartbeat.
+pub const HEARTBEAT_INTERVAL_MS: u64 = 10_000;
+
+/// how many heartbeats in a row a runner can miss before the driver decides it is gone.
+pub const MISSED_HEARTBEAT_LIMIT: u64 = 6;
+
+/// what a driver and runner agreed to speak for the rest of a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    pub version: u32,
+    pub features: Vec<String>,
+}
+
+impl Negotiated {
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+/// pick the newest protocol version in both `PROTOCOL_VERSIONS` and what a peer offered, along with
+/// the features both support. the error says why there's no version in common, for the peer's
+/// operator to read.
+pub fn negotiate(versions: &[u32], features: &[String]) -> Result<Negotiated, String> {
+    let version = PROTOCOL_VERSIONS.iter()
+        .filter(|v| versions.contains(v))
+        .max()
+        .copied();
+
+    let version = match version {
+        Some(version) => version,
+        None if versions.is_empty() => {
+            return Err(format!(
+                "runner does not declare a protocol version, and is probably older than this driver. \
+                 it needs to be updated to speak one of versions {:?}",
+                PROTOCOL_VERSIONS
+            ));
+        }
+        None => {
+            return Err(format!(
+                "no protocol version in common: runner speaks {:?}, driver speaks {:?}",
+                versions, PROTOCOL_VERSIONS
+            ));
+        }
+    };
+
+    let features = features.iter()
+        .filter(|f| PROTOCOL_FEATURES.contains(&f.as_str()))
+        .cloned()
+        .collect();
+
+    Ok(Negotiated { version, features })
+}
+
+impl TerminateReason {
+    /// the outcome of a task that was stopped for this reason.
+    pub fn outcome(&self) -> TaskOutcome {
+        match self {
+            TerminateReason::TimedOut => TaskOutcome::TimedOut,
+            TerminateReason::Cancelled => TaskOutcome::Cancelled,
+        }
+    }
 }
 
 impl CommandInfo {
-    pub fn started(command: impl Into<Vec<String>>, cwd: Option<&str>, id: u32) -> Self {
-        CommandInfo::Started { command: command.into(), cwd: cwd.map(ToOwned::to_owned), id }
+    pub fn started(command: impl Into<Vec<String>>, cwd: Option<&str>, id: u32, step: &[String], outputs: Option<(&str, &str)>) -> Self {
+        let (stdout_artifact, stderr_artifact) = match outputs {
+            Some((stdout, stderr)) => (Some(stdout.to_owned()), Some(stderr.to_owned())),
+            None => (None, None),
+        };
+        CommandInfo::Started {
+            command: command.into(),
+            cwd: cwd.map(ToOwned::to_owned),
+            id,
+            step: step.to_vec(),
+            stdout_artifact,
+            stderr_artifact,
+        }
     }
 
     pub fn finished(exit_code: Option<i32>, id: u32) -> Self {
@@ -64,12 +347,35 @@ impl CommandInfo {
 }
 
 impl TaskInfo {
-    pub fn finished(status: impl Into<String>) -> Self {
-        TaskInfo::Finished { status: status.into() }
+    pub fn finished(outcome: TaskOutcome, status: impl Into<String>) -> Self {
+        TaskInfo::Finished { status: status.into(), outcome: Some(outcome) }
+    }
+
+    pub fn interrupted(outcome: TaskOutcome, status: impl Into<String>, description: impl Into<Option<String>>) -> Self {
+        TaskInfo::Interrupted { status: status.into(), description: description.into(), outcome: Some(outcome) }
+    }
+
+    /// how the task ended. older runners only report a status, which says whether the task
+    /// passed and, for a bad checkout, that it couldn't check the commit out.
+    pub fn outcome(&self) -> TaskOutcome {
+        match self {
+            TaskInfo::Finished { outcome: Some(outcome), .. } |
+            TaskInfo::Interrupted { outcome: Some(outcome), .. } => *outcome,
+            TaskInfo::Finished { status, outcome: None } => match status.as_str() {
+                "pass" => TaskOutcome::Passed,
+                "bad_ref" => TaskOutcome::CheckoutFailed,
+                _ => TaskOutcome::BuildFailed,
+            },
+            TaskInfo::Interrupted { outcome: None, .. } => TaskOutcome::BuildFailed,
+        }
     }
 
-    pub fn interrupted(status: impl Into<String>, description: impl Into<Option<String>>) -> Self {
-        TaskInfo::Interrupted { status: status.into(), description: description.into() }
+    /// what the runner had to say about how the task ended.
+    pub fn description(&self) -> &str {
+        match self {
+            TaskInfo::Finished { status, .. } => status,
+            TaskInfo::Interrupted { status, description, .. } => description.as_deref().unwrap_or(status),
+        }
     }
 }
 
@@ -111,4 +417,269 @@ pub struct RequestedJob {
     pub commit: String,
     pub remote_url: String,
     pub build_token: String,
+    /// how long the task may take, in milliseconds, before the driver gives up on it. a runner
+    /// should kill the task itself if it's still going well after this, in case the driver can't
+    /// reach it to say so.
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+}
+
+/// the longest frame `FrameDecoder` will buffer. anything longer is almost certainly not a message
+/// from a well-behaved peer.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// serialize `msg` as one frame of the driver/runner stream.
+///
+/// messages are newline-delimited json: compact json never contains a raw newline (newlines in
+/// strings are escaped), so a newline always ends a frame. the transport underneath is free to
+/// split or merge frames however it likes, and `FrameDecoder` puts them back together.
+pub fn encode_frame<T: Serialize>(msg: &T) -> Result<String, serde_json::Error> {
+    let mut frame = serde_json::to_string(msg)?;
+    frame.push('\n');
+    Ok(frame)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// a frame grew past `MAX_FRAME_LEN` without being terminated.
+    TooLong,
+    /// the stream ended partway through a frame.
+    Truncated,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::TooLong => write!(f, "frame longer than {} bytes", MAX_FRAME_LEN),
+            FrameError::Truncated => write!(f, "stream ended partway through a frame"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// reassembles frames written by `encode_frame` from chunks of the stream, whatever their
+/// boundaries. `push` every chunk as it arrives, then take frames with `next_frame` until it has
+/// nothing more; at the end of the stream, `finish` reports whether a frame was cut short.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    // how much of `buf` is known to not contain a newline, so a frame arriving in many small
+    // chunks isn't rescanned from the start each time.
+    scanned: usize,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.buf.extend_from_slice(chunk);
+    }
+
+    /// the next complete frame, without its newline, or `None` if more of the stream is needed.
+    /// blank lines are skipped rather than reported as empty frames.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        loop {
+            let end = match self.buf[self.scanned..].iter().position(|b| *b == b'\n') {
+                Some(offset) => self.scanned + offset,
+                None => {
+                    self.scanned = self.buf.len();
+                    if self.buf.len() > MAX_FRAME_LEN {
+                        return Err(FrameError::TooLong);
+                    }
+                    return Ok(None);
+                }
+            };
+
+            if end > MAX_FRAME_LEN {
+                return Err(FrameError::TooLong);
+            }
+
+            let mut frame: Vec<u8> = self.buf.drain(..=end).collect();
+            self.scanned = 0;
+            frame.pop();
+
+            if !frame.iter().all(|b| b.is_ascii_whitespace()) {
+                return Ok(Some(frame));
+            }
+        }
+    }
+
+    /// check that the stream ended between frames. call this once the stream is over and
+    /// `next_frame` has returned everything it can.
+    pub fn finish(&self) -> Result<(), FrameError> {
+        if self.buf.iter().all(|b| b.is_ascii_whitespace()) {
+            Ok(())
+        } else {
+            Err(FrameError::Truncated)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages() -> Vec<ClientProto> {
+        vec![
+            ClientProto::Hello { version: 1, features: vec!["feature".to_string()] },
+            ClientProto::Ping,
+            ClientProto::metric("line\nbreaks", "in\nvalues\r\n"),
+            ClientProto::command(CommandInfo::started(
+                vec!["cargo".to_string(), "build".to_string()],
+                Some("tmpdir"),
+                7,
+                &["build".to_string()],
+                Some(("cargo build (stdout)", "cargo build (stderr)")),
+            )),
+            ClientProto::metric("empty", ""),
+            ClientProto::set_timeout(60000),
+            ClientProto::terminate(TerminateReason::Cancelled),
+            ClientProto::heartbeat(HeartbeatInfo {
+                step: vec!["build".to_string(), "test\nsuite".to_string()],
+                elapsed_ms: 12345,
+                load_avg: Some(1.5),
+                mem_available_kb: None,
+            }),
+            ClientProto::task_status(TaskInfo::finished(TaskOutcome::Passed, "pass")),
+        ]
+    }
+
+    fn stream() -> Vec<u8> {
+        messages()
+            .iter()
+            .map(|msg| encode_frame(msg).expect("can encode"))
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    // decode `chunks` as they would arrive off the wire, compared as json so `ClientProto` doesn't
+    // need to be `PartialEq`.
+    fn decode<'a>(chunks: impl IntoIterator<Item = &'a [u8]>) -> Result<Vec<serde_json::Value>, FrameError> {
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = Vec::new();
+        for chunk in chunks {
+            decoder.push(chunk);
+            while let Some(frame) = decoder.next_frame()? {
+                decoded.push(serde_json::from_slice(&frame).expect("frame is json"));
+            }
+        }
+        decoder.finish()?;
+        Ok(decoded)
+    }
+
+    fn expected() -> Vec<serde_json::Value> {
+        messages()
+            .iter()
+            .map(|msg| serde_json::to_value(msg).expect("can encode"))
+            .collect()
+    }
+
+    #[test]
+    fn frames_are_one_line() {
+        for msg in messages() {
+            let frame = encode_frame(&msg).expect("can encode");
+            assert_eq!(frame.find('\n'), Some(frame.len() - 1));
+        }
+    }
+
+    #[test]
+    fn merged_chunks() {
+        let stream = stream();
+        assert_eq!(decode([&stream[..]]).expect("decodes"), expected());
+    }
+
+    #[test]
+    fn split_anywhere() {
+        let stream = stream();
+        for split in 0..=stream.len() {
+            let (head, tail) = stream.split_at(split);
+            assert_eq!(decode([head, tail]).expect("decodes"), expected(), "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn byte_at_a_time() {
+        let stream = stream();
+        assert_eq!(decode(stream.chunks(1)).expect("decodes"), expected());
+    }
+
+    #[test]
+    fn arbitrary_chunks() {
+        let stream = stream();
+        // a small lcg is plenty to pick chunk sizes, and keeps failures reproducible.
+        let mut state: u64 = 0x2545f4914f6cdd1d;
+        for _ in 0..1000 {
+            let mut chunks = Vec::new();
+            let mut rest = &stream[..];
+            while !rest.is_empty() {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                let len = ((state >> 33) as usize % 64).min(rest.len());
+                let (chunk, tail) = rest.split_at(len);
+                chunks.push(chunk);
+                rest = tail;
+            }
+            assert_eq!(decode(chunks.iter().copied()).expect("decodes"), expected());
+        }
+    }
+
+    #[test]
+    fn blank_lines_are_skipped() {
+        let stream = messages()
+            .iter()
+            .map(|msg| "\n\r\n".to_string() + &encode_frame(msg).expect("can encode"))
+            .collect::<String>()
+            .into_bytes();
+        assert_eq!(decode([&stream[..]]).expect("decodes"), expected());
+    }
+
+    #[test]
+    fn truncated_stream() {
+        let stream = stream();
+        let cut = &stream[..stream.len() - 2];
+        assert_eq!(decode([cut]), Err(FrameError::Truncated));
+    }
+
+    #[test]
+    fn overlong_frame() {
+        let mut decoder = FrameDecoder::new();
+        let chunk = vec![b'a'; 1024 * 1024];
+        for _ in 0..(MAX_FRAME_LEN / chunk.len()) {
+            decoder.push(&chunk);
+            assert_eq!(decoder.next_frame(), Ok(None));
+        }
+        decoder.push(b"a");
+        assert_eq!(decoder.next_frame(), Err(FrameError::TooLong));
+
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&vec![b'a'; MAX_FRAME_LEN + 1]);
+        decoder.push(b"\n");
+        assert_eq!(decoder.next_frame(), Err(FrameError::TooLong));
+    }
+
+    #[test]
+    fn negotiate_picks_newest_common_version() {
+        let newest = *PROTOCOL_VERSIONS.iter().max().expect("speaks some version");
+        let offered = vec![newest, newest + 1, 0];
+        let negotiated = negotiate(&offered, &[]).expect("has a version in common");
+        assert_eq!(negotiated.version, newest);
+    }
+
+    #[test]
+    fn negotiate_drops_unknown_features() {
+        let features = vec!["not_a_real_feature".to_string(), FEATURE_HEARTBEAT.to_string()];
+        let negotiated = negotiate(PROTOCOL_VERSIONS, &features).expect("has a version in common");
+        assert_eq!(negotiated.features, vec![FEATURE_HEARTBEAT.to_string()]);
+        assert!(negotiated.has_feature(FEATURE_HEARTBEAT));
+        assert!(!negotiated.has_feature("not_a_real_feature"));
+    }
+
+    #[test]
+    fn negotiate_rejects_unversioned_and_incompatible_runners() {
+        assert!(negotiate(&[], &[]).is_err());
+        let newer = PROTOCOL_VERSIONS.iter().max().expect("speaks some version") + 1;
+        assert!(negotiate(&[newer], &[]).is_err());
+    }
 }
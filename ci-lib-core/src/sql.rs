@@ -2,42 +2,222 @@
 
 use std::convert::TryFrom;
 
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ValueRef};
+use serde::{Serialize, Deserialize};
+
+use crate::protocol::TaskOutcome;
+
+/// a value read from the database has the right type for its column, but is not a value that
+/// column should ever hold (an unknown run state, for example). this is carried through
+/// `FromSqlError::Other` so that `DbError` can report it as a corrupt row rather than a failed
+/// type conversion.
+#[derive(Debug)]
+pub struct InvalidValue(pub String);
+
+impl std::fmt::Display for InvalidValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidValue {}
+
 #[derive(Debug, Clone)]
 pub struct PendingRun {
     pub id: u64,
     pub job_id: u64,
     pub create_time: u64,
-}
-
-impl Run {
-    fn into_pending_run(self) -> PendingRun {
-        PendingRun {
-            id: self.id,
-            job_id: self.job_id,
-            create_time: self.create_time,
-        }
-    }
+    pub host_preference: Option<u32>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TokenValidity {
     Expired,
     Invalid,
+    /// the token's time isn't up, but the credential its runner connected with has been revoked or
+    /// has expired since.
+    Revoked,
     Valid,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// what a runner's build token says about who it is.
+#[derive(Debug, Clone)]
+pub struct TokenAttempt {
+    pub attempt_id: u64,
+    pub run_id: u64,
+    pub artifacts_path: Option<String>,
+    pub validity: TokenValidity,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct MetricRecord {
     pub id: u64,
     pub run_id: u64,
+    pub attempt_id: Option<u64>,
     pub name: String,
-    pub value: String
+    pub value: MetricValue,
+}
+
+/// what a numeric metric measures. this decides how its values are displayed, and metrics are
+/// only compared against metrics in the same unit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricUnit {
+    Ms,
+    Bytes,
+    Count,
+    Instructions,
+}
+
+impl MetricUnit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MetricUnit::Ms => "ms",
+            MetricUnit::Bytes => "bytes",
+            MetricUnit::Count => "count",
+            MetricUnit::Instructions => "instructions",
+        }
+    }
+}
+
+impl TryFrom<&str> for MetricUnit {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, String> {
+        match value {
+            "ms" => Ok(MetricUnit::Ms),
+            "bytes" => Ok(MetricUnit::Bytes),
+            "count" => Ok(MetricUnit::Count),
+            "instructions" => Ok(MetricUnit::Instructions),
+            other => Err(format!("invalid metric unit: {}", other)),
+        }
+    }
+}
+
+impl FromSql for MetricUnit {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        MetricUnit::try_from(value.as_str()?)
+            .map_err(|e| FromSqlError::Other(Box::new(InvalidValue(e))))
+    }
+}
+
+/// which way a numeric metric should move for a change to count as an improvement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MetricDirection {
+    #[serde(rename = "lower")]
+    LowerIsBetter,
+    #[serde(rename = "higher")]
+    HigherIsBetter,
+}
+
+impl MetricDirection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MetricDirection::LowerIsBetter => "lower",
+            MetricDirection::HigherIsBetter => "higher",
+        }
+    }
+}
+
+impl TryFrom<&str> for MetricDirection {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, String> {
+        match value {
+            "lower" => Ok(MetricDirection::LowerIsBetter),
+            "higher" => Ok(MetricDirection::HigherIsBetter),
+            other => Err(format!("invalid metric direction: {}", other)),
+        }
+    }
+}
+
+impl FromSql for MetricDirection {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        MetricDirection::try_from(value.as_str()?)
+            .map_err(|e| FromSqlError::Other(Box::new(InvalidValue(e))))
+    }
+}
+
+/// a metric as reported by a goodfile. text metrics are labels (a version string, a target
+/// name); only numbers can be compared.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MetricValue {
+    Text(String),
+    Number {
+        value: f64,
+        #[serde(default)]
+        unit: Option<MetricUnit>,
+        #[serde(default)]
+        direction: Option<MetricDirection>,
+    },
+}
+
+impl From<String> for MetricValue {
+    fn from(value: String) -> Self {
+        MetricValue::Text(value)
+    }
+}
+
+impl From<&str> for MetricValue {
+    fn from(value: &str) -> Self {
+        MetricValue::Text(value.to_owned())
+    }
+}
+
+/// how a numeric metric changed from one measurement to a later one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MetricDelta {
+    pub before: f64,
+    pub after: f64,
+    /// `after - before`.
+    pub change: f64,
+    /// `change` as a fraction of `before`, or `None` if `before` is zero.
+    pub relative: Option<f64>,
+    /// whether the change is for the better, if the metric says which way is better and it
+    /// changed at all.
+    pub improved: Option<bool>,
+}
+
+impl MetricValue {
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            MetricValue::Text(_) => None,
+            MetricValue::Number { value, .. } => Some(*value),
+        }
+    }
+
+    /// compare this measurement against an `earlier` one of the same metric. only numbers in the
+    /// same unit can be compared.
+    pub fn delta_from(&self, earlier: &MetricValue) -> Option<MetricDelta> {
+        match (earlier, self) {
+            (
+                MetricValue::Number { value: before, unit: before_unit, .. },
+                MetricValue::Number { value: after, unit: after_unit, direction },
+            ) => {
+                if before_unit != after_unit {
+                    return None;
+                }
+                let change = after - before;
+                let relative = if *before == 0.0 { None } else { Some(change / before.abs()) };
+                let improved = match direction {
+                    _ if change == 0.0 => None,
+                    Some(MetricDirection::LowerIsBetter) => Some(change < 0.0),
+                    Some(MetricDirection::HigherIsBetter) => Some(change > 0.0),
+                    None => None,
+                };
+                Some(MetricDelta { before: *before, after: *after, change, relative, improved })
+            }
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ArtifactRecord {
     pub id: u64,
     pub run_id: u64,
+    pub attempt_id: Option<u64>,
     pub name: String,
     pub desc: String,
     pub created_time: u64,
@@ -49,6 +229,9 @@ pub struct Repo {
     pub id: u64,
     pub name: String,
     pub default_run_preference: Option<String>,
+    /// how long, in milliseconds, runs of this repo may take unless their goodfile says otherwise.
+    /// `None` means the driver's default.
+    pub default_run_timeout: Option<u64>,
 }
 
 #[derive(Debug)]
@@ -62,6 +245,226 @@ pub struct Remote {
     pub notifier_config_path: String,
 }
 
+/// how long a repo's run data is kept around. `None` in any field means "forever", so a repo
+/// without a policy, or with an empty one, is never garbage collected.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub repo_id: u64,
+    /// days after which a run's artifacts (logs, build outputs) are deleted.
+    pub log_days: Option<u64>,
+    /// days after which a run's metrics are deleted.
+    pub metric_days: Option<u64>,
+    /// runs for the most recent `keep_commits` commits on each branch are kept regardless of age.
+    pub keep_commits: Option<u64>,
+}
+
+/// how closely a repo's metrics are watched for changes between commits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricPolicy {
+    pub repo_id: u64,
+    /// a metric that moves by more than this fraction of its baseline is flagged. 0.05 is 5%.
+    pub threshold: f64,
+    /// how many of the nearest ancestors built on the same host make up the baseline. 1 compares
+    /// against just the closest one, usually the parent commit.
+    pub window: u64,
+    /// if set, a change must also be more than this many standard deviations of the baseline to
+    /// be flagged. only applies when the baseline has at least two samples.
+    pub noise_sigmas: Option<f64>,
+}
+
+impl MetricPolicy {
+    /// the policy for repos that haven't set one.
+    pub fn default_for(repo_id: u64) -> Self {
+        MetricPolicy {
+            repo_id,
+            threshold: 0.05,
+            window: 1,
+            noise_sigmas: None,
+        }
+    }
+}
+
+/// a metric from one attempt that changed by more than its repo's `MetricPolicy` allows,
+/// compared to the same host's measurements of recent ancestor commits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricFinding {
+    pub id: u64,
+    pub run_id: u64,
+    pub attempt_id: u64,
+    pub name: String,
+    pub unit: Option<MetricUnit>,
+    pub value: f64,
+    /// the mean of the baseline measurements.
+    pub baseline: f64,
+    /// how many ancestor commits `baseline` was computed from.
+    pub baseline_samples: u64,
+    pub change: f64,
+    pub relative: Option<f64>,
+    /// `None` if the metric doesn't say which direction is better.
+    pub improved: Option<bool>,
+    pub created_time: u64,
+}
+
+/// a machine runners have connected from. hosts are identified by the identity their runner keeps
+/// on disk, or by their hardware fingerprint for runners that predate identities. hosts are never
+/// deleted, so that runs keep pointing at the hardware they ran on.
+///
+/// the hardware fields are the most recent of the host's `HostFacts`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Host {
+    pub id: u64,
+    pub runner_id: Option<String>,
+    /// a name the operator gave this host, if any.
+    pub name: Option<String>,
+    pub hostname: String,
+    pub cpu_vendor_id: String,
+    pub cpu_model_name: String,
+    pub cpu_family: String,
+    pub cpu_model: String,
+    pub cpu_microcode: String,
+    pub cpu_max_freq_khz: u64,
+    pub cpu_cores: u32,
+    pub mem_total: String,
+    pub arch: String,
+    pub family: String,
+    pub os: String,
+    /// disabled hosts are not given any work, but may be enabled again later.
+    pub enabled: bool,
+    /// when this host was retired. retired hosts are never given work again.
+    pub retired_time: Option<u64>,
+}
+
+impl Host {
+    /// the operator-assigned name if there is one, otherwise the hostname the runner reported.
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.hostname)
+    }
+
+    pub fn accepts_work(&self) -> bool {
+        self.enabled && self.retired_time.is_none()
+    }
+}
+
+/// a token a runner authenticates to the driver with. only a hash of the token is kept. a
+/// credential is bound to the host it first authenticates as, and only ever authenticates that
+/// host afterward.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunnerCredential {
+    pub id: u64,
+    pub name: String,
+    pub created_time: u64,
+    pub expires_time: Option<u64>,
+    pub revoked_time: Option<u64>,
+    pub last_used_time: Option<u64>,
+    /// the host this credential is bound to, once it has been used.
+    pub host_id: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CredentialState {
+    Active,
+    Expired,
+    Revoked,
+}
+
+impl RunnerCredential {
+    pub fn state(&self, now: u64) -> CredentialState {
+        if self.revoked_time.is_some() {
+            CredentialState::Revoked
+        } else if self.expires_time.map(|expires| expires <= now).unwrap_or(false) {
+            CredentialState::Expired
+        } else {
+            CredentialState::Active
+        }
+    }
+}
+
+/// the hardware a host reported over some span of time. a host gets a new version of its facts
+/// whenever any of them change, such as after a microcode update or a kernel upgrade that shifts
+/// `mem_total`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostFacts {
+    pub host_id: u64,
+    pub version: u32,
+    pub hostname: String,
+    pub cpu_vendor_id: String,
+    pub cpu_model_name: String,
+    pub cpu_family: String,
+    pub cpu_model: String,
+    pub cpu_microcode: String,
+    pub cpu_max_freq_khz: u64,
+    pub cpu_cores: u32,
+    pub mem_total: String,
+    pub arch: String,
+    pub family: String,
+    pub os: String,
+    /// `None` for facts recorded before hosts had versioned facts.
+    pub first_seen: Option<u64>,
+    pub last_seen: Option<u64>,
+}
+
+/// one command a runner ran during an attempt, such as each `Build.run` in a goodfile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandRecord {
+    pub id: u64,
+    pub run_id: u64,
+    pub attempt_id: u64,
+    /// the runner's id for this command, unique within the attempt. commands ran in this order.
+    pub command_id: u32,
+    pub argv: Vec<String>,
+    pub cwd: Option<String>,
+    /// the names of the steps this command ran under, outermost first.
+    pub step: Vec<String>,
+    pub start_time: u64,
+    pub end_time: Option<u64>,
+    /// `None` until the command finishes, and after if it was killed by a signal.
+    pub exit_code: Option<i32>,
+    pub stdout_artifact_id: Option<u64>,
+    pub stderr_artifact_id: Option<u64>,
+}
+
+/// what to look for with `DbCtx::search_logs`. filters that are `None` match every log.
+#[derive(Debug, Clone, Default)]
+pub struct LogQuery {
+    /// words to find, in this order, on one line.
+    pub text: String,
+    pub repo_name: Option<String>,
+    pub host_id: Option<u64>,
+    /// only logs created at or after this time, in ms since the epoch.
+    pub since: Option<u64>,
+    /// only logs created before this time, in ms since the epoch.
+    pub until: Option<u64>,
+    pub limit: u64,
+    /// how many lines before and after each matching line to include with it.
+    pub context: u64,
+}
+
+/// a line of some run's stdout or stderr that matched a `LogQuery`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogMatch {
+    pub run_id: u64,
+    pub attempt_id: Option<u64>,
+    pub artifact_id: u64,
+    pub artifact_name: String,
+    pub repo_name: String,
+    pub remote_path: String,
+    pub sha: String,
+    pub host_id: Option<u64>,
+    pub created_time: u64,
+    /// the matching line's number in its log, counting from 1.
+    pub line_number: u64,
+    /// the matching line and the lines around it, in order, with their line numbers.
+    pub lines: Vec<(u64, String)>,
+}
+
+/// a finished run with some data that its repo's retention policy says should be deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExpiredRun {
+    pub run_id: u64,
+    pub logs_expired: bool,
+    pub metrics_expired: bool,
+}
+
 // a job tracks when we became aware of a commit from remote. typically a job will have a 1-1
 // relationship with commits, and potentially many *runs* of that job.
 #[derive(Debug, Clone)]
@@ -74,6 +477,31 @@ pub struct Job {
     pub run_preferences: Option<String>,
 }
 
+/// what `DbCtx::create_job` did for a pushed commit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobCreation {
+    /// the commit had no job yet: a job and its initial pending run were created for it.
+    Created { job_id: u64, commit_id: u64, run_id: u64 },
+    /// the commit already had a job, which was left as it was.
+    Existing { job_id: u64, commit_id: u64 },
+}
+
+impl JobCreation {
+    pub fn job_id(&self) -> u64 {
+        match self {
+            JobCreation::Created { job_id, .. } => *job_id,
+            JobCreation::Existing { job_id, .. } => *job_id,
+        }
+    }
+
+    pub fn commit_id(&self) -> u64 {
+        match self {
+            JobCreation::Created { commit_id, .. } => *commit_id,
+            JobCreation::Existing { commit_id, .. } => *commit_id,
+        }
+    }
+}
+
 // a run tracks the intent or obligation to have some runner somewhere run a goodfile and report
 // results. a job may have many runs from many different hosts rebuliding history, or reruns of the
 // same job on the same hardware to collect more datapoints on the operation.
@@ -91,6 +519,48 @@ pub struct Run {
     pub run_timeout: Option<u64>,
     pub build_result: Option<u8>,
     pub final_text: Option<String>,
+    /// how the run's latest attempt ended. runs finished before outcomes were recorded may not
+    /// have one.
+    pub outcome: Option<TaskOutcome>,
+}
+
+// an attempt is one execution of a run by some runner. a run that is retried keeps every attempt,
+// each with its own token, timing, artifacts and metrics, so earlier executions stay around as
+// data points instead of being overwritten. the run's own state and times mirror its latest
+// attempt.
+#[derive(Debug, Clone)]
+pub struct Attempt {
+    pub id: u64,
+    pub run_id: u64,
+    /// 1 for a run's first attempt, 2 for its second, and so on.
+    pub attempt: u32,
+    pub host_id: Option<u64>,
+    pub build_token: Option<String>,
+    pub artifacts_path: Option<String>,
+    pub state: RunState,
+    pub start_time: Option<u64>,
+    pub complete_time: Option<u64>,
+    pub build_result: Option<u8>,
+    pub final_text: Option<String>,
+    /// the last time the driver knew this attempt's runner was still there. for runners that send
+    /// heartbeats, this is the last time one was heard from.
+    pub heartbeat_time: Option<u64>,
+    /// if this attempt was abandoned and its run queued again, why.
+    pub requeue_reason: Option<String>,
+    pub outcome: Option<TaskOutcome>,
+}
+
+/// what an attempt's runner said it was doing in its most recent heartbeat. runners that don't send
+/// heartbeats never say, and leave everything but `heartbeat_time` empty.
+#[derive(Debug, Clone)]
+pub struct AttemptProgress {
+    pub attempt_id: u64,
+    pub heartbeat_time: Option<u64>,
+    /// the names of the steps the runner was in, outermost first.
+    pub step: Vec<String>,
+    pub elapsed_ms: Option<u64>,
+    pub load_avg: Option<f64>,
+    pub mem_available_kb: Option<u64>,
 }
 
 #[derive(Debug, Clone)]
@@ -121,6 +591,14 @@ impl TryFrom<u8> for NameState {
     }
 }
 
+impl FromSql for NameState {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        let value: u8 = FromSql::column_result(value)?;
+        NameState::try_from(value)
+            .map_err(|e| FromSqlError::Other(Box::new(InvalidValue(e))))
+    }
+}
+
 pub struct CommitName {
     pub name: String,
     pub state: NameState,
@@ -147,6 +625,10 @@ pub enum RunState {
     Finished = 2,
     Error = 3,
     Invalid = 4,
+    /// the run went past its deadline and was stopped by the driver.
+    TimedOut = 5,
+    /// someone asked for the run to stop. if it had started, the driver stopped it.
+    Cancelled = 6,
 }
 
 impl TryFrom<u8> for RunState {
@@ -159,11 +641,28 @@ impl TryFrom<u8> for RunState {
             2 => Ok(RunState::Finished),
             3 => Ok(RunState::Error),
             4 => Ok(RunState::Invalid),
+            5 => Ok(RunState::TimedOut),
+            6 => Ok(RunState::Cancelled),
             other => Err(format!("invalid job state: {}", other)),
         }
     }
 }
 
+impl FromSql for RunState {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        let value: u8 = FromSql::column_result(value)?;
+        RunState::try_from(value)
+            .map_err(|e| FromSqlError::Other(Box::new(InvalidValue(e))))
+    }
+}
+
+impl FromSql for TaskOutcome {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        TaskOutcome::try_from(value.as_str()?)
+            .map_err(|e| FromSqlError::Other(Box::new(InvalidValue(e))))
+    }
+}
+
 /*
 pub(crate) fn row2run(row: &rusqlite::Row) -> Run {
     let (id, job_id, artifacts_path, state, host_id, build_token, create_time, start_time, complete_time, run_timeout, build_result, final_text) = row.try_into().unwrap();
@@ -185,9 +684,21 @@ pub(crate) fn row2run(row: &rusqlite::Row) -> Run {
 }
 */
 
+// the `CREATE_*` statements below are the schema as of version 1 (see `crate::migrations`). they
+// are applied exactly as written to every database, so changes to the schema belong in a new
+// migration, not here.
+
+pub const CREATE_SCHEMA_VERSION_TABLE: &str = "\
+    CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY,
+        description TEXT,
+        applied_time INTEGER);";
+
+pub const SCHEMA_VERSION: &str = "\
+    select coalesce(max(version), 0) from schema_version;";
+
 // remote_id is the remote from which we were notified. this is necessary so we know which remote
 // to pull from to actually run the job.
-pub const CREATE_JOBS_TABLE: &'static str = "\
+pub const CREATE_JOBS_TABLE: &str = "\
     CREATE TABLE IF NOT EXISTS jobs (id INTEGER PRIMARY KEY AUTOINCREMENT,
         source TEXT,
         created_time INTEGER,
@@ -195,7 +706,7 @@ pub const CREATE_JOBS_TABLE: &'static str = "\
         commit_id INTEGER,
         run_preferences TEXT);";
 
-pub const CREATE_METRICS_TABLE: &'static str = "\
+pub const CREATE_METRICS_TABLE: &str = "\
     CREATE TABLE IF NOT EXISTS metrics (id INTEGER PRIMARY KEY AUTOINCREMENT,
         run_id INTEGER,
         name TEXT,
@@ -203,16 +714,16 @@ pub const CREATE_METRICS_TABLE: &'static str = "\
         UNIQUE(run_id, name)
     );";
 
-pub const CREATE_COMMITS_TABLE: &'static str = "\
+pub const CREATE_COMMITS_TABLE: &str = "\
     CREATE TABLE IF NOT EXISTS commits (id INTEGER PRIMARY KEY AUTOINCREMENT, sha TEXT UNIQUE);";
 
-pub const CREATE_COMMIT_NAMES_TABLE: &'static str = "\
+pub const CREATE_COMMIT_NAMES_TABLE: &str = "\
     CREATE TABLE IF NOT EXISTS commit_names (id INTEGER PRIMARY KEY AUTOINCREMENT, commit_id INTEGER, name TEXT, name_state INTEGER);";
 
-pub const CREATE_COMMIT_NAMES_INDEX: &'static str = "\
+pub const CREATE_COMMIT_NAMES_INDEX: &str = "\
     CREATE INDEX IF NOT EXISTS 'names_by_commit' ON commit_names(commit_id);";
 
-pub const CREATE_REPOS_TABLE: &'static str = "\
+pub const CREATE_REPOS_TABLE: &str = "\
     CREATE TABLE IF NOT EXISTS repos (id INTEGER PRIMARY KEY AUTOINCREMENT,
         repo_name TEXT,
         default_run_preference TEXT);";
@@ -223,7 +734,7 @@ pub const CREATE_REPOS_TABLE: &'static str = "\
 // * for others.. who knows.
 // remote_url is a url for human interaction with the remote (think https://git.iximeow.net/zvm)
 // remote_git_url is a url that can be `git clone`'d to fetch sources
-pub const CREATE_REMOTES_TABLE: &'static str = "\
+pub const CREATE_REMOTES_TABLE: &str = "\
     CREATE TABLE IF NOT EXISTS remotes (id INTEGER PRIMARY KEY AUTOINCREMENT,
         repo_id INTEGER,
         remote_path TEXT,
@@ -232,7 +743,7 @@ pub const CREATE_REMOTES_TABLE: &'static str = "\
         remote_git_url TEXT,
         notifier_config_path TEXT);";
 
-pub const CREATE_ARTIFACTS_TABLE: &'static str = "\
+pub const CREATE_ARTIFACTS_TABLE: &str = "\
     CREATE TABLE IF NOT EXISTS artifacts (id INTEGER PRIMARY KEY AUTOINCREMENT,
         run_id INTEGER,
         name TEXT,
@@ -240,7 +751,7 @@ pub const CREATE_ARTIFACTS_TABLE: &'static str = "\
         created_time INTEGER,
         completed_time INTEGER);";
 
-pub const CREATE_RUNS_TABLE: &'static str = "\
+pub const CREATE_RUNS_TABLE: &str = "\
     CREATE TABLE IF NOT EXISTS runs (id INTEGER PRIMARY KEY AUTOINCREMENT,
         job_id INTEGER,
         artifacts_path TEXT,
@@ -255,7 +766,7 @@ pub const CREATE_RUNS_TABLE: &'static str = "\
         build_result INTEGER,
         final_status TEXT);";
 
-pub const CREATE_HOSTS_TABLE: &'static str = "\
+pub const CREATE_HOSTS_TABLE: &str = "\
     CREATE TABLE IF NOT EXISTS hosts (id INTEGER PRIMARY KEY AUTOINCREMENT,
         hostname TEXT,
         cpu_vendor_id TEXT,
@@ -271,22 +782,494 @@ pub const CREATE_HOSTS_TABLE: &'static str = "\
         os TEXT,
         UNIQUE(hostname, cpu_vendor_id, cpu_model_name, cpu_family, cpu_model, cpu_microcode, cpu_cores, mem_total, arch, family, os));";
 
-pub const CREATE_REMOTES_INDEX: &'static str = "\
+pub const CREATE_REMOTES_INDEX: &str = "\
     CREATE INDEX IF NOT EXISTS 'repo_to_remote' ON remotes(repo_id);";
 
-pub const CREATE_REPO_NAME_INDEX: &'static str = "\
+pub const CREATE_REPO_NAME_INDEX: &str = "\
     CREATE UNIQUE INDEX IF NOT EXISTS 'repo_names' ON repos(repo_name);";
 
-pub const PENDING_RUNS: &'static str = "\
+pub const CREATE_RETENTION_POLICIES_TABLE: &str = "\
+    CREATE TABLE IF NOT EXISTS retention_policies (repo_id INTEGER PRIMARY KEY,
+        log_days INTEGER,
+        metric_days INTEGER,
+        keep_commits INTEGER);";
+
+// baseline runs are reference points for comparison, and are never garbage collected.
+pub const ADD_RUNS_BASELINE: &str = "\
+    ALTER TABLE runs ADD COLUMN baseline INTEGER NOT NULL DEFAULT 0;";
+
+// parents are stored by sha, not commit id: most commits we learn parents for are never the tip
+// of a push, so they never get a row in `commits`, but history still has to pass through them.
+// parent_index is the parent's position in the commit; 0 is the first parent.
+pub const CREATE_COMMIT_PARENTS_TABLE: &str = "\
+    CREATE TABLE IF NOT EXISTS commit_parents (sha TEXT NOT NULL,
+        parent_sha TEXT NOT NULL,
+        parent_index INTEGER NOT NULL,
+        PRIMARY KEY (sha, parent_index));";
+
+pub const CREATE_COMMIT_CHILDREN_INDEX: &str = "\
+    CREATE INDEX IF NOT EXISTS 'children_by_parent' ON commit_parents(parent_sha);";
+
+// ancestry is kept per repo: forks and mirrors share shas, but not necessarily each other's
+// guesses at what their parents are.
+pub const CREATE_REPO_COMMIT_PARENTS_TABLE: &str = "\
+    CREATE TABLE IF NOT EXISTS repo_commit_parents (repo_id INTEGER NOT NULL,
+        sha TEXT NOT NULL,
+        parent_sha TEXT NOT NULL,
+        parent_index INTEGER NOT NULL,
+        PRIMARY KEY (repo_id, sha, parent_index));";
+
+// parents recorded before they were per repo go to every repo with a commit they're in the
+// history of. parents of commits no repo has ever built are dropped; no query could reach them.
+pub const BACKFILL_REPO_COMMIT_PARENTS: &str = "\
+    WITH RECURSIVE reachable(repo_id, sha) AS ( \
+        SELECT repo_id, sha FROM commits \
+        UNION \
+        SELECT reachable.repo_id, commit_parents.parent_sha FROM reachable \
+        JOIN commit_parents ON commit_parents.sha=reachable.sha \
+    ) \
+    INSERT OR IGNORE INTO repo_commit_parents (repo_id, sha, parent_sha, parent_index) \
+    SELECT reachable.repo_id, commit_parents.sha, commit_parents.parent_sha, commit_parents.parent_index \
+    FROM reachable JOIN commit_parents ON commit_parents.sha=reachable.sha;";
+
+pub const DROP_COMMIT_PARENTS: &str = "\
+    DROP TABLE commit_parents;";
+
+pub const RENAME_REPO_COMMIT_PARENTS: &str = "\
+    ALTER TABLE repo_commit_parents RENAME TO commit_parents;";
+
+pub const CREATE_REPO_COMMIT_CHILDREN_INDEX: &str = "\
+    CREATE INDEX IF NOT EXISTS 'children_by_parent' ON commit_parents(repo_id, parent_sha);";
+
+// refs are the branches and tags of a remote, as of the last push we were told about.
+// ref_name is the full ref: `refs/heads/main`, `refs/tags/v1.0`, and so on.
+pub const CREATE_REFS_TABLE: &str = "\
+    CREATE TABLE IF NOT EXISTS refs (remote_id INTEGER NOT NULL,
+        ref_name TEXT NOT NULL,
+        sha TEXT NOT NULL,
+        updated_time INTEGER NOT NULL,
+        PRIMARY KEY (remote_id, ref_name));";
+
+pub const CREATE_REFS_SHA_INDEX: &str = "\
+    CREATE INDEX IF NOT EXISTS 'refs_by_sha' ON refs(sha);";
+
+// every sha a ref has ever pointed to, in the order we learned about them. this is what lets a
+// commit a branch has moved on from still be shown as "was main".
+pub const CREATE_REF_LOG_TABLE: &str = "\
+    CREATE TABLE IF NOT EXISTS ref_log (id INTEGER PRIMARY KEY AUTOINCREMENT,
+        remote_id INTEGER NOT NULL,
+        ref_name TEXT NOT NULL,
+        sha TEXT NOT NULL,
+        updated_time INTEGER NOT NULL);";
+
+pub const CREATE_REF_LOG_SHA_INDEX: &str = "\
+    CREATE INDEX IF NOT EXISTS 'ref_log_by_sha' ON ref_log(sha);";
+
+// commit_names only stored short names for branches (`main`, not `refs/heads/main`), and
+// full names for everything else. its rows are the closest thing we have to a history of refs, and
+// the newest row for each name is the best guess at where that name points now.
+pub const BACKFILL_REF_LOG: &str = "\
+    insert into ref_log (remote_id, ref_name, sha, updated_time) \
+    select jobs.remote_id, \
+        case when commit_names.name like 'refs/%' then commit_names.name else 'refs/heads/' || commit_names.name end, \
+        commits.sha, \
+        jobs.created_time \
+    from commit_names \
+    join commits on commits.id=commit_names.commit_id \
+    join jobs on jobs.commit_id=commits.id \
+    order by commit_names.id asc;";
+
+pub const BACKFILL_REFS: &str = "\
+    insert or replace into refs (remote_id, ref_name, sha, updated_time) \
+    select remote_id, ref_name, sha, updated_time from ref_log \
+    where id in (select max(id) from ref_log group by remote_id, ref_name);";
+
+pub const DROP_COMMIT_NAMES: &str = "\
+    DROP TABLE IF EXISTS commit_names;";
+
+// the same sha can be pushed to unrelated repos (forks, mirrors under a different name, a
+// vendored copy), and each should get its own commit and jobs. sqlite can't drop the old unique
+// constraint on `sha` in place, so `commits` is rebuilt. ids are kept as they were, so
+// `jobs.commit_id` is still correct after the rebuild.
+pub const CREATE_REPO_COMMITS_TABLE: &str = "\
+    CREATE TABLE IF NOT EXISTS repo_commits (id INTEGER PRIMARY KEY AUTOINCREMENT,
+        repo_id INTEGER,
+        sha TEXT,
+        UNIQUE(repo_id, sha));";
+
+// until now a commit could only have had jobs from one repo, so the first job's remote settles
+// which repo each commit belongs to.
+pub const BACKFILL_REPO_COMMITS: &str = "\
+    insert into repo_commits (id, repo_id, sha) \
+    select commits.id, \
+        (select remotes.repo_id from jobs \
+            join remotes on remotes.id=jobs.remote_id \
+            where jobs.commit_id=commits.id \
+            order by jobs.id asc limit 1), \
+        commits.sha \
+    from commits;";
+
+pub const DROP_COMMITS: &str = "\
+    DROP TABLE commits;";
+
+pub const RENAME_REPO_COMMITS: &str = "\
+    ALTER TABLE repo_commits RENAME TO commits;";
+
+pub const CREATE_COMMITS_SHA_INDEX: &str = "\
+    CREATE INDEX IF NOT EXISTS 'commits_by_sha' ON commits(sha);";
+
+pub const CREATE_ATTEMPTS_TABLE: &str = "\
+    CREATE TABLE IF NOT EXISTS attempts (id INTEGER PRIMARY KEY AUTOINCREMENT,
+        run_id INTEGER NOT NULL,
+        attempt INTEGER NOT NULL,
+        host_id INTEGER,
+        build_token TEXT,
+        artifacts_path TEXT,
+        state INTEGER NOT NULL,
+        started_time INTEGER,
+        complete_time INTEGER,
+        build_result INTEGER,
+        final_status TEXT,
+        UNIQUE(run_id, attempt));";
+
+pub const CREATE_ATTEMPTS_TOKEN_INDEX: &str = "\
+    CREATE INDEX IF NOT EXISTS 'attempts_by_token' ON attempts(build_token);";
+
+// every run that was ever started has been attempted exactly once, as far as anyone can tell: a
+// restart overwrote the run in place.
+pub const BACKFILL_ATTEMPTS: &str = "\
+    insert into attempts (run_id, attempt, host_id, build_token, artifacts_path, state, started_time, complete_time, build_result, final_status) \
+    select id, 1, host_id, build_token, artifacts_path, state, started_time, complete_time, build_result, final_status \
+    from runs where started_time is not null;";
+
+pub const ADD_ARTIFACTS_ATTEMPT: &str = "\
+    ALTER TABLE artifacts ADD COLUMN attempt_id INTEGER;";
+
+pub const BACKFILL_ARTIFACTS_ATTEMPT: &str = "\
+    update artifacts set attempt_id=(select attempts.id from attempts where attempts.run_id=artifacts.run_id);";
+
+pub const CREATE_ARTIFACTS_ATTEMPT_INDEX: &str = "\
+    CREATE INDEX IF NOT EXISTS 'artifacts_by_attempt' ON artifacts(attempt_id);";
+
+// metrics were unique per (run, name), so a retried run overwrote its earlier measurements. they
+// are unique per (attempt, name) now, which needs the table rebuilt to drop the old constraint.
+pub const CREATE_ATTEMPT_METRICS_TABLE: &str = "\
+    CREATE TABLE IF NOT EXISTS attempt_metrics (id INTEGER PRIMARY KEY AUTOINCREMENT,
+        run_id INTEGER,
+        attempt_id INTEGER,
+        name TEXT,
+        value TEXT,
+        UNIQUE(attempt_id, name)
+    );";
+
+pub const BACKFILL_ATTEMPT_METRICS: &str = "\
+    insert into attempt_metrics (id, run_id, attempt_id, name, value) \
+    select metrics.id, metrics.run_id, \
+        (select attempts.id from attempts where attempts.run_id=metrics.run_id), \
+        metrics.name, metrics.value \
+    from metrics;";
+
+pub const DROP_METRICS: &str = "\
+    DROP TABLE metrics;";
+
+pub const RENAME_ATTEMPT_METRICS: &str = "\
+    ALTER TABLE attempt_metrics RENAME TO metrics;";
+
+pub const CREATE_METRICS_RUN_INDEX: &str = "\
+    CREATE INDEX IF NOT EXISTS 'metrics_by_run' ON metrics(run_id);";
+
+// `value` is still the text of every metric, numeric or not, so anything that only knows about
+// `value` keeps working. numeric metrics additionally have `numeric_value`, and may have a unit
+// and a direction (`lower` or `higher` is better).
+pub const ADD_METRICS_NUMERIC_VALUE: &str = "\
+    ALTER TABLE metrics ADD COLUMN numeric_value REAL;";
+
+pub const ADD_METRICS_UNIT: &str = "\
+    ALTER TABLE metrics ADD COLUMN unit TEXT;";
+
+pub const ADD_METRICS_DIRECTION: &str = "\
+    ALTER TABLE metrics ADD COLUMN direction TEXT;";
+
+// goodfiles could only report strings before, but plenty of those strings were numbers. without
+// a unit they can at least be compared with each other.
+pub const BACKFILL_METRICS_NUMERIC_VALUE: &str = "\
+    update metrics set numeric_value=cast(value as real) \
+    where value glob '[0-9]*' and value not glob '*[^0-9.]*' and value not glob '*.*.*';";
+
+pub const CREATE_METRIC_POLICIES_TABLE: &str = "\
+    CREATE TABLE IF NOT EXISTS metric_policies (repo_id INTEGER PRIMARY KEY,
+        threshold REAL NOT NULL,
+        window INTEGER NOT NULL,
+        noise_sigmas REAL);";
+
+pub const CREATE_METRIC_FINDINGS_TABLE: &str = "\
+    CREATE TABLE IF NOT EXISTS metric_findings (id INTEGER PRIMARY KEY AUTOINCREMENT,
+        run_id INTEGER NOT NULL,
+        attempt_id INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        unit TEXT,
+        value REAL NOT NULL,
+        baseline REAL NOT NULL,
+        baseline_samples INTEGER NOT NULL,
+        change REAL NOT NULL,
+        relative REAL,
+        improved INTEGER,
+        created_time INTEGER NOT NULL,
+        UNIQUE(attempt_id, name));";
+
+pub const CREATE_METRIC_FINDINGS_RUN_INDEX: &str = "\
+    CREATE INDEX IF NOT EXISTS 'metric_findings_by_run' ON metric_findings(run_id);";
+
+// hosts get an operator-assigned name, an enabled flag, and can be retired. none of these are
+// part of the hardware fingerprint `hosts` is unique on.
+pub const ADD_HOSTS_NAME: &str = "\
+    ALTER TABLE hosts ADD COLUMN name TEXT;";
+
+pub const CREATE_HOSTS_NAME_INDEX: &str = "\
+    CREATE UNIQUE INDEX IF NOT EXISTS 'host_names' ON hosts(name);";
+
+pub const ADD_HOSTS_ENABLED: &str = "\
+    ALTER TABLE hosts ADD COLUMN enabled INTEGER NOT NULL DEFAULT 1;";
+
+pub const ADD_HOSTS_RETIRED_TIME: &str = "\
+    ALTER TABLE hosts ADD COLUMN retired_time INTEGER;";
+
+pub const CREATE_HOST_LABELS_TABLE: &str = "\
+    CREATE TABLE IF NOT EXISTS host_labels (host_id INTEGER NOT NULL,
+        label TEXT NOT NULL,
+        PRIMARY KEY(host_id, label));";
+
+pub const CREATE_HOST_LABELS_LABEL_INDEX: &str = "\
+    CREATE INDEX IF NOT EXISTS 'hosts_by_label' ON host_labels(label);";
+
+// hosts are no longer unique on their hardware fingerprint: a runner with an identity stays the
+// same host however its hardware changes, and the history of that hardware lives in host_facts.
+pub const CREATE_RUNNER_HOSTS_TABLE: &str = "\
+    CREATE TABLE IF NOT EXISTS runner_hosts (id INTEGER PRIMARY KEY AUTOINCREMENT,
+        runner_id TEXT,
+        name TEXT,
+        enabled INTEGER NOT NULL DEFAULT 1,
+        retired_time INTEGER,
+        hostname TEXT,
+        cpu_vendor_id TEXT,
+        cpu_model_name TEXT,
+        cpu_family TEXT,
+        cpu_model TEXT,
+        cpu_microcode TEXT,
+        cpu_max_freq_khz INTEGER,
+        cpu_cores INTEGER,
+        mem_total TEXT,
+        arch TEXT,
+        family TEXT,
+        os TEXT);";
+
+pub const BACKFILL_RUNNER_HOSTS: &str = "\
+    INSERT INTO runner_hosts (id, runner_id, name, enabled, retired_time, hostname, cpu_vendor_id, \
+        cpu_model_name, cpu_family, cpu_model, cpu_microcode, cpu_max_freq_khz, cpu_cores, mem_total, \
+        arch, family, os) \
+    SELECT id, null, name, enabled, retired_time, hostname, cpu_vendor_id, \
+        cpu_model_name, cpu_family, cpu_model, cpu_microcode, cpu_max_freq_khz, cpu_cores, mem_total, \
+        arch, family, os \
+    FROM hosts;";
+
+pub const CREATE_HOST_FACTS_TABLE: &str = "\
+    CREATE TABLE IF NOT EXISTS host_facts (id INTEGER PRIMARY KEY AUTOINCREMENT,
+        host_id INTEGER NOT NULL,
+        version INTEGER NOT NULL,
+        hostname TEXT,
+        cpu_vendor_id TEXT,
+        cpu_model_name TEXT,
+        cpu_family TEXT,
+        cpu_model TEXT,
+        cpu_microcode TEXT,
+        cpu_max_freq_khz INTEGER,
+        cpu_cores INTEGER,
+        mem_total TEXT,
+        arch TEXT,
+        family TEXT,
+        os TEXT,
+        first_seen INTEGER,
+        last_seen INTEGER,
+        UNIQUE(host_id, version));";
+
+// each existing host gets its fingerprint as its first version of facts, seen over the span of
+// the runs it started.
+pub const BACKFILL_HOST_FACTS: &str = "\
+    INSERT INTO host_facts (host_id, version, hostname, cpu_vendor_id, cpu_model_name, cpu_family, \
+        cpu_model, cpu_microcode, cpu_max_freq_khz, cpu_cores, mem_total, arch, family, os, \
+        first_seen, last_seen) \
+    SELECT hosts.id, 1, hostname, cpu_vendor_id, cpu_model_name, cpu_family, \
+        cpu_model, cpu_microcode, cpu_max_freq_khz, cpu_cores, mem_total, arch, family, os, \
+        (select min(started_time) from runs where runs.host_id=hosts.id), \
+        (select max(started_time) from runs where runs.host_id=hosts.id) \
+    FROM hosts;";
+
+pub const DROP_HOSTS: &str = "\
+    DROP TABLE hosts;";
+
+pub const RENAME_RUNNER_HOSTS: &str = "\
+    ALTER TABLE runner_hosts RENAME TO hosts;";
+
+pub const CREATE_HOSTS_RUNNER_ID_INDEX: &str = "\
+    CREATE UNIQUE INDEX IF NOT EXISTS 'host_runner_ids' ON hosts(runner_id);";
+
+pub const CREATE_HOSTS_HOSTNAME_INDEX: &str = "\
+    CREATE INDEX IF NOT EXISTS 'hosts_by_hostname' ON hosts(hostname);";
+
+// tokens are only stored as the hex sha256 of the token.
+pub const CREATE_RUNNER_CREDENTIALS_TABLE: &str = "\
+    CREATE TABLE IF NOT EXISTS runner_credentials (id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL,
+        token_hash TEXT NOT NULL,
+        created_time INTEGER NOT NULL,
+        expires_time INTEGER,
+        revoked_time INTEGER,
+        last_used_time INTEGER,
+        UNIQUE(name),
+        UNIQUE(token_hash));";
+
+// the credential a host's runner authenticates with. a credential authenticates at most one host.
+pub const ADD_HOSTS_CREDENTIAL_ID: &str = "\
+    ALTER TABLE hosts ADD COLUMN credential_id INTEGER;";
+
+pub const CREATE_HOSTS_CREDENTIAL_INDEX: &str = "\
+    CREATE UNIQUE INDEX IF NOT EXISTS 'hosts_by_credential' ON hosts(credential_id);";
+
+// argv and step are json arrays of strings.
+pub const CREATE_COMMANDS_TABLE: &str = "\
+    CREATE TABLE IF NOT EXISTS commands (id INTEGER PRIMARY KEY AUTOINCREMENT,
+        run_id INTEGER NOT NULL,
+        attempt_id INTEGER NOT NULL,
+        command_id INTEGER NOT NULL,
+        argv TEXT NOT NULL,
+        cwd TEXT,
+        step TEXT NOT NULL,
+        start_time INTEGER NOT NULL,
+        end_time INTEGER,
+        exit_code INTEGER,
+        stdout_artifact_id INTEGER,
+        stderr_artifact_id INTEGER,
+        UNIQUE(attempt_id, command_id));";
+
+pub const CREATE_COMMANDS_RUN_INDEX: &str = "\
+    CREATE INDEX IF NOT EXISTS 'commands_by_run' ON commands(run_id);";
+
+pub const ADD_REPOS_DEFAULT_RUN_TIMEOUT: &str = "\
+    ALTER TABLE repos ADD COLUMN default_run_timeout INTEGER;";
+
+// set when a started run is cancelled. the driver running it notices and stops the runner.
+pub const ADD_RUNS_CANCEL_REQUESTED_TIME: &str = "\
+    ALTER TABLE runs ADD COLUMN cancel_requested_time INTEGER;";
+
+pub const ADD_ATTEMPTS_HEARTBEAT_TIME: &str = "\
+    ALTER TABLE attempts ADD COLUMN heartbeat_time INTEGER;";
+
+pub const ADD_ATTEMPTS_REQUEUE_REASON: &str = "\
+    ALTER TABLE attempts ADD COLUMN requeue_reason TEXT;";
+
+pub const CREATE_ATTEMPTS_STATE_INDEX: &str = "\
+    CREATE INDEX IF NOT EXISTS 'attempts_by_state' ON attempts(state);";
+
+// the contents of the last `Heartbeat` a runner sent for an attempt. steps are a json list of
+// step names, like `commands.step`.
+pub const ADD_ATTEMPTS_HEARTBEAT_STEP: &str = "\
+    ALTER TABLE attempts ADD COLUMN heartbeat_step TEXT;";
+
+pub const ADD_ATTEMPTS_HEARTBEAT_ELAPSED: &str = "\
+    ALTER TABLE attempts ADD COLUMN heartbeat_elapsed_ms INTEGER;";
+
+pub const ADD_ATTEMPTS_HEARTBEAT_LOAD_AVG: &str = "\
+    ALTER TABLE attempts ADD COLUMN heartbeat_load_avg REAL;";
+
+pub const ADD_ATTEMPTS_HEARTBEAT_MEM_AVAILABLE: &str = "\
+    ALTER TABLE attempts ADD COLUMN heartbeat_mem_available_kb INTEGER;";
+
+// outcomes are `TaskOutcome::as_str`.
+pub const ADD_RUNS_OUTCOME: &str = "\
+    ALTER TABLE runs ADD COLUMN outcome TEXT;";
+
+pub const ADD_ATTEMPTS_OUTCOME: &str = "\
+    ALTER TABLE attempts ADD COLUMN outcome TEXT;";
+
+// before outcomes, every failure was an error with whatever status the runner gave. only some of
+// those statuses say what kind of failure it was; the rest are left without an outcome.
+pub const BACKFILL_RUNS_OUTCOME: &str = "\
+    UPDATE runs SET outcome=CASE \
+        WHEN state=2 AND build_result=0 THEN 'passed' \
+        WHEN state=5 THEN 'timed_out' \
+        WHEN state=6 THEN 'cancelled' \
+        WHEN final_status='bad_ref' THEN 'checkout_failed' \
+        WHEN final_status='lost signal' THEN 'infra_error' \
+        ELSE NULL END;";
+
+pub const BACKFILL_ATTEMPTS_OUTCOME: &str = "\
+    UPDATE attempts SET outcome=CASE \
+        WHEN state=2 AND build_result=0 THEN 'passed' \
+        WHEN state=5 THEN 'timed_out' \
+        WHEN state=6 THEN 'cancelled' \
+        WHEN final_status='bad_ref' THEN 'checkout_failed' \
+        WHEN final_status='lost signal' THEN 'infra_error' \
+        ELSE NULL END;";
+
+// every line of every stdout and stderr artifact. `log_search` indexes these by content, and pages
+// show a match's neighbors by line number.
+pub const CREATE_LOG_LINES_TABLE: &str = "\
+    CREATE TABLE IF NOT EXISTS log_lines (id INTEGER PRIMARY KEY AUTOINCREMENT,
+        artifact_id INTEGER NOT NULL,
+        line_number INTEGER NOT NULL,
+        line TEXT NOT NULL);";
+
+pub const CREATE_LOG_LINES_ARTIFACT_INDEX: &str = "\
+    CREATE INDEX IF NOT EXISTS 'log_lines_by_artifact' ON log_lines(artifact_id, line_number);";
+
+// an external-content index: the text lives only in `log_lines`, and the triggers below keep the
+// index in step with it.
+pub const CREATE_LOG_SEARCH_TABLE: &str = "\
+    CREATE VIRTUAL TABLE IF NOT EXISTS log_search USING fts5(line, content='log_lines', content_rowid='id');";
+
+pub const CREATE_LOG_LINES_INSERT_TRIGGER: &str = "\
+    CREATE TRIGGER IF NOT EXISTS log_lines_insert AFTER INSERT ON log_lines BEGIN
+        INSERT INTO log_search (rowid, line) VALUES (new.id, new.line);
+    END;";
+
+pub const CREATE_LOG_LINES_DELETE_TRIGGER: &str = "\
+    CREATE TRIGGER IF NOT EXISTS log_lines_delete AFTER DELETE ON log_lines BEGIN
+        INSERT INTO log_search (log_search, rowid, line) VALUES ('delete', old.id, old.line);
+    END;";
+
+// oldest logs first: the usual question is when something started happening.
+pub const SEARCH_LOGS: &str = "\
+    select artifacts.run_id, artifacts.attempt_id, artifacts.id, artifacts.name, repos.repo_name, remotes.remote_path, commits.sha, attempts.host_id, artifacts.created_time, log_lines.line_number \
+    from log_search \
+    join log_lines on log_lines.id=log_search.rowid \
+    join artifacts on artifacts.id=log_lines.artifact_id \
+    join attempts on attempts.id=artifacts.attempt_id \
+    join runs on runs.id=artifacts.run_id \
+    join jobs on jobs.id=runs.job_id \
+    join commits on commits.id=jobs.commit_id \
+    join remotes on remotes.id=jobs.remote_id \
+    join repos on repos.id=remotes.repo_id \
+    where log_search match ?1 \
+        and (?2 is null or repos.repo_name=?2) \
+        and (?3 is null or attempts.host_id=?3) \
+        and (?4 is null or artifacts.created_time>=?4) \
+        and (?5 is null or artifacts.created_time<?5) \
+    order by artifacts.created_time asc, log_lines.artifact_id asc, log_lines.line_number asc \
+    limit ?6;";
+
+pub const LOG_LINES_BETWEEN: &str = "\
+    select line_number, line from log_lines where artifact_id=?1 and line_number>=?2 and line_number<=?3 order by line_number asc;";
+
+pub const PENDING_RUNS: &str = "\
     select id, job_id, created_time, host_preference from runs where state=0 and (host_preference=?1 or host_preference is null) order by created_time desc;";
 
-pub const JOBS_NEEDING_HOST_RUN: &'static str = "\
+pub const JOBS_NEEDING_HOST_RUN: &str = "\
     select jobs.id, jobs.source, jobs.created_time, jobs.remote_id, jobs.commit_id, jobs.run_preferences from jobs \
     where jobs.run_preferences=\"all\" and jobs.created_time > ?1 \
     and not exists \
         (select 1 from runs r2 where r2.job_id = jobs.id and r2.host_id = ?2);";
 
-pub const ACTIVE_RUNS: &'static str = "\
+pub const ACTIVE_RUNS: &str = "\
     select id,
         job_id,
         artifacts_path,
@@ -298,45 +1281,76 @@ pub const ACTIVE_RUNS: &'static str = "\
         complete_time,
         run_timeout,
         build_result,
-        final_status from runs where state=1 or state=0;";
+        final_status,
+        outcome from runs where state=1 or state=0;";
+
+pub const LAST_ARTIFACTS_FOR_RUN: &str = "\
+    select id, run_id, attempt_id, name, desc, created_time, completed_time from artifacts where run_id=?1 and (name like \"%(stderr)%\" or name like \"%(stdout)%\") order by id desc limit ?2;";
+
+// logs that have been completely written, and so can be indexed for search.
+pub const COMPLETED_LOG_ARTIFACTS: &str = "\
+    select id, run_id, attempt_id, name, desc, created_time, completed_time from artifacts where completed_time is not null and (name like \"%(stderr)%\" or name like \"%(stdout)%\") order by id asc;";
 
-pub const LAST_ARTIFACTS_FOR_RUN: &'static str = "\
-    select * from artifacts where run_id=?1 and (name like \"%(stderr)%\" or name like \"%(stdout)%\") order by id desc limit ?2;";
+pub const ARTIFACTS_FOR_ATTEMPT: &str = "\
+    select id, run_id, attempt_id, name, desc, created_time, completed_time from artifacts where attempt_id=?1 order by id desc limit ?2;";
 
-pub const JOB_BY_COMMIT_ID: &'static str = "\
+pub const JOB_BY_COMMIT_ID: &str = "\
     select id, source, created_time, remote_id, commit_id, run_preferences from jobs where commit_id=?1;";
 
-pub const ARTIFACT_BY_ID: &'static str = "\
-    select * from artifacts where id=?1 and run_id=?2;";
+pub const ARTIFACT_BY_ID: &str = "\
+    select id, run_id, attempt_id, name, desc, created_time, completed_time from artifacts where id=?1 and run_id=?2;";
 
-pub const JOB_BY_ID: &'static str = "\
+pub const JOB_BY_ID: &str = "\
     select id, source, created_time, remote_id, commit_id, run_preferences from jobs where id=?1";
 
-pub const NAMES_FOR_COMMIT: &'static str = "\
-    select id, name, name_state from commit_names where commit_id=?1 order by id asc;";
+// the name a commit is currently known by, if any ref on one of its repo's remotes points at it.
+// most recently updated wins.
+pub const CURRENT_REF_FOR_COMMIT: &str = "\
+    select refs.ref_name from refs \
+    join remotes on remotes.id=refs.remote_id \
+    join commits on commits.sha=refs.sha and commits.repo_id=remotes.repo_id \
+    where commits.id=?1 \
+    order by refs.updated_time desc limit 1;";
 
-pub const METRICS_FOR_RUN: &'static str = "\
-    select * from metrics where run_id=?1 order by id asc;";
+// the name a commit was most recently known by, for commits no ref points at any more.
+pub const PAST_REF_FOR_COMMIT: &str = "\
+    select ref_log.ref_name from ref_log \
+    join remotes on remotes.id=ref_log.remote_id \
+    join commits on commits.sha=ref_log.sha and commits.repo_id=remotes.repo_id \
+    where commits.id=?1 \
+    order by ref_log.id desc limit 1;";
 
-pub const METRICS_FOR_JOB: &'static str = "\
-    select metrics.id, metrics.run_id, metrics.name, metrics.value from metrics \
+pub const METRICS_FOR_RUN: &str = "\
+    select id, run_id, attempt_id, name, value, numeric_value, unit, direction from metrics where run_id=?1 order by id asc;";
+
+pub const METRICS_FOR_ATTEMPT: &str = "\
+    select id, run_id, attempt_id, name, value, numeric_value, unit, direction from metrics where attempt_id=?1 order by id asc;";
+
+pub const METRICS_FOR_JOB: &str = "\
+    select metrics.id, metrics.run_id, metrics.attempt_id, metrics.name, metrics.value, metrics.numeric_value, metrics.unit, metrics.direction from metrics \
     join runs on runs.id=metrics.run_id \
     where runs.job_id=?1 \
     order by metrics.run_id desc, metrics.id desc;";
 
-pub const COMMIT_TO_ID: &'static str = "\
-    select id from commits where sha=?1;";
+pub const COMMIT_TO_ID: &str = "\
+    select id from commits where repo_id=?1 and sha=?2;";
 
-pub const REMOTES_FOR_REPO: &'static str = "\
+pub const JOBS_FOR_SHA: &str = "\
+    select jobs.id, jobs.source, jobs.created_time, jobs.remote_id, jobs.commit_id, jobs.run_preferences from jobs \
+    join commits on commits.id=jobs.commit_id \
+    where commits.sha=?1 \
+    order by jobs.id asc;";
+
+pub const REMOTES_FOR_REPO: &str = "\
     select * from remotes where repo_id=?1;";
 
-pub const ALL_REPOS: &'static str = "\
-    select id, repo_name, default_run_preference from repos;";
+pub const ALL_REPOS: &str = "\
+    select id, repo_name, default_run_preference, default_run_timeout from repos;";
 
-pub const LAST_JOBS_FROM_REMOTE: &'static str = "\
+pub const LAST_JOBS_FROM_REMOTE: &str = "\
     select id, source, created_time, remote_id, commit_id, run_preferences from jobs where remote_id=?1 order by created_time desc limit ?2;";
 
-pub const LAST_RUN_FOR_JOB: &'static str = "\
+pub const LAST_RUN_FOR_JOB: &str = "\
     select id,
         job_id,
         artifacts_path,
@@ -348,7 +1362,8 @@ pub const LAST_RUN_FOR_JOB: &'static str = "\
         complete_time,
         run_timeout,
         build_result,
-        final_status from runs where job_id=?1 order by started_time desc limit 1;";
+        final_status,
+        outcome from runs where job_id=?1 order by started_time desc limit 1;";
 
 // HELLO READER, I DO NOT UNDERSTAND SQL WELL ENOUGH, THIS MAY NOT WORK CORRECTLY!
 // the intent of this query is to select one run per host that has run a job. which makes for an
@@ -379,10 +1394,10 @@ pub const LAST_RUN_FOR_JOB: &'static str = "\
 //  query this more portable form that is more obviously correct: only select aggregations or
 //  non-aggregations that are part of `group by`. so only select `id` and `host_id`, subsequent
 //  fields for each row have to be selected later on-demand.
-pub const RUNS_FOR_JOB: &'static str = "\
+pub const RUNS_FOR_JOB: &str = "\
     select max(id) from runs where job_id=?1 group by host_id;";
 
-pub const RUN_TO_FIELDS: &'static str = "\
+pub const RUN_TO_FIELDS: &str = "\
     select id,
         job_id,
         artifacts_path,
@@ -394,9 +1409,328 @@ pub const RUN_TO_FIELDS: &'static str = "\
         complete_time,
         run_timeout,
         build_result,
-        final_status from runs where id=?1;";
+        final_status,
+        outcome from runs where id=?1;";
+
+pub const ALL_RUNS: &str = "\
+    select id,
+        job_id,
+        artifacts_path,
+        state,
+        host_id,
+        build_token,
+        created_time,
+        started_time,
+        complete_time,
+        run_timeout,
+        build_result,
+        final_status,
+        outcome from runs order by created_time asc;";
+
+pub const RETENTION_POLICY_FOR_REPO: &str = "\
+    select repo_id, log_days, metric_days, keep_commits from retention_policies where repo_id=?1;";
+
+pub const SET_RETENTION_POLICY: &str = "\
+    insert into retention_policies (repo_id, log_days, metric_days, keep_commits) values (?1, ?2, ?3, ?4) \
+    on conflict (repo_id) do update set \
+        log_days=excluded.log_days, metric_days=excluded.metric_days, keep_commits=excluded.keep_commits;";
+
+// finished runs with artifacts or metrics older than their repo's retention policy allows.
+//
+// a run is kept regardless of age if it is a baseline, or if its commit is one of the
+// `keep_commits` most recent commits some ref (branch) in the repo has pointed to. "most recent"
+// is by when the ref was first moved to the commit. a ref can point to the same commit many times
+// over (force pushes back and forth), so ref history is deduplicated before ranking.
+//
+// runs that are pending or started are never expired: their artifacts are still being written.
+pub const RUNS_PAST_RETENTION: &str = "\
+    with named_commits as ( \
+        select remotes.repo_id, ref_log.ref_name, commits.id as commit_id, min(ref_log.updated_time) as created_time \
+        from ref_log \
+        join remotes on remotes.id=ref_log.remote_id \
+        join commits on commits.sha=ref_log.sha and commits.repo_id=remotes.repo_id \
+        group by remotes.repo_id, ref_log.ref_name, commits.id \
+    ), ranked_commits as ( \
+        select repo_id, commit_id, \
+            row_number() over (partition by repo_id, ref_name order by created_time desc) as recency \
+        from named_commits \
+    ), candidates as ( \
+        select runs.id as run_id, \
+            (retention_policies.log_days is not null and runs.created_time < ?1 - retention_policies.log_days * 86400000) as logs_expired, \
+            (retention_policies.metric_days is not null and runs.created_time < ?1 - retention_policies.metric_days * 86400000) as metrics_expired \
+        from runs \
+        join jobs on jobs.id=runs.job_id \
+        join remotes on remotes.id=jobs.remote_id \
+        join retention_policies on retention_policies.repo_id=remotes.repo_id \
+        where runs.baseline=0 and runs.state not in (0, 1) \
+        and not exists ( \
+            select 1 from ranked_commits \
+            where ranked_commits.repo_id=remotes.repo_id and ranked_commits.commit_id=jobs.commit_id \
+            and ranked_commits.recency <= coalesce(retention_policies.keep_commits, 0) \
+        ) \
+    ) \
+    select run_id, logs_expired, metrics_expired from candidates \
+    where (logs_expired and exists (select 1 from artifacts where artifacts.run_id=candidates.run_id)) \
+    or (metrics_expired and exists (select 1 from metrics where metrics.run_id=candidates.run_id));";
+
+pub const PARENTS_OF_COMMIT: &str = "\
+    select parent_sha from commit_parents where repo_id=?1 and sha=?2 order by parent_index asc;";
+
+// walk first parents in repo ?3 back from ?1, yielding at most ?2 shas, starting with ?1 itself.
+pub const FIRST_PARENT_HISTORY: &str = "\
+    with recursive history(sha, depth) as ( \
+        select ?1, 0 \
+        union all \
+        select commit_parents.parent_sha, history.depth + 1 from history \
+        join commit_parents on commit_parents.repo_id=?3 and commit_parents.sha=history.sha and commit_parents.parent_index=0 \
+        where history.depth + 1 < ?2 \
+    ) \
+    select sha from history order by depth asc;";
+
+// the closest strict ancestor of ?1, through any parent, with a finished run on host ?2 for repo
+// ?4. ancestors more than ?3 commits away aren't considered, so a host that's never built this
+// repo doesn't walk all of history.
+pub const NEAREST_ANCESTOR_WITH_RUN: &str = "\
+    with recursive ancestors(sha, depth) as ( \
+        select ?1, 0 \
+        union \
+        select commit_parents.parent_sha, ancestors.depth + 1 from ancestors \
+        join commit_parents on commit_parents.repo_id=?4 and commit_parents.sha=ancestors.sha \
+        where ancestors.depth < ?3 \
+    ) \
+    select runs.id, \
+        runs.job_id, \
+        runs.artifacts_path, \
+        runs.state, \
+        runs.host_id, \
+        runs.build_token, \
+        runs.created_time, \
+        runs.started_time, \
+        runs.complete_time, \
+        runs.run_timeout, \
+        runs.build_result, \
+        runs.final_status, \
+        runs.outcome, \
+        ancestors.sha \
+    from ancestors \
+    join commits on commits.sha=ancestors.sha and commits.repo_id=?4 \
+    join jobs on jobs.commit_id=commits.id \
+    join runs on runs.job_id=jobs.id \
+    where ancestors.depth > 0 and runs.host_id=?2 and runs.state=2 and runs.build_result=0 \
+    order by ancestors.depth asc, runs.complete_time desc limit 1;";
+
+// where the branch ?2 in repo ?1 points. if several of the repo's remotes have the branch, the
+// most recently updated one wins.
+pub const BRANCH_TIP: &str = "\
+    select refs.sha from refs \
+    join remotes on remotes.id=refs.remote_id \
+    where remotes.repo_id=?1 and refs.ref_name='refs/heads/' || ?2 \
+    order by refs.updated_time desc limit 1;";
+
+pub const ATTEMPTS_FOR_RUN: &str = "\
+    select id, run_id, attempt, host_id, build_token, artifacts_path, state, started_time, complete_time, build_result, final_status, \
+        heartbeat_time, requeue_reason, outcome \
+    from attempts where run_id=?1 order by attempt asc;";
+
+pub const LATEST_ATTEMPT_FOR_RUN: &str = "\
+    select id, run_id, attempt, host_id, build_token, artifacts_path, state, started_time, complete_time, build_result, final_status, \
+        heartbeat_time, requeue_reason, outcome \
+    from attempts where run_id=?1 order by attempt desc limit 1;";
+
+// started attempts that haven't had a heartbeat since ?2. attempts from before heartbeats were
+// recorded count from when they started.
+pub const STALE_ATTEMPTS: &str = "\
+    select id, run_id, attempt, host_id, build_token, artifacts_path, state, started_time, complete_time, build_result, final_status, \
+        heartbeat_time, requeue_reason, outcome \
+    from attempts where state=?1 and coalesce(heartbeat_time, started_time, 0) < ?2 order by id asc;";
+
+pub const ATTEMPT_PROGRESS: &str = "\
+    select id, heartbeat_time, heartbeat_step, heartbeat_elapsed_ms, heartbeat_load_avg, heartbeat_mem_available_kb \
+    from attempts where id=?1;";
+
+pub const RECORD_RUNNER_HEARTBEAT: &str = "\
+    update attempts set heartbeat_time=?1, heartbeat_step=?2, heartbeat_elapsed_ms=?3, heartbeat_load_avg=?4, heartbeat_mem_available_kb=?5 \
+    where id=?6 and state=?7;";
+
+pub const ATTEMPT_FOR_TOKEN: &str = "\
+    select attempts.id, attempts.run_id, attempts.artifacts_path, attempts.started_time, runs.run_timeout, \
+        runner_credentials.revoked_time, runner_credentials.expires_time from attempts \
+    join runs on runs.id=attempts.run_id \
+    left join hosts on hosts.id=attempts.host_id \
+    left join runner_credentials on runner_credentials.id=hosts.credential_id \
+    where attempts.build_token=?1;";
+
+pub const ATTEMPT_BY_ID: &str = "\
+    select id, run_id, attempt, host_id, build_token, artifacts_path, state, started_time, complete_time, build_result, final_status, \
+        heartbeat_time, requeue_reason, outcome \
+    from attempts where id=?1;";
+
+pub const METRIC_POLICY_FOR_REPO: &str = "\
+    select repo_id, threshold, window, noise_sigmas from metric_policies where repo_id=?1;";
+
+pub const SET_METRIC_POLICY: &str = "\
+    insert into metric_policies (repo_id, threshold, window, noise_sigmas) values (?1, ?2, ?3, ?4) \
+    on conflict (repo_id) do update set \
+        threshold=excluded.threshold, window=excluded.window, noise_sigmas=excluded.noise_sigmas;";
+
+pub const METRIC_FINDINGS_FOR_ATTEMPT: &str = "\
+    select id, run_id, attempt_id, name, unit, value, baseline, baseline_samples, change, relative, improved, created_time \
+    from metric_findings where attempt_id=?1 order by id asc;";
+
+// like `NEAREST_ANCESTOR_WITH_RUN`, but the ?5 closest ancestors with a finished run on host ?2,
+// each with its most recently completed run. this is the baseline a run's metrics are compared
+// against.
+pub const ANCESTORS_WITH_RUNS: &str = "\
+    with recursive ancestors(sha, depth) as ( \
+        select ?1, 0 \
+        union \
+        select commit_parents.parent_sha, ancestors.depth + 1 from ancestors \
+        join commit_parents on commit_parents.repo_id=?4 and commit_parents.sha=ancestors.sha \
+        where ancestors.depth < ?3 \
+    ), ancestor_runs as ( \
+        select runs.id, \
+            runs.job_id, \
+            runs.artifacts_path, \
+            runs.state, \
+            runs.host_id, \
+            runs.build_token, \
+            runs.created_time, \
+            runs.started_time, \
+            runs.complete_time, \
+            runs.run_timeout, \
+            runs.build_result, \
+            runs.final_status, \
+            runs.outcome, \
+            ancestors.sha, \
+            ancestors.depth, \
+            row_number() over (partition by ancestors.sha order by ancestors.depth asc, runs.complete_time desc) as newest \
+        from ancestors \
+        join commits on commits.sha=ancestors.sha and commits.repo_id=?4 \
+        join jobs on jobs.commit_id=commits.id \
+        join runs on runs.job_id=jobs.id \
+        where ancestors.depth > 0 and runs.host_id=?2 and runs.state=2 and runs.build_result=0 \
+    ) \
+    select id, job_id, artifacts_path, state, host_id, build_token, created_time, started_time, complete_time, run_timeout, build_result, final_status, outcome, sha \
+    from ancestor_runs where newest=1 \
+    order by depth asc limit ?5;";
+
+pub const ALL_HOSTS: &str = "\
+    select id, runner_id, name, hostname, cpu_vendor_id, cpu_model_name, cpu_family, cpu_model, cpu_microcode, \
+        cpu_max_freq_khz, cpu_cores, mem_total, arch, family, os, enabled, retired_time \
+    from hosts order by id asc;";
+
+pub const HOST_BY_ID: &str = "\
+    select id, runner_id, name, hostname, cpu_vendor_id, cpu_model_name, cpu_family, cpu_model, cpu_microcode, \
+        cpu_max_freq_khz, cpu_cores, mem_total, arch, family, os, enabled, retired_time \
+    from hosts where id=?1;";
+
+pub const HOST_BY_NAME: &str = "\
+    select id, runner_id, name, hostname, cpu_vendor_id, cpu_model_name, cpu_family, cpu_model, cpu_microcode, \
+        cpu_max_freq_khz, cpu_cores, mem_total, arch, family, os, enabled, retired_time \
+    from hosts where name=?1;";
+
+pub const ALL_RUNNER_CREDENTIALS: &str = "\
+    select runner_credentials.id, runner_credentials.name, created_time, expires_time, revoked_time, last_used_time, hosts.id \
+    from runner_credentials left join hosts on hosts.credential_id=runner_credentials.id \
+    order by runner_credentials.id asc;";
+
+pub const RUNNER_CREDENTIAL_BY_ID: &str = "\
+    select runner_credentials.id, runner_credentials.name, created_time, expires_time, revoked_time, last_used_time, hosts.id \
+    from runner_credentials left join hosts on hosts.credential_id=runner_credentials.id \
+    where runner_credentials.id=?1;";
+
+pub const RUNNER_CREDENTIAL_BY_NAME: &str = "\
+    select runner_credentials.id, runner_credentials.name, created_time, expires_time, revoked_time, last_used_time, hosts.id \
+    from runner_credentials left join hosts on hosts.credential_id=runner_credentials.id \
+    where runner_credentials.name=?1;";
+
+pub const RUNNER_CREDENTIAL_BY_TOKEN_HASH: &str = "\
+    select runner_credentials.id, runner_credentials.name, created_time, expires_time, revoked_time, last_used_time, hosts.id \
+    from runner_credentials left join hosts on hosts.credential_id=runner_credentials.id \
+    where runner_credentials.token_hash=?1;";
+
+pub const RUNNER_CREDENTIAL_FOR_HOST: &str = "\
+    select runner_credentials.id, runner_credentials.name, created_time, expires_time, revoked_time, last_used_time, hosts.id \
+    from runner_credentials join hosts on hosts.credential_id=runner_credentials.id \
+    where hosts.id=?1;";
+
+// a host can be taken over by another credential only once the credential it was bound to can no
+// longer be used.
+pub const BIND_HOST_CREDENTIAL: &str = "\
+    update hosts set credential_id=?1 \
+    where id=?2 and (credential_id is null or credential_id=?1 or credential_id in \
+        (select id from runner_credentials where revoked_time is not null or expires_time <= ?3));";
+
+pub const LABELS_FOR_HOST: &str = "\
+    select label from host_labels where host_id=?1 order by label asc;";
+
+pub const HOST_BY_RUNNER_ID: &str = "\
+    select id from hosts where runner_id=?1;";
+
+// hosts from before runners had identities were told apart by their entire hardware fingerprint.
+pub const HOST_BY_FINGERPRINT: &str = "\
+    select id from hosts where runner_id is null and \
+        hostname=?1 and cpu_vendor_id=?2 and cpu_model_name=?3 and cpu_family=?4 and \
+        cpu_model=?5 and cpu_microcode=?6 and cpu_max_freq_khz=?7 and \
+        cpu_cores=?8 and mem_total=?9 and arch=?10 and family=?11 and os=?12 \
+    order by id desc limit 1;";
+
+// like `HOST_BY_FINGERPRINT`, but ignoring the parts of the fingerprint that change without the
+// machine meaningfully changing: microcode, the exact amount of memory the kernel reports, and
+// family/os. retired hosts are left alone.
+pub const HOST_LIKE_FINGERPRINT: &str = "\
+    select id from hosts where runner_id is null and retired_time is null and \
+        hostname=?1 and cpu_vendor_id=?2 and cpu_model_name=?3 and cpu_family=?4 and \
+        cpu_model=?5 and cpu_max_freq_khz=?6 and cpu_cores=?7 and arch=?8 \
+    order by id desc limit 1;";
+
+pub const INSERT_HOST: &str = "\
+    insert into hosts (runner_id, hostname, cpu_vendor_id, cpu_model_name, cpu_family, \
+        cpu_model, cpu_microcode, cpu_max_freq_khz, cpu_cores, mem_total, arch, family, os) \
+    values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13);";
+
+// the newest facts for host ?1, if they are exactly the facts in ?2 through ?13.
+pub const CURRENT_HOST_FACTS_MATCHING: &str = "\
+    select id from host_facts where host_id=?1 and \
+        version=(select max(version) from host_facts where host_id=?1) and \
+        hostname=?2 and cpu_vendor_id=?3 and cpu_model_name=?4 and cpu_family=?5 and \
+        cpu_model=?6 and cpu_microcode=?7 and cpu_max_freq_khz=?8 and \
+        cpu_cores=?9 and mem_total=?10 and arch=?11 and family=?12 and os=?13;";
+
+pub const INSERT_HOST_FACTS: &str = "\
+    insert into host_facts (host_id, version, hostname, cpu_vendor_id, cpu_model_name, cpu_family, \
+        cpu_model, cpu_microcode, cpu_max_freq_khz, cpu_cores, mem_total, arch, family, os, \
+        first_seen, last_seen) \
+    values (?1, (select coalesce(max(version), 0) + 1 from host_facts where host_id=?1), \
+        ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?14);";
+
+pub const UPDATE_HOST_HARDWARE: &str = "\
+    update hosts set hostname=?2, cpu_vendor_id=?3, cpu_model_name=?4, cpu_family=?5, \
+        cpu_model=?6, cpu_microcode=?7, cpu_max_freq_khz=?8, cpu_cores=?9, mem_total=?10, \
+        arch=?11, family=?12, os=?13 \
+    where id=?1;";
+
+pub const FACTS_FOR_HOST: &str = "\
+    select host_id, version, hostname, cpu_vendor_id, cpu_model_name, cpu_family, cpu_model, \
+        cpu_microcode, cpu_max_freq_khz, cpu_cores, mem_total, arch, family, os, first_seen, last_seen \
+    from host_facts where host_id=?1 order by version asc;";
+
+pub const INSERT_COMMAND: &str = "\
+    insert into commands (run_id, attempt_id, command_id, argv, cwd, step, start_time) \
+    select attempts.run_id, attempts.id, ?2, ?3, ?4, ?5, ?6 from attempts where attempts.id=?1;";
+
+// the artifacts a command's output is written to are the newest of those names in its attempt,
+// since the runner creates them just before it starts the command.
+pub const LINK_COMMAND_ARTIFACTS: &str = "\
+    update commands set \
+        stdout_artifact_id=(select max(id) from artifacts where attempt_id=?1 and name=?3), \
+        stderr_artifact_id=(select max(id) from artifacts where attempt_id=?1 and name=?4) \
+    where attempt_id=?1 and command_id=?2;";
+
+pub const FINISH_COMMAND: &str = "\
+    update commands set end_time=?3, exit_code=?4 where attempt_id=?1 and command_id=?2;";
 
-pub const SELECT_ALL_RUNS_WITH_JOB_INFO: &'static str = "\
-    select jobs.id as job_id, runs.id as run_id, runs.state, runs.created_time, jobs.commit_id, jobs.run_preferences
-    from jobs join runs on jobs.id=runs.job_id
-    oder by runs.created_time asc;";
+pub const COMMANDS_FOR_ATTEMPT: &str = "\
+    select id, run_id, attempt_id, command_id, argv, cwd, step, start_time, end_time, exit_code, \
+        stdout_artifact_id, stderr_artifact_id \
+    from commands where attempt_id=?1 order by command_id asc;";
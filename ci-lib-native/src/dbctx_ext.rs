@@ -1,12 +1,16 @@
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use crate::io::ArtifactDescriptor;
 use crate::notifier::{RemoteNotifier, NotifierConfig};
 
 use ci_lib_core::dbctx::DbCtx;
+use ci_lib_core::protocol::TaskOutcome;
+use ci_lib_core::sql::MetricFinding;
 
 pub fn notifiers_by_repo(ctx: &DbCtx, repo_id: u64) -> Result<Vec<RemoteNotifier>, String> {
-    let remotes = ctx.remotes_by_repo(repo_id)?;
+    let remotes = ctx.remotes_by_repo(repo_id)
+        .map_err(|e| e.to_string())?;
 
     let mut notifiers: Vec<RemoteNotifier> = Vec::new();
 
@@ -43,21 +47,37 @@ pub fn notifiers_by_repo(ctx: &DbCtx, repo_id: u64) -> Result<Vec<RemoteNotifier
     Ok(notifiers)
 }
 
-pub async fn reserve_artifact(ctx: &DbCtx, artifact_path: PathBuf, run_id: u64, name: &str, desc: &str) -> Result<ArtifactDescriptor, String> {
-    let artifact_id = {
-        let created_time = ci_lib_core::now_ms();
-        let conn = ctx.conn.lock().unwrap();
-        conn
-            .execute(
-                "insert into artifacts (run_id, name, desc, created_time) values (?1, ?2, ?3, ?4)",
-                (run_id, name, desc, created_time)
-            )
-            .map_err(|e| {
-                format!("{:?}", e)
-            })?;
-
-        conn.last_insert_rowid() as u64
-    };
+/// tell every notifier of `run_id`'s repo that the run was cancelled.
+pub async fn notify_cancelled(ctx: &Arc<DbCtx>, run_id: u64) -> Result<(), String> {
+    notify_complete(ctx, run_id, TaskOutcome::Cancelled, "", &[]).await
+}
+
+/// tell every notifier of `run_id`'s repo that the run ended with `outcome`.
+pub async fn notify_complete(ctx: &Arc<DbCtx>, run_id: u64, outcome: TaskOutcome, desc: &str, findings: &[MetricFinding]) -> Result<(), String> {
+    let run = ctx.run_by_id(run_id)
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("no run {}", run_id))?;
+    let job = ctx.job_by_id(run.job_id)
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("run {} references missing job {}", run_id, run.job_id))?;
+    let repo_id = ctx.repo_id_by_remote(job.remote_id)
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("job {} references missing remote {}", job.id, job.remote_id))?;
+    let sha = ctx.commit_sha(job.commit_id)
+        .map_err(|e| e.to_string())?;
+
+    for notifier in notifiers_by_repo(ctx, repo_id)? {
+        if let Err(e) = notifier.tell_complete_job(ctx, repo_id, &sha, run_id, outcome, desc, findings).await {
+            eprintln!("could not notify {:?}: {:?}", notifier.remote_path, e);
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn reserve_artifact(ctx: &DbCtx, artifact_path: PathBuf, run_id: u64, attempt_id: u64, name: &str, desc: &str) -> Result<ArtifactDescriptor, String> {
+    let artifact_id = ctx.new_artifact(attempt_id, name, desc)
+        .map_err(|e| format!("{:?}", e))?;
 
     ArtifactDescriptor::new(artifact_path, run_id, artifact_id).await
 }
@@ -0,0 +1,53 @@
+use std::path::Path;
+
+use ci_lib_core::dbctx::{DbCtx, DbError};
+
+/// what one pass of `collect_garbage` got rid of.
+#[derive(Debug, Default)]
+pub struct GcReport {
+    pub runs_collected: usize,
+    pub artifacts_deleted: usize,
+    pub bytes_freed: u64,
+    pub metrics_deleted: usize,
+}
+
+/// delete artifacts and metrics that each repo's retention policy says have expired.
+///
+/// artifact records are deleted before their files. if we die partway through, the worst case is a
+/// file in `artifact_path` that nothing refers to, rather than a record for an artifact that
+/// pages will try (and fail) to read.
+pub fn collect_garbage(ctx: &DbCtx, artifact_path: &Path) -> Result<GcReport, DbError> {
+    let mut report = GcReport::default();
+
+    for expired in ctx.runs_past_retention(ci_lib_core::now_ms())? {
+        report.runs_collected += 1;
+
+        if expired.logs_expired {
+            let run_dir = artifact_path.join(expired.run_id.to_string());
+
+            for artifact in ctx.delete_artifacts_for_run(expired.run_id)? {
+                let artifact_file = run_dir.join(artifact.id.to_string());
+                if let Ok(metadata) = std::fs::metadata(&artifact_file) {
+                    report.bytes_freed += metadata.len();
+                }
+                match std::fs::remove_file(&artifact_file) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => {
+                        eprintln!("[-] could not delete artifact file {}: {}", artifact_file.display(), e);
+                    }
+                }
+                report.artifacts_deleted += 1;
+            }
+
+            // only succeeds if the directory is now empty, which is exactly when we want it gone.
+            let _ = std::fs::remove_dir(&run_dir);
+        }
+
+        if expired.metrics_expired {
+            report.metrics_deleted += ctx.delete_metrics_for_run(expired.run_id)?;
+        }
+    }
+
+    Ok(report)
+}
@@ -2,7 +2,7 @@ use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use futures_util::StreamExt;
 use tokio::fs::File;
 use tokio::fs::OpenOptions;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::task::{Poll, Context};
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
@@ -12,13 +12,19 @@ pub struct VecSink {
     body: Arc<Mutex<Vec<u8>>>,
 }
 
+impl Default for VecSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VecSink {
     pub fn new() -> Self {
         Self { body: Arc::new(Mutex::new(Vec::new())) }
     }
 
     pub fn take_buf(&self) -> Vec<u8> {
-        std::mem::replace(&mut *self.body.lock().unwrap(), Vec::new())
+        std::mem::take(&mut *self.body.lock().unwrap())
     }
 }
 
@@ -94,6 +100,7 @@ pub struct ArtifactDescriptor {
     #[allow(dead_code)]
     job_id: u64,
     pub artifact_id: u64,
+    path: PathBuf,
     file: File,
 }
 
@@ -111,10 +118,16 @@ impl ArtifactDescriptor {
         Ok(ArtifactDescriptor {
             job_id,
             artifact_id,
+            path,
             file,
         })
     }
 
+    /// where the artifact is written to.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub async fn store_all(&mut self, mut data: axum::extract::BodyStream) -> Result<(), String> {
         loop {
             let chunk = data.next().await;
@@ -123,11 +136,17 @@ impl ArtifactDescriptor {
                 Some(Ok(chunk)) => chunk,
                 Some(Err(e)) => {
                     eprintln!("error: {:?}", e);
+                    // what did arrive is still worth keeping, so it has to be on disk too.
+                    if let Err(flush_err) = self.file.flush().await {
+                        return Err(format!("error reading: {:?}, and failed to flush: {:?}", e, flush_err));
+                    }
                     return Err(format!("error reading: {:?}", e));
                 }
                 None => {
                     eprintln!("body done?");
-                    return Ok(());
+                    // make sure it's all on disk before anyone (like `finalize_artifact`) reads it.
+                    return self.file.flush().await
+                        .map_err(|e| format!("failed to flush: {:?}", e));
                 }
             };
 
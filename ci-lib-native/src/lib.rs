@@ -1,9 +1,22 @@
 pub mod io;
 pub mod dbctx_ext;
 pub mod notifier;
+pub mod gc;
+pub mod regressions;
 
 use axum::http::StatusCode;
 
+use ci_lib_core::dbctx::DbError;
+
+/// the http status a request that failed with `e` ought to be answered with.
+pub fn db_error_status(e: &DbError) -> StatusCode {
+    match e {
+        DbError::NotFound => StatusCode::NOT_FOUND,
+        DbError::Busy => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
 pub struct GithubApi<'a> {
     pub ci_server: &'a str,
     pub token: &'a str,
@@ -20,7 +33,7 @@ impl GithubApi<'_> {
         });
 
         let client = reqwest::Client::new();
-        let req = client.post(&format!("https://api.github.com/repos/{}/statuses/{}", remote_path, sha))
+        let req = client.post(format!("https://api.github.com/repos/{}/statuses/{}", remote_path, sha))
             .body(serde_json::to_string(&status_info).expect("can stringify json"))
             .header("content-type", "application/json")
             .header("user-agent", "iximeow")
@@ -53,21 +66,21 @@ impl GithubApi<'_> {
         let hooks: serde_json::Value = serde_json::from_slice(&bytes)
             .map_err(|e| format!("could not parse response body: {:?}", e))?;
 
-        for v in hooks.as_array().ok_or_else(|| format!("response json was not an array"))? {
-            let o = v.as_object().ok_or_else(|| format!("response array did not contain objects"))?;
+        for v in hooks.as_array().ok_or_else(|| "response json was not an array".to_string())? {
+            let o = v.as_object().ok_or_else(|| "response array did not contain objects".to_string())?;
             let conf = o.get("config")
-                .ok_or_else(|| format!("hook objects do not have config"))?
-                .as_object().ok_or_else(|| format!("hook objects have config but it is not an object"))?;
+                .ok_or_else(|| "hook objects do not have config".to_string())?
+                .as_object().ok_or_else(|| "hook objects have config but it is not an object".to_string())?;
             let url = conf.get("url")
-                .ok_or_else(|| format!("config object does not have url"))?
-                .as_str().ok_or_else(|| format!("config url has url but it not a string"))?;
+                .ok_or_else(|| "config object does not have url".to_string())?
+                .as_str().ok_or_else(|| "config url has url but it not a string".to_string())?;
             
             if url.starts_with(&format!("http://{}", &self.ci_server)) {
                 return Ok(true);
             }
         }
 
-        return Ok(false);
+        Ok(false)
     }
 
     pub async fn create_ci_webhook(&self, remote_path: &str) -> Result<(), String> {
@@ -86,7 +99,7 @@ impl GithubApi<'_> {
         });
 
         let client = reqwest::Client::new();
-        let req = client.post(&format!("https://api.github.com/repos/{remote_path}/hooks"))
+        let req = client.post(format!("https://api.github.com/repos/{remote_path}/hooks"))
             .body(serde_json::to_string(&webhook_config).expect("can stringify json"))
             .header("content-type", "application/json")
             .header("user-agent", "iximeow")
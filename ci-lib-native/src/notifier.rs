@@ -9,6 +9,8 @@ use std::time::Duration;
 use std::path::Path;
 
 use ci_lib_core::dbctx::DbCtx;
+use ci_lib_core::protocol::TaskOutcome;
+use ci_lib_core::sql::MetricFinding;
 
 pub struct RemoteNotifier {
     pub remote_path: String,
@@ -64,8 +66,8 @@ impl NotifierConfig {
 
     pub fn ci_server(&self) -> &str {
         match self {
-            Self::Email { ci_server, .. } => &ci_server,
-            Self::GitHub { ci_server, .. } => &ci_server
+            Self::Email { ci_server, .. } => ci_server,
+            Self::GitHub { ci_server, .. } => ci_server
         }
     }
 }
@@ -79,25 +81,36 @@ impl RemoteNotifier {
         ).await
     }
 
-    pub async fn tell_complete_job(&self, ctx: &Arc<DbCtx>, repo_id: u64, sha: &str, job_id: u64, desc: Result<String, String>) -> Result<(), String> {
-        match desc {
-            Ok(status) => {
-                self.tell_job_status(
-                    ctx,
-                    repo_id, sha, job_id,
-                    "success", &status, &format!("https://{}/{}/{}", self.notifier.ci_server(), &self.remote_path, sha)
-                ).await
-            },
-            Err(status) => {
-                self.tell_job_status(
-                    ctx,
-                    repo_id, sha, job_id,
-                    "failure", &status, &format!("https://{}/{}/{}", self.notifier.ci_server(), &self.remote_path, sha)
-                ).await
-            }
+    /// report a finished job. `desc` is whatever the runner said about how the job ended, and
+    /// `findings` are any metric changes the job turned up, which are summarized alongside it.
+    ///
+    /// only a failed build is reported as a failure of the commit. anything else that stops a job
+    /// is an error, since it says nothing about the commit being built.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn tell_complete_job(&self, ctx: &Arc<DbCtx>, repo_id: u64, sha: &str, job_id: u64, outcome: TaskOutcome, desc: &str, findings: &[MetricFinding]) -> Result<(), String> {
+        let state = match outcome {
+            TaskOutcome::Passed => "success",
+            TaskOutcome::BuildFailed | TaskOutcome::TimedOut => "failure",
+            TaskOutcome::CheckoutFailed | TaskOutcome::GoodfileError | TaskOutcome::InfraError | TaskOutcome::Cancelled => "error",
+        };
+
+        let mut status = if outcome == TaskOutcome::Passed || desc.is_empty() || desc == outcome.summary() {
+            outcome.summary().to_string()
+        } else {
+            format!("{}: {}", outcome.summary(), desc)
+        };
+        if let Some(summary) = crate::regressions::summarize_findings(findings) {
+            status = format!("{}; {}", status, summary);
         }
+
+        self.tell_job_status(
+            ctx,
+            repo_id, sha, job_id,
+            state, &status, &format!("https://{}/{}/{}", self.notifier.ci_server(), &self.remote_path, sha)
+        ).await
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn tell_job_status(&self, _ctx: &Arc<DbCtx>, _repo_id: u64, sha: &str, _job_id: u64, state: &str, desc: &str, target_url: &str) -> Result<(), String> {
         match &self.notifier {
             NotifierConfig::GitHub { ci_server, token, webhook_token } => {
@@ -118,12 +131,12 @@ impl RemoteNotifier {
                     }
                 }
             }
-            NotifierConfig::Email { ci_server, username, password, mailserver, from, to } => {
+            NotifierConfig::Email { ci_server: _, username, password, mailserver, from, to } => {
                 eprintln!("[.] emailing {} for job {} via {}", state, &self.remote_path, mailserver);
 
                 let subject = format!("{}: job for {}", state, &self.remote_path);
 
-                let body = format!("{}", subject);
+                let body = format!("{}\n\n{}\n{}", subject, desc, target_url);
 
                 // TODO: when ci.butactuallyin.space has valid certs again, ... fix this.
                 let tls = TlsParametersBuilder::new(mailserver.to_string())
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+
+use ci_lib_core::dbctx::{DbCtx, DbError};
+use ci_lib_core::sql::{MetricFinding, MetricPolicy, MetricValue};
+
+/// ancestors further back than this are never part of a baseline, however large the window.
+const MAX_BASELINE_DEPTH: u64 = 200;
+
+/// compare `attempt_id`'s numeric metrics against the same host's metrics on recent ancestor
+/// commits, record whatever changed by more than the repo's `MetricPolicy` allows, and return it.
+///
+/// an attempt with nothing to compare against (the first build of a repo on a host, or a metric
+/// that's new in this commit) has no findings. neither does a metric whose unit changed.
+pub fn detect_metric_changes(ctx: &DbCtx, attempt_id: u64) -> Result<Vec<MetricFinding>, DbError> {
+    let attempt = ctx.attempt_by_id(attempt_id)?
+        .ok_or(DbError::NotFound)?;
+    let host_id = match attempt.host_id {
+        Some(host_id) => host_id,
+        None => { return Ok(Vec::new()); }
+    };
+    let run = ctx.run_by_id(attempt.run_id)?
+        .ok_or_else(|| DbError::CorruptRow(format!("attempt {} references missing run {}", attempt.id, attempt.run_id)))?;
+    let job = ctx.job_by_id(run.job_id)?
+        .ok_or_else(|| DbError::CorruptRow(format!("run {} references missing job {}", run.id, run.job_id)))?;
+    let repo_id = ctx.repo_id_by_remote(job.remote_id)?
+        .ok_or_else(|| DbError::CorruptRow(format!("job {} references missing remote {}", job.id, job.remote_id)))?;
+    let sha = ctx.commit_sha(job.commit_id)?;
+
+    let policy = ctx.metric_policy(repo_id)?
+        .unwrap_or_else(|| MetricPolicy::default_for(repo_id));
+
+    let mut baselines: HashMap<String, Vec<MetricValue>> = HashMap::new();
+    for (_, ancestor_run) in ctx.ancestors_with_runs(repo_id, &sha, host_id, MAX_BASELINE_DEPTH, policy.window.max(1))? {
+        let ancestor_attempt = match ctx.latest_attempt_for_run(ancestor_run.id)? {
+            Some(ancestor_attempt) => ancestor_attempt,
+            None => { continue; }
+        };
+        for metric in ctx.metrics_for_attempt(ancestor_attempt.id)? {
+            baselines.entry(metric.name).or_default().push(metric.value);
+        }
+    }
+
+    let mut findings = Vec::new();
+
+    for metric in ctx.metrics_for_attempt(attempt.id)? {
+        let (unit, direction) = match &metric.value {
+            MetricValue::Number { unit, direction, .. } => (*unit, *direction),
+            MetricValue::Text(_) => { continue; }
+        };
+
+        let samples: Vec<f64> = baselines.get(&metric.name)
+            .map(|values| values.iter().filter_map(|value| match value {
+                MetricValue::Number { value, unit: sample_unit, .. } if *sample_unit == unit => Some(*value),
+                _ => None,
+            }).collect())
+            .unwrap_or_default();
+
+        if samples.is_empty() {
+            continue;
+        }
+
+        let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+        let baseline = MetricValue::Number { value: mean, unit, direction };
+        let delta = match metric.value.delta_from(&baseline) {
+            Some(delta) => delta,
+            None => { continue; }
+        };
+
+        let beyond_threshold = match delta.relative {
+            Some(relative) => relative.abs() > policy.threshold,
+            // the baseline is zero, so any change at all is infinitely large.
+            None => delta.change != 0.0,
+        };
+
+        let beyond_noise = match policy.noise_sigmas {
+            Some(sigmas) if samples.len() >= 2 => {
+                let variance = samples.iter().map(|sample| (sample - mean).powi(2)).sum::<f64>() / (samples.len() - 1) as f64;
+                delta.change.abs() > sigmas * variance.sqrt()
+            }
+            _ => true,
+        };
+
+        if beyond_threshold && beyond_noise {
+            findings.push(MetricFinding {
+                id: 0,
+                run_id: run.id,
+                attempt_id: attempt.id,
+                name: metric.name,
+                unit,
+                value: delta.after,
+                baseline: delta.before,
+                baseline_samples: samples.len() as u64,
+                change: delta.change,
+                relative: delta.relative,
+                improved: delta.improved,
+                created_time: 0,
+            });
+        }
+    }
+
+    ctx.replace_metric_findings(attempt.id, &findings)?;
+
+    Ok(findings)
+}
+
+/// a short description of `findings` suitable for a commit status, like
+/// "2 metrics regressed, 1 improved".
+pub fn summarize_findings(findings: &[MetricFinding]) -> Option<String> {
+    if findings.is_empty() {
+        return None;
+    }
+
+    let regressed = findings.iter().filter(|finding| finding.improved == Some(false)).count();
+    let improved = findings.iter().filter(|finding| finding.improved == Some(true)).count();
+    let changed = findings.len() - regressed - improved;
+
+    let mut parts = Vec::new();
+    if regressed != 0 {
+        parts.push(format!("{} regressed", regressed));
+    }
+    if improved != 0 {
+        parts.push(format!("{} improved", improved));
+    }
+    if changed != 0 {
+        parts.push(format!("{} changed", changed));
+    }
+
+    Some(format!("metrics: {}", parts.join(", ")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ci_lib_core::dbctx::DEFAULT_RUN_TIMEOUT_MS;
+    use ci_lib_core::protocol::TaskOutcome;
+    use ci_lib_core::sql::{MetricDirection, MetricUnit};
+
+    struct Repo {
+        db: DbCtx,
+        repo_id: u64,
+        remote_id: u64,
+    }
+
+    impl Repo {
+        fn new(policy: impl FnOnce(u64) -> MetricPolicy) -> Self {
+            let db = DbCtx::in_memory().expect("can open database");
+            db.migrate().expect("can migrate");
+            let repo_id = db.new_repo("ci").unwrap();
+            let remote_id = db.new_remote(repo_id, "iximeow/ci", "github", "ci.json").unwrap();
+            db.set_metric_policy(&policy(repo_id)).unwrap();
+            Repo { db, repo_id, remote_id }
+        }
+
+        // build `sha`, a child of `parent`, on `host_id`, reporting `build_ms` as `value`, and
+        // return the attempt's id.
+        fn build(&self, sha: &str, parent: Option<&str>, host_id: u32, value: f64, outcome: TaskOutcome) -> u64 {
+            if let Some(parent) = parent {
+                self.db.record_commit_parents(self.repo_id, sha, &[parent]).unwrap();
+            }
+            let run_id = self.db.new_run(self.db.create_job(self.remote_id, sha, None).unwrap().job_id(), None).unwrap().id;
+            let attempt_id = self.db.start_run(run_id, host_id, "artifacts", &format!("token-{}-{}", sha, run_id), DEFAULT_RUN_TIMEOUT_MS).unwrap();
+            let metric = MetricValue::Number { value, unit: Some(MetricUnit::Ms), direction: Some(MetricDirection::LowerIsBetter) };
+            self.db.insert_metric(attempt_id, "build_ms", &metric).unwrap();
+            self.db.complete_attempt(attempt_id, outcome, outcome.as_str()).unwrap();
+            attempt_id
+        }
+    }
+
+    fn policy(threshold: f64, window: u64, noise_sigmas: Option<f64>) -> impl FnOnce(u64) -> MetricPolicy {
+        move |repo_id| MetricPolicy { repo_id, threshold, window, noise_sigmas }
+    }
+
+    #[test]
+    fn changes_within_the_threshold_are_not_findings() {
+        let repo = Repo::new(policy(0.05, 1, None));
+        repo.build("c1", None, 1, 1000.0, TaskOutcome::Passed);
+        let small = repo.build("c2", Some("c1"), 1, 1040.0, TaskOutcome::Passed);
+        assert!(detect_metric_changes(&repo.db, small).unwrap().is_empty());
+
+        let large = repo.build("c3", Some("c1"), 1, 1100.0, TaskOutcome::Passed);
+        let findings = detect_metric_changes(&repo.db, large).unwrap();
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].name, "build_ms");
+        assert_eq!(findings[0].baseline, 1000.0);
+        assert_eq!(findings[0].baseline_samples, 1);
+        assert_eq!(findings[0].relative, Some(0.1));
+        assert_eq!(findings[0].improved, Some(false));
+        assert_eq!(repo.db.metric_findings_for_attempt(large).unwrap().len(), 1);
+        assert_eq!(summarize_findings(&findings).as_deref(), Some("metrics: 1 regressed"));
+    }
+
+    #[test]
+    fn changes_within_the_noise_are_not_findings() {
+        let repo = Repo::new(policy(0.05, 3, Some(2.0)));
+        repo.build("c1", None, 1, 1000.0, TaskOutcome::Passed);
+        repo.build("c2", Some("c1"), 1, 1100.0, TaskOutcome::Passed);
+        repo.build("c3", Some("c2"), 1, 900.0, TaskOutcome::Passed);
+
+        // 15% slower, but within two standard deviations (100ms) of the mean.
+        let noisy = repo.build("c4", Some("c3"), 1, 1150.0, TaskOutcome::Passed);
+        assert!(detect_metric_changes(&repo.db, noisy).unwrap().is_empty());
+
+        let faster = repo.build("c5", Some("c3"), 1, 750.0, TaskOutcome::Passed);
+        let findings = detect_metric_changes(&repo.db, faster).unwrap();
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].baseline, 1000.0);
+        assert_eq!(findings[0].baseline_samples, 3);
+        assert_eq!(findings[0].improved, Some(true));
+    }
+
+    #[test]
+    fn baselines_only_come_from_passing_runs_on_the_same_host() {
+        let repo = Repo::new(policy(0.05, 1, None));
+        repo.build("c1", None, 1, 1000.0, TaskOutcome::Passed);
+        repo.build("c2", Some("c1"), 2, 5000.0, TaskOutcome::Passed);
+        repo.build("c3", Some("c2"), 1, 5000.0, TaskOutcome::BuildFailed);
+
+        // c2 and c3 are nearer, but were built elsewhere or failed: c1 is the baseline.
+        let attempt = repo.build("c4", Some("c3"), 1, 1010.0, TaskOutcome::Passed);
+        assert!(detect_metric_changes(&repo.db, attempt).unwrap().is_empty());
+
+        // the first build on a host has nothing to compare against.
+        let first = repo.build("c5", Some("c4"), 3, 9000.0, TaskOutcome::Passed);
+        assert!(detect_metric_changes(&repo.db, first).unwrap().is_empty());
+    }
+}
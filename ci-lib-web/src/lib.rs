@@ -2,8 +2,9 @@ use std::sync::Arc;
 
 use chrono::{Utc, TimeZone};
 
-use ci_lib_core::dbctx::DbCtx;
-use ci_lib_core::sql::{Job, Run, RunState};
+use ci_lib_core::dbctx::{DbCtx, DbError};
+use ci_lib_core::protocol::TaskOutcome;
+use ci_lib_core::sql::{Job, MetricUnit, MetricValue, Run, RunState};
 
 /// return a duration rendered as the largest two non-zero units.
 ///
@@ -43,11 +44,71 @@ pub fn duration_as_human_string(duration_ms: u64) -> String {
     }
 }
 
+/// render a metric in its unit:
+///             1030 ms -> 1.030s
+///          1536 bytes -> 1.50KiB
+/// 1234567 instructions -> 1.23M instructions
+/// text metrics are shown as they were reported.
+pub fn format_metric_value(value: &MetricValue) -> String {
+    let (value, unit) = match value {
+        MetricValue::Text(text) => { return text.clone(); }
+        MetricValue::Number { value, unit, .. } => (*value, *unit),
+    };
+
+    match unit {
+        Some(MetricUnit::Ms) if value >= 1000.0 => {
+            duration_as_human_string(value as u64)
+        }
+        Some(MetricUnit::Ms) => {
+            format!("{}ms", format_number(value))
+        }
+        Some(MetricUnit::Bytes) => {
+            const SUFFIXES: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+            if value.abs() < 1024.0 {
+                return format!("{}B", format_number(value));
+            }
+            let mut scaled = value / 1024.0;
+            let mut suffix = 0;
+            while scaled.abs() >= 1024.0 && suffix < SUFFIXES.len() - 1 {
+                scaled /= 1024.0;
+                suffix += 1;
+            }
+            format!("{:.2}{}", scaled, SUFFIXES[suffix])
+        }
+        Some(MetricUnit::Instructions) => {
+            const SUFFIXES: &[&str] = &["", "k", "M", "G", "T"];
+            let mut scaled = value;
+            let mut suffix = 0;
+            while scaled.abs() >= 1000.0 && suffix < SUFFIXES.len() - 1 {
+                scaled /= 1000.0;
+                suffix += 1;
+            }
+            if suffix == 0 {
+                format!("{} instructions", format_number(scaled))
+            } else {
+                format!("{:.2}{} instructions", scaled, SUFFIXES[suffix])
+            }
+        }
+        Some(MetricUnit::Count) | None => {
+            format_number(value)
+        }
+    }
+}
+
+// whole numbers without a trailing `.0`, everything else to three places.
+fn format_number(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{}", value as i64)
+    } else {
+        format!("{:.3}", value)
+    }
+}
+
 /// try producing a url for whatever caused this job to be started, if possible
-pub fn commit_url(job: &Job, commit_sha: &str, ctx: &Arc<DbCtx>) -> (String, Option<String>) {
-    let remote = ctx.remote_by_id(job.remote_id).expect("query succeeds").expect("existing job references existing remote");
+pub fn commit_url(job: &Job, commit_sha: &str, ctx: &Arc<DbCtx>) -> Result<(String, Option<String>), DbError> {
+    let remote = remote_for_job(job, ctx)?;
 
-    match remote.remote_api.as_str() {
+    let url = match remote.remote_api.as_str() {
         "github" => {
             ("github".to_string(), Some(format!("{}/commit/{}", remote.remote_url, commit_sha)))
         },
@@ -57,18 +118,68 @@ pub fn commit_url(job: &Job, commit_sha: &str, ctx: &Arc<DbCtx>) -> (String, Opt
         other => {
             (other.to_string(), None)
         }
-    }
+    };
+
+    Ok(url)
 }
 
 /// produce a url to the job details page
-pub fn job_url(job: &Job, commit_sha: &str, ctx: &Arc<DbCtx>) -> String {
-    let remote = ctx.remote_by_id(job.remote_id).expect("query succeeds").expect("existing job references existing remote");
+pub fn job_url(job: &Job, commit_sha: &str, ctx: &Arc<DbCtx>) -> Result<String, DbError> {
+    let remote = remote_for_job(job, ctx)?;
 
     if remote.remote_api != "github" {
         eprintln!("job url for remote type {} can't be constructed, i think", &remote.remote_api);
     }
 
-    format!("{}/{}", &remote.remote_path, commit_sha)
+    Ok(format!("{}/{}", &remote.remote_path, commit_sha))
+}
+
+fn remote_for_job(job: &Job, ctx: &Arc<DbCtx>) -> Result<ci_lib_core::sql::Remote, DbError> {
+    ctx.remote_by_id(job.remote_id)?
+        .ok_or_else(|| DbError::CorruptRow(format!("job {} references missing remote {}", job.id, job.remote_id)))
+}
+
+/// escape `text` for use in html, including in attribute values.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// render the result of a run for a table of runs: how it ended if it has, otherwise whether it's
+/// started.
+pub fn display_run_result(run: &Run) -> &'static str {
+    if let Some(outcome) = run.outcome {
+        return match outcome {
+            TaskOutcome::Passed => "<span style='color:green;'>pass</span>",
+            TaskOutcome::BuildFailed => "<span style='color:red;'>fail</span>",
+            TaskOutcome::CheckoutFailed => "<span style='color:red;'>bad checkout</span>",
+            TaskOutcome::GoodfileError => "<span style='color:red;'>goodfile error</span>",
+            TaskOutcome::InfraError => "<span style='color:darkorange;'>infra error</span>",
+            TaskOutcome::TimedOut => "<span style='color:red;'>timed out</span>",
+            TaskOutcome::Cancelled => "cancelled",
+        };
+    }
+
+    match run.build_result {
+        Some(0) => "<span style='color:green;'>pass</span>",
+        Some(_) => "<span style='color:red;'>fail</span>",
+        None => match run.state {
+            RunState::Pending => { "unstarted" },
+            RunState::Started => { "<span style='color:darkgoldenrod;'>in progress</span>" },
+            RunState::Cancelled => { "cancelled" },
+            _ => { "<span style='color:red;'>unreported</span>" }
+        }
+    }
 }
 
 /// render how long a run took, or is taking, in a human-friendly way
@@ -88,8 +199,8 @@ pub fn display_run_time(run: &Run) -> String {
                 }
             } else {
                 let duration_ms = complete_time - start_time;
-                let duration = duration_as_human_string(duration_ms);
-                duration
+                
+                duration_as_human_string(duration_ms)
             }
         } else {
             if run.state != RunState::Invalid {
@@ -106,14 +217,42 @@ pub fn display_run_time(run: &Run) -> String {
     }
 }
 
-pub fn build_repo_index(ctx: &Arc<DbCtx>) -> Result<String, String> {
-    let repos = match ctx.get_repos() {
-        Ok(repos) => repos,
-        Err(e) => {
-            eprintln!("failed to get repos: {:?}", e);
-            return Err("gonna feel that one tomorrow".to_string());
+/// render when the runner for a run's latest attempt was last heard from, and what it was doing
+/// then, if it said.
+pub fn display_last_seen(ctx: &Arc<DbCtx>, run: &Run) -> Result<String, DbError> {
+    let attempt = match ctx.latest_attempt_for_run(run.id)? {
+        Some(attempt) if attempt.state == RunState::Started => attempt,
+        _ => {
+            return Ok("n/a".to_string());
+        }
+    };
+    let progress = ctx.progress_for_attempt(attempt.id)?;
+
+    let last_seen = match attempt.heartbeat_time.or(attempt.start_time) {
+        Some(time) => time,
+        None => {
+            return Ok("never".to_string());
         }
     };
+    let mut html = format!("{} ago", duration_as_human_string(ci_lib_core::now_ms().saturating_sub(last_seen)));
+
+    if let Some(progress) = progress {
+        if !progress.step.is_empty() {
+            html.push_str(&format!(", in {}", escape_html(&progress.step.join(" > "))));
+        }
+        if let Some(load_avg) = progress.load_avg {
+            html.push_str(&format!(", load {:.2}", load_avg));
+        }
+        if let Some(mem_available_kb) = progress.mem_available_kb {
+            html.push_str(&format!(", {}MB free", mem_available_kb / 1024));
+        }
+    }
+
+    Ok(html)
+}
+
+pub fn build_repo_index(ctx: &Arc<DbCtx>) -> Result<String, DbError> {
+    let repos = ctx.get_repos()?;
 
     let mut response = String::new();
 
@@ -125,9 +264,10 @@ pub fn build_repo_index(ctx: &Arc<DbCtx>) -> Result<String, String> {
     response.push_str(".even-row { background: #ddd; }\n");
     response.push_str("</style>\n");
     response.push_str("<h1>builds and build accessories</h1>\n");
+    response.push_str("<a href=/logs/search>search build logs</a>\n");
 
     match repos.len() {
-        0 => { response.push_str(&format!("<p>no repos configured, so there are no builds</p>\n")); },
+        0 => { response.push_str("<p>no repos configured, so there are no builds</p>\n"); },
         1 => { response.push_str("<p>1 repo configured</p>\n"); },
         other => { response.push_str(&format!("<p>{} repos configured</p>\n", other)); },
     }
@@ -140,15 +280,13 @@ pub fn build_repo_index(ctx: &Arc<DbCtx>) -> Result<String, String> {
     }
     response.push_str("</tr>\n");
 
-    let mut row_num = 0;
-
-    for repo in repos {
+    for (row_num, repo) in repos.into_iter().enumerate() {
         let mut most_recent_run: Option<(Job, Run)> = None;
 
-        for remote in ctx.remotes_by_repo(repo.id).expect("remotes by repo works") {
-            let last_job = ctx.last_job_from_remote(remote.id).expect("job by remote works");
+        for remote in ctx.remotes_by_repo(repo.id)? {
+            let last_job = ctx.last_job_from_remote(remote.id)?;
             if let Some(last_job) = last_job {
-                if let Some(last_run) = ctx.last_run_for_job(last_job.id).expect("can query") {
+                if let Some(last_run) = ctx.last_run_for_job(last_job.id)? {
                     if most_recent_run.as_ref().map(|run| run.1.create_time < last_run.create_time).unwrap_or(true) {
                         most_recent_run = Some((last_job, last_run));
                     }
@@ -160,16 +298,16 @@ pub fn build_repo_index(ctx: &Arc<DbCtx>) -> Result<String, String> {
 
         let row_html: String = match most_recent_run {
             Some((job, run)) => {
-                let job_commit = ctx.commit_sha(job.commit_id).expect("job has a commit");
-                let nice_name = ctx.nice_name_for_commit(job.commit_id).expect("can try to get a nice name");
-                let commit_html = match (job_url(&job, &job_commit, &ctx), nice_name) {
+                let job_commit = ctx.commit_sha(job.commit_id)?;
+                let nice_name = ctx.nice_name_for_commit(job.commit_id)?;
+                let commit_html = match (job_url(&job, &job_commit, ctx)?, nice_name) {
                     (url, Some(name)) => format!("<a href=\"{}\">{}</a> (job {}) {}", url, &job_commit[..9], job.id, name.stringy()),
                     (url, None) => format!("<a href=\"{}\">{}</a> (job {})", url, &job_commit[..9], job.id),
                 };
 
-                let remote_html = match commit_url(&job, &job_commit, &ctx) {
+                let remote_html = match commit_url(&job, &job_commit, ctx)? {
                     (source, Some(url)) => format!("<a href=\"{}\">{}</a>", url, source),
-                    (source, None) => format!("{}", source),
+                    (source, None) => source.to_string(),
                 };
 
                 let last_build_time = Utc.timestamp_millis_opt(run.create_time as i64).unwrap().to_rfc2822();
@@ -177,17 +315,9 @@ pub fn build_repo_index(ctx: &Arc<DbCtx>) -> Result<String, String> {
 
                 let status = format!("{:?}", run.state).to_lowercase();
 
-                let result = match run.build_result {
-                    Some(0) => "<span style='color:green;'>pass</span>",
-                    Some(_) => "<span style='color:red;'>fail</span>",
-                    None => match run.state {
-                        RunState::Pending => { "unstarted" },
-                        RunState::Started => { "<span style='color:darkgoldenrod;'>in progress</span>" },
-                        _ => { "<span style='color:red;'>unreported</span>" }
-                    }
-                };
+                let result = display_run_result(&run);
 
-                let entries = [repo_html.as_str(), last_build_time.as_str(), commit_html.as_str(), remote_html.as_str(), &duration, &status, &result];
+                let entries = [repo_html.as_str(), last_build_time.as_str(), commit_html.as_str(), remote_html.as_str(), &duration, &status, result];
                 let entries = entries.iter().chain(std::iter::repeat(&"")).take(headings.len());
 
                 let mut row_html = String::new();
@@ -213,64 +343,56 @@ pub fn build_repo_index(ctx: &Arc<DbCtx>) -> Result<String, String> {
         response.push_str(&row_html);
         response.push_str("</tr>");
         response.push('\n');
-
-        row_num += 1;
     }
     response.push_str("</table>");
 
     response.push_str("<h4>active tasks</h4>\n");
 
-    let runs = ctx.get_active_runs().expect("can query");
-    if runs.len() == 0 {
+    let runs = ctx.get_active_runs()?;
+    if runs.is_empty() {
         response.push_str("<p>(none)</p>\n");
     } else {
         response.push_str("<table class='build-table'>");
         response.push_str("<tr>\n");
-        let headings = ["repo", "last build", "commit/job", "remote", "duration", "status", "result"];
+        let headings = ["repo", "last build", "commit/job", "remote", "duration", "status", "result", "last seen"];
         for heading in headings {
             response.push_str(&format!("<th class='row-item'>{}</th>", heading));
         }
         response.push_str("</tr>\n");
 
-        let mut row_num = 0;
-
-        for run in runs.iter() {
+        for (row_num, run) in runs.iter().enumerate() {
             let row_index = row_num % 2;
 
-            let job = ctx.job_by_id(run.job_id).expect("query succeeds").expect("job id is valid");
-            let remote = ctx.remote_by_id(job.remote_id).expect("query succeeds").expect("remote id is valid");
-            let repo = ctx.repo_by_id(remote.repo_id).expect("query succeeds").expect("repo id is valid");
+            let job = ctx.job_by_id(run.job_id)?
+                .ok_or_else(|| DbError::CorruptRow(format!("run {} references missing job {}", run.id, run.job_id)))?;
+            let remote = remote_for_job(&job, ctx)?;
+            let repo = ctx.repo_by_id(remote.repo_id)?
+                .ok_or_else(|| DbError::CorruptRow(format!("remote {} references missing repo {}", remote.id, remote.repo_id)))?;
 
             let repo_html = format!("<a href=\"/{}\">{}</a>", &repo.name, &repo.name);
 
-            let job_commit = ctx.commit_sha(job.commit_id).expect("job has a commit");
-            let nice_name = ctx.nice_name_for_commit(job.commit_id).expect("can try to get a nice name");
-            let commit_html = match (job_url(&job, &job_commit, &ctx), nice_name) {
+            let job_commit = ctx.commit_sha(job.commit_id)?;
+            let nice_name = ctx.nice_name_for_commit(job.commit_id)?;
+            let commit_html = match (job_url(&job, &job_commit, ctx)?, nice_name) {
                 (url, Some(name)) => format!("<a href=\"{}\">{}</a> (job {}) {}", url, &job_commit[..9], job.id, name.stringy()),
                 (url, None) => format!("<a href=\"{}\">{}</a> (job {})", url, &job_commit[..9], job.id),
             };
 
-            let remote_html = match commit_url(&job, &job_commit, &ctx) {
+            let remote_html = match commit_url(&job, &job_commit, ctx)? {
                 (source, Some(url)) => format!("<a href=\"{}\">{}</a>", url, source),
-                (source, None) => format!("{}", source),
+                (source, None) => source.to_string(),
             };
 
             let last_build_time = Utc.timestamp_millis_opt(run.create_time as i64).unwrap().to_rfc2822();
-            let duration = display_run_time(&run);
+            let duration = display_run_time(run);
 
             let status = format!("{:?}", run.state).to_lowercase();
 
-            let result = match run.build_result {
-                Some(0) => "<span style='color:green;'>pass</span>",
-                Some(_) => "<span style='color:red;'>fail</span>",
-                None => match run.state {
-                    RunState::Pending => { "unstarted" },
-                    RunState::Started => { "<span style='color:darkgoldenrod;'>in progress</span>" },
-                    _ => { "<span style='color:red;'>unreported</span>" }
-                }
-            };
+            let result = display_run_result(run);
+
+            let last_seen = display_last_seen(ctx, run)?;
 
-            let entries = [repo_html.as_str(), last_build_time.as_str(), commit_html.as_str(), remote_html.as_str(), &duration, &status, &result];
+            let entries = [repo_html.as_str(), last_build_time.as_str(), commit_html.as_str(), remote_html.as_str(), &duration, &status, result, &last_seen];
             let entries = entries.iter().chain(std::iter::repeat(&"")).take(headings.len());
 
             let mut row_html = String::new();
@@ -283,8 +405,6 @@ pub fn build_repo_index(ctx: &Arc<DbCtx>) -> Result<String, String> {
             response.push_str(&row_html);
             response.push_str("</tr>");
             response.push('\n');
-
-            row_num += 1;
         }
 
         response.push_str("</table>\n");
@@ -1,11 +1,13 @@
 use crate::RunningJob;
 
+use ci_lib_core::protocol::TaskOutcome;
+
 use rlua::prelude::*;
 
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
-pub const DEFAULT_RUST_GOODFILE: &'static [u8] = include_bytes!("../../../config/goodfiles/rust.lua");
+pub const DEFAULT_RUST_GOODFILE: &[u8] = include_bytes!("../../../config/goodfiles/rust.lua");
 
 pub struct BuildEnv {
     lua: Lua,
@@ -30,6 +32,8 @@ mod lua_exports {
     use crate::RunningJob;
     use crate::lua::RunParams;
 
+    use ci_lib_core::sql::{MetricDirection, MetricUnit, MetricValue};
+
     use std::collections::HashMap;
     use std::sync::{Arc, Mutex};
     use std::path::PathBuf;
@@ -70,7 +74,7 @@ mod lua_exports {
                         None
                     },
                     _other => {
-                        return Err(LuaError::RuntimeError(format!("params[\"step\"] must be a string")));
+                        return Err(LuaError::RuntimeError("params[\"step\"] must be a string".to_string()));
                     }
                 };
                 let name = match table.get("name").expect("can get from table") {
@@ -81,7 +85,7 @@ mod lua_exports {
                         None
                     },
                     _other => {
-                        return Err(LuaError::RuntimeError(format!("params[\"name\"] must be a string")));
+                        return Err(LuaError::RuntimeError("params[\"name\"] must be a string".to_string()));
                     }
                 };
                 let cwd = match table.get("cwd").expect("can get from table") {
@@ -92,7 +96,7 @@ mod lua_exports {
                         None
                     },
                     _other => {
-                        return Err(LuaError::RuntimeError(format!("params[\"cwd\"] must be a string")));
+                        return Err(LuaError::RuntimeError("params[\"cwd\"] must be a string".to_string()));
                     }
                 };
                 let env = match table.get("env").expect("can get from table") {
@@ -108,7 +112,7 @@ mod lua_exports {
                         None
                     },
                     _other => {
-                        return Err(LuaError::RuntimeError(format!("params[\"env\"] must be a table")));
+                        return Err(LuaError::RuntimeError("params[\"env\"] must be a table".to_string()));
                     }
                 };
 
@@ -144,7 +148,7 @@ mod lua_exports {
             .build()
             .unwrap();
         rt.block_on(async move {
-            job_ctx.lock().unwrap().run_command(&args, params.cwd.as_ref().map(|x| x.as_str()), params._env).await
+            job_ctx.lock().unwrap().run_command(&args, params.cwd.as_deref(), params._env).await
                 .map_err(|e| LuaError::RuntimeError(format!("run_command error: {:?}", e)))
         })
     }
@@ -158,7 +162,7 @@ mod lua_exports {
             .build()
             .unwrap();
         let command_output = rt.block_on(async move {
-            job_ctx.lock().unwrap().run_with_output(&args, params.cwd.as_ref().map(|x| x.as_str()), params._env).await
+            job_ctx.lock().unwrap().run_with_output(&args, params.cwd.as_deref(), params._env).await
                 .map_err(|e| LuaError::RuntimeError(format!("run_command error: {:?}", e)))
         })?;
 
@@ -180,14 +184,70 @@ mod lua_exports {
             }
         }
 
-        if missing_deps.len() > 0 {
+        if !missing_deps.is_empty() {
             return Err(LuaError::RuntimeError(format!("missing dependencies: {}", missing_deps.join(", "))));
         }
 
         Ok(())
     }
 
-    pub fn metric(name: String, value: String, job_ctx: Arc<Mutex<Box<RunningJob>>>) -> Result<(), rlua::Error> {
+    /// `Build.metric(name, value, params)`. strings are reported as-is, as labels. numbers are
+    /// reported as numbers, optionally with `params.unit` (one of `ms`, `bytes`, `count`,
+    /// `instructions`) and `params.better` (`lower` or `higher`) saying which way is an
+    /// improvement.
+    pub fn metric(name: String, value: LuaValue, params: LuaValue, job_ctx: Arc<Mutex<Box<RunningJob>>>) -> Result<(), rlua::Error> {
+        let (unit, direction) = match params {
+            LuaValue::Table(table) => {
+                let unit = match table.get("unit").expect("can get from table") {
+                    LuaValue::String(v) => {
+                        Some(MetricUnit::try_from(v.to_str()?).map_err(LuaError::RuntimeError)?)
+                    },
+                    LuaValue::Nil => {
+                        None
+                    },
+                    _other => {
+                        return Err(LuaError::RuntimeError("params[\"unit\"] must be a string".to_string()));
+                    }
+                };
+                let direction = match table.get("better").expect("can get from table") {
+                    LuaValue::String(v) => {
+                        Some(MetricDirection::try_from(v.to_str()?).map_err(LuaError::RuntimeError)?)
+                    },
+                    LuaValue::Nil => {
+                        None
+                    },
+                    _other => {
+                        return Err(LuaError::RuntimeError("params[\"better\"] must be a string".to_string()));
+                    }
+                };
+                (unit, direction)
+            },
+            LuaValue::Nil => {
+                (None, None)
+            },
+            other => {
+                return Err(LuaError::RuntimeError(format!("argument 3 was not a table: {:?}", other)));
+            }
+        };
+
+        let value = match value {
+            LuaValue::Integer(v) => {
+                MetricValue::Number { value: v as f64, unit, direction }
+            },
+            LuaValue::Number(v) => {
+                MetricValue::Number { value: v, unit, direction }
+            },
+            LuaValue::String(v) => {
+                if unit.is_some() || direction.is_some() {
+                    return Err(LuaError::RuntimeError(format!("metric {} is a string, so it can't have a unit or direction", name)));
+                }
+                MetricValue::Text(v.to_str()?.to_owned())
+            },
+            other => {
+                return Err(LuaError::RuntimeError(format!("metric {} must be a number or a string, was {:?}", name, other)));
+            }
+        };
+
         let rt = tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()
@@ -235,6 +295,36 @@ mod lua_exports {
         })
     }
 
+    /// `Build.timeout(seconds)`. replaces the repo's default timeout for this run, still counted
+    /// from when the run started.
+    pub fn timeout(seconds: LuaValue, job_ctx: Arc<Mutex<Box<RunningJob>>>) -> Result<(), rlua::Error> {
+        let timeout_ms = match seconds {
+            LuaValue::Integer(v) if v > 0 => {
+                (v as u64).checked_mul(1000)
+            },
+            LuaValue::Number(v) if v > 0.0 && (v * 1000.0) < u64::MAX as f64 => {
+                Some((v * 1000.0) as u64)
+            },
+            LuaValue::Number(v) if v > 0.0 => {
+                None
+            },
+            other => {
+                return Err(LuaError::RuntimeError(format!("timeout must be a positive number of seconds, was {:?}", other)));
+            }
+        };
+        let timeout_ms = timeout_ms
+            .ok_or_else(|| LuaError::RuntimeError(format!("timeout of {:?} seconds is too long", seconds)))?;
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async move {
+            job_ctx.lock().unwrap().set_timeout(timeout_ms).await
+                .map_err(|e| LuaError::RuntimeError(format!("set_timeout error: {:?}", e)))
+        })
+    }
+
     pub fn has_cmd(name: &str) -> Result<bool, rlua::Error> {
         Ok(std::process::Command::new("which")
             .arg(name)
@@ -244,7 +334,7 @@ mod lua_exports {
     }
 
     pub fn file_size(path: &str) -> Result<u64, rlua::Error> {
-        Ok(std::fs::metadata(&format!("tmpdir/{}", path))
+        Ok(std::fs::metadata(format!("tmpdir/{}", path))
             .map_err(|_e| LuaError::RuntimeError(format!("could not stat {:?}", path)))?
             .len())
     }
@@ -318,8 +408,10 @@ impl BuildEnv {
             Ok(())
         })?;
 
-        let check_dependencies = decl_env.create_function("dependencies", move |_, _job_ref, commands: Vec<String>| {
+        let check_dependencies = decl_env.create_function("dependencies", move |_, job_ref, commands: Vec<String>| {
+            // a runner without the tools a build needs can't build it, but another runner might.
             lua_exports::check_dependencies(commands)
+                .map_err(|e| job_ref.lock().unwrap().fail(TaskOutcome::InfraError, e))
         })?;
 
         let build = decl_env.create_function("build", move |_, job_ref, (command, params): (LuaValue, LuaValue)| {
@@ -330,8 +422,12 @@ impl BuildEnv {
             lua_exports::check_output_impl(ctx, command, params, job_ref)
         })?;
 
-        let metric = decl_env.create_function("metric", move |_, job_ref, (name, value): (String, String)| {
-            lua_exports::metric(name, value, job_ref)
+        let metric = decl_env.create_function("metric", move |_, job_ref, (name, value, params): (String, LuaValue, LuaValue)| {
+            lua_exports::metric(name, value, params, job_ref)
+        })?;
+
+        let timeout = decl_env.create_function("timeout", move |_, job_ref, seconds: LuaValue| {
+            lua_exports::timeout(seconds, job_ref)
         })?;
 
         let now_ms = decl_env.create_function("now_ms", move |_, _job_ref, ()| Ok(ci_lib_core::now_ms()))?;
@@ -340,7 +436,8 @@ impl BuildEnv {
             lua_exports::artifact(path, name, job_ref)
         })?;
 
-        let error = decl_env.create_function("error", move |_, _job_ref, msg: String| {
+        let error = decl_env.create_function("error", move |_, job_ref, msg: String| {
+            job_ref.lock().unwrap().failure = Some(TaskOutcome::BuildFailed);
             Err::<(), LuaError>(LuaError::RuntimeError(format!("explicit error: {}", msg)))
         })?;
 
@@ -382,6 +479,7 @@ impl BuildEnv {
                 ("artifact", artifact),
                 ("now_ms", now_ms),
                 ("check_output", check_output),
+                ("timeout", timeout),
             ]
         ).unwrap();
         build_functions.set("environment", build_environment).unwrap();
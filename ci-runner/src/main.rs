@@ -1,7 +1,11 @@
-use std::time::Duration;
+// the running job is shared with lua behind a `std::sync::Mutex`, and every step of a job runs
+// while holding that lock. jobs are strictly sequential, so this is (for now) fine.
+#![allow(clippy::await_holding_lock)]
+
+use std::time::{Duration, Instant};
 use std::os::unix::process::ExitStatusExt;
 use rlua::prelude::LuaError;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, Weak};
 use reqwest::{StatusCode, Response};
 use tokio::process::Command;
 use std::collections::HashMap;
@@ -12,10 +16,16 @@ use tokio::fs::OpenOptions;
 use serde::{Deserialize, de::DeserializeOwned, Serialize};
 use std::marker::Unpin;
 use std::path::PathBuf;
+use futures_util::{SinkExt, StreamExt};
+use futures_util::stream::{SplitSink, SplitStream};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
 
 use ci_lib_native::io;
 use ci_lib_native::io::{ArtifactStream, VecSink};
-use ci_lib_core::protocol::{ClientProto, CommandInfo, TaskInfo, RequestedJob};
+use ci_lib_core::protocol::{self, ClientProto, CommandInfo, FrameDecoder, HeartbeatInfo, Negotiated, PROTOCOL_VERSIONS, TaskInfo, TaskOutcome, TerminateReason, RequestedJob};
+use ci_lib_core::sql::MetricValue;
 
 mod lua;
 
@@ -24,7 +34,10 @@ use crate::lua::CommandOutput;
 #[allow(dead_code)]
 #[derive(Debug)]
 enum WorkAcquireError {
+    /// the driver couldn't be reached at all.
+    Unreachable(String),
     Reqwest(reqwest::Error),
+    WebSocket(tungstenite::Error),
     EarlyEof,
     Protocol(String),
 }
@@ -38,7 +51,8 @@ trait Runner: Send + Sync + 'static {
     async fn report_start(&mut self) -> Result<(), String>;
     async fn report_task_status(&mut self, status: TaskInfo) -> Result<(), String>;
     async fn report_command_info(&mut self, info: CommandInfo) -> Result<(), String>;
-    async fn send_metric(&mut self, name: &str, value: String) -> Result<(), String>;
+    async fn send_metric(&mut self, name: &str, value: MetricValue) -> Result<(), String>;
+    async fn report_timeout(&mut self, timeout_ms: u64) -> Result<(), String>;
     async fn create_artifact(&self, name: &str, desc: &str, build_token: &str) -> Result<Box<dyn AsyncWrite + Unpin + Send>, String>;
 }
 
@@ -64,8 +78,12 @@ impl Runner for LocalRunner {
         println!("command info: {:?}", info);
         Ok(())
     }
-    async fn send_metric(&mut self, name: &str, value: String) -> Result<(), String> {
-        println!("metric reported: {} = {}", name, value);
+    async fn send_metric(&mut self, name: &str, value: MetricValue) -> Result<(), String> {
+        println!("metric reported: {} = {:?}", name, value);
+        Ok(())
+    }
+    async fn report_timeout(&mut self, timeout_ms: u64) -> Result<(), String> {
+        println!("timeout set: {}ms", timeout_ms);
         Ok(())
     }
     async fn create_artifact(&self, name: &str, _desc: &str, _build_token: &str) -> Result<Box<dyn AsyncWrite + Unpin + Send>, String> {
@@ -75,6 +93,7 @@ impl Runner for LocalRunner {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(true)
             .open(path)
             .await
             .map_err(|e| format!("error opening file to store artifact {}: {:?}", name, e))?;
@@ -88,8 +107,11 @@ struct RemoteServerRunner {
     http: reqwest::Client,
     #[allow(dead_code)]
     host: String,
-    tx: hyper::body::Sender,
-    rx: Response,
+    // shared with the task sending heartbeats while a job runs.
+    tx: Arc<tokio::sync::Mutex<DriverSink>>,
+    // taken by the task listening for the driver once a job starts.
+    rx: Option<DriverStream>,
+    protocol: Negotiated,
     #[allow(dead_code)]
     current_job: Option<RequestedJob>,
 }
@@ -108,11 +130,16 @@ impl Runner for RemoteServerRunner {
             .await
             .map_err(|e| format!("failed to report command info: {:?})", e))
     }
-    async fn send_metric(&mut self, name: &str, value: String) -> Result<(), String> {
+    async fn send_metric(&mut self, name: &str, value: MetricValue) -> Result<(), String> {
         self.send_typed(&ClientProto::metric(name, value))
             .await
             .map_err(|e| format!("failed to send metric {}: {:?})", name, e))
     }
+    async fn report_timeout(&mut self, timeout_ms: u64) -> Result<(), String> {
+        self.send_typed(&ClientProto::set_timeout(timeout_ms))
+            .await
+            .map_err(|e| format!("failed to report timeout: {:?})", e))
+    }
     // TODO: panics if hyper finds the channel is closed. hum
     async fn create_artifact(&self, name: &str, desc: &str, build_token: &str) -> Result<Box<dyn AsyncWrite + Unpin + Send>, String> {
         let (sender, body) = hyper::Body::channel();
@@ -145,20 +172,35 @@ impl RunningJob {
             std::fs::remove_dir_all("ci_working_dir").unwrap();
         }
         std::fs::create_dir(&working_dir).expect("can create artifacts working dir");
+        let control = Arc::new(JobControl::new(job.timeout_ms));
         Self {
             job,
+            control,
             runner_ctx: Box::new(LocalRunner {
                 working_dir,
                 current_job: None,
             }) as Box<dyn Runner>,
             current_step: StepTracker::new(),
+            next_command_id: 1,
+            failure: None,
         }
     }
-    fn remote_from_job(job: RequestedJob, client: RemoteServerRunner) -> Self {
+    fn remote_from_job(job: RequestedJob, mut client: RemoteServerRunner) -> Self {
+        let control = Arc::new(JobControl::new(job.timeout_ms));
+        let current_step = StepTracker::new();
+        if let Some(rx) = client.rx.take() {
+            tokio::spawn(listen_for_driver(rx, Arc::clone(&control)));
+        }
+        if client.protocol.has_feature(protocol::FEATURE_HEARTBEAT) {
+            tokio::spawn(send_heartbeats(Arc::downgrade(&client.tx), Arc::clone(&control), current_step.clone()));
+        }
         Self {
             job,
+            control,
             runner_ctx: Box::new(client) as Box<dyn Runner>,
-            current_step: StepTracker::new(),
+            current_step,
+            next_command_id: 1,
+            failure: None,
         }
     }
 }
@@ -190,8 +232,13 @@ impl JobEnv {
 
 pub struct RunningJob {
     job: RequestedJob,
+    control: Arc<JobControl>,
     runner_ctx: Box<dyn Runner>,
     current_step: StepTracker,
+    // the id the next command run for this job is reported with.
+    next_command_id: u32,
+    // what went wrong most recently, if anything. if the goodfile fails, this is why.
+    failure: Option<TaskOutcome>,
 }
 
 #[allow(dead_code)]
@@ -201,36 +248,286 @@ enum RepoError {
     CheckoutFailedMissingRef,
 }
 
+/// the steps a job is in. clones share the same steps, so the task sending heartbeats can see where
+/// the job is while lua holds the lock on `RunningJob`.
+#[derive(Clone)]
 pub struct StepTracker {
-    scopes: Vec<String>
+    scopes: Arc<Mutex<Vec<String>>>
+}
+
+impl Default for StepTracker {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl StepTracker {
     pub fn new() -> Self {
         StepTracker {
-            scopes: Vec::new()
+            scopes: Arc::new(Mutex::new(Vec::new()))
         }
     }
 
     pub fn push(&mut self, name: String) {
-        self.scopes.push(name);
+        self.scopes.lock().unwrap().push(name);
     }
 
     pub fn pop(&mut self) {
-        self.scopes.pop();
+        self.scopes.lock().unwrap().pop();
     }
 
     pub fn clear(&mut self) {
-        self.scopes.clear();
+        self.scopes.lock().unwrap().clear();
+    }
+
+    pub fn full_step_path(&self) -> Vec<String> {
+        self.scopes.lock().unwrap().clone()
+    }
+}
+
+/// how long past its deadline a job may keep running before the runner kills it without being
+/// told to. normally the driver's `Terminate` arrives well before this.
+const LOCAL_TIMEOUT_GRACE: Duration = Duration::from_secs(30);
+
+/// the deadline a job runs under and, once it has been stopped, why. this is shared between the
+/// job, the task listening for the driver, and the watchdog enforcing the deadline locally, so
+/// a job can be stopped even while lua holds the lock on `RunningJob`.
+pub struct JobControl {
+    started: Instant,
+    timeout: Mutex<Option<Duration>>,
+    terminated: Mutex<Option<TerminateReason>>,
+    // the process group of the command currently running, if any.
+    current_pgid: Mutex<Option<i32>>,
+}
+
+impl JobControl {
+    fn new(timeout_ms: Option<u64>) -> Self {
+        JobControl {
+            started: Instant::now(),
+            timeout: Mutex::new(timeout_ms.map(Duration::from_millis)),
+            terminated: Mutex::new(None),
+            current_pgid: Mutex::new(None),
+        }
+    }
+
+    fn set_timeout(&self, timeout_ms: u64) {
+        *self.timeout.lock().unwrap() = Some(Duration::from_millis(timeout_ms));
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.timeout.lock().unwrap().map(|timeout| self.started + timeout)
+    }
+
+    fn terminated(&self) -> Option<TerminateReason> {
+        *self.terminated.lock().unwrap()
+    }
+
+    /// stop the job: kill whatever command it's running, and refuse to start any more.
+    fn terminate(&self, reason: TerminateReason) {
+        let mut terminated = self.terminated.lock().unwrap();
+        if terminated.is_some() {
+            return;
+        }
+        eprintln!("[!] terminating job: {:?}", reason);
+        *terminated = Some(reason);
+        std::mem::drop(terminated);
+
+        if let Some(pgid) = *self.current_pgid.lock().unwrap() {
+            Self::kill_group(pgid);
+        }
+    }
+
+    /// record the process group a command was just spawned into. if the job was terminated in
+    /// the meantime, the group is killed right away.
+    fn running(&self, pgid: Option<i32>) {
+        *self.current_pgid.lock().unwrap() = pgid;
+        if let (Some(pgid), Some(_)) = (pgid, self.terminated()) {
+            Self::kill_group(pgid);
+        }
+    }
+
+    fn kill_group(pgid: i32) {
+        eprintln!("[!] killing process group {}", pgid);
+        if unsafe { libc::killpg(pgid, libc::SIGKILL) } != 0 {
+            eprintln!("[-] could not kill process group {}: {}", pgid, std::io::Error::last_os_error());
+        }
+    }
+}
+
+/// how to connect to the driver. both carry the same messages; a websocket is better at getting
+/// through reverse proxies, and the driver pings it to keep it open while a job is quiet.
+#[derive(Deserialize, Serialize, Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Transport {
+    /// a long-lived `POST /api/next_job`.
+    #[default]
+    Http,
+    /// a websocket to `/api/next_job/ws`.
+    WebSocket,
+}
+
+type DriverSocket = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// messages to the driver.
+enum DriverSink {
+    /// the body of the request to `/api/next_job`.
+    Http(hyper::body::Sender),
+    /// messages for `forward_to_websocket`, which closes the websocket once this is dropped.
+    WebSocket(mpsc::Sender<tungstenite::Message>),
+}
+
+impl DriverSink {
+    async fn send_frame(&mut self, frame: String) -> Result<(), String> {
+        match self {
+            DriverSink::Http(sender) => {
+                sender.send_data(frame.into())
+                    .await
+                    .map_err(|e| format!("send error: {:?}", e))
+            }
+            DriverSink::WebSocket(sender) => {
+                sender.send(tungstenite::Message::Text(frame))
+                    .await
+                    .map_err(|e| format!("send error: {:?}", e))
+            }
+        }
+    }
+}
+
+// everything sent over a websocket goes through here, so that the socket is closed, and the driver
+// knows we're done, once the last `DriverSink` is gone.
+async fn forward_to_websocket(mut rx: mpsc::Receiver<tungstenite::Message>, mut sink: SplitSink<DriverSocket, tungstenite::Message>) {
+    while let Some(msg) = rx.recv().await {
+        if let Err(e) = sink.send(msg).await {
+            eprintln!("[-] could not send to the driver: {:?}", e);
+            return;
+        }
+    }
+    if let Err(e) = sink.close().await {
+        eprintln!("[-] could not close connection to the driver: {:?}", e);
+    }
+}
+
+/// messages from the driver, read off the response to `/api/next_job` or from the websocket.
+enum DriverStream {
+    Http {
+        res: Response,
+        frames: FrameDecoder,
+    },
+    WebSocket(SplitStream<DriverSocket>),
+}
+
+impl DriverStream {
+    /// the next whole message the driver sent, or `None` if it hung up between messages.
+    async fn recv_frame(&mut self) -> Result<Option<Vec<u8>>, String> {
+        match self {
+            DriverStream::Http { res, frames } => {
+                loop {
+                    if let Some(frame) = frames.next_frame().map_err(|e| e.to_string())? {
+                        return Ok(Some(frame));
+                    }
+
+                    match res.chunk().await {
+                        Ok(Some(chunk)) => {
+                            frames.push(&chunk);
+                        }
+                        Ok(None) => {
+                            frames.finish().map_err(|e| e.to_string())?;
+                            return Ok(None);
+                        }
+                        Err(e) => {
+                            return Err(format!("error in recv: {:?}", e));
+                        }
+                    }
+                }
+            }
+            DriverStream::WebSocket(stream) => {
+                loop {
+                    match stream.next().await {
+                        Some(Ok(tungstenite::Message::Text(text))) => return Ok(Some(text.into_bytes())),
+                        Some(Ok(tungstenite::Message::Binary(bytes))) => return Ok(Some(bytes)),
+                        Some(Ok(tungstenite::Message::Close(_))) | None => return Ok(None),
+                        // tungstenite answers pings itself.
+                        Some(Ok(_)) => continue,
+                        Some(Err(e)) => return Err(format!("error in recv: {:?}", e)),
+                    }
+                }
+            }
+        }
+    }
+}
+
+// while a job runs, the only thing the driver has to say is that the job should stop.
+async fn listen_for_driver(mut rx: DriverStream, control: Arc<JobControl>) {
+    loop {
+        match rx.recv_frame().await {
+            Ok(Some(frame)) => {
+                match serde_json::from_slice::<ClientProto>(&frame) {
+                    Ok(ClientProto::Terminate { reason }) => {
+                        control.terminate(reason);
+                    }
+                    Ok(other) => {
+                        eprintln!("[!] unexpected message during a job: {:?}", other);
+                    }
+                    Err(e) => {
+                        eprintln!("[-] not json: {:?}", e);
+                    }
+                }
+            }
+            Ok(None) => {
+                return;
+            }
+            Err(e) => {
+                eprintln!("[-] lost connection to the driver: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+// tell the driver the job is still alive, and where it's at. this stops once the job has let go of
+// its connection to the driver.
+async fn send_heartbeats(tx: Weak<tokio::sync::Mutex<DriverSink>>, control: Arc<JobControl>, step: StepTracker) {
+    let mut interval = tokio::time::interval(Duration::from_millis(protocol::HEARTBEAT_INTERVAL_MS));
+    loop {
+        interval.tick().await;
+
+        let tx = match tx.upgrade() {
+            Some(tx) => tx,
+            None => {
+                return;
+            }
+        };
+
+        let heartbeat = ClientProto::heartbeat(HeartbeatInfo {
+            step: step.full_step_path(),
+            elapsed_ms: control.started.elapsed().as_millis() as u64,
+            load_avg: host_info::load_avg(),
+            mem_available_kb: host_info::mem_available_kb(),
+        });
+        let frame = protocol::encode_frame(&heartbeat).expect("can encode heartbeat");
+        let res = tx.lock().await.send_frame(frame).await;
+        if let Err(e) = res {
+            eprintln!("[-] could not send heartbeat: {:?}", e);
+            return;
+        }
     }
+}
 
-    pub fn full_step_path(&self) -> &[String] {
-        self.scopes.as_slice()
+// the driver enforces timeouts, but if it can't be reached the job still has to stop.
+async fn watchdog(control: Arc<JobControl>) {
+    loop {
+        if let Some(deadline) = control.deadline() {
+            if Instant::now() >= deadline + LOCAL_TIMEOUT_GRACE {
+                control.terminate(TerminateReason::TimedOut);
+                return;
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
     }
 }
 
 impl RunningJob {
-    async fn send_metric(&mut self, name: &str, value: String) -> Result<(), String> {
+    async fn send_metric(&mut self, name: &str, value: MetricValue) -> Result<(), String> {
         self.runner_ctx.send_metric(name, value).await
     }
 
@@ -238,6 +535,11 @@ impl RunningJob {
         self.runner_ctx.create_artifact(name, desc, &self.job.build_token).await
     }
 
+    async fn set_timeout(&mut self, timeout_ms: u64) -> Result<(), String> {
+        self.control.set_timeout(timeout_ms);
+        self.runner_ctx.report_timeout(timeout_ms).await
+    }
+
     async fn clone_remote(&self) -> Result<(), RepoError> {
         let mut git_clone = Command::new("git");
         git_clone
@@ -307,8 +609,23 @@ impl RunningJob {
     }
 
     async fn execute_command(&self, mut command: Command, name: &str, _desc: &str, mut stdout_reporter: impl AsyncWrite + Unpin + Send + 'static, mut stderr_reporter: impl AsyncWrite + Unpin + Send + 'static) -> Result<ExitStatus, String> {
+        if let Some(reason) = self.control.terminated() {
+            return Err(format!("not running '{}', job was terminated: {:?}", name, reason));
+        }
+
         eprintln!("[.] running {}: {:?}", name, command);
 
+        // each command gets its own process group so that terminating the job kills everything
+        // the command started, not just the command itself.
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setpgid(0, 0) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+
         let mut child = command
             .stdin(Stdio::null())
             .stdout(Stdio::piped())
@@ -316,16 +633,30 @@ impl RunningJob {
             .spawn()
             .map_err(|e| format!("failed to spawn '{}', {:?}", name, e))?;
 
+        self.control.running(child.id().map(|pid| pid as i32));
+
         let mut child_stdout = child.stdout.take().unwrap();
         let mut child_stderr = child.stderr.take().unwrap();
 
         eprintln!("[.] '{}': forwarding stdout", name);
-        tokio::spawn(async move { io::forward_data(&mut child_stdout, &mut stdout_reporter).await });
+        let stdout_forward = tokio::spawn(async move { io::forward_data(&mut child_stdout, &mut stdout_reporter).await });
         eprintln!("[.] '{}': forwarding stderr", name);
-        tokio::spawn(async move { io::forward_data(&mut child_stderr, &mut stderr_reporter).await });
+        let stderr_forward = tokio::spawn(async move { io::forward_data(&mut child_stderr, &mut stderr_reporter).await });
 
         let res = child.wait().await
-            .map_err(|e| format!("failed to wait? {:?}", e))?;
+            .map_err(|e| format!("failed to wait? {:?}", e));
+
+        self.control.running(None);
+
+        let res = res?;
+
+        if let Some(reason) = self.control.terminated() {
+            // the whole process group is gone, so its output is complete. make sure it's uploaded
+            // before the job reports that it stopped.
+            let _ = stdout_forward.await;
+            let _ = stderr_forward.await;
+            return Err(format!("'{}' was killed, job was terminated: {:?}", name, reason));
+        }
 
         if res.success() {
             eprintln!("[+] '{}' success", name);
@@ -339,6 +670,9 @@ impl RunningJob {
     async fn run(mut self) {
         self.runner_ctx.report_start().await.unwrap();
 
+        let control = Arc::clone(&self.control);
+        let watchdog = tokio::spawn(watchdog(Arc::clone(&control)));
+
         if std::path::Path::new("tmpdir").exists() {
             eprintln!("[!] removing prior tmpdir to rebuild into");
             std::fs::remove_dir_all("tmpdir").unwrap();
@@ -349,30 +683,49 @@ impl RunningJob {
 
         let checkout_res = ctx.lock().unwrap().clone_remote().await;
 
-        if let Err(_e) = checkout_res {
-            let status = "bad_ref";
-            let status = TaskInfo::finished(status);
+        if let (Err(_), Some(reason)) = (&checkout_res, control.terminated()) {
+            let status = TaskInfo::interrupted(reason.outcome(), "terminated", format!("job was terminated: {:?}", reason));
+            let res = ctx.lock().unwrap().runner_ctx.report_task_status(status).await;
+            if let Err(e) = res {
+                eprintln!("[!] FAILED TO REPORT JOB STATUS (terminated): {:?}", e);
+            }
+
+            watchdog.abort();
+            return;
+        }
+
+        if let Err(e) = checkout_res {
+            // a clone that fails is more likely the runner's network than the commit, but a
+            // commit that can't be checked out is just as bad on any other runner.
+            let outcome = match e {
+                RepoError::CloneFailedIdk { .. } => TaskOutcome::InfraError,
+                RepoError::CheckoutFailedIdk { .. } |
+                RepoError::CheckoutFailedMissingRef => TaskOutcome::CheckoutFailed,
+            };
+            let status = TaskInfo::finished(outcome, "bad_ref");
             eprintln!("checkout failed, reporting status: {:?}", status);
 
             let res = ctx.lock().unwrap().runner_ctx.report_task_status(status).await;
             if let Err(e) = res {
-                eprintln!("[!] FAILED TO REPORT JOB STATUS ({}): {:?}", "success", e);
+                eprintln!("[!] FAILED TO REPORT JOB STATUS (success): {:?}", e);
             }
 
+            watchdog.abort();
             return;
         }
 
         let lua_env = JobEnv::new(&ctx);
 
         let metadata = std::fs::metadata("./tmpdir/goodfile");
-        let res: Result<String, (String, String)> = match metadata {
+        let res: Result<String, (TaskOutcome, String, String)> = match metadata {
             Ok(_) => {
                 match lua_env.exec_goodfile().await {
                     Ok(()) => {
                         Ok("pass".to_string())
                     },
                     Err(lua_err) => {
-                        Err(("failed".to_string(), lua_err.to_string()))
+                        let outcome = ctx.lock().unwrap().failure.unwrap_or(TaskOutcome::GoodfileError);
+                        Err((outcome, "failed".to_string(), lua_err.to_string()))
                     }
                 }
             },
@@ -382,30 +735,40 @@ impl RunningJob {
                         Ok("pass".to_string())
                     },
                     Err(lua_err) => {
-                        Err(("failed".to_string(), lua_err.to_string()))
+                        let outcome = ctx.lock().unwrap().failure.unwrap_or(TaskOutcome::GoodfileError);
+                        Err((outcome, "failed".to_string(), lua_err.to_string()))
                     }
                 }
             },
             Err(e) => {
                 eprintln!("[-] error finding goodfile: {:?}", e);
-                Err(("failed".to_string(), "inaccessible goodfile".to_string()))
+                Err((TaskOutcome::GoodfileError, "failed".to_string(), "inaccessible goodfile".to_string()))
             }
         };
 
+        watchdog.abort();
+
+        // whatever the goodfile made of its commands being killed, the job stopped because it was
+        // terminated.
+        let res = match control.terminated() {
+            Some(reason) => Err((reason.outcome(), "terminated".to_string(), format!("job was terminated: {:?}", reason))),
+            None => res,
+        };
+
         match res {
             Ok(status) => {
                 eprintln!("[+] job success!");
-                let status = TaskInfo::finished(status);
+                let status = TaskInfo::finished(TaskOutcome::Passed, status);
                 eprintln!("reporting status: {:?}", status);
 
                 let res = ctx.lock().unwrap().runner_ctx.report_task_status(status).await;
                 if let Err(e) = res {
-                    eprintln!("[!] FAILED TO REPORT JOB STATUS ({}): {:?}", "success", e);
+                    eprintln!("[!] FAILED TO REPORT JOB STATUS (success): {:?}", e);
                 }
             }
-            Err((status, lua_err)) => {
-                eprintln!("[-] job error: {}", status);
-                let status = TaskInfo::interrupted(status, lua_err.to_string());
+            Err((outcome, status, lua_err)) => {
+                eprintln!("[-] job error: {} ({})", status, outcome.summary());
+                let status = TaskInfo::interrupted(outcome, status, lua_err);
 
                 let res = ctx
                     .lock()
@@ -442,27 +805,47 @@ impl RunningJob {
         (cmd, human_name)
     }
 
+    // note that the job is failing because of `outcome`, passing `err` along.
+    fn fail<E>(&mut self, outcome: TaskOutcome, err: E) -> E {
+        self.failure = Some(outcome);
+        err
+    }
+
     async fn run_with_output(&mut self, command: &[String], working_dir: Option<&str>, env: Option<HashMap<String, String>>) -> Result<CommandOutput, String> {
         let (cmd, human_name) = Self::prep_command(command, working_dir, env);
 
-        let cmd_res = self.execute_command_capture_output(cmd, &format!("{} log", human_name), &human_name).await?;
+        let cmd_res = self.execute_command_capture_output(cmd, &format!("{} log", human_name), &human_name).await
+            .map_err(|e| self.fail(TaskOutcome::InfraError, e))?;
 
         if !cmd_res.exit_status.success() {
+            self.failure = Some(TaskOutcome::BuildFailed);
             return Err(format!("{} failed: {:?}", &human_name, cmd_res.exit_status));
         }
         Ok(cmd_res)
     }
 
     async fn run_command(&mut self, command: &[String], working_dir: Option<&str>, env: Option<HashMap<String, String>>) -> Result<(), String> {
-        self.runner_ctx.report_command_info(CommandInfo::started(command, working_dir, 1)).await.unwrap();
+        let command_id = self.next_command_id;
+        self.next_command_id += 1;
 
         let (cmd, human_name) = Self::prep_command(command, working_dir, env);
 
-        let cmd_res = self.execute_command_and_report(cmd, &format!("{} log", human_name), &human_name).await?;
+        let name = format!("{} log", human_name);
+        let stdout_name = format!("{} (stdout)", name);
+        let stderr_name = format!("{} (stderr)", name);
+        let stdout_artifact = self.create_artifact(&stdout_name, &format!("{} (stdout)", human_name)).await.expect("works");
+        let stderr_artifact = self.create_artifact(&stderr_name, &format!("{} (stderr)", human_name)).await.expect("works");
+
+        let started = CommandInfo::started(command, working_dir, command_id, &self.current_step.full_step_path(), Some((&stdout_name, &stderr_name)));
+        self.runner_ctx.report_command_info(started).await.unwrap();
+
+        let cmd_res = self.execute_command(cmd, &name, &human_name, stdout_artifact, stderr_artifact).await
+            .map_err(|e| self.fail(TaskOutcome::InfraError, e))?;
 
-        self.runner_ctx.report_command_info(CommandInfo::finished(cmd_res.code(), 1)).await.unwrap();
+        self.runner_ctx.report_command_info(CommandInfo::finished(cmd_res.code(), command_id)).await.unwrap();
 
         if !cmd_res.success() {
+            self.failure = Some(TaskOutcome::BuildFailed);
             return Err(format!("{} failed: {:?}", &human_name, cmd_res));
         }
 
@@ -471,15 +854,26 @@ impl RunningJob {
 }
 
 impl RemoteServerRunner {
-    async fn new(host: &str, sender: hyper::body::Sender, mut res: Response) -> Result<Self, String> {
-        if res.status() != StatusCode::OK {
-            return Err(format!("server returned a bad response: {:?}, response itself: {:?}", res.status(), res));
-        }
-
-        let hello = res.chunk().await.expect("chunk");
-        if hello.as_ref().map(|x| &x[..]) != Some(b"hello") {
-            return Err(format!("bad hello: {:?}", hello));
-        }
+    async fn new(host: &str, sender: DriverSink, mut rx: DriverStream) -> Result<Self, String> {
+        let hello = rx.recv_frame().await?.map(|frame| serde_json::from_slice::<ClientProto>(&frame));
+        let protocol = match hello {
+            Some(Ok(ClientProto::Hello { version, features })) => {
+                if !PROTOCOL_VERSIONS.contains(&version) {
+                    return Err(format!("driver picked protocol version {}, which this runner doesn't speak", version));
+                }
+                Negotiated { version, features }
+            }
+            Some(Ok(ClientProto::Rejected { reason })) => {
+                return Err(format!("driver rejected this runner: {}", reason));
+            }
+            None => {
+                return Err("driver hung up without saying hello. it may not speak this runner's protocol".to_string());
+            }
+            other => {
+                return Err(format!("bad hello: {:?}", other));
+            }
+        };
+        eprintln!("[+] speaking protocol version {} with features {:?}", protocol.version, protocol.features);
 
         Ok(Self {
             http: reqwest::ClientBuilder::new()
@@ -488,8 +882,9 @@ impl RemoteServerRunner {
                 .build()
                 .expect("can build client"),
             host: host.to_string(),
-            tx: sender,
-            rx: res,
+            tx: Arc::new(tokio::sync::Mutex::new(sender)),
+            rx: Some(rx),
+            protocol,
             current_job: None,
         })
     }
@@ -526,28 +921,23 @@ impl RemoteServerRunner {
     }
 
     async fn recv_typed<T: DeserializeOwned>(&mut self) -> Result<Option<T>, String> {
-        match self.rx.chunk().await {
-            Ok(Some(chunk)) => {
-                serde_json::from_slice(&chunk)
+        let rx = self.rx.as_mut().ok_or_else(|| "connection is owned by a running job".to_string())?;
+        match rx.recv_frame().await? {
+            Some(frame) => {
+                serde_json::from_slice(&frame)
                     .map(Option::Some)
                     .map_err(|e| {
                         format!("not json: {:?}", e)
                     })
             },
-            Ok(None) => Ok(None),
-            Err(e) => {
-                Err(format!("error in recv: {:?}", e))
-            }
+            None => Ok(None),
         }
     }
 
     async fn send_typed<T: Serialize>(&mut self, t: &T) -> Result<(), String> {
-        self.tx.send_data(
-            serde_json::to_vec(t)
-                .map_err(|e| format!("json error: {:?}", e))?
-                .into()
-        ).await
-            .map_err(|e| format!("send error: {:?}", e))
+        let frame = protocol::encode_frame(t)
+            .map_err(|e| format!("json error: {:?}", e))?;
+        self.tx.lock().await.send_frame(frame).await
     }
 }
 
@@ -556,6 +946,98 @@ struct RunnerConfig {
     server_address: String,
     auth_secret: String,
     allowed_pushers: Option<Vec<String>>,
+    /// where this runner's identity is kept. defaults to `runner_id` next to the runner config.
+    identity_path: Option<String>,
+    /// how to connect to the driver. defaults to `http`.
+    #[serde(default)]
+    transport: Transport,
+}
+
+// ask for work with a long-lived `POST /api/next_job`.
+async fn connect_http(client: &reqwest::Client, server_address: &str, auth_secret: &str, request: &ClientProto) -> Result<(DriverSink, DriverStream), WorkAcquireError> {
+    let (mut sender, body) = hyper::Body::channel();
+
+    sender.send_data(protocol::encode_frame(request).unwrap().into()).await.expect("req");
+
+    let res = client.post(format!("https://{}/api/next_job", server_address))
+        .header("user-agent", "ci-butactuallyin-space-runner")
+        .header("authorization", auth_secret.trim())
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| {
+            if e.is_connect() {
+                WorkAcquireError::Unreachable(e.to_string())
+            } else {
+                WorkAcquireError::Reqwest(e)
+            }
+        })?;
+
+    // a runner the driver won't talk to, or won't let act as this host, still gets a body saying why.
+    if ![StatusCode::OK, StatusCode::UPGRADE_REQUIRED, StatusCode::FORBIDDEN].contains(&res.status()) {
+        return Err(WorkAcquireError::Protocol(format!("server returned a bad response: {:?}, response itself: {:?}", res.status(), res)));
+    }
+
+    Ok((DriverSink::Http(sender), DriverStream::Http { res, frames: FrameDecoder::new() }))
+}
+
+// ask for work over a websocket to `/api/next_job/ws`.
+async fn connect_websocket(server_address: &str, auth_secret: &str, request: &ClientProto) -> Result<(DriverSink, DriverStream), WorkAcquireError> {
+    let mut ws_request = format!("wss://{}/api/next_job/ws", server_address)
+        .into_client_request()
+        .map_err(WorkAcquireError::WebSocket)?;
+    let auth_secret = auth_secret.trim().parse()
+        .map_err(|e| WorkAcquireError::Protocol(format!("auth secret is not a valid header: {:?}", e)))?;
+    ws_request.headers_mut().insert("user-agent", tungstenite::http::HeaderValue::from_static("ci-butactuallyin-space-runner"));
+    ws_request.headers_mut().insert("authorization", auth_secret);
+
+    let (socket, _) = tokio_tungstenite::connect_async(ws_request)
+        .await
+        .map_err(|e| match e {
+            tungstenite::Error::Io(e) => WorkAcquireError::Unreachable(e.to_string()),
+            other => WorkAcquireError::WebSocket(other),
+        })?;
+
+    let (sink, stream) = socket.split();
+    let (sender, receiver) = mpsc::channel(8);
+    tokio::spawn(forward_to_websocket(receiver, sink));
+
+    let mut sender = DriverSink::WebSocket(sender);
+    sender.send_frame(protocol::encode_frame(request).unwrap()).await
+        .map_err(WorkAcquireError::Protocol)?;
+
+    Ok((sender, DriverStream::WebSocket(stream)))
+}
+
+// the identity is generated the first time a runner starts and reused from then on, so the driver
+// sees the same runner no matter what happens to the hardware under it.
+fn load_or_create_identity(path: &std::path::Path) -> String {
+    match std::fs::read_to_string(path) {
+        Ok(identity) if !identity.trim().is_empty() => {
+            return identity.trim().to_string();
+        }
+        Ok(_) => {
+            eprintln!("[!] runner identity at {} is empty, generating a new one", path.display());
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            eprintln!("[.] no runner identity at {}, generating one", path.display());
+        }
+        Err(e) => {
+            panic!("could not read runner identity at '{}': {}", path.display(), e);
+        }
+    }
+
+    let mut data = [0u8; 16];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut f| std::io::Read::read_exact(&mut f, &mut data))
+        .expect("can read /dev/urandom");
+    let identity: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+
+    std::fs::write(path, &identity).unwrap_or_else(|e| {
+        panic!("could not write runner identity to '{}': {}", path.display(), e);
+    });
+
+    identity
 }
 
 #[tokio::main]
@@ -581,7 +1063,7 @@ async fn run_local(config_path: String) {
     // TODO: check for uncommitted changes, warn that these will not be cloned or built...
     let log_output = Command::new("git")
         .current_dir(repo)
-        .args(&["log", "--pretty=format:%H", "-n", "1"])
+        .args(["log", "--pretty=format:%H", "-n", "1"])
         .output()
         .await
         .expect("git log completes");
@@ -591,6 +1073,7 @@ async fn run_local(config_path: String) {
         commit: current_commit,
         remote_url: repo.display().to_string(),
         build_token: "n/a".to_string(),
+        timeout_ms: None,
     };
     let job = RunningJob::local_from_job(job);
     job.run().await;
@@ -607,29 +1090,36 @@ async fn run_remote(config_path: String) {
         .build()
         .expect("can build client");
 
+    let identity_path = match runner_config.identity_path.as_ref() {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let config_dir = std::path::Path::new(&config_path).parent().unwrap_or(std::path::Path::new("."));
+            config_dir.join("runner_id")
+        }
+    };
+    let runner_id = load_or_create_identity(&identity_path);
+    eprintln!("runner id: {}", runner_id);
+
     let host_info = host_info::collect_host_info();
     eprintln!("host info: {:?}", host_info);
 
-    let base_url = format!("https://{}", runner_config.server_address);
+    eprintln!("connecting to the driver over {:?}", runner_config.transport);
 
     loop {
-        let (mut sender, body) = hyper::Body::channel();
-
-        sender.send_data(serde_json::to_string(&ClientProto::new_task_please(
+        let request = ClientProto::new_task_please(
             runner_config.allowed_pushers.clone(),
             host_info.clone(),
-        )).unwrap().into()).await.expect("req");
+            runner_id.clone(),
+        );
 
-        let poll = client.post(format!("{base_url}/api/next_job"))
-            .header("user-agent", "ci-butactuallyin-space-runner")
-            .header("authorization", runner_config.auth_secret.trim())
-            .body(body)
-            .send()
-            .await;
+        let connection = match runner_config.transport {
+            Transport::Http => connect_http(&client, &runner_config.server_address, &runner_config.auth_secret, &request).await,
+            Transport::WebSocket => connect_websocket(&runner_config.server_address, &runner_config.auth_secret, &request).await,
+        };
 
-        match poll {
-            Ok(res) => {
-                let mut client = match RemoteServerRunner::new(&runner_config.server_address, sender, res).await {
+        match connection {
+            Ok((sender, rx)) => {
+                let mut client = match RemoteServerRunner::new(&runner_config.server_address, sender, rx).await {
                     Ok(client) => client,
                     Err(e) => {
                         eprintln!("failed to initialize client: {:?}", e);
@@ -658,16 +1148,12 @@ async fn run_remote(config_path: String) {
                 job.run().await;
                 std::thread::sleep(Duration::from_millis(10000));
             },
+            Err(WorkAcquireError::Unreachable(e)) => {
+                eprintln!("could not reach server ({}). sleeping a bit and retrying.", e);
+                std::thread::sleep(Duration::from_millis(5000));
+            }
             Err(e) => {
-                let message = format!("{}", e);
-
-                if message.contains("tcp connect error") {
-                    eprintln!("could not reach server. sleeping a bit and retrying.");
-                    std::thread::sleep(Duration::from_millis(5000));
-                    continue;
-                }
-
-                eprintln!("unhandled error: {}", message);
+                eprintln!("unhandled error: {:?}", e);
 
                 std::thread::sleep(Duration::from_millis(1000));
             }
@@ -681,7 +1167,7 @@ mod host_info {
     // get host model name, microcode, and how many cores
     fn collect_cpu_info() -> CpuInfo {
         fn find_line(lines: &[String], prefix: &str) -> String {
-            try_find_line(lines, prefix).expect(&format!("{} line is present", prefix))
+            try_find_line(lines, prefix).unwrap_or_else(|| panic!("{} line is present", prefix))
         }
 
         fn try_find_line(lines: &[String], prefix: &str) -> Option<String> {
@@ -752,8 +1238,8 @@ mod host_info {
                     let model_name_path = std::path::Path::new("/proc/device-tree/compatible");
                     if model_name_path.exists() {
                         let model_name = std::fs::read_to_string(model_name_path).unwrap();
-                        let model_name = model_name.replace("\x00", ";");
-                        model_name
+                        
+                        model_name.replace("\x00", ";")
                     } else {
                         eprintln!("[!] {} does not exist, model name is unknown!", model_name_path.display());
                         "unknown".to_string()
@@ -797,6 +1283,19 @@ mod host_info {
         MemoryInfo { total, available }
     }
 
+    /// the one-minute load average, from `/proc/loadavg`.
+    pub fn load_avg() -> Option<f64> {
+        let loadavg = std::fs::read_to_string("/proc/loadavg").ok()?;
+        loadavg.split_whitespace().next()?.parse().ok()
+    }
+
+    /// `MemAvailable` from `/proc/meminfo`, in kB.
+    pub fn mem_available_kb() -> Option<u64> {
+        let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+        let line = meminfo.lines().find(|line| line.starts_with("MemAvailable:"))?;
+        line.split_whitespace().nth(1)?.parse().ok()
+    }
+
     fn hostname() -> String {
         let mut bytes = [0u8; 4096];
         let res = unsafe {
@@ -338,15 +338,12 @@ pub fn handle_req(db: rusqlite::Connection, req: &Request) -> Result<String, Str
 
     use ci_lib_core::dbctx::DbCtx;
     use std::sync::Arc;
-    use std::sync::Mutex;
 
-    let ctx = Arc::new(DbCtx {
-        config_path: "/".into(),
-        conn: Mutex::new(db),
-    });
+    let ctx = Arc::new(DbCtx::from_connection("/", db));
 
     if req.get_url().path() == "/" {
         ci_lib_web::build_repo_index(&ctx)
+            .map_err(|e| e.to_string())
     } else {
         Ok("a".to_string())
     }
@@ -14,7 +14,7 @@ use axum::routing::*;
 use axum::Router;
 use axum::response::{IntoResponse, Response, Html};
 use std::net::SocketAddr;
-use axum::extract::{Path, State, Query};
+use axum::extract::{Form, Path, State, Query};
 use http_body::combinators::UnsyncBoxBody;
 use axum::{Error, Json};
 use axum::extract::rejection::JsonRejection;
@@ -35,11 +35,30 @@ use sha2::Sha256;
 
 use ci_lib_core::sql::RunState;
 
-use ci_lib_core::dbctx::DbCtx;
-use ci_lib_core::sql::{ArtifactRecord, Job, Run};
+use ci_lib_core::dbctx::{DbCtx, DbError};
+use ci_lib_core::protocol::TaskOutcome;
+use ci_lib_core::sql::{ArtifactRecord, Attempt, CommandRecord, Job, JobCreation, LogQuery, MetricFinding, MetricValue, Run};
 
 use rusqlite::OptionalExtension;
 
+/// a database operation failed while handling a request. the details are logged here, the client
+/// only learns the status.
+struct WebError(DbError);
+
+impl From<DbError> for WebError {
+    fn from(e: DbError) -> Self {
+        WebError(e)
+    }
+}
+
+impl IntoResponse for WebError {
+    fn into_response(self) -> axum::response::Response {
+        eprintln!("[-] database error handling request: {}", self.0);
+        let status = ci_lib_native::db_error_status(&self.0);
+        (status, Html(format!("<html><body>{}</body></html>", status))).into_response()
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 struct WebserverConfig {
     psks: Vec<GithubPsk>,
@@ -49,7 +68,10 @@ struct WebserverConfig {
     artifact_path: PathBuf,
     debug_addr: Option<serde_json::Value>,
     server_addr: Option<serde_json::Value>,
-    server_host: String 
+    server_host: String,
+    // lets whoever knows it cancel runs from the commit page. without it, runs can't be
+    // cancelled from the web at all.
+    admin_token: Option<String>,
 }
 
 #[derive(Clone)]
@@ -57,6 +79,7 @@ struct WebserverState {
     server_host: String,
     jobs_path: PathBuf,
     artifact_path: PathBuf,
+    admin_token: Option<String>,
     dbctx: Arc<DbCtx>,
 }
 
@@ -79,7 +102,7 @@ enum GithubHookError {
 
 #[derive(Debug)]
 enum GithubEvent {
-    Push { tip: String, repo_name: String, head_commit: serde_json::Map<String, serde_json::Value>, pusher: serde_json::Map<String, serde_json::Value>, ref_name: String },
+    Push { tip: String, before: String, commits: Vec<String>, deleted: bool, forced: bool, repo_name: String, head_commit: Option<serde_json::Map<String, serde_json::Value>>, pusher: serde_json::Map<String, serde_json::Value>, ref_name: String },
     Other {}
 }
 
@@ -93,6 +116,26 @@ fn parse_push_event(body: serde_json::Value) -> Result<GithubEvent, GithubHookEr
         .ok_or(GithubHookError::BadType { path: "after", expected: "str" })?
         .to_owned();
 
+    let before = body.get("before")
+        .ok_or(GithubHookError::MissingElement { path: "before" })?
+        .as_str()
+        .ok_or(GithubHookError::BadType { path: "before", expected: "str" })?
+        .to_owned();
+
+    let mut commits = Vec::new();
+    if let Some(commit_list) = body.get("commits") {
+        let commit_list = commit_list.as_array()
+            .ok_or(GithubHookError::BadType { path: "commits", expected: "array" })?;
+        for commit in commit_list.iter() {
+            let sha = commit.get("id")
+                .ok_or(GithubHookError::MissingElement { path: "commits/id" })?
+                .as_str()
+                .ok_or(GithubHookError::BadType { path: "commits/id", expected: "str" })?
+                .to_owned();
+            commits.push(sha);
+        }
+    }
+
     let repo_name = body.get("repository")
         .ok_or(GithubHookError::MissingElement { path: "repository" })?
         .as_object()
@@ -103,11 +146,25 @@ fn parse_push_event(body: serde_json::Value) -> Result<GithubEvent, GithubHookEr
         .ok_or(GithubHookError::BadType { path: "repository/full_name", expected: "str" })?
         .to_owned();
 
-    let head_commit = body.get("head_commit")
-        .ok_or(GithubHookError::MissingElement { path: "head_commit" })?
-        .as_object()
-        .ok_or(GithubHookError::BadType { path: "head_commit", expected: "obj" })?
-        .to_owned();
+    // `head_commit` is null when a ref is deleted.
+    let head_commit = match body.get("head_commit") {
+        Some(serde_json::Value::Null) | None => None,
+        Some(head_commit) => Some(head_commit.as_object()
+            .ok_or(GithubHookError::BadType { path: "head_commit", expected: "obj" })?
+            .to_owned()),
+    };
+
+    let deleted = match body.get("deleted") {
+        Some(deleted) => deleted.as_bool()
+            .ok_or(GithubHookError::BadType { path: "deleted", expected: "bool" })?,
+        None => false,
+    };
+
+    let forced = match body.get("forced") {
+        Some(forced) => forced.as_bool()
+            .ok_or(GithubHookError::BadType { path: "forced", expected: "bool" })?,
+        None => false,
+    };
 
     let pusher = body.get("pusher")
         .ok_or(GithubHookError::MissingElement { path: "pusher" })?
@@ -121,12 +178,40 @@ fn parse_push_event(body: serde_json::Value) -> Result<GithubEvent, GithubHookEr
         .ok_or(GithubHookError::BadType { path: "ref", expected: "str" })?
         .to_owned();
 
-    Ok(GithubEvent::Push { tip, repo_name, head_commit, pusher, ref_name })
+    Ok(GithubEvent::Push { tip, before, commits, deleted, forced, repo_name, head_commit, pusher, ref_name })
 }
 
-async fn process_push_event(ctx: Arc<DbCtx>, owner: String, repo: String, event: GithubEvent) -> impl IntoResponse {
-    let (sha, repo, head_commit, pusher, ref_name) = if let GithubEvent::Push { tip, repo_name, head_commit, pusher, ref_name } = event {
-        (tip, repo_name, head_commit, pusher, ref_name)
+/// github only sends up to this many commits with a push event. if a push has this many, there may
+/// have been more, so the oldest one we're told about isn't necessarily a child of `before`.
+const GITHUB_PUSH_COMMIT_LIMIT: usize = 20;
+
+/// guess at (commit, parent) pairs from a push event. push events don't include parents, but they
+/// list pushed commits oldest first, so for the usual case of a linear push each commit's parent
+/// is the one listed before it, and the first commit's parent is the old tip.
+///
+/// this is wrong for merges pushed alongside the branch they merge, where it linearizes history.
+/// `ci_ctl import-ancestry` can correct this from a real clone. guesses never overwrite parents
+/// we already know, so a wrong guess sticks until then.
+fn push_ancestry(before: &str, commits: &[String], forced: bool) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+
+    // an all-zero `before` means the ref was just created. after a force push or rebase, the old
+    // tip usually isn't an ancestor of the new commits at all.
+    let new_ref = before.chars().all(|c| c == '0');
+    if !new_ref && !forced && !commits.is_empty() && commits.len() < GITHUB_PUSH_COMMIT_LIMIT {
+        pairs.push((commits[0].clone(), before.to_owned()));
+    }
+
+    for window in commits.windows(2) {
+        pairs.push((window[1].clone(), window[0].clone()));
+    }
+
+    pairs
+}
+
+async fn process_push_event(ctx: Arc<DbCtx>, owner: String, repo: String, event: GithubEvent) -> Result<(StatusCode, String), WebError> {
+    let (sha, before, commits, deleted, forced, repo, head_commit, pusher, ref_name) = if let GithubEvent::Push { tip, before, commits, deleted, forced, repo_name, head_commit, pusher, ref_name } = event {
+        (tip, before, commits, deleted, forced, repo_name, head_commit, pusher, ref_name)
     } else {
         panic!("process push event on non-push event");
     };
@@ -135,90 +220,69 @@ async fn process_push_event(ctx: Arc<DbCtx>, owner: String, repo: String, event:
 
     let remote_url = format!("https://www.github.com/{}.git", repo);
     eprintln!("looking for remote url: {}", remote_url);
-    let (remote_id, repo_id): (u64, u64) = match ctx.conn.lock().unwrap()
-        .query_row("select id, repo_id from remotes where remote_git_url=?1;", [&remote_url], |row| Ok((row.get(0).unwrap(), row.get(1).unwrap())))
-        .optional()
-        .unwrap() {
-        Some(elems) => elems,
+    let (remote_id, repo_id): (u64, u64) = match ctx.remote_by_git_url(&remote_url)? {
+        Some(remote) => (remote.id, remote.repo_id),
         None => {
             eprintln!("no remote registered for url {} (repo {})", remote_url, repo);
-            return (StatusCode::NOT_FOUND, String::new());
+            return Ok((StatusCode::NOT_FOUND, String::new()));
         }
     };
 
+    if deleted {
+        eprintln!("[.] ref {} deleted from remote {}", ref_name, remote_id);
+        ctx.delete_ref(remote_id, &ref_name)?;
+        return Ok((StatusCode::OK, String::new()));
+    }
+
     // push event is in terms of a ref, but we don't know if it's a new commit (yet).
     // in terms of CI jobs, we care mainly about new commits.
     // so...
-    // * look up the commit,
-    // * if it known, no new job needed, skip the rest (new ref for existing commit we've already handled some way)
-    // * create a new commit ref
-    // * create a new job (state=pending) for the commit ref
-    // however, for display purposes we do want a fresh understanding of refs, so:
-    // * look up if the ref used to reference another commit in the repo
-    // * if so, the old commit name is now stale and should be marked as such
-    // * create a new name for the head commit pushed here, named the ref that we were told
-    // this is not necessarily sufficient for fully correct ref names, but should be most of the
-    // time! this is intended to keep ref names roughly correct in a push manner, with a secondary
-    // pull mechanism to pull correct names from remotes on a less frequent cadence.
-    let commit_id: Option<u64> = ctx.conn.lock().unwrap()
-        .query_row(ci_lib_core::sql::COMMIT_TO_ID, [sha.clone()], |row| row.get(0))
-        .optional()
-        .expect("can run query");
-
-    let commit_id = match commit_id {
-        Some(id) => {
-            eprintln!("commit already exists");
-            id
-        }
-        None => {
-            let repo_default_run_pref: Option<String> = ctx.conn.lock().unwrap()
-                .query_row("select default_run_preference from repos where id=?1;", [repo_id], |row| {
-                    Ok((row.get(0)).unwrap())
-                })
-                .expect("can query");
+    // * look up the commit in the repo this remote belongs to,
+    // * if it known, no new job needed (new ref for existing commit we've already handled some way)
+    // * otherwise create a new job (state=pending) for the commit
+    // either way, the ref now points at the pushed commit. whatever it pointed at before keeps
+    // the ref's name in `ref_log`, and so shows up as a stale name for that commit.
+    for (commit, parent) in push_ancestry(&before, &commits, forced) {
+        ctx.record_commit_parents(repo_id, &commit, &[&parent])?;
+    }
 
-            let pusher_email = pusher
-                .get("email")
-                .expect("has email")
-                .as_str()
-                .expect("is str");
+    let pusher_email = pusher
+        .get("email")
+        .and_then(|email| email.as_str());
 
-            let (job_id, commit_id) = ctx.new_job(remote_id, &sha, Some(pusher_email), repo_default_run_pref).unwrap();
-            // at this point we have a commit id, record the ref..
-            let _ = ctx.new_run(job_id, None).unwrap();
-            
+    match ctx.create_job(remote_id, &sha, pusher_email)? {
+        JobCreation::Existing { job_id, .. } => {
+            eprintln!("commit already exists in repo {} as job {}", repo_id, job_id);
+        }
+        JobCreation::Created { job_id, .. } => {
             let notifiers = ci_lib_native::dbctx_ext::notifiers_by_repo(&ctx, repo_id).expect("can get notifiers");
 
             for notifier in notifiers {
                 notifier.tell_pending_job(&ctx, repo_id, &sha, job_id).await.expect("can notify");
             }
-
-            commit_id
         }
-    };
+    }
 
-    let short_ref_name = if ref_name.starts_with("refs/heads/") {
-        &ref_name["refs/heads/".len()..]
-    } else {
-        &ref_name
-    };
-    ctx.update_commit_name(commit_id, short_ref_name).expect("can update ref");
+    ctx.update_ref(remote_id, &ref_name, &sha)?;
 
-    (StatusCode::OK, String::new())
+    Ok((StatusCode::OK, String::new()))
 }
 
 async fn handle_github_event(ctx: Arc<DbCtx>, owner: String, repo: String, event_kind: String, body: serde_json::Value) -> Response<UnsyncBoxBody<Bytes, Error>> {
     println!("got github event: {}, {}, {}", owner, repo, event_kind);
     match event_kind.as_str() {
         "push" => {
-            let push_event = parse_push_event(body)
-                .map_err(|e| {
-                    eprintln!("TODO: handle push event error: {:?}", e);
-                    panic!()
-                })
-                .expect("parse works");
-            let res = process_push_event(ctx, owner, repo, push_event).await;
-            "ok".into_response()
+            let push_event = match parse_push_event(body) {
+                Ok(push_event) => push_event,
+                Err(e) => {
+                    eprintln!("[-] couldn't parse push event: {:?}", e);
+                    return (StatusCode::BAD_REQUEST, "").into_response();
+                }
+            };
+            match process_push_event(ctx, owner, repo, push_event).await {
+                Ok(_) => "ok".into_response(),
+                Err(e) => e.into_response(),
+            }
         },
         "status" => {
             eprintln!("[.] status update");
@@ -231,63 +295,123 @@ async fn handle_github_event(ctx: Arc<DbCtx>, owner: String, repo: String, event
     }
 }
 
-async fn handle_ci_index(State(ctx): State<WebserverState>) -> impl IntoResponse {
+async fn handle_ci_index(State(ctx): State<WebserverState>) -> Result<(StatusCode, Html<String>), WebError> {
     eprintln!("root index");
-    match ci_lib_web::build_repo_index(&ctx.dbctx) {
-        Ok(html) => {
-            (StatusCode::OK, Html(html))
-        }
-        Err(e) => {
-            (StatusCode::INTERNAL_SERVER_ERROR, Html(e))
-        }
-    }
+    let html = ci_lib_web::build_repo_index(&ctx.dbctx)?;
+    Ok((StatusCode::OK, Html(html)))
+}
+
+#[derive(Debug, Deserialize)]
+struct CommitParams {
+    attempt: Option<u32>,
 }
 
-async fn handle_commit_status(Path(path): Path<(String, String, String)>, State(ctx): State<WebserverState>) -> impl IntoResponse {
+async fn handle_commit_status(Path(path): Path<(String, String, String)>, commit_params: Query<CommitParams>, State(ctx): State<WebserverState>) -> Result<(StatusCode, Html<String>), WebError> {
     eprintln!("path: {}/{}, sha {}", path.0, path.1, path.2);
     let remote_path = format!("{}/{}", path.0, path.1);
 
-    let (remote_id, repo_id): (u64, u64) = match ctx.dbctx.conn.lock().unwrap()
-        .query_row("select id, repo_id from remotes where remote_path=?1;", [&remote_path], |row| Ok((row.get_unwrap(0), row.get_unwrap(1)))) {
-        Ok((remote_id, repo_id)) => {
-            (remote_id, repo_id)
-        },
-        Err(_) => {
-            return (StatusCode::NOT_FOUND, Html("<html><body>no such remote</body></html>".to_string()));
+    let (remote_id, repo_id): (u64, u64) = match ctx.dbctx.remote_by_path(&remote_path)? {
+        Some(remote) => (remote.id, remote.repo_id),
+        None => {
+            return Ok((StatusCode::NOT_FOUND, Html("<html><body>no such remote</body></html>".to_string())));
         }
     };
 
     let sha = path.2;
 
     let (commit_id, sha): (u64, String) = if sha.len() >= 7 {
-        match ctx.dbctx.conn.lock().unwrap()
-            .query_row("select id, sha from commits where sha like ?1;", [&format!("{}%", sha)], |row| Ok((row.get_unwrap(0), row.get_unwrap(1))))
-            .optional()
-            .expect("can query") {
-            Some((commit_id, sha)) => (commit_id, sha),
-            None => {
-                return (StatusCode::NOT_FOUND, Html("<html><body>no such commit</body></html>".to_string()));
+        match ctx.dbctx.commit_by_sha_prefix(repo_id, &sha) {
+            Ok(Some((commit_id, sha))) => (commit_id, sha),
+            Ok(None) => {
+                return Ok((StatusCode::NOT_FOUND, Html("<html><body>no such commit</body></html>".to_string())));
+            }
+            Err(DbError::Ambiguous(_)) => {
+                return Ok((StatusCode::BAD_REQUEST, Html("<html><body>more than one commit starts with that sha, use more of it</body></html>".to_string())));
+            }
+            Err(e) => {
+                return Err(e.into());
             }
         }
     } else {
-        return (StatusCode::NOT_FOUND, Html("<html><body>no such commit</body></html>".to_string()));
+        return Ok((StatusCode::NOT_FOUND, Html("<html><body>no such commit</body></html>".to_string())));
     };
 
-    let nice_name = ctx.dbctx.nice_name_for_commit(commit_id).expect("can query");
+    let nice_name = ctx.dbctx.nice_name_for_commit(commit_id)?;
 
     let short_sha = &sha[0..9];
 
-    let job = ctx.dbctx.job_by_commit_id(commit_id).expect("can query").expect("job exists");
+    let job = match ctx.dbctx.job_by_commit_id(commit_id)? {
+        Some(job) => job,
+        None => {
+            return Ok((StatusCode::NOT_FOUND, Html("<html><body>no job for commit</body></html>".to_string())));
+        }
+    };
+
+    let run = match ctx.dbctx.last_run_for_job(job.id)? {
+        Some(run) => run,
+        None => {
+            return Ok((StatusCode::NOT_FOUND, Html("<html><body>no runs for job</body></html>".to_string())));
+        }
+    };
+
+    let attempts = ctx.dbctx.attempts_for_run(run.id)?;
+    let attempt: Option<&Attempt> = match commit_params.attempt {
+        Some(number) => match attempts.iter().find(|attempt| attempt.attempt == number) {
+            Some(attempt) => Some(attempt),
+            None => {
+                return Ok((StatusCode::NOT_FOUND, Html("<html><body>no such attempt</body></html>".to_string())));
+            }
+        },
+        None => attempts.last(),
+    };
 
-    let run = ctx.dbctx.last_run_for_job(job.id).expect("can query").expect("run exists");
+    // the rest of the page describes one attempt of the run. for the latest attempt this is
+    // the same as the run itself, but an earlier attempt has its own status and timing.
+    let run = match attempt {
+        Some(attempt) => Run {
+            artifacts_path: attempt.artifacts_path.clone(),
+            state: attempt.state,
+            host_id: attempt.host_id,
+            start_time: attempt.start_time,
+            complete_time: attempt.complete_time,
+            build_token: attempt.build_token.clone(),
+            build_result: attempt.build_result,
+            final_text: attempt.final_text.clone(),
+            outcome: attempt.outcome,
+            ..run
+        },
+        None => run,
+    };
 
     let complete_time = run.complete_time.unwrap_or_else(ci_lib_core::now_ms);
 
-    let (status_elem, status_desc) = match run.state {
-        RunState::Pending | RunState::Started => {
+    let (status_elem, status_desc) = match (run.outcome, run.state) {
+        (Some(TaskOutcome::Passed), _) => {
+            ("<span style='color:green;'>pass</span>", "✅ passed")
+        }
+        (Some(TaskOutcome::BuildFailed), _) => {
+            ("<span style='color:red;'>failed</span>", "❌ build failed")
+        }
+        (Some(TaskOutcome::CheckoutFailed), _) => {
+            ("<span style='color:red;'>bad checkout</span>", "🔀 could not check out commit")
+        }
+        (Some(TaskOutcome::GoodfileError), _) => {
+            ("<span style='color:red;'>goodfile error</span>", "📜 goodfile error")
+        }
+        (Some(TaskOutcome::InfraError), _) => {
+            ("<span style='color:darkorange;'>infra error</span>", "🧯 infrastructure error")
+        }
+        (Some(TaskOutcome::TimedOut), _) => {
+            ("<span style='color:red;'>timed out</span>", "⏱️ timed out")
+        }
+        (Some(TaskOutcome::Cancelled), _) => {
+            ("<span style='color:#666;'>cancelled</span>", "🚫 cancelled")
+        }
+        // runs that have yet to end, or that ended before outcomes were recorded
+        (None, RunState::Pending | RunState::Started) => {
             ("<span style='color:#660;'>pending</span>", "⌛in progress")
         },
-        RunState::Finished => {
+        (None, RunState::Finished) => {
             if let Some(build_result) = run.build_result {
                 if build_result == 0 {
                     ("<span style='color:green;'>pass</span>", "✅ passed")
@@ -299,18 +423,27 @@ async fn handle_commit_status(Path(path): Path<(String, String, String)>, State(
                 ("<span style='color:red;'>unreported</span>", "❔ missing status")
             }
         },
-        RunState::Error => {
+        (None, RunState::Error) => {
             ("<span style='color:red;'>error</span>", "🧯 error, uncompleted")
         }
-        RunState::Invalid => {
+        (None, RunState::Invalid) => {
             ("<span style='color:red;'>(server error)</span>", "dude even i don't know")
         }
+        (None, RunState::TimedOut) => {
+            ("<span style='color:red;'>timed out</span>", "⏱️ timed out")
+        }
+        (None, RunState::Cancelled) => {
+            ("<span style='color:#666;'>cancelled</span>", "🚫 cancelled")
+        }
+    };
+    let debug_info = match run.outcome {
+        Some(outcome) => outcome != TaskOutcome::Passed,
+        None => run.state == RunState::Finished && run.build_result == Some(1) || run.state == RunState::Error || run.state == RunState::TimedOut || run.state == RunState::Cancelled,
     };
-    let debug_info = run.state == RunState::Finished && run.build_result == Some(1) || run.state == RunState::Error;
 
-    let repo_name: String = ctx.dbctx.conn.lock().unwrap()
-        .query_row("select repo_name from repos where id=?1;", [repo_id], |row| row.get(0))
-        .expect("can query");
+    let repo_name: String = ctx.dbctx.repo_by_id(repo_id)?
+        .ok_or_else(|| DbError::CorruptRow(format!("remote {} references missing repo {}", remote_id, repo_id)))?
+        .name;
 
     let deployed = false;
 
@@ -321,8 +454,8 @@ async fn handle_commit_status(Path(path): Path<(String, String, String)>, State(
     head.push_str(&format!("<title>{server_host} - {}</title>", repo_name));
     let include_og_tags = true;
     if include_og_tags {
-        head.push_str("\n");
-        head.push_str(&format!("<meta property=\"og:type\" content=\"website\">\n"));
+        head.push('\n');
+        head.push_str("<meta property=\"og:type\" content=\"website\">\n");
         head.push_str(&format!("<meta property=\"og:site_name\" content=\"{server_host}\">\n"));
         head.push_str(&format!("<meta property=\"og:url\" content=\"/{}/{}/{}\">\n", &path.0, &path.1, &sha));
         head.push_str(&format!("<meta property=\"og:title\" contents=\"{}/{} commit {}\">", &path.0, &path.1, &short_sha));
@@ -340,13 +473,25 @@ async fn handle_commit_status(Path(path): Path<(String, String, String)>, State(
     let remote_commit_elem = format!("<a href=\"https://www.github.com/{}/commit/{}\">{}</a>", &remote_path, &sha, &sha);
 
     let mut artifacts_fragment = String::new();
-    let mut artifacts: Vec<ArtifactRecord> = ctx.dbctx.artifacts_for_run(run.id, None).unwrap()
-        .into_iter() // HACK: filter out artifacts for previous runs of a run. artifacts should be attached to a run, runs should be distinct from run. but i'm sleepy.
-        .filter(|artifact| artifact.created_time >= run.start_time.unwrap_or_else(ci_lib_core::now_ms))
-        .collect();
+    let mut artifacts: Vec<ArtifactRecord> = match attempt {
+        Some(attempt) => ctx.dbctx.artifacts_for_attempt(attempt.id, None)?,
+        None => Vec::new(),
+    };
 
     artifacts.sort_by_key(|artifact| artifact.created_time);
 
+    let commands: Vec<CommandRecord> = match attempt {
+        Some(attempt) => ctx.dbctx.commands_for_attempt(attempt.id)?,
+        None => Vec::new(),
+    };
+    let artifact_path = ctx.artifact_path.to_string_lossy();
+    let commands_section = commands_to_html(&commands, &artifact_path, debug_info);
+
+    // command output is shown with its command; everything else is listed on its own.
+    artifacts.retain(|artifact| !commands.iter().any(|command| {
+        command.stdout_artifact_id == Some(artifact.id) || command.stderr_artifact_id == Some(artifact.id)
+    }));
+
     fn diff_times(run_completed: u64, artifact_completed: Option<u64>) -> u64 {
         let artifact_completed = artifact_completed.unwrap_or_else(ci_lib_core::now_ms);
         let run_completed = std::cmp::max(run_completed, artifact_completed);
@@ -356,13 +501,13 @@ async fn handle_commit_status(Path(path): Path<(String, String, String)>, State(
     let recent_artifacts: Vec<ArtifactRecord> = artifacts.iter().filter(|artifact| diff_times(complete_time, artifact.completed_time) <= 60_000).cloned().collect();
     let old_artifacts: Vec<ArtifactRecord> = artifacts.iter().filter(|artifact| diff_times(complete_time, artifact.completed_time) > 60_000).cloned().collect();
 
-    let artifact_path = ctx.artifact_path.to_string_lossy();
-
     for artifact in old_artifacts.iter() {
         let created_time_str = Utc.timestamp_millis_opt(artifact.created_time as i64).unwrap().to_rfc2822();
         artifacts_fragment.push_str(&format!("<div><pre style='display:inline;'>{}</pre> step: <pre style='display:inline;'>{}</pre></div>\n", created_time_str, &artifact.name));
         let duration_str = ci_lib_web::duration_as_human_string(artifact.completed_time.unwrap_or_else(ci_lib_core::now_ms) - artifact.created_time);
-        let size_str = (std::fs::metadata(&format!("{artifact_path}/{}/{}", artifact.run_id, artifact.id)).expect("metadata exists").len() / 1024).to_string();
+        let size_str = std::fs::metadata(format!("{artifact_path}/{}/{}", artifact.run_id, artifact.id)).map(|md| {
+            (md.len() / 1024).to_string()
+        }).unwrap_or_else(|e| format!("[{}]", e));
         artifacts_fragment.push_str(&format!("<pre>  {}kb in {} </pre>\n", size_str, duration_str));
     }
 
@@ -375,16 +520,22 @@ async fn handle_commit_status(Path(path): Path<(String, String, String)>, State(
             artifacts_fragment.push_str("</pre>\n");
         } else {
             let duration_str = ci_lib_web::duration_as_human_string(artifact.completed_time.unwrap_or_else(ci_lib_core::now_ms) - artifact.created_time);
-            let size_str = std::fs::metadata(&format!("{artifact_path}/{}/{}", artifact.run_id, artifact.id)).map(|md| {
+            let size_str = std::fs::metadata(format!("{artifact_path}/{}/{}", artifact.run_id, artifact.id)).map(|md| {
                 (md.len() / 1024).to_string()
             }).unwrap_or_else(|e| format!("[{}]", e));
             artifacts_fragment.push_str(&format!("<pre>  {}kb in {} </pre>\n", size_str, duration_str));
         }
     }
 
-    let metrics = summarize_job_metrics(&ctx.dbctx, run.id, run.job_id).unwrap();
+    let metrics = summarize_job_metrics(&ctx.dbctx, run.job_id, attempt)?;
     let metrics_table = job_metrics_to_html_table(&metrics);
 
+    let findings = match attempt {
+        Some(attempt) => ctx.dbctx.metric_findings_for_attempt(attempt.id)?,
+        None => Vec::new(),
+    };
+    let findings_section = metric_findings_to_html(&findings);
+
     let mut html = String::new();
     html.push_str("<html>\n");
     html.push_str(&format!("  {}\n", head));
@@ -398,26 +549,87 @@ async fn handle_commit_status(Path(path): Path<(String, String, String)>, State(
         commit_line.push_str(&format!(" aka <b>{}</b>", name.stringy()));
     }
     html.push_str(&format!("{}, run: {}\n", commit_line, run.id));
+    if attempts.len() > 1 {
+        let mut attempts_line = String::new();
+        attempts_line.push_str("attempts:");
+        for other in attempts.iter() {
+            if Some(other.id) == attempt.map(|attempt| attempt.id) {
+                attempts_line.push_str(&format!(" <b>{}</b>", other.attempt));
+            } else {
+                attempts_line.push_str(&format!(" <a href=\"/{}/{}/{}?attempt={}\">{}</a>", &path.0, &path.1, &sha, other.attempt, other.attempt));
+            }
+            if let Some(reason) = other.requeue_reason.as_ref() {
+                attempts_line.push_str(&format!(" (retried after {})", reason));
+            }
+        }
+        html.push_str(&format!("{}\n", attempts_line));
+    }
     html.push_str(&format!("status: {} in {}\n", status_elem, ci_lib_web::display_run_time(&run)));
     if let Some(desc) = run.final_text.as_ref() {
         html.push_str(&format!("  description: {}\n  ", desc));
     }
+    if let Some(reason) = attempt.and_then(|attempt| attempt.requeue_reason.as_ref()) {
+        html.push_str(&format!("  retried after {}\n  ", reason));
+    }
     html.push_str(&format!("deployed: {}\n", deployed));
     html.push_str("    </pre>\n");
-    if artifacts_fragment.len() > 0 {
+    if ctx.admin_token.is_some() && (run.state == RunState::Pending || run.state == RunState::Started) {
+        html.push_str(&format!("    <form method=\"post\" action=\"/run/{}/cancel\"><input type=\"password\" name=\"token\" placeholder=\"admin token\"> <button type=\"submit\">cancel run</button></form>\n", run.id));
+    }
+    if let Some(commands) = commands_section {
+        html.push_str(&commands);
+    }
+    if !artifacts_fragment.is_empty() {
         html.push_str("    <div>artifacts</div>\n");
         html.push_str(&artifacts_fragment);
     }
+    if let Some(findings) = findings_section {
+        html.push_str(&findings);
+    }
     if let Some(metrics) = metrics_table {
         html.push_str(&metrics);
     }
     html.push_str("  </body>\n");
     html.push_str("</html>");
 
-    (StatusCode::OK, Html(html))
+    Ok((StatusCode::OK, Html(html)))
+}
+
+#[derive(Debug, Deserialize)]
+struct CancelParams {
+    token: String,
 }
 
-fn summarize_job_metrics(dbctx: &Arc<DbCtx>, run_id: u64, job_id: u64) -> Result<MetricsInfo, String> {
+async fn handle_cancel_run(Path(run_id): Path<u64>, State(ctx): State<WebserverState>, Form(params): Form<CancelParams>) -> Result<(StatusCode, Html<String>), WebError> {
+    match ctx.admin_token.as_ref() {
+        Some(token) if token == &params.token => {}
+        _ => {
+            return Ok((StatusCode::FORBIDDEN, Html("<html><body>not allowed</body></html>".to_string())));
+        }
+    }
+
+    let message = match ctx.dbctx.cancel_run(run_id) {
+        Ok(RunState::Cancelled) => {
+            if let Err(e) = ci_lib_native::dbctx_ext::notify_cancelled(&ctx.dbctx, run_id).await {
+                eprintln!("[-] could not notify cancellation of run {}: {}", run_id, e);
+            }
+            format!("run {} cancelled", run_id)
+        }
+        Ok(_) => format!("run {} will be stopped shortly", run_id),
+        Err(DbError::NotFound) => {
+            return Ok((StatusCode::NOT_FOUND, Html("<html><body>no pending or running run</body></html>".to_string())));
+        }
+        Err(e) => {
+            return Err(e.into());
+        }
+    };
+
+    Ok((StatusCode::OK, Html(format!("<html><body>{}</body></html>", message))))
+}
+
+// metrics for each host's latest run of `job_id`. each run's metrics come from its latest attempt,
+// except for `shown`, whose run is represented by that attempt instead.
+fn summarize_job_metrics(dbctx: &Arc<DbCtx>, job_id: u64, shown: Option<&Attempt>) -> Result<MetricsInfo, DbError> {
     let runs = dbctx.runs_for_job_one_per_host(job_id)?;
 
     // very silly ordering issue: need an authoritative ordering of metrics to display metrics
@@ -430,27 +642,33 @@ fn summarize_job_metrics(dbctx: &Arc<DbCtx>, run_id: u64, job_id: u64) -> Result
     let mut all_names: Vec<String> = Vec::new();
 
     let all_metrics: Vec<(HashMap<String, String>, HostDesc)> = runs.iter().map(|run| {
-        let metrics = dbctx.metrics_for_run(run.id).unwrap();
+        let metrics = match shown {
+            Some(attempt) if attempt.run_id == run.id => dbctx.metrics_for_attempt(attempt.id)?,
+            _ => match dbctx.latest_attempt_for_run(run.id)? {
+                Some(attempt) => dbctx.metrics_for_attempt(attempt.id)?,
+                None => Vec::new(),
+            },
+        };
 
         let mut metrics_map = HashMap::new();
         for metric in metrics.into_iter() {
             if !all_names.contains(&metric.name) {
                 all_names.push(metric.name.clone());
             }
-            metrics_map.insert(metric.name, metric.value);
+            metrics_map.insert(metric.name, ci_lib_web::format_metric_value(&metric.value));
         }
 
         let (hostname, cpu_vendor_id, cpu_family, cpu_model, cpu_max_freq_khz) = match run.host_id {
             Some(host_id) => {
-                dbctx.host_model_info(host_id).unwrap()
+                dbctx.host_model_info(host_id)?
             }
             None => {
                 ("unknown".to_string(), "unknown".to_string(), "0".to_string(), "0".to_string(), 0)
             }
         };
 
-        (metrics_map, HostDesc::from_parts(hostname, cpu_vendor_id, cpu_family, cpu_model, cpu_max_freq_khz))
-    }).collect();
+        Ok((metrics_map, HostDesc::from_parts(hostname, cpu_vendor_id, cpu_family, cpu_model, cpu_max_freq_khz)))
+    }).collect::<Result<Vec<_>, DbError>>()?;
 
     Ok(MetricsInfo {
         all_names,
@@ -463,6 +681,102 @@ struct MetricsInfo {
     all_metrics: Vec<(HashMap<String, String>, HostDesc)>,
 }
 
+fn metric_findings_to_html(findings: &[MetricFinding]) -> Option<String> {
+    if findings.is_empty() {
+        return None;
+    }
+
+    let mut section = String::new();
+    section.push_str("<div>\n");
+    section.push_str("<h3>metric changes</h3>\n");
+    section.push_str("<table style='font-family: monospace;'>\n");
+    section.push_str("<tr><th>name</th><th>baseline</th><th>now</th><th>change</th><th></th></tr>\n");
+
+    for finding in findings.iter() {
+        let baseline = ci_lib_web::format_metric_value(&MetricValue::Number { value: finding.baseline, unit: finding.unit, direction: None });
+        let value = ci_lib_web::format_metric_value(&MetricValue::Number { value: finding.value, unit: finding.unit, direction: None });
+        let change = match finding.relative {
+            Some(relative) => format!("{:+.1}%", relative * 100.0),
+            None => "from zero".to_string(),
+        };
+        let verdict = match finding.improved {
+            Some(true) => "<span style='color:green;'>improved</span>",
+            Some(false) => "<span style='color:red;'>regressed</span>",
+            None => "changed",
+        };
+        let samples = if finding.baseline_samples == 1 {
+            "1 commit".to_string()
+        } else {
+            format!("mean of {} commits", finding.baseline_samples)
+        };
+        section.push_str(&format!(
+            "<tr><td>{}</td><td>{} ({})</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            ci_lib_web::escape_html(&finding.name), baseline, samples, value, change, verdict
+        ));
+    }
+    section.push_str("</table>\n");
+    section.push_str("</div>\n");
+
+    Some(section)
+}
+
+// the commands an attempt ran, in order. when the run failed, the output of whichever commands
+// failed is included inline. all of it comes from whatever commit was pushed, so it's escaped.
+fn commands_to_html(commands: &[CommandRecord], artifact_path: &str, debug_info: bool) -> Option<String> {
+    if commands.is_empty() {
+        return None;
+    }
+
+    let mut section = String::new();
+    section.push_str("<div>\n");
+    section.push_str("<h3>commands</h3>\n");
+    section.push_str("<table style='font-family: monospace;'>\n");
+    section.push_str("<tr><th>step</th><th>command</th><th>cwd</th><th>took</th><th>exit</th><th>output</th></tr>\n");
+    for command in commands.iter() {
+        let took = ci_lib_web::duration_as_human_string(
+            command.end_time.unwrap_or_else(ci_lib_core::now_ms).saturating_sub(command.start_time)
+        );
+        let exit = match (command.end_time, command.exit_code) {
+            (None, _) => "<span style='color:#660;'>running</span>".to_string(),
+            (Some(_), Some(0)) => "<span style='color:green;'>0</span>".to_string(),
+            (Some(_), Some(code)) => format!("<span style='color:red;'>{}</span>", code),
+            (Some(_), None) => "<span style='color:red;'>signal</span>".to_string(),
+        };
+        let mut output = String::new();
+        if let Some(artifact_id) = command.stdout_artifact_id {
+            output.push_str(&format!("<a href=\"/artifact/{}/{}\">stdout</a> ", command.run_id, artifact_id));
+        }
+        if let Some(artifact_id) = command.stderr_artifact_id {
+            output.push_str(&format!("<a href=\"/artifact/{}/{}\">stderr</a>", command.run_id, artifact_id));
+        }
+        section.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            ci_lib_web::escape_html(&command.step.join(" / ")),
+            ci_lib_web::escape_html(&command.argv.join(" ")),
+            ci_lib_web::escape_html(command.cwd.as_deref().unwrap_or(".")),
+            took, exit, output
+        ));
+    }
+    section.push_str("</table>\n");
+
+    if debug_info {
+        for command in commands.iter().filter(|command| command.end_time.is_some() && command.exit_code != Some(0)) {
+            for artifact_id in [command.stdout_artifact_id, command.stderr_artifact_id].into_iter().flatten() {
+                if let Ok(content) = std::fs::read_to_string(format!("{artifact_path}/{}/{}", command.run_id, artifact_id)) {
+                    section.push_str(&format!("<div>output of <pre style='display:inline;'>{}</pre></div>\n", ci_lib_web::escape_html(&command.argv.join(" "))));
+                    section.push_str("<pre>");
+                    section.push_str(&ci_lib_web::escape_html(&content));
+                    section.push_str("</pre>\n");
+                }
+            }
+        }
+    }
+
+    section.push_str("</div>\n");
+
+    Some(section)
+}
+
 fn job_metrics_to_html_table(metrics_info: &MetricsInfo) -> Option<String> {
     if metrics_info.all_metrics.is_empty() {
         return None;
@@ -475,7 +789,7 @@ fn job_metrics_to_html_table(metrics_info: &MetricsInfo) -> Option<String> {
 
     let mut header = "<tr><th>name</th>".to_string();
     for (_, host) in metrics_info.all_metrics.iter() {
-        header.push_str(&format!("<th>{}</br>{} @ {:.3}GHz</th>", &host.hostname, &host.cpu_desc, (host.cpu_max_freq_khz as f64) / 1000_000.0));
+        header.push_str(&format!("<th>{}</br>{} @ {:.3}GHz</th>", &host.hostname, &host.cpu_desc, (host.cpu_max_freq_khz as f64) / 1_000_000.0));
     }
     header.push_str("</tr>\n");
     section.push_str(&header);
@@ -483,8 +797,7 @@ fn job_metrics_to_html_table(metrics_info: &MetricsInfo) -> Option<String> {
     for name in metrics_info.all_names.iter() {
         let mut row = format!("<tr><td>{}</td>", &name);
         for (metrics, _) in metrics_info.all_metrics.iter() {
-            let value = metrics.get(name)
-                .map(|x| x.clone())
+            let value = metrics.get(name).cloned()
                 .unwrap_or_else(String::new);
             row.push_str(&format!("<td>{}</td>", value));
         }
@@ -502,11 +815,14 @@ async fn handle_get_artifact(Path(path): Path<(String, String)>, State(ctx): Sta
     let run: u64 = path.0.parse().unwrap();
     let artifact_id: u64 = path.1.parse().unwrap();
 
-    let artifact_descriptor = match ctx.dbctx.lookup_artifact(run, artifact_id).unwrap() {
-        Some(artifact) => artifact,
-        None => {
+    let artifact_descriptor = match ctx.dbctx.lookup_artifact(run, artifact_id) {
+        Ok(Some(artifact)) => artifact,
+        Ok(None) => {
             return (StatusCode::NOT_FOUND, Html("no such artifact")).into_response();
         }
+        Err(e) => {
+            return WebError(e).into_response();
+        }
     };
 
     let mut live_artifact = false;
@@ -539,9 +855,17 @@ async fn handle_get_artifact(Path(path): Path<(String, String)>, State(ctx): Sta
                         // this would be much implemented as yielding on a condvar woken when an
                         // inotify event on the file indicates a write has occurred. but i am
                         // dreadfully lazy, so we'll just uhh. busy-poll on the file? lmao.
-                        artifact = ctx.dbctx.lookup_artifact(artifact.run_id, artifact.id)
-                            .expect("can query db")
-                            .expect("artifact still exists");
+                        artifact = match ctx.dbctx.lookup_artifact(artifact.run_id, artifact.id) {
+                            Ok(Some(artifact)) => artifact,
+                            Ok(None) => {
+                                eprintln!("[-] artifact {} disappeared while streaming it", artifact.id);
+                                return;
+                            }
+                            Err(e) => {
+                                eprintln!("[-] could not refresh artifact {}: {}", artifact.id, e);
+                                return;
+                            }
+                        };
                     }
                     Err(e) => {
                         eprintln!("artifact file streaming failed: {}", e);
@@ -557,37 +881,168 @@ async fn handle_get_artifact(Path(path): Path<(String, String)>, State(ctx): Sta
     }
 }
 
+#[derive(Debug, Deserialize)]
+struct LogSearchParams {
+    q: Option<String>,
+    repo: Option<String>,
+    /// a host's id or name
+    host: Option<String>,
+    /// YYYY-MM-DD, in UTC
+    since: Option<String>,
+    /// YYYY-MM-DD, in UTC
+    until: Option<String>,
+}
+
+// the start of `day`, a YYYY-MM-DD date in UTC, in ms since the epoch.
+fn day_start_ms(day: &str) -> Option<u64> {
+    let date = chrono::NaiveDate::parse_from_str(day, "%Y-%m-%d").ok()?;
+    let start = Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0)?);
+    Some(start.timestamp_millis() as u64)
+}
+
+async fn handle_log_search(search_params: Query<LogSearchParams>, State(ctx): State<WebserverState>) -> Result<(StatusCode, Html<String>), WebError> {
+    const MAX_MATCHES: u64 = 100;
+    const CONTEXT_LINES: u64 = 2;
+
+    // an empty field in the search form means "don't filter on this".
+    let param = |value: &Option<String>| value.as_deref().map(str::trim).filter(|value| !value.is_empty()).map(str::to_owned);
+    let text = param(&search_params.q);
+    let repo = param(&search_params.repo);
+    let host = param(&search_params.host);
+    let since = param(&search_params.since);
+    let until = param(&search_params.until);
+
+    let mut html = String::new();
+    html.push_str("<html>\n");
+    html.push_str(&format!("<title> {} - log search </title>\n", ctx.server_host));
+    html.push_str("<style>\n");
+    html.push_str(".match { margin-bottom: 1em; }\n");
+    html.push_str(".match pre { margin: 0; background: #eee; }\n");
+    html.push_str(".match .hit { background: #fd8; }\n");
+    html.push_str("</style>\n");
+    html.push_str("<h1>log search</h1>\n");
+    html.push_str("<a href=/>full repos index</a><p> </p>\n");
+
+    let field = |value: &Option<String>| ci_lib_web::escape_html(value.as_deref().unwrap_or(""));
+    html.push_str("<form method=get action=/logs/search>\n");
+    html.push_str(&format!("text <input name=q size=60 value=\"{}\"> ", field(&text)));
+    html.push_str(&format!("repo <input name=repo value=\"{}\"> ", field(&repo)));
+    html.push_str(&format!("host <input name=host size=10 value=\"{}\"> ", field(&host)));
+    html.push_str(&format!("from <input name=since type=date value=\"{}\"> ", field(&since)));
+    html.push_str(&format!("until <input name=until type=date value=\"{}\"> ", field(&until)));
+    html.push_str("<input type=submit value=search>\n");
+    html.push_str("</form>\n");
+
+    let text = match text {
+        Some(text) => text,
+        None => {
+            html.push_str("</html>");
+            return Ok((StatusCode::OK, Html(html)));
+        }
+    };
+
+    let host_id = match host {
+        Some(host) => {
+            let found = match host.parse::<u64>() {
+                Ok(host_id) => ctx.dbctx.host_by_id(host_id)?,
+                Err(_) => ctx.dbctx.host_by_name(&host)?,
+            };
+            match found {
+                Some(found) => Some(found.id),
+                None => {
+                    html.push_str(&format!("<p>no such host: {}</p>\n</html>", ci_lib_web::escape_html(&host)));
+                    return Ok((StatusCode::NOT_FOUND, Html(html)));
+                }
+            }
+        }
+        None => None,
+    };
+
+    let since = match since.as_deref().map(day_start_ms) {
+        Some(None) => {
+            html.push_str("<p>\"from\" is not a date</p>\n</html>");
+            return Ok((StatusCode::BAD_REQUEST, Html(html)));
+        }
+        since => since.flatten(),
+    };
+    let until = match until.as_deref().map(day_start_ms) {
+        Some(None) => {
+            html.push_str("<p>\"until\" is not a date</p>\n</html>");
+            return Ok((StatusCode::BAD_REQUEST, Html(html)));
+        }
+        until => until.flatten(),
+    };
+
+    let matches = ctx.dbctx.search_logs(&LogQuery {
+        text,
+        repo_name: repo,
+        host_id,
+        since,
+        until,
+        limit: MAX_MATCHES,
+        context: CONTEXT_LINES,
+    })?;
+
+    match matches.len() as u64 {
+        0 => html.push_str("<p>no matches</p>\n"),
+        MAX_MATCHES => html.push_str(&format!("<p>first {} matches, oldest first</p>\n", MAX_MATCHES)),
+        count => html.push_str(&format!("<p>{} matches, oldest first</p>\n", count)),
+    }
+
+    for log_match in matches.iter() {
+        let created_time = Utc.timestamp_millis_opt(log_match.created_time as i64).unwrap().to_rfc2822();
+        let host = log_match.host_id.map(|host_id| format!("host {}", host_id)).unwrap_or_else(|| "no host".to_owned());
+        html.push_str("<div class='match'>\n");
+        html.push_str(&format!(
+            "<div>{} <a href=\"/{}/{}\">{}</a> run {} on {}, {}: <a href=\"/artifact/{}/{}\">{}</a> line {}</div>\n",
+            ci_lib_web::escape_html(&log_match.repo_name),
+            log_match.remote_path, log_match.sha, &log_match.sha[..9.min(log_match.sha.len())],
+            log_match.run_id, host, created_time,
+            log_match.run_id, log_match.artifact_id, ci_lib_web::escape_html(&log_match.artifact_name),
+            log_match.line_number,
+        ));
+        html.push_str("<pre>");
+        for (line_number, line) in log_match.lines.iter() {
+            let class = if *line_number == log_match.line_number { " class='hit'" } else { "" };
+            html.push_str(&format!("<span{}>{:>6} | {}</span>\n", class, line_number, ci_lib_web::escape_html(line)));
+        }
+        html.push_str("</pre>\n");
+        html.push_str("</div>\n");
+    }
+
+    html.push_str("</html>");
+
+    Ok((StatusCode::OK, Html(html)))
+}
+
 #[derive(Debug, Deserialize)]
 struct SummaryParams {
     rows: Option<usize>,
 }
 
-async fn handle_repo_summary(Path(path): Path<String>, summary_params: Query<SummaryParams>, State(ctx): State<WebserverState>) -> impl IntoResponse {
+async fn handle_repo_summary(Path(path): Path<String>, summary_params: Query<SummaryParams>, State(ctx): State<WebserverState>) -> Result<(StatusCode, Html<String>), WebError> {
     eprintln!("get repo summary: {:?}", path);
 
     const MAX_ROWS: usize = 100;
     const DEFAULT_ROWS: usize = 10;
 
-    let rows = summary_params.rows.unwrap_or(DEFAULT_ROWS).min(MAX_ROWS).max(DEFAULT_ROWS);
+    let rows = summary_params.rows.unwrap_or(DEFAULT_ROWS).clamp(DEFAULT_ROWS, MAX_ROWS);
 
     let mut last_builds = Vec::new();
 
-    let (repo_id, repo_name, default_run_preference): (u64, String, Option<String>) = match ctx.dbctx.conn.lock().unwrap()
-        .query_row("select id, repo_name, default_run_preference from repos where repo_name=?1;", [&path], |row| Ok((row.get(0).unwrap(), row.get(1).unwrap(), row.get(2).unwrap())))
-        .optional()
-        .unwrap() {
-        Some(elem) => elem,
+    let (repo_id, repo_name, default_run_preference): (u64, String, Option<String>) = match ctx.dbctx.repo_by_name(&path)? {
+        Some(repo) => (repo.id, repo.name, repo.default_run_preference),
         None => {
             eprintln!("no repo named {}", path);
-            return (StatusCode::NOT_FOUND, Html(String::new()));
+            return Ok((StatusCode::NOT_FOUND, Html(String::new())));
         }
     };
 
     // TODO: display default_run_preference somehow on the web summary?
 
-    for remote in ctx.dbctx.remotes_by_repo(repo_id).expect("can get repo from a path") {
-        let mut last_ten_jobs = ctx.dbctx.recent_jobs_from_remote(remote.id, rows as u64).expect("can look up jobs for a repo");
-        last_builds.extend(last_ten_jobs.drain(..));
+    for remote in ctx.dbctx.remotes_by_repo(repo_id)? {
+        let mut last_ten_jobs = ctx.dbctx.recent_jobs_from_remote(remote.id, rows as u64)?;
+        last_builds.append(&mut last_ten_jobs);
     }
     last_builds.sort_by_key(|job| -(job.created_time as i64));
 
@@ -611,20 +1066,25 @@ async fn handle_repo_summary(Path(path): Path<String>, summary_params: Query<Sum
     }
     response.push_str("</tr>\n");
 
-    let mut row_num = 0;
-
-    for job in last_builds.iter().take(rows) {
-        let run = ctx.dbctx.last_run_for_job(job.id).expect("query succeeds").expect("TODO: run exists if job exists (small race if querying while creating job ....");
-        let job_commit = ctx.dbctx.commit_sha(job.commit_id).expect("job has a commit");
-        let nice_name = ctx.dbctx.nice_name_for_commit(job.commit_id).expect("can try to get a nice name");
-        let commit_html = match (ci_lib_web::job_url(&job, &job_commit, &ctx.dbctx), nice_name) {
+    for (row_num, job) in last_builds.iter().take(rows).enumerate() {
+        let run = match ctx.dbctx.last_run_for_job(job.id)? {
+            Some(run) => run,
+            None => {
+                // small race if querying while creating a job: the job exists, its run does not
+                // yet. skip it, it'll show up next time.
+                continue;
+            }
+        };
+        let job_commit = ctx.dbctx.commit_sha(job.commit_id)?;
+        let nice_name = ctx.dbctx.nice_name_for_commit(job.commit_id)?;
+        let commit_html = match (ci_lib_web::job_url(job, &job_commit, &ctx.dbctx)?, nice_name) {
             (url, Some(name)) => format!("<a href=\"{}\">{}</a> (job {}) {}", url, &job_commit[..9], job.id, name.stringy()),
             (url, None) => format!("<a href=\"{}\">{}</a> (job {})", url, &job_commit[..9], job.id),
         };
 
-        let remote_html = match ci_lib_web::commit_url(&job, &job_commit, &ctx.dbctx) {
+        let remote_html = match ci_lib_web::commit_url(job, &job_commit, &ctx.dbctx)? {
             (source, Some(url)) => format!("<a href=\"{}\">{}</a>", url, source),
-            (source, None) => format!("{}", source),
+            (source, None) => source.to_string(),
         };
 
         let last_build_time = Utc.timestamp_millis_opt(run.create_time as i64).unwrap().to_rfc2822();
@@ -632,17 +1092,9 @@ async fn handle_repo_summary(Path(path): Path<String>, summary_params: Query<Sum
 
         let status = format!("{:?}", run.state).to_lowercase();
 
-        let result = match run.build_result {
-            Some(0) => "<span style='color:green;'>pass</span>",
-            Some(_) => "<span style='color:red;'>fail</span>",
-            None => match run.state {
-                RunState::Pending => { "unstarted" },
-                RunState::Started => { "<span style='color:darkgoldenrod;'>in progress</span>" },
-                _ => { "<span style='color:red;'>unreported</span>" }
-            }
-        };
+        let result = ci_lib_web::display_run_result(&run);
 
-        let entries = [last_build_time.as_str(), commit_html.as_str(), remote_html.as_str(), &duration, &status, &result];
+        let entries = [last_build_time.as_str(), commit_html.as_str(), remote_html.as_str(), &duration, &status, result];
         let entries = entries.iter().chain(std::iter::repeat(&"")).take(headings.len());
 
         let mut row_html = String::new();
@@ -654,8 +1106,6 @@ async fn handle_repo_summary(Path(path): Path<String>, summary_params: Query<Sum
         response.push_str(&format!("<tr class=\"{}\">", ["even-row", "odd-row"][row_index]));
         response.push_str(&row_html);
         response.push_str("</tr>\n");
-
-        row_num += 1;
     }
 
     let has_metrics = false;
@@ -679,7 +1129,7 @@ async fn handle_repo_summary(Path(path): Path<String>, summary_params: Query<Sum
             };
             response.push_str("</td>");
             let job = job_for_commit(commit);
-            let metrics = summarize_job_metrics(&ctx.dbctx, run.id, run.job_id).unwrap();
+            let metrics = summarize_job_metrics(&ctx.dbctx, run.job_id, None)?;
             for metric in metric.all_names {
                 // add table row for metric name
                 // add table row for hostname under each metric
@@ -698,7 +1148,7 @@ async fn handle_repo_summary(Path(path): Path<String>, summary_params: Query<Sum
     }
     response.push_str("</html>");
 
-    (StatusCode::OK, Html(response))
+    Ok((StatusCode::OK, Html(response)))
 }
 
 async fn handle_repo_event(Path(path): Path<(String, String)>, headers: HeaderMap, State(ctx): State<WebserverState>, body: Bytes) -> impl IntoResponse {
@@ -754,7 +1204,7 @@ async fn handle_repo_event(Path(path): Path<(String, String)>, headers: HeaderMa
 }
 
 
-async fn make_app_server(server_host: String, jobs_path: PathBuf, cfg_path: &PathBuf, artifact_path: PathBuf, db_path: &PathBuf) -> Router {
+async fn make_app_server(server_host: String, jobs_path: PathBuf, cfg_path: &PathBuf, artifact_path: PathBuf, db_path: &PathBuf, admin_token: Option<String>) -> Router {
     /*
 
     // GET /hello/warp => 200 OK with body "Hello, warp!"
@@ -815,25 +1265,31 @@ async fn make_app_server(server_host: String, jobs_path: PathBuf, cfg_path: &Pat
         "post resp"
     }
 
+    let dbctx = Arc::new(DbCtx::new(cfg_path, db_path).expect("can open database"));
+    dbctx.migrate().expect("can migrate database to a usable schema");
+
     Router::new()
         .route("/:owner/:repo/:sha", get(handle_commit_status))
         .route("/:owner", get(handle_repo_summary))
         .route("/:owner/:repo", post(handle_repo_event))
         .route("/artifact/:b/:artifact_id", get(handle_get_artifact))
+        .route("/run/:run_id/cancel", post(handle_cancel_run))
+        .route("/logs/search", get(handle_log_search))
         .route("/", get(handle_ci_index))
         .fallback(fallback_get)
         .with_state(WebserverState {
             server_host,
             jobs_path,
             artifact_path,
-            dbctx: Arc::new(DbCtx::new(cfg_path, db_path))
+            admin_token,
+            dbctx,
         })
 }
 
 async fn bind_server(conf: serde_json::Value, web_config: WebserverConfig) -> std::io::Result<()> {
-    let WebserverConfig { jobs_path, config_path, db_path, artifact_path, server_host, .. } = web_config;
+    let WebserverConfig { jobs_path, config_path, db_path, artifact_path, server_host, admin_token, .. } = web_config;
 
-    let server = make_app_server(server_host, jobs_path, &config_path, artifact_path, &db_path).await.into_make_service();
+    let server = make_app_server(server_host, jobs_path, &config_path, artifact_path, &db_path, admin_token).await.into_make_service();
     use serde_json::Value;
     match conf {
         Value::String(address) => {
@@ -883,10 +1339,8 @@ async fn main() {
     args.next().expect("first arg exists");
     let config_path = args.next().unwrap_or("./webserver_config.json".to_string());
     let web_config: WebserverConfig = serde_json::from_reader(std::fs::File::open(config_path).expect("file exists and is accessible")).expect("valid json for WebserverConfig");
-    let mut psks = PSKS.write().expect("can write lock");
-    *psks = web_config.psks.clone();
-    // drop write lock so we can read PSKS elsewhere WITHOUT deadlocking.
-    std::mem::drop(psks);
+    // write lock is only a temporary, so we can read PSKS elsewhere WITHOUT deadlocking.
+    *PSKS.write().expect("can write lock") = web_config.psks.clone();
 
     let jobs_path = web_config.jobs_path.clone();
     let config_path = web_config.config_path.clone();
@@ -925,3 +1379,33 @@ impl HostDesc {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shas(shas: &[&str]) -> Vec<String> {
+        shas.iter().map(|sha| sha.to_string()).collect()
+    }
+
+    #[test]
+    fn push_ancestry_chains_pushed_commits_onto_the_old_tip() {
+        let pairs = push_ancestry("a", &shas(&["b", "c"]), false);
+        assert_eq!(pairs, vec![
+            ("b".to_string(), "a".to_string()),
+            ("c".to_string(), "b".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn push_ancestry_skips_the_old_tip_when_it_may_not_be_a_parent() {
+        let expected = vec![("c".to_string(), "b".to_string())];
+        // a new ref has no old tip.
+        assert_eq!(push_ancestry("0000000000000000000000000000000000000000", &shas(&["b", "c"]), false), expected);
+        // a force push can replace history entirely.
+        assert_eq!(push_ancestry("a", &shas(&["b", "c"]), true), expected);
+        // github may have left out commits between the old tip and the first one listed.
+        let truncated = shas(&["b"; GITHUB_PUSH_COMMIT_LIMIT]);
+        assert!(push_ancestry("a", &truncated, false).iter().all(|(_, parent)| parent != "a"));
+    }
+}